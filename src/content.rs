@@ -0,0 +1,276 @@
+use crate::data;
+use crate::message::AppCode;
+use crate::session::PlayerId;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+pub type AnnouncementId = u32;
+pub type CmsMessageId = u32;
+pub type WebContentId = u32;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Announcement {
+    pub id: AnnouncementId,
+    pub title: String,
+    pub body: String,
+    /// Unix seconds it shows up from, always if there isn't one
+    #[serde(default)]
+    pub starts: Option<i64>,
+    #[serde(default)]
+    pub ends: Option<i64>,
+}
+
+impl Announcement {
+    pub fn is_live(&self, now: DateTime<Utc>) -> bool {
+        let now = now.timestamp();
+
+        self.starts.is_none_or(|starts| now >= starts) && self.ends.is_none_or(|ends| now < ends)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CmsMessage {
+    pub id: CmsMessageId,
+    pub title: String,
+    pub body: String,
+}
+
+/// A snippet of the website shown in game, looked up by id or by its ptag
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebContent {
+    pub id: WebContentId,
+    pub ptag: String,
+    pub body: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Eula {
+    pub version: u32,
+    pub text: String,
+}
+
+/// What `data/content.json` looks like
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ContentData {
+    pub announcements: Vec<Announcement>,
+    pub cms_messages: Vec<CmsMessage>,
+    pub system_messages: Vec<String>,
+    pub web_content: Vec<WebContent>,
+    /// The newest version is the one everyone has to accept
+    pub eulas: Vec<Eula>,
+    /// `GetSiteFrame`, links and the like around the game window
+    pub site_frame: BTreeMap<String, String>,
+}
+
+/// Operator-written content. Editing the file swaps it in without a restart,
+/// what players have read and accepted stays put
+#[derive(Debug, Default)]
+pub struct Content {
+    data: ContentData,
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,
+    read: HashMap<PlayerId, BTreeSet<AnnouncementId>>,
+    accepted: HashMap<PlayerId, u32>,
+}
+
+impl Content {
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+
+        Ok(Self {
+            data: data::load(path)?,
+            path: Some(path.to_path_buf()),
+            modified: std::fs::metadata(path)?.modified().ok(),
+            ..Default::default()
+        })
+    }
+
+    pub fn from_data(data: ContentData) -> Self {
+        Self {
+            data,
+            ..Default::default()
+        }
+    }
+
+    /// Reads the file again if it changed since last time. A broken file keeps the old content
+    pub fn reload(&mut self) -> std::io::Result<bool> {
+        let Some(path) = &self.path else {
+            return Ok(false);
+        };
+
+        let modified = std::fs::metadata(path)?.modified().ok();
+        if modified == self.modified {
+            return Ok(false);
+        }
+
+        // Noted before loading, so a broken file is only tried once until it's saved again
+        self.modified = modified;
+        self.data = data::load(path)?;
+
+        Ok(true)
+    }
+
+    /// `GetAnnouncements`, the ones running right now
+    pub fn announcements(&self, now: DateTime<Utc>) -> Vec<&Announcement> {
+        self.data
+            .announcements
+            .iter()
+            .filter(|announcement| announcement.is_live(now))
+            .collect()
+    }
+
+    /// `GetAnnouncement`
+    pub fn announcement(&self, announcement: AnnouncementId) -> Result<&Announcement, AppCode> {
+        self.data
+            .announcements
+            .iter()
+            .find(|found| found.id == announcement)
+            .ok_or(AppCode::NotFound)
+    }
+
+    /// Live announcements the player hasn't read yet
+    pub fn unread_announcements(&self, player: PlayerId, now: DateTime<Utc>) -> Vec<&Announcement> {
+        let read = self.read.get(&player);
+
+        self.announcements(now)
+            .into_iter()
+            .filter(|announcement| !read.is_some_and(|read| read.contains(&announcement.id)))
+            .collect()
+    }
+
+    /// `MarkAnnouncementRead`
+    pub fn mark_announcement_read(
+        &mut self,
+        player: PlayerId,
+        announcement: AnnouncementId,
+    ) -> Result<(), AppCode> {
+        self.announcement(announcement)?;
+        self.read.entry(player).or_default().insert(announcement);
+
+        Ok(())
+    }
+
+    /// `ListCmsMessages`
+    pub fn cms_messages(&self) -> &[CmsMessage] {
+        &self.data.cms_messages
+    }
+
+    /// `GetCmsMessage`
+    pub fn cms_message(&self, message: CmsMessageId) -> Result<&CmsMessage, AppCode> {
+        self.data
+            .cms_messages
+            .iter()
+            .find(|found| found.id == message)
+            .ok_or(AppCode::NotFound)
+    }
+
+    /// `GetSystemMessages`
+    pub fn system_messages(&self) -> &[String] {
+        &self.data.system_messages
+    }
+
+    /// `GetWebContent`
+    pub fn web_content(&self, content: WebContentId) -> Result<&WebContent, AppCode> {
+        self.data
+            .web_content
+            .iter()
+            .find(|found| found.id == content)
+            .ok_or(AppCode::NotFound)
+    }
+
+    /// `GetWebContentByPtag`
+    pub fn web_content_by_ptag(&self, ptag: &str) -> Result<&WebContent, AppCode> {
+        self.data
+            .web_content
+            .iter()
+            .find(|found| found.ptag == ptag)
+            .ok_or(AppCode::NotFound)
+    }
+
+    /// `GetSiteFrame`
+    pub fn site_frame(&self) -> &BTreeMap<String, String> {
+        &self.data.site_frame
+    }
+
+    /// `GetEula`, the current one
+    pub fn eula(&self) -> Result<&Eula, AppCode> {
+        self.data
+            .eulas
+            .iter()
+            .max_by_key(|eula| eula.version)
+            .ok_or(AppCode::NotFound)
+    }
+
+    /// `AcceptEula`, only the current version can be accepted
+    pub fn accept_eula(&mut self, player: PlayerId, version: u32) -> Result<(), AppCode> {
+        if self.eula()?.version != version {
+            return Err(AppCode::Input);
+        }
+
+        self.accepted.insert(player, version);
+        Ok(())
+    }
+
+    /// Players can't play until they've accepted the current EULA. A new version
+    /// means accepting again
+    pub fn check_eula(&self, player: PlayerId) -> Result<(), AppCode> {
+        let Ok(current) = self.eula() else {
+            return Ok(());
+        };
+
+        if self.accepted.get(&player) != Some(&current.version) {
+            return Err(AppCode::Perm);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eula(version: u32) -> Eula {
+        Eula {
+            version,
+            text: String::new(),
+        }
+    }
+
+    #[test]
+    fn a_new_eula_has_to_be_accepted_again() {
+        let mut content = Content::from_data(ContentData {
+            eulas: vec![eula(1)],
+            ..Default::default()
+        });
+
+        assert_eq!(content.check_eula(1), Err(AppCode::Perm));
+        assert_eq!(content.accept_eula(1, 2), Err(AppCode::Input));
+        content.accept_eula(1, 1).unwrap();
+        assert_eq!(content.check_eula(1), Ok(()));
+
+        content.data.eulas.push(eula(2));
+        assert_eq!(content.check_eula(1), Err(AppCode::Perm));
+        assert_eq!(content.accept_eula(1, 1), Err(AppCode::Input));
+    }
+
+    #[test]
+    fn a_broken_file_is_only_read_once() {
+        let path = std::env::temp_dir().join(format!("content-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"system_messages": ["hello"]}"#).unwrap();
+        let mut content = Content::load(&path).unwrap();
+
+        std::fs::write(&path, "{").unwrap();
+        content.modified = None;
+
+        assert!(content.reload().is_err());
+        assert!(matches!(content.reload(), Ok(false)));
+        assert_eq!(content.system_messages(), ["hello"]);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
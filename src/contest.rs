@@ -0,0 +1,413 @@
+use crate::filter::{ChatMode, WordFilter};
+use crate::friends::Friends;
+use crate::home::Homes;
+use crate::message::AppCode;
+use crate::session::PlayerId;
+use chrono::{DateTime, Utc};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+pub type ContestId = u64;
+pub type EntryId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContestStatus {
+    Open,
+    Completed,
+}
+
+/// Something a player put up to be voted on, a home or a maze or the like
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContestEntry {
+    pub id: EntryId,
+    pub player: PlayerId,
+    /// The entrant's home location, what's voted on is the home or the maze in it
+    pub instance: u64,
+    pub votes: u32,
+}
+
+/// A contest players make and everyone else votes in
+#[derive(Debug, Clone, PartialEq)]
+pub struct Contest {
+    pub id: ContestId,
+    pub creator: PlayerId,
+    pub title: String,
+    pub closes_at: DateTime<Utc>,
+    pub status: ContestStatus,
+    pub entries: BTreeMap<EntryId, ContestEntry>,
+    /// Most votes first, the earlier entry wins a tie. Kept up to date with every vote
+    ranking: BTreeSet<(Reverse<u32>, EntryId)>,
+    voted: HashSet<(PlayerId, EntryId)>,
+}
+
+impl Contest {
+    fn is_open(&self, now: DateTime<Utc>) -> bool {
+        self.status == ContestStatus::Open && now < self.closes_at
+    }
+
+    fn ranked(&self) -> impl Iterator<Item = &ContestEntry> {
+        self.ranking
+            .iter()
+            .filter_map(|(_, entry)| self.entries.get(entry))
+    }
+}
+
+/// `GetPlayerVotedData`, one entry as a particular player sees it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryInfo {
+    pub entry: EntryId,
+    pub player: PlayerId,
+    pub instance: u64,
+    pub votes: u32,
+    pub rank: usize,
+    pub voted: bool,
+}
+
+/// A line on a leaderboard, ranks start at 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LeaderboardRow {
+    pub rank: usize,
+    pub entry: EntryId,
+    pub player: PlayerId,
+    pub votes: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct Contests {
+    contests: BTreeMap<ContestId, Contest>,
+    next_id: ContestId,
+    next_entry_id: EntryId,
+}
+
+impl Contests {
+    /// `CreatePlayerVoted`
+    pub fn create_player_voted(
+        &mut self,
+        creator: PlayerId,
+        title: &str,
+        closes_at: DateTime<Utc>,
+        filter: &WordFilter,
+        now: DateTime<Utc>,
+    ) -> Result<ContestId, AppCode> {
+        if title.trim().is_empty() {
+            return Err(AppCode::NameCannotBeEmpty);
+        }
+
+        if closes_at <= now {
+            return Err(AppCode::Input);
+        }
+
+        filter.check_chat(title, ChatMode::Open)?;
+
+        self.next_id += 1;
+        self.contests.insert(
+            self.next_id,
+            Contest {
+                id: self.next_id,
+                creator,
+                title: title.trim().to_string(),
+                closes_at,
+                status: ContestStatus::Open,
+                entries: BTreeMap::new(),
+                ranking: BTreeSet::new(),
+                voted: HashSet::new(),
+            },
+        );
+
+        Ok(self.next_id)
+    }
+
+    /// Put something up in an open contest, one entry per player and only from
+    /// their own home
+    pub fn enter_player_voted(
+        &mut self,
+        player: PlayerId,
+        contest: ContestId,
+        instance: u64,
+        now: DateTime<Utc>,
+    ) -> Result<EntryId, AppCode> {
+        if Homes::owner_of(instance) != Some(player) {
+            return Err(AppCode::Perm);
+        }
+
+        let id = self.next_entry_id + 1;
+        let contest = self.open_contest(contest, now)?;
+
+        if contest.entries.values().any(|entry| entry.player == player) {
+            return Err(AppCode::DupRequest);
+        }
+
+        contest.entries.insert(
+            id,
+            ContestEntry {
+                id,
+                player,
+                instance,
+                votes: 0,
+            },
+        );
+        contest.ranking.insert((Reverse(0), id));
+        self.next_entry_id = id;
+
+        Ok(id)
+    }
+
+    /// `WithdrawInstanceForVoted`, the entry and its votes are gone
+    pub fn withdraw_instance_for_voted(
+        &mut self,
+        player: PlayerId,
+        contest: ContestId,
+        entry: EntryId,
+        now: DateTime<Utc>,
+    ) -> Result<(), AppCode> {
+        let contest = self.open_contest(contest, now)?;
+
+        match contest.entries.get(&entry) {
+            Some(found) if found.player == player => {}
+            Some(_) => return Err(AppCode::Perm),
+            None => return Err(AppCode::NotFound),
+        }
+
+        if let Some(removed) = contest.entries.remove(&entry) {
+            contest.ranking.remove(&(Reverse(removed.votes), entry));
+        }
+        contest.voted.retain(|&(_, voted)| voted != entry);
+
+        Ok(())
+    }
+
+    /// `VoteOnPlayerVoted`, one vote per player per entry and never for your own
+    pub fn vote_on_player_voted(
+        &mut self,
+        voter: PlayerId,
+        contest: ContestId,
+        entry: EntryId,
+        now: DateTime<Utc>,
+    ) -> Result<u32, AppCode> {
+        let contest = self.open_contest(contest, now)?;
+        let found = contest.entries.get_mut(&entry).ok_or(AppCode::NotFound)?;
+
+        if found.player == voter {
+            return Err(AppCode::Perm);
+        }
+
+        if !contest.voted.insert((voter, entry)) {
+            return Err(AppCode::DupRequest);
+        }
+
+        contest.ranking.remove(&(Reverse(found.votes), entry));
+        found.votes += 1;
+        contest.ranking.insert((Reverse(found.votes), entry));
+
+        Ok(found.votes)
+    }
+
+    /// `CompletePlayerVoted`, the creator closing it early
+    pub fn complete_player_voted(
+        &mut self,
+        player: PlayerId,
+        contest: ContestId,
+    ) -> Result<(), AppCode> {
+        let contest = self.contests.get_mut(&contest).ok_or(AppCode::NotFound)?;
+
+        if contest.creator != player {
+            return Err(AppCode::Perm);
+        }
+
+        if contest.status == ContestStatus::Completed {
+            return Err(AppCode::State);
+        }
+
+        contest.status = ContestStatus::Completed;
+        Ok(())
+    }
+
+    /// Completes whatever ran out of time, returns the ones that just closed
+    pub fn close_contests(&mut self, now: DateTime<Utc>) -> Vec<ContestId> {
+        self.contests
+            .values_mut()
+            .filter(|contest| contest.status == ContestStatus::Open && now >= contest.closes_at)
+            .map(|contest| {
+                contest.status = ContestStatus::Completed;
+                contest.id
+            })
+            .collect()
+    }
+
+    /// `GetPlayerVoted`
+    pub fn player_voted(&self, contest: ContestId) -> Result<&Contest, AppCode> {
+        self.contests.get(&contest).ok_or(AppCode::NotFound)
+    }
+
+    /// `GetPlayerVotedList`, the ones still taking votes
+    pub fn player_voted_list(&self, now: DateTime<Utc>) -> Vec<&Contest> {
+        self.contests
+            .values()
+            .filter(|contest| contest.is_open(now))
+            .collect()
+    }
+
+    /// `GetPlayerVotedData`
+    pub fn player_voted_data(
+        &self,
+        player: PlayerId,
+        contest: ContestId,
+        entry: EntryId,
+    ) -> Result<EntryInfo, AppCode> {
+        let contest = self.player_voted(contest)?;
+        let found = contest.entries.get(&entry).ok_or(AppCode::NotFound)?;
+        let rank = contest
+            .ranking
+            .range(..(Reverse(found.votes), entry))
+            .count()
+            + 1;
+
+        Ok(EntryInfo {
+            entry,
+            player: found.player,
+            instance: found.instance,
+            votes: found.votes,
+            rank,
+            voted: contest.voted.contains(&(player, entry)),
+        })
+    }
+
+    /// `GetLeaderBoardInfoForPlayerVoted`, the top `limit` entries
+    pub fn leaderboard(
+        &self,
+        contest: ContestId,
+        limit: usize,
+    ) -> Result<Vec<LeaderboardRow>, AppCode> {
+        let contest = self.player_voted(contest)?;
+
+        Ok(Self::rows(contest.ranked().take(limit)))
+    }
+
+    /// `GetFriendsPlayerVoteds`, the leaderboard with only the player and their friends on it
+    pub fn friends_leaderboard(
+        &self,
+        player: PlayerId,
+        contest: ContestId,
+        friends: &Friends,
+    ) -> Result<Vec<LeaderboardRow>, AppCode> {
+        let contest = self.player_voted(contest)?;
+
+        Ok(Self::rows(contest.ranked().filter(|entry| {
+            entry.player == player || friends.are_friends(player, entry.player)
+        })))
+    }
+
+    fn rows<'a>(entries: impl Iterator<Item = &'a ContestEntry>) -> Vec<LeaderboardRow> {
+        entries
+            .enumerate()
+            .map(|(index, entry)| LeaderboardRow {
+                rank: index + 1,
+                entry: entry.id,
+                player: entry.player,
+                votes: entry.votes,
+            })
+            .collect()
+    }
+
+    fn open_contest(
+        &mut self,
+        contest: ContestId,
+        now: DateTime<Utc>,
+    ) -> Result<&mut Contest, AppCode> {
+        let contest = self.contests.get_mut(&contest).ok_or(AppCode::NotFound)?;
+
+        if !contest.is_open(now) {
+            return Err(AppCode::State);
+        }
+
+        Ok(contest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(day: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, day, 12, 0, 0).unwrap()
+    }
+
+    fn home(player: PlayerId) -> u64 {
+        Homes::location(player).unwrap()
+    }
+
+    fn contest(contests: &mut Contests) -> ContestId {
+        contests
+            .create_player_voted(9, "Best home", at(10), &WordFilter::default(), at(1))
+            .unwrap()
+    }
+
+    #[test]
+    fn players_only_enter_their_own_home() {
+        let mut contests = Contests::default();
+        let first = contest(&mut contests);
+        let second = contest(&mut contests);
+
+        assert_eq!(
+            contests.enter_player_voted(1, first, home(2), at(1)),
+            Err(AppCode::Perm)
+        );
+        assert_eq!(
+            contests.enter_player_voted(1, first, 5, at(1)),
+            Err(AppCode::Perm)
+        );
+
+        assert_eq!(contests.enter_player_voted(1, first, home(1), at(1)), Ok(1));
+        assert_eq!(
+            contests.enter_player_voted(1, first, home(1), at(1)),
+            Err(AppCode::DupRequest)
+        );
+        assert_eq!(
+            contests.enter_player_voted(1, second, home(1), at(1)),
+            Ok(2)
+        );
+        assert_eq!(contest(&mut contests), 3);
+    }
+
+    #[test]
+    fn one_vote_per_entry_and_none_after_closing() {
+        let mut contests = Contests::default();
+        let mut friends = Friends::default();
+        let id = contest(&mut contests);
+        let mine = contests.enter_player_voted(1, id, home(1), at(1)).unwrap();
+        let theirs = contests.enter_player_voted(2, id, home(2), at(1)).unwrap();
+
+        assert_eq!(
+            contests.vote_on_player_voted(1, id, mine, at(2)),
+            Err(AppCode::Perm)
+        );
+        assert_eq!(contests.vote_on_player_voted(3, id, theirs, at(2)), Ok(1));
+        assert_eq!(
+            contests.vote_on_player_voted(3, id, theirs, at(2)),
+            Err(AppCode::DupRequest)
+        );
+
+        let top = contests.leaderboard(id, 10).unwrap();
+        assert_eq!(top.iter().map(|row| row.player).collect::<Vec<_>>(), [2, 1]);
+        assert_eq!(contests.player_voted_data(3, id, mine).unwrap().rank, 2);
+        assert!(contests.player_voted_data(3, id, theirs).unwrap().voted);
+
+        friends.add(1, 3);
+        let friendly = contests.friends_leaderboard(3, id, &friends).unwrap();
+        assert_eq!(
+            friendly
+                .iter()
+                .map(|row| (row.rank, row.player))
+                .collect::<Vec<_>>(),
+            [(1, 1)]
+        );
+
+        assert!(contests.close_contests(at(9)).is_empty());
+        assert_eq!(contests.close_contests(at(10)), [id]);
+        assert_eq!(
+            contests.vote_on_player_voted(4, id, mine, at(10)),
+            Err(AppCode::State)
+        );
+    }
+}
@@ -1,7 +1,35 @@
-use crate::message::{decode_message, MessageType};
+use crate::content::Content;
+use crate::contest::Contests;
+use crate::crafting::Crafting;
+use crate::currency::Wallets;
+use crate::daily_award::DailyAwards;
+use crate::filter::{ChatMode, WordFilter};
+use crate::friends::Friends;
+use crate::garden::Garden;
+use crate::gift::{GiftId, Gifts};
+use crate::home::Homes;
+use crate::inventory::{Inventory, InventoryItemId, ItemCatalog};
+use crate::mail::Mailbox;
+use crate::message::{decode_message, encode_message, AppCode, MessageType};
+use crate::minigame::{GameId, GameOfferId, Minigames};
+use crate::notification::{self, Notifications};
+use crate::npc::NpcRuntime;
+use crate::outfit::Wardrobe;
+use crate::progression::Progression;
+use crate::quest::{QuestEngine, QuestId};
+use crate::rules::{AwardId, RulesEngine};
+use crate::session::{ClientEvent, Outbox, PlayerId};
+use crate::shard::{ServerConfig, ServerId, SessionHandoff, Shard, VillageSnapshot};
+use crate::shared_quest::{HostedQuestId, SharedQuests};
+use crate::social::SocialSignals;
+use crate::store::UserStores;
+use crate::surprise::Surprises;
+use crate::village::{Allocation, TemplateId, Villages};
+use crate::world::{LocationId, Position, SyncRequest, World};
+use chrono::Utc;
 use std::collections::HashMap;
-use std::net::IpAddr;
-use std::time::Duration;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::time::timeout;
@@ -10,18 +38,599 @@ use tokio::time::timeout;
 pub struct AmazingWorldServer {
     listener: TcpListener,
     socket: Vec<TcpStream>,
+    /// Which connection each player's events go out on
+    sessions: HashMap<PlayerId, SocketAddr>,
     message_handlers: HashMap<MessageType, fn()>,
+    pub filter: WordFilter,
+    pub content: Content,
+    pub contests: Contests,
+    pub world: World,
+    pub outbox: Outbox,
+    pub shard: Shard,
+    pub npcs: NpcRuntime,
+    pub items: ItemCatalog,
+    pub inventory: Inventory,
+    pub outfits: Wardrobe,
+    pub homes: Homes,
+    pub garden: Garden,
+    pub villages: Villages,
+    pub wallets: Wallets,
+    pub stores: UserStores,
+    pub surprises: Surprises,
+    pub games: Minigames,
+    pub gifts: Gifts,
+    pub mail: Mailbox,
+    pub notifications: Notifications,
+    pub quests: QuestEngine,
+    pub friends: Friends,
+    pub shared_quests: SharedQuests,
+    pub social: SocialSignals,
+    pub rules: RulesEngine,
+    pub progression: Progression,
+    pub daily_awards: DailyAwards,
+    pub crafting: Crafting,
+    last_tick: Instant,
 }
 
 impl AmazingWorldServer {
     pub async fn new(binding_address: IpAddr) -> Self {
-        let me = Self {
-            listener: TcpListener::bind((binding_address, 8182)).await.unwrap(),
+        Self::start(ServerConfig {
+            address: binding_address,
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn start(config: ServerConfig) -> std::io::Result<Self> {
+        let listener = TcpListener::bind((config.address, config.client_port)).await?;
+        let shard = Shard::start(&config, listener.local_addr()?).await?;
+
+        let mut me = Self {
+            listener,
             socket: Vec::new(),
+            sessions: HashMap::new(),
             message_handlers: HashMap::new(),
+            filter: WordFilter::load("data/filter.json").unwrap_or_else(|e| {
+                log::warn!("Could not load the word filter: {}", e);
+                WordFilter::default()
+            }),
+            content: Content::load("data/content.json").unwrap_or_else(|e| {
+                log::warn!("Could not load content: {}", e);
+                Content::default()
+            }),
+            contests: Contests::default(),
+            world: World::default(),
+            outbox: Outbox::default(),
+            shard,
+            npcs: NpcRuntime::load("data/npcs.json").unwrap_or_else(|e| {
+                log::warn!("Could not load NPCs: {}", e);
+                NpcRuntime::default()
+            }),
+            items: ItemCatalog::load("data/items.json").unwrap_or_else(|e| {
+                log::warn!("Could not load the item catalog: {}", e);
+                ItemCatalog::default()
+            }),
+            inventory: Inventory::default(),
+            outfits: Wardrobe::default(),
+            homes: Homes::load("data/homes.json").unwrap_or_else(|e| {
+                log::warn!("Could not load home themes: {}", e);
+                Homes::default()
+            }),
+            garden: Garden::load("data/crops.json").unwrap_or_else(|e| {
+                log::warn!("Could not load crops: {}", e);
+                Garden::default()
+            }),
+            villages: Villages::load("data/villages.json").unwrap_or_else(|e| {
+                log::warn!("Could not load village templates: {}", e);
+                Villages::default()
+            }),
+            wallets: Wallets::default(),
+            stores: UserStores::default(),
+            surprises: Surprises::load("data/surprises.json").unwrap_or_else(|e| {
+                log::warn!("Could not load surprises: {}", e);
+                Surprises::default()
+            }),
+            games: Minigames::load("data/games.json").unwrap_or_else(|e| {
+                log::warn!("Could not load minigames: {}", e);
+                Minigames::default()
+            }),
+            gifts: Gifts::default(),
+            notifications: Notifications::load("data/notifications.json").unwrap_or_else(|e| {
+                log::warn!("Could not load notification categories: {}", e);
+                Notifications::default()
+            }),
+            mail: Mailbox::open("save/mail.json").unwrap_or_else(|e| {
+                log::warn!("Could not load saved mail: {}", e);
+                Mailbox::default()
+            }),
+            quests: QuestEngine::load("data/quests.json").unwrap_or_else(|e| {
+                log::warn!("Could not load quests: {}", e);
+                QuestEngine::default()
+            }),
+            friends: Friends::default(),
+            shared_quests: SharedQuests::default(),
+            social: SocialSignals::default(),
+            rules: RulesEngine::load("data/rules.json").unwrap_or_else(|e| {
+                log::warn!("Could not load rules: {}", e);
+                RulesEngine::default()
+            }),
+            progression: Progression::load("data/progression.json").unwrap_or_else(|e| {
+                log::warn!("Could not load progression: {}", e);
+                Progression::default()
+            }),
+            daily_awards: DailyAwards::load("data/daily_awards.json").unwrap_or_else(|e| {
+                log::warn!("Could not load daily awards: {}", e);
+                DailyAwards::default()
+            }),
+            crafting: Crafting::load("data/crafting.json").unwrap_or_else(|e| {
+                log::warn!("Could not load crafting recipes: {}", e);
+                Crafting::default()
+            }),
+            last_tick: Instant::now(),
         };
 
-        me
+        me.world.set_server(config.id);
+        me.villages.set_server(config.id, config.max_villages);
+
+        for &village in config.villages.iter() {
+            me.world.add_location(village);
+            me.villages.open(village);
+            me.npcs
+                .start_npcs(village, &mut me.world, &mut me.outbox)
+                .unwrap();
+        }
+
+        Ok(me)
+    }
+
+    pub fn client_address(&self) -> SocketAddr {
+        self.listener.local_addr().unwrap()
+    }
+
+    /// Make room in the world for villages other servers handed to us
+    pub fn sync_shard(&mut self) {
+        for (village, snapshot) in self.shard.take_arrived_villages() {
+            self.world.add_location(village);
+
+            if let Some(state) = snapshot.village {
+                self.villages.adopt(state);
+            }
+            self.villages.open(village);
+
+            if let Err(e) = self
+                .world
+                .restore_objects(village, snapshot.objects, &mut self.outbox)
+            {
+                log::warn!("Some objects in village {} didn't fit: {:?}", village, e);
+            }
+
+            let _ = self
+                .npcs
+                .start_npcs(village, &mut self.world, &mut self.outbox);
+        }
+    }
+
+    /// A player asking to go somewhere
+    pub async fn enter_village(
+        &mut self,
+        player: PlayerId,
+        village: LocationId,
+        position: Position,
+    ) -> Result<(), AppCode> {
+        self.hand_off_player(player, village, position).await
+    }
+
+    /// Move a player into a village, sending them to whichever server owns it.
+    /// Nobody gets in before accepting the current EULA
+    pub async fn hand_off_player(
+        &mut self,
+        player: PlayerId,
+        village: LocationId,
+        position: Position,
+    ) -> Result<(), AppCode> {
+        self.content.check_eula(player)?;
+        self.move_player(player, village, position).await
+    }
+
+    /// `hand_off_player` without the EULA check, for players who are already in
+    async fn move_player(
+        &mut self,
+        player: PlayerId,
+        village: LocationId,
+        position: Position,
+    ) -> Result<(), AppCode> {
+        if self.shard.owns(village) {
+            return self
+                .world
+                .enter_loc(player, village, position, &mut self.outbox);
+        }
+
+        let (server, token) = self
+            .shard
+            .hand_off_session(SessionHandoff {
+                player,
+                village,
+                position,
+                eula: self
+                    .content
+                    .check_eula(player)
+                    .and_then(|_| self.content.eula())
+                    .ok()
+                    .map(|eula| eula.version),
+            })
+            .await?;
+
+        let _ = self.world.exit_loc(player, &mut self.outbox);
+        self.outbox.push(
+            player,
+            ClientEvent::ChangeServer {
+                address: server.client_address,
+                token,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// A handed off client reconnected here with its token. The EULA they accepted
+    /// comes along, but it still has to be the one this server has
+    pub fn claim_handoff(&mut self, token: u64) -> Result<PlayerId, AppCode> {
+        self.sync_shard();
+
+        let handoff = self.shard.claim(token).ok_or(AppCode::InvalidToken)?;
+        if let Some(version) = handoff.eula {
+            let _ = self.content.accept_eula(handoff.player, version);
+        }
+        self.content.check_eula(handoff.player)?;
+
+        self.world.enter_loc(
+            handoff.player,
+            handoff.village,
+            handoff.position,
+            &mut self.outbox,
+        )?;
+
+        Ok(handoff.player)
+    }
+
+    /// Give a whole village to another server, taking everyone and everything inside along with it
+    pub async fn hand_off_village(
+        &mut self,
+        village: LocationId,
+        to: ServerId,
+    ) -> Result<(), AppCode> {
+        // The new owner starts its own NPCs, theirs shouldn't come along
+        self.npcs
+            .stop_npcs(village, &mut self.world, &mut self.outbox);
+
+        let snapshot = VillageSnapshot {
+            objects: self
+                .world
+                .location(village)
+                .map(|location| location.objects.values().cloned().collect())
+                .unwrap_or_default(),
+            village: self.villages.village(village).ok().cloned(),
+        };
+
+        if let Err(e) = self.shard.hand_off_village(village, to, snapshot).await {
+            let _ = self
+                .npcs
+                .start_npcs(village, &mut self.world, &mut self.outbox);
+            return Err(e);
+        }
+
+        let players: Vec<(PlayerId, Position)> = self
+            .world
+            .location(village)
+            .map(|location| {
+                location
+                    .players
+                    .iter()
+                    .map(|(&player, &position)| (player, position))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        for (player, position) in players {
+            if let Err(e) = self.move_player(player, village, position).await {
+                log::warn!(
+                    "Could not move player {} with village {}: {:?}",
+                    player,
+                    village,
+                    e
+                );
+            }
+        }
+
+        self.world.remove_location(village, &mut self.outbox);
+        self.villages.close(village);
+
+        Ok(())
+    }
+
+    /// `AllocatePlayerVillage`, binding the village if a new one had to be opened
+    pub async fn allocate_player_village(
+        &mut self,
+        player: PlayerId,
+    ) -> Result<Allocation, AppCode> {
+        let allocation = self
+            .villages
+            .allocate_player_village(player, &mut self.world)?;
+
+        if allocation.created {
+            self.shard.bind(allocation.village).await;
+        }
+
+        Ok(allocation)
+    }
+
+    /// `CreatePrivateVillage`, templates unlock with the player's level
+    pub async fn create_private_village(
+        &mut self,
+        player: PlayerId,
+        name: &str,
+        template: TemplateId,
+    ) -> Result<LocationId, AppCode> {
+        let village = self.villages.create_private_village(
+            player,
+            name,
+            template,
+            self.progression.level(player),
+            &self.filter,
+            &mut self.world,
+        )?;
+
+        self.shard.bind(village).await;
+        Ok(village)
+    }
+
+    /// `ValidateName`, asked before a name gets used for anything
+    pub fn validate_name(&self, name: &str) -> Result<(), AppCode> {
+        self.filter.validate_name(name)
+    }
+
+    /// `CheckUsername`, same as a name but all one word
+    pub fn check_username(&self, username: &str) -> Result<(), AppCode> {
+        self.filter.check_username(username)
+    }
+
+    /// Friends hear about it in their notifications
+    pub fn add_friend(&mut self, player: PlayerId, friend: PlayerId) {
+        if player == friend || self.friends.are_friends(player, friend) {
+            return;
+        }
+
+        self.friends.add(player, friend);
+
+        for (to, from) in [(player, friend), (friend, player)] {
+            self.notify(to, notification::FRIENDS, "You have a new friend", from);
+        }
+    }
+
+    /// `AddToPlayerStat`, then runs the rules against the new stats and hands out
+    /// whatever they award
+    pub fn add_to_player_stat(
+        &mut self,
+        player: PlayerId,
+        stat: &str,
+        delta: i64,
+    ) -> Result<Vec<AwardId>, AppCode> {
+        self.progression.add_to_player_stat(
+            player,
+            stat,
+            delta,
+            &mut self.inventory,
+            &mut self.wallets,
+            Utc::now(),
+        )?;
+
+        let stats = self.progression.player_stats(player);
+        self.rules.evaluate(player, &stats);
+
+        Ok(self.rules.deliver_awards(
+            player,
+            &mut self.inventory,
+            &mut self.wallets,
+            &mut self.outbox,
+        ))
+    }
+
+    /// `SendQuestInvite`, with a notification for the invitee
+    pub fn send_quest_invite(
+        &mut self,
+        host: PlayerId,
+        quest: QuestId,
+        invitee: PlayerId,
+    ) -> Result<HostedQuestId, AppCode> {
+        let hosted = self.shared_quests.send_quest_invite(
+            host,
+            quest,
+            invitee,
+            &self.friends,
+            &self.quests,
+        )?;
+
+        self.notify(
+            invitee,
+            notification::QUESTS,
+            "You were invited on a quest",
+            host,
+        );
+        Ok(hosted)
+    }
+
+    /// Sends a gift with a notification for the recipient
+    pub fn send_gift(
+        &mut self,
+        sender: PlayerId,
+        recipient: PlayerId,
+        items: &[InventoryItemId],
+        message: &str,
+    ) -> Result<GiftId, AppCode> {
+        let gift = self.gifts.send_gift(
+            sender,
+            recipient,
+            items,
+            message,
+            &mut self.inventory,
+            &self.world,
+            &mut self.outbox,
+            Utc::now(),
+        )?;
+
+        self.notify(
+            recipient,
+            notification::GIFTS,
+            "You were sent a gift",
+            sender,
+        );
+        Ok(gift)
+    }
+
+    /// `SendGame`, with a notification for the friend
+    pub fn send_game(
+        &mut self,
+        from: PlayerId,
+        to: PlayerId,
+        game: GameId,
+    ) -> Result<GameOfferId, AppCode> {
+        let offer = self
+            .games
+            .send_game(from, to, game, &self.friends, Utc::now())?;
+
+        self.notify(to, notification::FRIENDS, "A friend sent you a game", from);
+        Ok(offer)
+    }
+
+    /// `SendVillageInvite`, with a notification for the invitee
+    pub fn send_village_invite(
+        &mut self,
+        from: PlayerId,
+        village: LocationId,
+        to: PlayerId,
+    ) -> Result<(), AppCode> {
+        self.villages
+            .send_village_invite(from, village, to, &mut self.outbox)?;
+
+        self.notify(
+            to,
+            notification::VILLAGES,
+            "You were invited to a village",
+            from,
+        );
+        Ok(())
+    }
+
+    fn notify(
+        &mut self,
+        player: PlayerId,
+        category: notification::CategoryId,
+        text: &str,
+        from: PlayerId,
+    ) {
+        let params = [("player".to_string(), from.to_string())].into();
+
+        self.notifications.publish(
+            player,
+            category,
+            text,
+            params,
+            &self.world,
+            &mut self.outbox,
+            Utc::now(),
+        );
+    }
+
+    /// Anything a client asks of the world, once they've accepted the current EULA.
+    /// Entering only works for villages this server owns, homes go through
+    /// `enter_building` and other servers' villages through `enter_village`
+    pub fn world_request(&mut self, player: PlayerId, request: SyncRequest) -> Result<(), AppCode> {
+        self.content.check_eula(player)?;
+
+        if let SyncRequest::EnterLoc { location, .. } = request {
+            if Homes::owner_of(location).is_some() || !self.shard.owns(location) {
+                return Err(AppCode::Perm);
+            }
+        }
+
+        self.world.apply(player, request, &mut self.outbox)
+    }
+
+    /// `EnterBuilding`, into somebody's home if they let the player in
+    pub fn enter_building(
+        &mut self,
+        player: PlayerId,
+        owner: PlayerId,
+        position: Position,
+    ) -> Result<(), AppCode> {
+        self.content.check_eula(player)?;
+        self.homes
+            .enter_building(player, owner, position, &mut self.world, &mut self.outbox)
+    }
+
+    /// Chat in the player's location, once they've accepted the current EULA
+    pub fn chat(&mut self, player: PlayerId, text: &str, mode: ChatMode) -> Result<(), AppCode> {
+        self.content.check_eula(player)?;
+        self.world
+            .chat(player, text, mode, &self.filter, &mut self.outbox)
+    }
+
+    /// From now on the player's events go out on the connection from `address`.
+    /// The EULA is accepted at login, before there's a session to attach
+    pub fn attach_session(&mut self, player: PlayerId, address: SocketAddr) -> Result<(), AppCode> {
+        self.content.check_eula(player)?;
+        self.sessions.insert(player, address);
+
+        Ok(())
+    }
+
+    /// Writes out everything queued for players with a session here. Events for
+    /// anyone else are dropped, nobody would ever read them
+    async fn flush_outbox(&mut self) {
+        for (player, events) in self.outbox.drain_all() {
+            let Some(address) = self.sessions.get(&player).copied() else {
+                continue;
+            };
+
+            let Some(socket) = self
+                .socket
+                .iter_mut()
+                .find(|socket| socket.peer_addr().ok() == Some(address))
+            else {
+                continue;
+            };
+
+            for event in events {
+                if socket
+                    .write_all(&encode_message(&event.message(), 0, 0))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Players whose connection went away leave the world
+    fn close_sockets(&mut self, closed: Vec<usize>) {
+        for index in closed.into_iter().rev() {
+            let socket = self.socket.remove(index);
+            let address = socket.peer_addr().ok();
+
+            let players: Vec<PlayerId> = self
+                .sessions
+                .iter()
+                .filter(|(_, session)| Some(**session) == address)
+                .map(|(&player, _)| player)
+                .collect();
+
+            for player in players {
+                self.sessions.remove(&player);
+                let _ = self.world.exit_loc(player, &mut self.outbox);
+            }
+        }
     }
 
     pub fn register_message_handler(&mut self, message: MessageType, handler: fn()) {
@@ -29,20 +638,53 @@ impl AmazingWorldServer {
     }
 
     pub async fn poll(&mut self) {
+        self.sync_shard();
+
+        let now = Instant::now();
+        self.npcs.tick(
+            (now - self.last_tick).as_secs_f32(),
+            &mut self.world,
+            &mut self.outbox,
+        );
+        self.last_tick = now;
+        self.homes
+            .close_empty_homes(&mut self.world, &mut self.outbox);
+        if let Err(e) = self.content.reload() {
+            log::warn!("Could not reload content: {}", e);
+        }
+
+        let today = Utc::now();
+        self.garden.tick(
+            &mut self.inventory,
+            &mut self.world,
+            &mut self.outbox,
+            today,
+        );
+        self.gifts
+            .expire_gifts(&mut self.inventory, &self.world, &mut self.outbox, today);
+        self.contests.close_contests(today);
+        self.shared_quests.expire_transactions(today);
+        self.mail.flush();
+        self.outfits
+            .take_off_missing(&self.inventory, &self.world, &mut self.outbox);
+
         let mut buf = [0; u8::MAX as usize];
 
-        if let Ok(result) = timeout(Duration::from_secs(1), self.listener.accept()).await {
-            if let Ok((stream, _)) = result {
-                self.socket.push(stream);
-            }
+        if let Ok(Ok((stream, _))) = timeout(Duration::from_secs(1), self.listener.accept()).await {
+            self.socket.push(stream);
         }
 
-        for socket in self.socket.iter_mut() {
+        let mut closed = Vec::new();
+
+        for (index, socket) in self.socket.iter_mut().enumerate() {
             let n = match socket.read(&mut buf).await {
                 // socket closed
-                Ok(n) if n == 0 => continue,
+                Ok(0) => {
+                    closed.push(index);
+                    continue;
+                }
                 Ok(n) => n,
-                Err(e) => {
+                Err(_) => {
                     continue;
                 }
             };
@@ -50,10 +692,73 @@ impl AmazingWorldServer {
             let message = decode_message(&buf[0..n]);
             log::info!("{:?}", message);
 
+            if let Some(handler) = message
+                .as_ref()
+                .and_then(|message| self.message_handlers.get(&message.message_type))
+            {
+                handler();
+            }
+
             // Write the data back
-            if let Err(e) = socket.write_all(&buf[0..n]).await {
+            if socket.write_all(&buf[0..n]).await.is_err() {
                 continue;
             }
         }
+
+        self.close_sockets(closed);
+        self.flush_outbox().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    async fn server(villages: Vec<LocationId>) -> AmazingWorldServer {
+        let mut server = AmazingWorldServer::start(ServerConfig {
+            id: 1,
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            client_port: 0,
+            peer_port: 0,
+            villages,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        let eula = server.content.eula().unwrap().version;
+        for player in [1, 2] {
+            server.content.accept_eula(player, eula).unwrap();
+        }
+        server
+    }
+
+    #[tokio::test]
+    async fn evicted_guests_cannot_walk_back_in() {
+        let mut server = server(vec![100]).await;
+        let home = Homes::location(1).unwrap();
+        let enter = |location| SyncRequest::EnterLoc {
+            location,
+            position: Position::default(),
+        };
+
+        server.enter_building(1, 1, Position::default()).unwrap();
+        server.enter_building(2, 1, Position::default()).unwrap();
+        assert_eq!(
+            server
+                .homes
+                .lock_home(1, true, &mut server.world, &mut server.outbox),
+            [2]
+        );
+
+        assert_eq!(server.world_request(2, enter(home)), Err(AppCode::Perm));
+        assert_eq!(
+            server.enter_building(2, 1, Position::default()),
+            Err(AppCode::Perm)
+        );
+        assert_eq!(server.world_request(2, enter(300)), Err(AppCode::Perm));
+        assert_eq!(server.world_request(2, enter(100)), Ok(()));
+        assert_ne!(server.world.player_location(2), Some(home));
     }
 }
@@ -0,0 +1,984 @@
+use crate::currency::{Amount, Wallets};
+use crate::data;
+use crate::inventory::{Inventory, InventoryItem, InventoryItemId, ItemId, ItemStack, Links};
+use crate::message::AppCode;
+use crate::session::PlayerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+
+pub type RecipeId = u64;
+pub type CraftableTypeId = u32;
+pub type BraceletId = u64;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CraftableType {
+    pub id: CraftableTypeId,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecipeSlot {
+    pub name: String,
+    /// Part kinds that fit here
+    pub accepts: Vec<String>,
+    #[serde(default)]
+    pub optional: bool,
+}
+
+/// A craftable item. Recipes with a `parent` build on top of an item the player
+/// already has, filling its slots with parts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Recipe {
+    #[serde(default)]
+    pub id: RecipeId,
+    pub name: String,
+    pub kind: CraftableTypeId,
+    #[serde(default)]
+    pub parent: Option<ItemId>,
+    #[serde(default)]
+    pub slots: Vec<RecipeSlot>,
+    #[serde(default)]
+    pub ingredients: Vec<ItemStack>,
+    #[serde(default)]
+    pub cost: Vec<Amount>,
+    pub output: ItemStack,
+}
+
+/// An item that can go into a recipe slot
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Part {
+    pub item: ItemId,
+    pub kind: String,
+}
+
+/// What `data/crafting.json` looks like
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CraftingData {
+    pub types: Vec<CraftableType>,
+    pub recipes: Vec<Recipe>,
+    pub parts: Vec<Part>,
+    /// Which craftable type is a bracelet
+    pub bracelet_type: Option<CraftableTypeId>,
+}
+
+/// A bracelet base that's been taken out of the inventory while the player picks charms
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingBracelet {
+    pub id: BraceletId,
+    pub owner: PlayerId,
+    pub recipe: RecipeId,
+    pub base: InventoryItem,
+    /// Whatever was attached to the base, it goes back on with it
+    pub links: Links,
+}
+
+/// Everything taken from a player so far, so a failed craft can put it all back.
+/// Whatever was attached to the taken items goes back on them too
+#[derive(Debug, Default)]
+struct Spent {
+    items: Vec<InventoryItem>,
+    links: Links,
+    currencies: Vec<Amount>,
+}
+
+impl Spent {
+    fn remove(
+        &mut self,
+        player: PlayerId,
+        id: InventoryItemId,
+        inventory: &mut Inventory,
+    ) -> Result<(), AppCode> {
+        let (item, links) = inventory.remove_linked(player, id)?;
+
+        self.items.push(item);
+        self.links.extend(links);
+        Ok(())
+    }
+
+    fn take(
+        &mut self,
+        player: PlayerId,
+        stack: &ItemStack,
+        inventory: &mut Inventory,
+    ) -> Result<(), AppCode> {
+        let (taken, links) = inventory
+            .take_linked(player, stack.item, stack.count)
+            .map_err(|_| AppCode::CraftingFail)?;
+
+        self.items.extend(taken);
+        self.links.extend(links);
+        Ok(())
+    }
+
+    fn pay(
+        &mut self,
+        player: PlayerId,
+        cost: &Amount,
+        wallets: &mut Wallets,
+    ) -> Result<(), AppCode> {
+        wallets.debit(player, cost.currency, cost.amount)?;
+
+        self.currencies.push(*cost);
+        Ok(())
+    }
+
+    fn roll_back(self, player: PlayerId, inventory: &mut Inventory, wallets: &mut Wallets) {
+        for item in self.items {
+            inventory.restore(item, player);
+        }
+        inventory.relink(self.links);
+
+        for amount in self.currencies {
+            wallets.credit(player, amount.currency, amount.amount);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Crafting {
+    types: BTreeMap<CraftableTypeId, CraftableType>,
+    recipes: BTreeMap<RecipeId, Recipe>,
+    parts: HashMap<ItemId, String>,
+    bracelet_type: Option<CraftableTypeId>,
+    pending: BTreeMap<BraceletId, PendingBracelet>,
+    arms: HashMap<PlayerId, Vec<InventoryItemId>>,
+    /// Which recipe made each crafted item, only these can be recycled
+    crafted: HashMap<InventoryItemId, RecipeId>,
+    next_id: u64,
+}
+
+impl Crafting {
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self::from_data(data::load(path)?))
+    }
+
+    pub fn from_data(data: CraftingData) -> Self {
+        let recipes: BTreeMap<RecipeId, Recipe> = data
+            .recipes
+            .into_iter()
+            .map(|recipe| (recipe.id, recipe))
+            .collect();
+
+        Self {
+            types: data.types.into_iter().map(|kind| (kind.id, kind)).collect(),
+            next_id: recipes.keys().next_back().copied().unwrap_or(0),
+            recipes,
+            parts: data
+                .parts
+                .into_iter()
+                .map(|part| (part.item, part.kind))
+                .collect(),
+            bracelet_type: data.bracelet_type,
+            pending: BTreeMap::new(),
+            arms: HashMap::new(),
+            crafted: HashMap::new(),
+        }
+    }
+
+    /// `GetCraftableTypes`
+    pub fn craftable_types(&self) -> impl Iterator<Item = &CraftableType> {
+        self.types.values()
+    }
+
+    /// `GetCraftableItems`, or `GetAllCraftableItems` without a type
+    pub fn craftable_items(&self, kind: Option<CraftableTypeId>) -> Vec<&Recipe> {
+        self.recipes
+            .values()
+            .filter(|recipe| kind.is_none_or(|kind| recipe.kind == kind))
+            .collect()
+    }
+
+    /// `GetCraftableItemById`
+    pub fn craftable_item(&self, recipe: RecipeId) -> Result<&Recipe, AppCode> {
+        self.recipes.get(&recipe).ok_or(AppCode::NotFound)
+    }
+
+    /// `CreateRecipe`
+    pub fn create_recipe(&mut self, mut recipe: Recipe) -> Result<RecipeId, AppCode> {
+        let slots: BTreeSet<&str> = recipe.slots.iter().map(|slot| slot.name.as_str()).collect();
+
+        if !self.types.contains_key(&recipe.kind)
+            || recipe.output.count == 0
+            || slots.len() != recipe.slots.len()
+        {
+            return Err(AppCode::Input);
+        }
+
+        self.next_id += 1;
+        recipe.id = self.next_id;
+        self.recipes.insert(recipe.id, recipe);
+
+        Ok(self.next_id)
+    }
+
+    /// `CraftItemByCraftableItemId`
+    pub fn craft(
+        &mut self,
+        player: PlayerId,
+        recipe: RecipeId,
+        inventory: &mut Inventory,
+        wallets: &mut Wallets,
+    ) -> Result<Vec<InventoryItemId>, AppCode> {
+        self.craft_many(player, &[recipe], inventory, wallets)
+    }
+
+    /// `CraftItemsByCraftableItemIds`, everything gets crafted or nothing does
+    pub fn craft_many(
+        &mut self,
+        player: PlayerId,
+        recipes: &[RecipeId],
+        inventory: &mut Inventory,
+        wallets: &mut Wallets,
+    ) -> Result<Vec<InventoryItemId>, AppCode> {
+        let mut spent = Spent::default();
+
+        for &recipe in recipes {
+            let result = self.craftable_item(recipe).and_then(|recipe| {
+                // Recipes on a parent need the parent item and parts, see `craft_by_items`
+                if recipe.parent.is_some() {
+                    return Err(AppCode::CraftingFail);
+                }
+
+                self.spend(player, recipe, None, &[], &mut spent, inventory, wallets)
+            });
+
+            if let Err(e) = result {
+                spent.roll_back(player, inventory, wallets);
+                return Err(e);
+            }
+        }
+
+        Ok(recipes
+            .iter()
+            .flat_map(|&recipe| self.make(player, recipe, inventory))
+            .collect())
+    }
+
+    /// `CraftItemByItems`, fills a parent item's slots with parts. The recipe is
+    /// whichever one builds on the parent
+    pub fn craft_by_items(
+        &mut self,
+        player: PlayerId,
+        parent: InventoryItemId,
+        parts: &[(String, InventoryItemId)],
+        inventory: &mut Inventory,
+        wallets: &mut Wallets,
+    ) -> Result<Vec<InventoryItemId>, AppCode> {
+        let recipe = self.recipe_on(player, parent, inventory, None)?;
+
+        self.craft_on(player, recipe, parent, parts, inventory, wallets)
+    }
+
+    /// `EnhanceRecipe`, turns an item into a better one
+    pub fn enhance_recipe(
+        &mut self,
+        player: PlayerId,
+        recipe: RecipeId,
+        item: InventoryItemId,
+        inventory: &mut Inventory,
+        wallets: &mut Wallets,
+    ) -> Result<Vec<InventoryItemId>, AppCode> {
+        let definition = self.craftable_item(recipe)?;
+        let owned = inventory.get(item).ok_or(AppCode::InventoryItemNotExist)?;
+
+        if definition.parent != Some(owned.item) {
+            return Err(AppCode::ParentNotCraftable);
+        }
+
+        self.craft_on(player, recipe, item, &[], inventory, wallets)
+    }
+
+    /// `RecycleRecipe`, breaks an item crafted here back down into the ingredients
+    /// it took. Parts and currency spent on it are gone for good
+    pub fn recycle_recipe(
+        &mut self,
+        player: PlayerId,
+        item: InventoryItemId,
+        inventory: &mut Inventory,
+    ) -> Result<Vec<InventoryItemId>, AppCode> {
+        let ingredients = self
+            .crafted
+            .get(&item)
+            .and_then(|recipe| self.recipes.get(recipe))
+            .filter(|recipe| recipe.output.count == 1)
+            .ok_or(AppCode::CraftingFail)?
+            .ingredients
+            .clone();
+
+        inventory.remove(player, item)?;
+        self.unwear(player, item);
+        self.crafted.remove(&item);
+
+        Ok(ingredients
+            .iter()
+            .flat_map(|stack| inventory.grant(player, stack.item, stack.count))
+            .collect())
+    }
+
+    /// `StartCraftingBracelet`, the base is held here until the bracelet is crafted or cancelled
+    pub fn start_crafting_bracelet(
+        &mut self,
+        player: PlayerId,
+        base: InventoryItemId,
+        inventory: &mut Inventory,
+    ) -> Result<BraceletId, AppCode> {
+        let recipe = self.recipe_on(player, base, inventory, self.bracelet_type)?;
+        let (base, links) = inventory.remove_linked(player, base)?;
+
+        self.next_id += 1;
+        self.pending.insert(
+            self.next_id,
+            PendingBracelet {
+                id: self.next_id,
+                owner: player,
+                recipe,
+                base,
+                links,
+            },
+        );
+
+        Ok(self.next_id)
+    }
+
+    pub fn cancel_crafting_bracelet(
+        &mut self,
+        player: PlayerId,
+        bracelet: BraceletId,
+        inventory: &mut Inventory,
+    ) -> Result<(), AppCode> {
+        let pending = self.take_pending(player, bracelet)?;
+
+        inventory.restore(pending.base, player);
+        inventory.relink(pending.links);
+        Ok(())
+    }
+
+    /// `CraftBracelet`, finished bracelets go on the end of the player's arm
+    pub fn craft_bracelet(
+        &mut self,
+        player: PlayerId,
+        bracelet: BraceletId,
+        charms: &[(String, InventoryItemId)],
+        inventory: &mut Inventory,
+        wallets: &mut Wallets,
+    ) -> Result<Vec<InventoryItemId>, AppCode> {
+        let pending = self.take_pending(player, bracelet)?;
+        let base = pending.base.id;
+
+        inventory.restore(pending.base.clone(), player);
+        inventory.relink(pending.links.clone());
+
+        match self.craft_on(player, pending.recipe, base, charms, inventory, wallets) {
+            Ok(crafted) => {
+                self.arms
+                    .entry(player)
+                    .or_default()
+                    .extend(crafted.iter().copied());
+                Ok(crafted)
+            }
+            Err(e) => {
+                // Keep the base on the workbench so they can try other charms. If it
+                // won't come out again it stays with the player
+                match inventory.remove_linked(player, base) {
+                    Ok((base, links)) => {
+                        self.pending.insert(
+                            bracelet,
+                            PendingBracelet {
+                                base,
+                                links,
+                                ..pending
+                            },
+                        );
+                    }
+                    Err(taken) => {
+                        log::warn!("Bracelet base {} stays in the inventory: {:?}", base, taken);
+                    }
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Bracelets the player is wearing, wrist first
+    pub fn bracelets(&self, player: PlayerId) -> &[InventoryItemId] {
+        self.arms
+            .get(&player)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// `SetBraceletsArmOrder`, anything left out comes off the arm
+    pub fn set_bracelets_arm_order(
+        &mut self,
+        player: PlayerId,
+        order: &[InventoryItemId],
+        inventory: &Inventory,
+    ) -> Result<(), AppCode> {
+        let unique: BTreeSet<&InventoryItemId> = order.iter().collect();
+        if unique.len() != order.len() {
+            return Err(AppCode::Input);
+        }
+
+        for &bracelet in order {
+            let owned = inventory
+                .get(bracelet)
+                .ok_or(AppCode::InventoryItemNotExist)?;
+
+            if owned.owner != player {
+                return Err(AppCode::ItemNotOwnedBySessionPlayer);
+            }
+
+            if !self.is_bracelet(owned.item) {
+                return Err(AppCode::InvalidItem);
+            }
+        }
+
+        self.arms.insert(player, order.to_vec());
+        Ok(())
+    }
+
+    fn craft_on(
+        &mut self,
+        player: PlayerId,
+        recipe: RecipeId,
+        parent: InventoryItemId,
+        parts: &[(String, InventoryItemId)],
+        inventory: &mut Inventory,
+        wallets: &mut Wallets,
+    ) -> Result<Vec<InventoryItemId>, AppCode> {
+        let definition = self.craftable_item(recipe)?;
+        let mut spent = Spent::default();
+
+        if let Err(e) = self.spend(
+            player,
+            definition,
+            Some(parent),
+            parts,
+            &mut spent,
+            inventory,
+            wallets,
+        ) {
+            spent.roll_back(player, inventory, wallets);
+            return Err(e);
+        }
+
+        self.unwear(player, parent);
+        self.crafted.remove(&parent);
+
+        Ok(self.make(player, recipe, inventory))
+    }
+
+    fn make(
+        &mut self,
+        player: PlayerId,
+        recipe: RecipeId,
+        inventory: &mut Inventory,
+    ) -> Vec<InventoryItemId> {
+        let output = self.recipes[&recipe].output;
+        let made = inventory.grant(player, output.item, output.count);
+
+        self.crafted.extend(made.iter().map(|&item| (item, recipe)));
+        made
+    }
+
+    /// Check and take everything a recipe needs, leaving it all in `spent`
+    #[allow(clippy::too_many_arguments)]
+    fn spend(
+        &self,
+        player: PlayerId,
+        recipe: &Recipe,
+        parent: Option<InventoryItemId>,
+        parts: &[(String, InventoryItemId)],
+        spent: &mut Spent,
+        inventory: &mut Inventory,
+        wallets: &mut Wallets,
+    ) -> Result<(), AppCode> {
+        if let Some(parent) = parent {
+            spent.remove(player, parent, inventory)?;
+        }
+
+        let mut filled = BTreeSet::new();
+
+        for (slot, part) in parts {
+            let definition = recipe
+                .slots
+                .iter()
+                .find(|candidate| &candidate.name == slot)
+                .ok_or(AppCode::SlotNotOnParent)?;
+
+            if !filled.insert(slot.as_str()) {
+                return Err(AppCode::SlotAlreadyUsed);
+            }
+
+            let item = inventory.get(*part).ok_or(AppCode::InventoryItemNotExist)?;
+            let fits = self
+                .parts
+                .get(&item.item)
+                .is_some_and(|kind| definition.accepts.contains(kind));
+
+            if !fits {
+                return Err(AppCode::IncompatibleSlot);
+            }
+
+            spent.remove(player, *part, inventory)?;
+        }
+
+        if recipe
+            .slots
+            .iter()
+            .any(|slot| !slot.optional && !filled.contains(slot.name.as_str()))
+        {
+            return Err(AppCode::CraftingFail);
+        }
+
+        for stack in recipe.ingredients.iter() {
+            spent.take(player, stack, inventory)?;
+        }
+
+        for cost in recipe.cost.iter() {
+            spent.pay(player, cost, wallets)?;
+        }
+
+        Ok(())
+    }
+
+    fn recipe_on(
+        &self,
+        player: PlayerId,
+        parent: InventoryItemId,
+        inventory: &Inventory,
+        kind: Option<CraftableTypeId>,
+    ) -> Result<RecipeId, AppCode> {
+        let owned = inventory
+            .get(parent)
+            .ok_or(AppCode::InventoryItemNotExist)?;
+
+        if owned.owner != player {
+            return Err(AppCode::ItemNotOwnedBySessionPlayer);
+        }
+
+        self.recipes
+            .values()
+            .find(|recipe| {
+                recipe.parent == Some(owned.item) && kind.is_none_or(|kind| recipe.kind == kind)
+            })
+            .map(|recipe| recipe.id)
+            .ok_or(AppCode::ParentNotCraftable)
+    }
+
+    fn take_pending(
+        &mut self,
+        player: PlayerId,
+        bracelet: BraceletId,
+    ) -> Result<PendingBracelet, AppCode> {
+        match self.pending.get(&bracelet) {
+            None => Err(AppCode::NotFound),
+            Some(pending) if pending.owner != player => Err(AppCode::Perm),
+            Some(_) => Ok(self.pending.remove(&bracelet).unwrap()),
+        }
+    }
+
+    fn is_bracelet(&self, item: ItemId) -> bool {
+        self.bracelet_type.is_some_and(|bracelet| {
+            self.recipes
+                .values()
+                .any(|recipe| recipe.kind == bracelet && recipe.output.item == item)
+        })
+    }
+
+    fn unwear(&mut self, player: PlayerId, item: InventoryItemId) {
+        if let Some(arm) = self.arms.get_mut(&player) {
+            arm.retain(|&worn| worn != item);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::currency::COINS;
+    use crate::inventory::{ItemCatalog, ItemData, ItemDefinition, ItemSlot};
+
+    const SWORD: ItemId = 1;
+    const GEM: ItemId = 2;
+    const ORE: ItemId = 3;
+    const BLADE: ItemId = 4;
+    const GREAT_SWORD: ItemId = 5;
+    const BASE: ItemId = 6;
+    const BRACELET: ItemId = 7;
+    const CHARM: ItemId = 8;
+
+    fn catalog() -> ItemCatalog {
+        let item = |id, kind: Option<&str>, slots| ItemDefinition {
+            id,
+            name: format!("item {id}"),
+            kind: kind.map(str::to_string),
+            slots,
+            body_slot: None,
+            footprint: None,
+        };
+
+        ItemCatalog::from_data(ItemData {
+            items: vec![
+                item(
+                    SWORD,
+                    None,
+                    vec![ItemSlot {
+                        name: "hilt".to_string(),
+                        accepts: vec!["gem".to_string()],
+                    }],
+                ),
+                item(GEM, Some("gem"), Vec::new()),
+            ],
+        })
+    }
+
+    fn recipe(
+        id: RecipeId,
+        parent: Option<ItemId>,
+        ingredients: Vec<ItemStack>,
+        output: ItemId,
+    ) -> Recipe {
+        Recipe {
+            id,
+            name: format!("recipe {id}"),
+            kind: 1,
+            parent,
+            slots: Vec::new(),
+            ingredients,
+            cost: vec![Amount {
+                currency: COINS,
+                amount: 5,
+            }],
+            output: ItemStack {
+                item: output,
+                count: 1,
+            },
+        }
+    }
+
+    fn crafting() -> Crafting {
+        Crafting::from_data(CraftingData {
+            types: vec![CraftableType {
+                id: 1,
+                name: "weapons".to_string(),
+            }],
+            recipes: vec![
+                recipe(
+                    1,
+                    None,
+                    vec![ItemStack {
+                        item: ORE,
+                        count: 2,
+                    }],
+                    BLADE,
+                ),
+                recipe(
+                    2,
+                    Some(SWORD),
+                    vec![ItemStack {
+                        item: ORE,
+                        count: 9,
+                    }],
+                    GREAT_SWORD,
+                ),
+            ],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn failed_crafts_put_attached_items_back_on() {
+        let mut crafting = crafting();
+        let mut inventory = Inventory::default();
+        let mut wallets = Wallets::default();
+        wallets.credit(1, COINS, 100);
+
+        let sword = inventory.grant(1, SWORD, 1)[0];
+        let gem = inventory.grant(1, GEM, 1)[0];
+        inventory.grant(1, ORE, 2);
+        inventory
+            .attach_single_item(1, sword, "hilt", gem, &catalog())
+            .unwrap();
+
+        assert_eq!(
+            crafting.craft_by_items(1, sword, &[], &mut inventory, &mut wallets),
+            Err(AppCode::CraftingFail)
+        );
+
+        assert_eq!(
+            inventory
+                .get(gem)
+                .unwrap()
+                .attached
+                .as_ref()
+                .unwrap()
+                .parent,
+            sword
+        );
+        assert_eq!(inventory.count(1, ORE), 2);
+        assert_eq!(wallets.balance(1, COINS), 100);
+    }
+
+    #[test]
+    fn crafting_many_is_all_or_nothing() {
+        let mut crafting = crafting();
+        let mut inventory = Inventory::default();
+        let mut wallets = Wallets::default();
+        wallets.credit(1, COINS, 100);
+        inventory.grant(1, ORE, 3);
+
+        assert_eq!(
+            crafting.craft_many(1, &[1, 1], &mut inventory, &mut wallets),
+            Err(AppCode::CraftingFail)
+        );
+        assert_eq!(inventory.count(1, ORE), 3);
+        assert_eq!(wallets.balance(1, COINS), 100);
+
+        assert_eq!(
+            crafting
+                .craft(1, 1, &mut inventory, &mut wallets)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(inventory.count(1, BLADE), 1);
+        assert_eq!(wallets.balance(1, COINS), 95);
+    }
+
+    #[test]
+    fn only_crafted_items_can_be_recycled() {
+        let mut crafting = crafting();
+        let mut inventory = Inventory::default();
+        let mut wallets = Wallets::default();
+        wallets.credit(1, COINS, 100);
+        inventory.grant(1, ORE, 2);
+
+        let bought = inventory.grant(1, BLADE, 1)[0];
+        assert_eq!(
+            crafting.recycle_recipe(1, bought, &mut inventory),
+            Err(AppCode::CraftingFail)
+        );
+
+        let crafted = crafting.craft(1, 1, &mut inventory, &mut wallets).unwrap()[0];
+        assert_eq!(inventory.count(1, ORE), 0);
+
+        crafting.recycle_recipe(1, crafted, &mut inventory).unwrap();
+        assert_eq!(inventory.count(1, ORE), 2);
+        assert_eq!(
+            crafting.recycle_recipe(1, crafted, &mut inventory),
+            Err(AppCode::CraftingFail)
+        );
+    }
+
+    /// Bracelets take a charm, and the plain crafting recipes come along too
+    fn charms() -> Crafting {
+        let mut bracelet = recipe(3, Some(BASE), Vec::new(), BRACELET);
+        bracelet.kind = 2;
+        bracelet.slots = vec![RecipeSlot {
+            name: "charm".to_string(),
+            accepts: vec!["charm".to_string()],
+            optional: false,
+        }];
+
+        let mut crafting = crafting();
+        crafting.types.insert(
+            2,
+            CraftableType {
+                id: 2,
+                name: "bracelets".to_string(),
+            },
+        );
+        crafting.recipes.insert(3, bracelet);
+        crafting.parts.insert(CHARM, "charm".to_string());
+        crafting.parts.insert(GEM, "gem".to_string());
+        crafting.bracelet_type = Some(2);
+        crafting
+    }
+
+    fn charmed_player() -> (Crafting, Inventory, Wallets) {
+        let mut inventory = Inventory::default();
+        let mut wallets = Wallets::default();
+        wallets.credit(1, COINS, 100);
+        inventory.grant(1, BASE, 2);
+        inventory.grant(1, CHARM, 2);
+        inventory.grant(1, GEM, 1);
+
+        (charms(), inventory, wallets)
+    }
+
+    fn first(inventory: &Inventory, item: ItemId) -> InventoryItemId {
+        inventory
+            .items(1)
+            .find(|owned| owned.item == item)
+            .unwrap()
+            .id
+    }
+
+    /// Crafts onto a base with these parts, and checks nothing was used up when it fails
+    fn craft_on_base(parts: &[(&str, ItemId)]) -> Result<Vec<InventoryItemId>, AppCode> {
+        let (mut crafting, mut inventory, mut wallets) = charmed_player();
+        let base = first(&inventory, BASE);
+        let mut used = BTreeSet::new();
+        let parts: Vec<(String, InventoryItemId)> = parts
+            .iter()
+            .map(|&(slot, item)| {
+                let id = inventory
+                    .items(1)
+                    .find(|owned| owned.item == item && !used.contains(&owned.id))
+                    .unwrap()
+                    .id;
+                used.insert(id);
+                (slot.to_string(), id)
+            })
+            .collect();
+
+        let result = crafting.craft_by_items(1, base, &parts, &mut inventory, &mut wallets);
+        if result.is_err() {
+            assert!(inventory.owns(1, base));
+            assert_eq!(inventory.count(1, CHARM), 2);
+            assert_eq!(wallets.balance(1, COINS), 100);
+        }
+
+        result
+    }
+
+    #[test]
+    fn a_slot_takes_one_part() {
+        assert_eq!(
+            craft_on_base(&[("charm", CHARM), ("charm", CHARM)]),
+            Err(AppCode::SlotAlreadyUsed)
+        );
+        assert_eq!(craft_on_base(&[("charm", CHARM)]).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn parts_go_in_slots_the_parent_has() {
+        assert_eq!(
+            craft_on_base(&[("clasp", CHARM)]),
+            Err(AppCode::SlotNotOnParent)
+        );
+    }
+
+    #[test]
+    fn parts_have_to_fit_their_slot() {
+        assert_eq!(
+            craft_on_base(&[("charm", GEM)]),
+            Err(AppCode::IncompatibleSlot)
+        );
+    }
+
+    #[test]
+    fn only_items_a_recipe_builds_on_can_be_crafted_on() {
+        let (mut crafting, mut inventory, mut wallets) = charmed_player();
+        let charm = first(&inventory, CHARM);
+        let base = first(&inventory, BASE);
+
+        assert_eq!(
+            crafting.craft_by_items(1, charm, &[], &mut inventory, &mut wallets),
+            Err(AppCode::ParentNotCraftable)
+        );
+        assert_eq!(
+            crafting.enhance_recipe(1, 2, base, &mut inventory, &mut wallets),
+            Err(AppCode::ParentNotCraftable)
+        );
+        assert_eq!(
+            crafting.start_crafting_bracelet(1, charm, &mut inventory),
+            Err(AppCode::ParentNotCraftable)
+        );
+        assert!(inventory.owns(1, charm) && inventory.owns(1, base));
+    }
+
+    #[test]
+    fn bracelets_go_from_the_workbench_onto_the_arm() {
+        let (mut crafting, mut inventory, mut wallets) = charmed_player();
+        let base = first(&inventory, BASE);
+        let gem = first(&inventory, GEM);
+
+        let pending = crafting
+            .start_crafting_bracelet(1, base, &mut inventory)
+            .unwrap();
+        assert!(inventory.get(base).is_none());
+        assert_eq!(
+            crafting.craft_bracelet(2, pending, &[], &mut inventory, &mut wallets),
+            Err(AppCode::Perm)
+        );
+
+        // A bad charm leaves the base on the workbench for another go
+        assert_eq!(
+            crafting.craft_bracelet(
+                1,
+                pending,
+                &[("charm".to_string(), gem)],
+                &mut inventory,
+                &mut wallets
+            ),
+            Err(AppCode::IncompatibleSlot)
+        );
+        assert!(inventory.get(base).is_none());
+        assert!(inventory.owns(1, gem));
+
+        let charm = first(&inventory, CHARM);
+        let made = crafting
+            .craft_bracelet(
+                1,
+                pending,
+                &[("charm".to_string(), charm)],
+                &mut inventory,
+                &mut wallets,
+            )
+            .unwrap();
+        assert_eq!(crafting.bracelets(1), made);
+        assert_eq!(wallets.balance(1, COINS), 95);
+
+        let base = first(&inventory, BASE);
+        let charm = first(&inventory, CHARM);
+        let pending = crafting
+            .start_crafting_bracelet(1, base, &mut inventory)
+            .unwrap();
+        let second = crafting
+            .craft_bracelet(
+                1,
+                pending,
+                &[("charm".to_string(), charm)],
+                &mut inventory,
+                &mut wallets,
+            )
+            .unwrap();
+        assert_eq!(crafting.bracelets(1), [made[0], second[0]]);
+
+        assert_eq!(
+            crafting.set_bracelets_arm_order(1, &[second[0], second[0]], &inventory),
+            Err(AppCode::Input)
+        );
+        assert_eq!(
+            crafting.set_bracelets_arm_order(1, &[second[0], gem], &inventory),
+            Err(AppCode::InvalidItem)
+        );
+        crafting
+            .set_bracelets_arm_order(1, &[second[0], made[0]], &inventory)
+            .unwrap();
+        assert_eq!(crafting.bracelets(1), [second[0], made[0]]);
+    }
+
+    #[test]
+    fn cancelled_bracelets_give_the_base_back() {
+        let (mut crafting, mut inventory, _) = charmed_player();
+        let base = first(&inventory, BASE);
+
+        let pending = crafting
+            .start_crafting_bracelet(1, base, &mut inventory)
+            .unwrap();
+        crafting
+            .cancel_crafting_bracelet(1, pending, &mut inventory)
+            .unwrap();
+
+        assert!(inventory.owns(1, base));
+        assert_eq!(
+            crafting.cancel_crafting_bracelet(1, pending, &mut inventory),
+            Err(AppCode::NotFound)
+        );
+    }
+}
@@ -0,0 +1,82 @@
+use crate::message::AppCode;
+use crate::session::PlayerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+pub type CurrencyId = u32;
+
+pub const COINS: CurrencyId = 1;
+pub const GEMS: CurrencyId = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Amount {
+    pub currency: CurrencyId,
+    pub amount: u64,
+}
+
+/// Every player's balance of every currency, what `GetCurrencies` reads from
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Wallets {
+    balances: HashMap<PlayerId, BTreeMap<CurrencyId, u64>>,
+}
+
+impl Wallets {
+    pub fn balance(&self, player: PlayerId, currency: CurrencyId) -> u64 {
+        self.balances
+            .get(&player)
+            .and_then(|wallet| wallet.get(&currency))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    pub fn balances(&self, player: PlayerId) -> BTreeMap<CurrencyId, u64> {
+        self.balances.get(&player).cloned().unwrap_or_default()
+    }
+
+    pub fn credit(&mut self, player: PlayerId, currency: CurrencyId, amount: u64) {
+        let balance = self
+            .balances
+            .entry(player)
+            .or_default()
+            .entry(currency)
+            .or_default();
+
+        *balance = balance.saturating_add(amount);
+    }
+
+    pub fn debit(
+        &mut self,
+        player: PlayerId,
+        currency: CurrencyId,
+        amount: u64,
+    ) -> Result<(), AppCode> {
+        let balance = self
+            .balances
+            .get_mut(&player)
+            .and_then(|wallet| wallet.get_mut(&currency))
+            .filter(|balance| **balance >= amount);
+
+        match balance {
+            Some(balance) => {
+                *balance -= amount;
+                Ok(())
+            }
+            None if amount == 0 => Ok(()),
+            None => Err(AppCode::InsufficientFunds),
+        }
+    }
+
+    /// Either both sides change or neither does
+    pub fn transfer(
+        &mut self,
+        from: PlayerId,
+        to: PlayerId,
+        currency: CurrencyId,
+        amount: u64,
+    ) -> Result<(), AppCode> {
+        self.debit(from, currency, amount)?;
+        self.credit(to, currency, amount);
+
+        Ok(())
+    }
+}
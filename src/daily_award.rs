@@ -0,0 +1,421 @@
+use crate::clock::{Clock, SystemClock};
+use crate::currency::Wallets;
+use crate::data;
+use crate::inventory::{Inventory, Reward};
+use crate::message::AppCode;
+use crate::session::PlayerId;
+use chrono::{Datelike, Duration, FixedOffset, NaiveDate};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+pub type AwardSetId = u32;
+
+/// Handed out once enough daily activities were done in one week
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WeeklyAward {
+    pub activities: u32,
+    #[serde(default)]
+    pub reward: Reward,
+}
+
+/// `GetAwardSets`, one table of daily and weekly rewards
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AwardSet {
+    pub id: AwardSetId,
+    pub name: String,
+    /// Day `n` of a streak gets `daily[n - 1]`, going round again after the last one
+    #[serde(default)]
+    pub daily: Vec<Reward>,
+    #[serde(default)]
+    pub weekly: Vec<WeeklyAward>,
+}
+
+/// What `data/daily_awards.json` looks like
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DailyAwardData {
+    /// Days start at `reset_hour` in this timezone, not at midnight UTC
+    pub utc_offset_minutes: i32,
+    pub reset_hour: u32,
+    pub active_set: AwardSetId,
+    pub award_sets: Vec<AwardSet>,
+}
+
+/// `GetDailyAwardStatus`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DailyAwardStatus {
+    /// Days in a row so far, including today if it's been done
+    pub streak: u32,
+    pub done_today: bool,
+    /// Which daily reward the next activity gets
+    pub next_day: u32,
+}
+
+/// One week of one player, `GetWeeklyAwardStatus` and `GetPlayerWeeklyAwardHistory`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayerWeeklyAward {
+    pub id: u64,
+    pub player: PlayerId,
+    /// The award day the week started on
+    pub week: NaiveDate,
+    pub activities: u32,
+    /// How many of the weekly awards have been handed out
+    pub awarded: usize,
+}
+
+#[derive(Debug, Default)]
+struct PlayerAwards {
+    streak: u32,
+    last_day: Option<NaiveDate>,
+    weeks: Vec<PlayerWeeklyAward>,
+}
+
+#[derive(Debug)]
+pub struct DailyAwards<C: Clock = SystemClock> {
+    clock: C,
+    offset: FixedOffset,
+    reset_hour: u32,
+    active_set: AwardSetId,
+    award_sets: BTreeMap<AwardSetId, AwardSet>,
+    players: HashMap<PlayerId, PlayerAwards>,
+    next_id: u64,
+}
+
+impl Default for DailyAwards {
+    fn default() -> Self {
+        Self::from_data(DailyAwardData::default(), SystemClock)
+    }
+}
+
+impl DailyAwards {
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self::from_data(data::load(path)?, SystemClock))
+    }
+}
+
+impl<C: Clock> DailyAwards<C> {
+    pub fn from_data(data: DailyAwardData, clock: C) -> Self {
+        Self {
+            clock,
+            offset: FixedOffset::east_opt(data.utc_offset_minutes * 60)
+                .unwrap_or(FixedOffset::east_opt(0).unwrap()),
+            reset_hour: data.reset_hour.min(23),
+            active_set: data.active_set,
+            award_sets: data
+                .award_sets
+                .into_iter()
+                .map(|set| (set.id, set))
+                .collect(),
+            players: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// The award day it is right now. It only turns over at the reset hour, local time
+    pub fn today(&self) -> NaiveDate {
+        let local = self.clock.now().with_timezone(&self.offset);
+
+        (local - Duration::hours(self.reset_hour as i64)).date_naive()
+    }
+
+    /// The award day the current week started on, weeks start on Monday
+    pub fn this_week(&self) -> NaiveDate {
+        let today = self.today();
+
+        today - Duration::days(today.weekday().num_days_from_monday() as i64)
+    }
+
+    /// `GetAwardSets`
+    pub fn award_sets(&self) -> impl Iterator<Item = &AwardSet> {
+        self.award_sets.values()
+    }
+
+    /// `GetDailyAwards`, the rewards in the set that's running now
+    pub fn daily_awards(&self) -> Result<&AwardSet, AppCode> {
+        self.award_sets
+            .get(&self.active_set)
+            .ok_or(AppCode::NotFound)
+    }
+
+    /// `GetDailyAwardStatus`
+    pub fn daily_award_status(&self, player: PlayerId) -> DailyAwardStatus {
+        let today = self.today();
+        let state = self.players.get(&player);
+        let last_day = state.and_then(|state| state.last_day);
+
+        let streak = match last_day {
+            Some(day) if day == today || day == today - Duration::days(1) => {
+                state.map_or(0, |state| state.streak)
+            }
+            _ => 0,
+        };
+        let done_today = last_day == Some(today);
+
+        DailyAwardStatus {
+            streak,
+            done_today,
+            next_day: if done_today { streak } else { streak + 1 },
+        }
+    }
+
+    /// `CompleteDailyActivity`, once per award day. Keeps the streak going, counts
+    /// towards the week and hands out whatever was earned
+    pub fn complete_daily_activity(
+        &mut self,
+        player: PlayerId,
+        inventory: &mut Inventory,
+        wallets: &mut Wallets,
+    ) -> Result<DailyAwardStatus, AppCode> {
+        let status = self.daily_award_status(player);
+        if status.done_today {
+            return Err(AppCode::DupRequest);
+        }
+
+        let set = self.daily_awards()?.clone();
+        let today = self.today();
+        let week = self.this_week();
+
+        let state = self.players.entry(player).or_default();
+        state.streak = status.next_day;
+        state.last_day = Some(today);
+
+        if !set.daily.is_empty() {
+            let index = (state.streak as usize - 1) % set.daily.len();
+            set.daily[index].grant(player, inventory, wallets);
+        }
+
+        if state.weeks.last().is_none_or(|last| last.week != week) {
+            self.next_id += 1;
+            state.weeks.push(PlayerWeeklyAward {
+                id: self.next_id,
+                player,
+                week,
+                activities: 0,
+                awarded: 0,
+            });
+        }
+
+        let current = state.weeks.last_mut().unwrap();
+        current.activities += 1;
+
+        while let Some(award) = set.weekly.get(current.awarded) {
+            if current.activities < award.activities {
+                break;
+            }
+
+            award.reward.grant(player, inventory, wallets);
+            current.awarded += 1;
+        }
+
+        Ok(self.daily_award_status(player))
+    }
+
+    /// `GetWeeklyAwardStatus`, nothing done yet this week is all zeroes
+    pub fn weekly_award_status(&self, player: PlayerId) -> PlayerWeeklyAward {
+        let week = self.this_week();
+
+        self.history(player)
+            .last()
+            .filter(|last| last.week == week)
+            .cloned()
+            .unwrap_or(PlayerWeeklyAward {
+                id: 0,
+                player,
+                week,
+                activities: 0,
+                awarded: 0,
+            })
+    }
+
+    /// `GetPlayerWeeklyAwardHistory`, oldest first
+    pub fn player_weekly_award_history(&self, player: PlayerId) -> &[PlayerWeeklyAward] {
+        self.history(player)
+    }
+
+    /// `GetWeeklyAwardStatusByPlayerWeeklyAwardId`
+    pub fn weekly_award_status_by_id(
+        &self,
+        player: PlayerId,
+        id: u64,
+    ) -> Result<&PlayerWeeklyAward, AppCode> {
+        self.history(player)
+            .iter()
+            .find(|week| week.id == id)
+            .ok_or(AppCode::NotFound)
+    }
+
+    fn history(&self, player: PlayerId) -> &[PlayerWeeklyAward] {
+        self.players
+            .get(&player)
+            .map(|state| state.weeks.as_slice())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::currency::{Amount, COINS};
+    use chrono::{DateTime, TimeZone, Utc};
+
+    fn coins(amount: u64) -> Reward {
+        Reward {
+            items: Vec::new(),
+            currencies: vec![Amount {
+                currency: COINS,
+                amount,
+            }],
+        }
+    }
+
+    fn at(year: i32, month: u32, day: u32, hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, day, hour, 0, 0).unwrap()
+    }
+
+    /// Days reset at 04:00 five hours behind UTC, which is 09:00 UTC
+    fn awards(clock: &ManualClock) -> DailyAwards<ManualClock> {
+        DailyAwards::from_data(
+            DailyAwardData {
+                utc_offset_minutes: -300,
+                reset_hour: 4,
+                active_set: 1,
+                award_sets: vec![AwardSet {
+                    id: 1,
+                    name: "Everyday".to_string(),
+                    daily: vec![coins(10), coins(20), coins(30)],
+                    weekly: vec![
+                        WeeklyAward {
+                            activities: 2,
+                            reward: coins(100),
+                        },
+                        WeeklyAward {
+                            activities: 5,
+                            reward: coins(500),
+                        },
+                    ],
+                }],
+            },
+            clock.clone(),
+        )
+    }
+
+    #[test]
+    fn streak_grows_on_consecutive_days_and_wraps_round_the_table() {
+        // Monday 2026-03-02
+        let clock = ManualClock::new(at(2026, 3, 2, 12));
+        let mut awards = awards(&clock);
+        let mut inventory = Inventory::default();
+        let mut wallets = Wallets::default();
+
+        let mut paid = Vec::new();
+        for _ in 0..4 {
+            let before = wallets.balance(1, COINS);
+            let status = awards
+                .complete_daily_activity(1, &mut inventory, &mut wallets)
+                .unwrap();
+            paid.push((status.streak, wallets.balance(1, COINS) - before));
+            clock.advance(Duration::days(1));
+        }
+
+        // Day two also earns the first weekly award
+        assert_eq!(paid, vec![(1, 10), (2, 120), (3, 30), (4, 10)]);
+    }
+
+    #[test]
+    fn once_per_award_day() {
+        let clock = ManualClock::new(at(2026, 3, 2, 12));
+        let mut awards = awards(&clock);
+        let mut inventory = Inventory::default();
+        let mut wallets = Wallets::default();
+
+        awards
+            .complete_daily_activity(1, &mut inventory, &mut wallets)
+            .unwrap();
+
+        clock.set(at(2026, 3, 3, 8));
+        assert_eq!(
+            awards.complete_daily_activity(1, &mut inventory, &mut wallets),
+            Err(AppCode::DupRequest)
+        );
+        assert!(awards.daily_award_status(1).done_today);
+
+        clock.set(at(2026, 3, 3, 9));
+        assert!(!awards.daily_award_status(1).done_today);
+        assert!(awards
+            .complete_daily_activity(1, &mut inventory, &mut wallets)
+            .is_ok());
+    }
+
+    #[test]
+    fn day_follows_the_configured_timezone() {
+        let clock = ManualClock::new(at(2026, 3, 3, 8));
+        let awards = awards(&clock);
+
+        // 03:00 local on the 3rd is still the 2nd until the reset hour
+        assert_eq!(awards.today(), NaiveDate::from_ymd_opt(2026, 3, 2).unwrap());
+
+        clock.set(at(2026, 3, 3, 9));
+        assert_eq!(awards.today(), NaiveDate::from_ymd_opt(2026, 3, 3).unwrap());
+    }
+
+    #[test]
+    fn missing_a_day_breaks_the_streak() {
+        let clock = ManualClock::new(at(2026, 3, 2, 12));
+        let mut awards = awards(&clock);
+        let mut inventory = Inventory::default();
+        let mut wallets = Wallets::default();
+
+        awards
+            .complete_daily_activity(1, &mut inventory, &mut wallets)
+            .unwrap();
+        clock.advance(Duration::days(1));
+        awards
+            .complete_daily_activity(1, &mut inventory, &mut wallets)
+            .unwrap();
+        assert_eq!(awards.daily_award_status(1).streak, 2);
+
+        clock.advance(Duration::days(2));
+        assert_eq!(awards.daily_award_status(1).streak, 0);
+
+        let status = awards
+            .complete_daily_activity(1, &mut inventory, &mut wallets)
+            .unwrap();
+        assert_eq!(status.streak, 1);
+    }
+
+    #[test]
+    fn weeks_roll_over_into_history() {
+        let clock = ManualClock::new(at(2026, 3, 2, 12));
+        let mut awards = awards(&clock);
+        let mut inventory = Inventory::default();
+        let mut wallets = Wallets::default();
+
+        for _ in 0..7 {
+            awards
+                .complete_daily_activity(1, &mut inventory, &mut wallets)
+                .unwrap();
+            clock.advance(Duration::days(1));
+        }
+
+        let first = awards.weekly_award_status(1);
+        assert_eq!(first.activities, 0);
+        assert_eq!(first.week, NaiveDate::from_ymd_opt(2026, 3, 9).unwrap());
+
+        awards
+            .complete_daily_activity(1, &mut inventory, &mut wallets)
+            .unwrap();
+
+        let history = awards.player_weekly_award_history(1);
+        assert_eq!(history.len(), 2);
+        assert_eq!((history[0].activities, history[0].awarded), (7, 2));
+        assert_eq!((history[1].activities, history[1].awarded), (1, 0));
+
+        let id = history[0].id;
+        assert_eq!(
+            awards.weekly_award_status_by_id(1, id).unwrap().week,
+            NaiveDate::from_ymd_opt(2026, 3, 2).unwrap()
+        );
+    }
+}
@@ -0,0 +1,37 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::Path;
+
+/// Read a JSON data file into whatever table the caller wants
+pub fn load<T: DeserializeOwned>(path: impl AsRef<Path>) -> std::io::Result<T> {
+    let contents = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&contents)?)
+}
+
+/// Like [`load`] but falls back to the default table if the file is missing or broken
+pub fn load_or_default<T: DeserializeOwned + Default>(path: impl AsRef<Path>) -> T {
+    let path = path.as_ref();
+
+    match load(path) {
+        Ok(table) => table,
+        Err(e) => {
+            log::warn!("Could not load {}: {}", path.display(), e);
+            T::default()
+        }
+    }
+}
+
+/// Write a table out as JSON. It goes to a temporary file first so a crash
+/// halfway through never leaves a broken file behind
+pub fn save<T: Serialize>(path: impl AsRef<Path>, table: &T) -> std::io::Result<()> {
+    let path = path.as_ref();
+    let contents = serde_json::to_string_pretty(table)?;
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let temp = path.with_extension("tmp");
+    std::fs::write(&temp, contents)?;
+    std::fs::rename(temp, path)
+}
@@ -0,0 +1,368 @@
+use crate::data;
+use crate::message::AppCode;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::ops::Range;
+use std::path::Path;
+
+/// What a file under `data/` looks like for the word filter
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct FilterLists {
+    #[serde(default)]
+    pub blocked: Vec<String>,
+    #[serde(default)]
+    pub allowed: Vec<String>,
+    #[serde(default)]
+    pub canned: Vec<CannedCategory>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CannedCategory {
+    pub id: u32,
+    pub name: String,
+    pub messages: Vec<CannedMessage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CannedMessage {
+    pub id: u32,
+    pub text: String,
+}
+
+/// Child accounts only get to pick from the canned phrases
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChatMode {
+    #[default]
+    Open,
+    Canned,
+}
+
+#[derive(Debug, Default)]
+pub struct WordFilter {
+    // (normalized, squeezed)
+    blocked: Vec<(String, String)>,
+    allowed: HashSet<String>,
+    canned: Vec<CannedCategory>,
+    canned_texts: HashSet<String>,
+}
+
+/// A word in the original text and the byte ranges it came from.
+/// Runs of single letters ("b a d", "b.a.d") get glued together into one word
+struct Word {
+    normalized: String,
+    spans: Vec<Range<usize>>,
+}
+
+impl WordFilter {
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self::from_lists(data::load(path)?))
+    }
+
+    pub fn from_lists(lists: FilterLists) -> Self {
+        let blocked = lists
+            .blocked
+            .iter()
+            .map(|word| normalize(word))
+            .filter(|word| !word.is_empty())
+            .map(|word| {
+                let squeezed = squeeze(&word);
+                (word, squeezed)
+            })
+            .collect();
+
+        let allowed = lists
+            .allowed
+            .iter()
+            .map(|word| normalize(word))
+            .filter(|word| !word.is_empty())
+            .collect();
+
+        let canned_texts = lists
+            .canned
+            .iter()
+            .flat_map(|category| category.messages.iter())
+            .map(|message| canned_key(&message.text))
+            .collect();
+
+        Self {
+            blocked,
+            allowed,
+            canned: lists.canned,
+            canned_texts,
+        }
+    }
+
+    pub fn contains_bad_word(&self, text: &str) -> bool {
+        words(text)
+            .iter()
+            .any(|word| self.is_blocked(&word.normalized))
+    }
+
+    /// Star out every bad word, this is what `FilterBadWord` hands back
+    pub fn filter_bad_word(&self, text: &str) -> String {
+        let masked: Vec<Range<usize>> = words(text)
+            .into_iter()
+            .filter(|word| self.is_blocked(&word.normalized))
+            .flat_map(|word| word.spans)
+            .collect();
+
+        text.char_indices()
+            .map(|(index, c)| {
+                if masked.iter().any(|span| span.contains(&index)) {
+                    '*'
+                } else {
+                    c
+                }
+            })
+            .collect()
+    }
+
+    pub fn check_chat(&self, text: &str, mode: ChatMode) -> Result<(), AppCode> {
+        match mode {
+            ChatMode::Open if self.contains_bad_word(text) => Err(AppCode::InappropriateLanguage),
+            ChatMode::Open => Ok(()),
+            ChatMode::Canned if self.canned_texts.contains(&canned_key(text)) => Ok(()),
+            ChatMode::Canned => Err(AppCode::InappropriateLanguage),
+        }
+    }
+
+    pub fn canned_message_categories(&self) -> &[CannedCategory] {
+        &self.canned
+    }
+
+    pub fn validate_name(&self, name: &str) -> Result<(), AppCode> {
+        let name = name.trim();
+
+        if name.is_empty() {
+            return Err(AppCode::NameCannotBeEmpty);
+        }
+
+        if !name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == ' ' || c == '_')
+        {
+            return Err(AppCode::InvalidName);
+        }
+
+        if self.contains_bad_word(name) {
+            return Err(AppCode::InappropriateLanguage);
+        }
+
+        Ok(())
+    }
+
+    pub fn check_username(&self, username: &str) -> Result<(), AppCode> {
+        if username.contains(' ') {
+            return Err(AppCode::InvalidName);
+        }
+
+        self.validate_name(username)
+    }
+
+    pub fn check_village_name(&self, name: &str) -> Result<(), AppCode> {
+        match self.validate_name(name) {
+            Err(AppCode::NameCannotBeEmpty | AppCode::InvalidName) => {
+                Err(AppCode::InvalidVillageName)
+            }
+            result => result,
+        }
+    }
+
+    /// Bad words count anywhere in the plain form, unless an allowed word covers
+    /// them ("whatevers"). Stretched words only count as a whole ("baaad", not "shaaater")
+    fn is_blocked(&self, word: &str) -> bool {
+        if word.is_empty() || self.allowed.contains(word) {
+            return false;
+        }
+
+        let squeezed = squeeze(word);
+
+        if self.allowed.contains(&squeezed) {
+            return false;
+        }
+
+        let allowed: Vec<Range<usize>> = self
+            .allowed
+            .iter()
+            .flat_map(|ok| {
+                word.match_indices(ok.as_str())
+                    .map(|(start, ok)| start..start + ok.len())
+            })
+            .collect();
+
+        self.blocked.iter().any(|(bad, bad_squeezed)| {
+            squeezed == *bad_squeezed
+                || word.match_indices(bad.as_str()).any(|(start, bad)| {
+                    !allowed
+                        .iter()
+                        .any(|ok| ok.start <= start && start + bad.len() <= ok.end)
+                })
+        })
+    }
+}
+
+fn unleet(c: char) -> Option<char> {
+    let c = c.to_ascii_lowercase();
+
+    Some(match c {
+        '0' => 'o',
+        '1' | '!' | '|' => 'i',
+        '3' => 'e',
+        '4' | '@' => 'a',
+        '5' | '$' => 's',
+        '7' => 't',
+        '8' => 'b',
+        '9' => 'g',
+        c if c.is_alphanumeric() => c,
+        _ => return None,
+    })
+}
+
+fn normalize(text: &str) -> String {
+    text.chars().filter_map(unleet).collect()
+}
+
+/// "baaaad" -> "bad". Doubled letters are left alone, plenty of real words have them
+fn squeeze(word: &str) -> String {
+    let chars: Vec<char> = word.chars().collect();
+    let mut squeezed = String::with_capacity(word.len());
+
+    for run in chars.chunk_by(|a, b| a == b) {
+        if run.len() >= 3 {
+            squeezed.push(run[0]);
+        } else {
+            squeezed.extend(run);
+        }
+    }
+
+    squeezed
+}
+
+fn canned_key(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+fn words(text: &str) -> Vec<Word> {
+    let mut raw: Vec<(String, Range<usize>)> = Vec::new();
+    let mut current: Option<(String, Range<usize>)> = None;
+
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((index, c)) = chars.next() {
+        // "hi!" is punctuation, "h!" in the middle of a word is leet
+        let trailing_bang =
+            c == '!' && !chars.peek().is_some_and(|(_, next)| next.is_alphanumeric());
+
+        match unleet(c).filter(|_| !trailing_bang) {
+            Some(normalized) => {
+                let (word, span) = current.get_or_insert_with(|| (String::new(), index..index));
+                word.push(normalized);
+                span.end = index + c.len_utf8();
+            }
+            None => raw.extend(current.take()),
+        }
+    }
+    raw.extend(current);
+
+    // Glue "b a d" back together
+    let mut words: Vec<Word> = Vec::new();
+    let mut gluing = false;
+
+    for (normalized, span) in raw {
+        let single = normalized.chars().count() == 1;
+
+        match words.last_mut() {
+            Some(last) if single && gluing => {
+                last.normalized.push_str(&normalized);
+                last.spans.push(span);
+            }
+            _ => words.push(Word {
+                normalized,
+                spans: std::iter::once(span).collect(),
+            }),
+        }
+
+        gluing = single;
+    }
+
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter() -> WordFilter {
+        WordFilter::from_lists(FilterLists {
+            blocked: ["idiot", "loser", "hate"].map(String::from).to_vec(),
+            allowed: ["whatever", "chateau"].map(String::from).to_vec(),
+            canned: vec![CannedCategory {
+                id: 1,
+                name: "Greetings".to_string(),
+                messages: vec![CannedMessage {
+                    id: 1,
+                    text: "Hi!".to_string(),
+                }],
+            }],
+        })
+    }
+
+    #[test]
+    fn catches_disguised_bad_words() {
+        let filter = filter();
+
+        for text in [
+            "idiot",
+            "ID10T",
+            "i d i o t",
+            "i.d.i.o.t",
+            "looooser",
+            "haaate",
+        ] {
+            assert!(filter.contains_bad_word(text), "{}", text);
+        }
+
+        assert!(filter.contains_bad_word("youidiot"));
+        assert_eq!(filter.filter_bad_word("you idiot!"), "you *****!");
+    }
+
+    #[test]
+    fn leaves_ordinary_words_alone() {
+        let filter = filter();
+
+        for text in [
+            "shatter",
+            "chatter",
+            "looser",
+            "whatever",
+            "whatevers",
+            "chateaux",
+            "hi!",
+        ] {
+            assert!(!filter.contains_bad_word(text), "{}", text);
+        }
+    }
+
+    #[test]
+    fn names_and_canned_chat() {
+        let filter = filter();
+
+        assert_eq!(filter.validate_name("  "), Err(AppCode::NameCannotBeEmpty));
+        assert_eq!(filter.validate_name("Bob!"), Err(AppCode::InvalidName));
+        assert_eq!(
+            filter.validate_name("Big Loser"),
+            Err(AppCode::InappropriateLanguage)
+        );
+        assert_eq!(filter.check_username("Big Bob"), Err(AppCode::InvalidName));
+        assert_eq!(filter.check_username("Big_Bob"), Ok(()));
+
+        assert_eq!(filter.check_chat("  hi! ", ChatMode::Canned), Ok(()));
+        assert_eq!(
+            filter.check_chat("hello", ChatMode::Canned),
+            Err(AppCode::InappropriateLanguage)
+        );
+    }
+}
@@ -0,0 +1,43 @@
+use crate::session::PlayerId;
+use std::collections::{BTreeSet, HashMap};
+
+/// Who is friends with who, always both ways round
+#[derive(Debug, Default)]
+pub struct Friends {
+    links: HashMap<PlayerId, BTreeSet<PlayerId>>,
+}
+
+impl Friends {
+    pub fn add(&mut self, player: PlayerId, friend: PlayerId) {
+        if player == friend {
+            return;
+        }
+
+        self.links.entry(player).or_default().insert(friend);
+        self.links.entry(friend).or_default().insert(player);
+    }
+
+    pub fn remove(&mut self, player: PlayerId, friend: PlayerId) {
+        if let Some(friends) = self.links.get_mut(&player) {
+            friends.remove(&friend);
+        }
+
+        if let Some(friends) = self.links.get_mut(&friend) {
+            friends.remove(&player);
+        }
+    }
+
+    pub fn are_friends(&self, player: PlayerId, other: PlayerId) -> bool {
+        self.links
+            .get(&player)
+            .is_some_and(|friends| friends.contains(&other))
+    }
+
+    /// `GetFriendList`
+    pub fn friends_of(&self, player: PlayerId) -> Vec<PlayerId> {
+        self.links
+            .get(&player)
+            .map(|friends| friends.iter().copied().collect())
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,364 @@
+use crate::data;
+use crate::inventory::{Inventory, InventoryItemId, ItemId, ItemStack};
+use crate::message::AppCode;
+use crate::session::{Outbox, PlayerId};
+use crate::world::{ObjectId, Position, World};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub type Stage = u8;
+
+/// An item that grows once it's planted
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Crop {
+    pub item: ItemId,
+    /// It's ripe once it gets through this many stages
+    pub stages: Stage,
+    pub stage_seconds: u32,
+    pub harvest: Vec<ItemStack>,
+    /// Starts growing again after a harvest instead of being used up
+    #[serde(default)]
+    pub regrows: bool,
+}
+
+/// What `data/crops.json` looks like
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CropData {
+    #[serde(default)]
+    pub crops: Vec<Crop>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Planting {
+    pub item: InventoryItemId,
+    pub owner: PlayerId,
+    pub crop: ItemId,
+    pub planted_at: DateTime<Utc>,
+    /// When it came back into the inventory, it doesn't grow again until it's planted
+    pub docked_at: Option<DateTime<Utc>>,
+    /// The last stage anyone was told about
+    pub stage: Stage,
+}
+
+#[derive(Debug, Default)]
+pub struct Garden {
+    crops: BTreeMap<ItemId, Crop>,
+    plantings: BTreeMap<InventoryItemId, Planting>,
+}
+
+impl Garden {
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self::from_data(data::load(path)?))
+    }
+
+    pub fn from_data(data: CropData) -> Self {
+        Self {
+            crops: data
+                .crops
+                .into_iter()
+                .map(|crop| (crop.item, crop))
+                .collect(),
+            plantings: BTreeMap::new(),
+        }
+    }
+
+    pub fn crop(&self, item: ItemId) -> Result<&Crop, AppCode> {
+        self.crops.get(&item).ok_or(AppCode::NotFound)
+    }
+
+    pub fn plantings(&self, player: PlayerId) -> impl Iterator<Item = &Planting> {
+        self.plantings
+            .values()
+            .filter(move |planting| planting.owner == player)
+    }
+
+    /// Where a planted item has got to by `now`, whether or not anyone was around to see it
+    pub fn stage(&self, item: InventoryItemId, now: DateTime<Utc>) -> Result<Stage, AppCode> {
+        let planting = self.plantings.get(&item).ok_or(AppCode::NotFound)?;
+
+        Ok(self.grown(planting, now))
+    }
+
+    /// `PlantPlayerItem`, puts the item out in the player's location and starts it growing.
+    /// Something that was growing before picks up where it left off
+    #[allow(clippy::too_many_arguments)]
+    pub fn plant_player_item(
+        &mut self,
+        player: PlayerId,
+        item: InventoryItemId,
+        position: Position,
+        inventory: &mut Inventory,
+        world: &mut World,
+        outbox: &mut Outbox,
+        now: DateTime<Utc>,
+    ) -> Result<ObjectId, AppCode> {
+        let owned = inventory.get(item).ok_or(AppCode::InventoryItemNotExist)?;
+
+        if owned.owner != player {
+            return Err(AppCode::ItemNotOwnedBySessionPlayer);
+        }
+
+        let crop = self.crop(owned.item).map_err(|_| AppCode::Input)?.item;
+        let planted_at = match self.plantings.get(&item) {
+            Some(planting) => match planting.docked_at {
+                Some(docked_at) => planting.planted_at + (now - docked_at).max(TimeDelta::zero()),
+                None => return Err(AppCode::DupRequest),
+            },
+            None => now,
+        };
+        let object = inventory.place_item(player, item, position, world, outbox)?;
+        let location = world.player_location(player).ok_or(AppCode::NotFound)?;
+        let planting = Planting {
+            item,
+            owner: player,
+            crop,
+            planted_at,
+            docked_at: None,
+            stage: 0,
+        };
+        let stage = self.grown(&planting, now);
+
+        self.plantings.insert(item, Planting { stage, ..planting });
+        world.change_object_state(location, object, stage, outbox)?;
+
+        Ok(object)
+    }
+
+    /// `HarvestPlayerItem`, only once it's ripe. Crops that don't regrow get used up
+    pub fn harvest_player_item(
+        &mut self,
+        player: PlayerId,
+        item: InventoryItemId,
+        inventory: &mut Inventory,
+        world: &mut World,
+        outbox: &mut Outbox,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<InventoryItemId>, AppCode> {
+        let planting = self.plantings.get(&item).ok_or(AppCode::NotFound)?;
+
+        if planting.owner != player {
+            return Err(AppCode::ItemNotOwnedBySessionPlayer);
+        }
+
+        let crop = self.crops[&planting.crop].clone();
+        if self.grown(planting, now) < crop.stages {
+            return Err(AppCode::InvalidCooldown);
+        }
+
+        let placed = inventory.placement(item);
+
+        if crop.regrows {
+            let planting = self.plantings.get_mut(&item).unwrap();
+            planting.planted_at = now;
+            planting.docked_at = planting.docked_at.map(|_| now);
+            planting.stage = 0;
+
+            if let Some(placed) = placed {
+                let _ = world.change_object_state(placed.location, placed.object, 0, outbox);
+            }
+        } else {
+            self.plantings.remove(&item);
+
+            if placed.is_some() {
+                inventory.dock_item(player, item, world, outbox)?;
+            }
+            inventory.remove(player, item)?;
+        }
+
+        Ok(crop
+            .harvest
+            .iter()
+            .flat_map(|stack| inventory.grant(player, stack.item, stack.count))
+            .collect())
+    }
+
+    /// Move plants on to the stage they've grown to and show anyone looking.
+    /// Plants that were picked back up stop growing until they're planted again.
+    /// So do ones whose location was unloaded or handed to another server, those
+    /// go back into the owner's inventory. Plants whose item is gone are forgotten
+    pub fn tick(
+        &mut self,
+        inventory: &mut Inventory,
+        world: &mut World,
+        outbox: &mut Outbox,
+        now: DateTime<Utc>,
+    ) {
+        self.plantings.retain(|&item, planting| {
+            inventory
+                .get(item)
+                .is_some_and(|owned| owned.owner == planting.owner)
+        });
+
+        for planting in self.plantings.values_mut() {
+            if planting.docked_at.is_some() {
+                continue;
+            }
+
+            let out = inventory.placement(planting.item).is_some_and(|placed| {
+                world
+                    .location(placed.location)
+                    .is_some_and(|location| location.objects.contains_key(&placed.object))
+            });
+
+            if !out {
+                if inventory.placement(planting.item).is_some() {
+                    let _ = inventory.dock_item(planting.owner, planting.item, world, outbox);
+                }
+                planting.docked_at = Some(now);
+            }
+        }
+
+        let grown: Vec<(InventoryItemId, Stage)> = self
+            .plantings
+            .values()
+            .filter(|planting| planting.docked_at.is_none())
+            .map(|planting| (planting.item, self.grown(planting, now)))
+            .filter(|&(item, stage)| self.plantings[&item].stage != stage)
+            .collect();
+
+        for (item, stage) in grown {
+            let placed = inventory.placement(item).unwrap();
+
+            let _ = world.change_object_state(placed.location, placed.object, stage, outbox);
+            self.plantings.get_mut(&item).unwrap().stage = stage;
+        }
+    }
+
+    fn grown(&self, planting: &Planting, now: DateTime<Utc>) -> Stage {
+        let crop = &self.crops[&planting.crop];
+        let until = planting.docked_at.unwrap_or(now);
+        let elapsed = (until - planting.planted_at).num_seconds().max(0);
+        let stages = elapsed / crop.stage_seconds.max(1) as i64;
+
+        stages.min(crop.stages as i64) as Stage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const SEED: ItemId = 1;
+    const PUMPKIN: ItemId = 2;
+
+    fn at(minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 1, 12, minute, 0).unwrap()
+    }
+
+    fn planted(regrows: bool) -> (Garden, Inventory, World, Outbox, InventoryItemId) {
+        let mut garden = Garden::from_data(CropData {
+            crops: vec![Crop {
+                item: SEED,
+                stages: 3,
+                stage_seconds: 60,
+                harvest: vec![ItemStack {
+                    item: PUMPKIN,
+                    count: 2,
+                }],
+                regrows,
+            }],
+        });
+        let mut inventory = Inventory::default();
+        let mut world = World::default();
+        let mut outbox = Outbox::default();
+
+        world.add_location(1);
+        world
+            .enter_loc(1, 1, Position::default(), &mut outbox)
+            .unwrap();
+        let seed = inventory.grant(1, SEED, 1)[0];
+        garden
+            .plant_player_item(
+                1,
+                seed,
+                Position::default(),
+                &mut inventory,
+                &mut world,
+                &mut outbox,
+                at(0),
+            )
+            .unwrap();
+
+        (garden, inventory, world, outbox, seed)
+    }
+
+    #[test]
+    fn crops_ripen_before_they_can_be_picked() {
+        let (mut garden, mut inventory, mut world, mut outbox, seed) = planted(false);
+
+        garden.tick(&mut inventory, &mut world, &mut outbox, at(2));
+        assert_eq!(garden.plantings(1).next().unwrap().stage, 2);
+        assert_eq!(
+            garden.harvest_player_item(1, seed, &mut inventory, &mut world, &mut outbox, at(2)),
+            Err(AppCode::InvalidCooldown)
+        );
+
+        let picked = garden
+            .harvest_player_item(1, seed, &mut inventory, &mut world, &mut outbox, at(3))
+            .unwrap();
+        assert_eq!(picked.len(), 2);
+        assert!(!inventory.owns(1, seed));
+        assert_eq!(garden.plantings(1).count(), 0);
+    }
+
+    #[test]
+    fn plants_in_unloaded_locations_stop_growing() {
+        let (mut garden, mut inventory, mut world, mut outbox, seed) = planted(true);
+
+        garden.tick(&mut inventory, &mut world, &mut outbox, at(1));
+        world.remove_location(1, &mut outbox);
+        garden.tick(&mut inventory, &mut world, &mut outbox, at(2));
+
+        assert_eq!(inventory.placement(seed), None);
+        assert_eq!(garden.stage(seed, at(30)), Ok(2));
+    }
+
+    #[test]
+    fn replanted_items_pick_up_where_they_left_off() {
+        let (mut garden, mut inventory, mut world, mut outbox, seed) = planted(false);
+
+        world.remove_location(1, &mut outbox);
+        garden.tick(&mut inventory, &mut world, &mut outbox, at(2));
+
+        world.add_location(1);
+        world
+            .enter_loc(1, 1, Position::default(), &mut outbox)
+            .unwrap();
+        garden
+            .plant_player_item(
+                1,
+                seed,
+                Position::default(),
+                &mut inventory,
+                &mut world,
+                &mut outbox,
+                at(30),
+            )
+            .unwrap();
+
+        assert_eq!(garden.plantings(1).next().unwrap().stage, 2);
+        assert_eq!(
+            garden.harvest_player_item(1, seed, &mut inventory, &mut world, &mut outbox, at(30)),
+            Err(AppCode::InvalidCooldown)
+        );
+        assert!(garden
+            .harvest_player_item(1, seed, &mut inventory, &mut world, &mut outbox, at(31))
+            .is_ok());
+    }
+
+    #[test]
+    fn plants_whose_item_is_gone_are_forgotten() {
+        let (mut garden, mut inventory, mut world, mut outbox, seed) = planted(false);
+
+        inventory
+            .dock_item(1, seed, &mut world, &mut outbox)
+            .unwrap();
+        inventory.remove(1, seed).unwrap();
+        garden.tick(&mut inventory, &mut world, &mut outbox, at(2));
+
+        assert_eq!(garden.stage(seed, at(2)), Err(AppCode::NotFound));
+    }
+}
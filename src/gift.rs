@@ -0,0 +1,483 @@
+use crate::currency::Wallets;
+use crate::inventory::{Inventory, InventoryItem, InventoryItemId, Reward};
+use crate::message::AppCode;
+use crate::session::{ClientEvent, Outbox, PlayerId};
+use crate::world::World;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+pub type GiftId = u64;
+
+/// How long a gift waits for an answer before it goes back to the sender
+pub const GIFT_EXPIRY_DAYS: i64 = 14;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GiftStatus {
+    Pending,
+    /// The recipient has looked at it but not made up their mind
+    Seen,
+    Claimed,
+    Declined,
+    Expired,
+}
+
+impl GiftStatus {
+    pub fn is_open(self) -> bool {
+        matches!(self, GiftStatus::Pending | GiftStatus::Seen)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gift {
+    pub id: GiftId,
+    /// Counts up separately for each recipient, what `FindGiftByPlayerGiftId` looks for
+    pub player_gift_id: u64,
+    /// Nobody for gifts the game hands out
+    pub sender: Option<PlayerId>,
+    pub recipient: PlayerId,
+    pub message: String,
+    /// Held here until the gift is claimed or goes back
+    pub items: Vec<InventoryItem>,
+    /// What a gift from the game gives, nothing is held for these
+    pub reward: Reward,
+    pub status: GiftStatus,
+    pub sent_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default)]
+pub struct Gifts {
+    gifts: BTreeMap<GiftId, Gift>,
+    received: HashMap<PlayerId, u64>,
+    next_id: GiftId,
+}
+
+impl Gifts {
+    pub fn gift(&self, gift: GiftId) -> Result<&Gift, AppCode> {
+        self.gifts.get(&gift).ok_or(AppCode::NotFound)
+    }
+
+    /// `GetPlayerGifts`, the ones the player sent
+    pub fn player_gifts(&self, player: PlayerId) -> Vec<&Gift> {
+        self.gifts
+            .values()
+            .filter(|gift| gift.sender == Some(player))
+            .collect()
+    }
+
+    /// `GetPlayerReceivedGifts`
+    pub fn player_received_gifts(&self, player: PlayerId) -> Vec<&Gift> {
+        self.gifts
+            .values()
+            .filter(|gift| gift.recipient == player)
+            .collect()
+    }
+
+    /// `FindGiftByPlayerGiftId`
+    pub fn find_gift_by_player_gift_id(
+        &self,
+        player: PlayerId,
+        player_gift_id: u64,
+    ) -> Result<&Gift, AppCode> {
+        self.gifts
+            .values()
+            .find(|gift| gift.recipient == player && gift.player_gift_id == player_gift_id)
+            .ok_or(AppCode::NotFound)
+    }
+
+    /// Takes the items out of the sender's inventory until the recipient answers
+    #[allow(clippy::too_many_arguments)]
+    pub fn send_gift(
+        &mut self,
+        sender: PlayerId,
+        recipient: PlayerId,
+        items: &[InventoryItemId],
+        message: &str,
+        inventory: &mut Inventory,
+        world: &World,
+        outbox: &mut Outbox,
+        now: DateTime<Utc>,
+    ) -> Result<GiftId, AppCode> {
+        if sender == recipient || items.is_empty() {
+            return Err(AppCode::Input);
+        }
+
+        let mut escrowed = Vec::new();
+        for &item in items {
+            match inventory.remove(sender, item) {
+                Ok(taken) => escrowed.push(taken),
+                Err(e) => {
+                    for taken in escrowed {
+                        inventory.restore(taken, sender);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(self.add(
+            Some(sender),
+            recipient,
+            message,
+            escrowed,
+            Reward::default(),
+            world,
+            outbox,
+            now,
+        ))
+    }
+
+    /// A gift from the game itself, for the player to claim whenever they like
+    pub fn give_claimable_gift(
+        &mut self,
+        recipient: PlayerId,
+        reward: Reward,
+        message: &str,
+        world: &World,
+        outbox: &mut Outbox,
+        now: DateTime<Utc>,
+    ) -> GiftId {
+        self.add(
+            None,
+            recipient,
+            message,
+            Vec::new(),
+            reward,
+            world,
+            outbox,
+            now,
+        )
+    }
+
+    /// `ClaimGift`
+    pub fn claim_gift(
+        &mut self,
+        player: PlayerId,
+        gift: GiftId,
+        inventory: &mut Inventory,
+        wallets: &mut Wallets,
+        world: &World,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        let claimed = self.answer(player, gift, GiftStatus::Claimed, world, outbox)?;
+
+        for item in std::mem::take(&mut claimed.items) {
+            inventory.restore(item, player);
+        }
+        claimed.reward.grant(player, inventory, wallets);
+
+        Ok(())
+    }
+
+    /// `ManageGiftRequest`, accepting claims it and declining sends it back
+    #[allow(clippy::too_many_arguments)]
+    pub fn manage_gift_request(
+        &mut self,
+        player: PlayerId,
+        gift: GiftId,
+        accept: bool,
+        inventory: &mut Inventory,
+        wallets: &mut Wallets,
+        world: &World,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        if accept {
+            return self.claim_gift(player, gift, inventory, wallets, world, outbox);
+        }
+
+        let declined = self.answer(player, gift, GiftStatus::Declined, world, outbox)?;
+        Self::return_items(declined, inventory);
+
+        Ok(())
+    }
+
+    /// `SetPlayerGiftStatus`, the recipient can only mark a gift as seen this way
+    pub fn set_player_gift_status(
+        &mut self,
+        player: PlayerId,
+        gift: GiftId,
+        status: GiftStatus,
+        world: &World,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        if status != GiftStatus::Seen {
+            return Err(AppCode::Input);
+        }
+
+        self.answer(player, gift, status, world, outbox)?;
+        Ok(())
+    }
+
+    /// Gifts nobody answered in time go back to the sender. Ones from the game wait forever
+    pub fn expire_gifts(
+        &mut self,
+        inventory: &mut Inventory,
+        world: &World,
+        outbox: &mut Outbox,
+        now: DateTime<Utc>,
+    ) {
+        let expiry = Duration::days(GIFT_EXPIRY_DAYS);
+
+        for gift in self.gifts.values_mut() {
+            if gift.sender.is_none() || !gift.status.is_open() || now - gift.sent_at < expiry {
+                continue;
+            }
+
+            gift.status = GiftStatus::Expired;
+            Self::notify(gift, world, outbox);
+            Self::return_items(gift, inventory);
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn add(
+        &mut self,
+        sender: Option<PlayerId>,
+        recipient: PlayerId,
+        message: &str,
+        items: Vec<InventoryItem>,
+        reward: Reward,
+        world: &World,
+        outbox: &mut Outbox,
+        now: DateTime<Utc>,
+    ) -> GiftId {
+        let received = self.received.entry(recipient).or_default();
+        *received += 1;
+
+        self.next_id += 1;
+        let gift = Gift {
+            id: self.next_id,
+            player_gift_id: *received,
+            sender,
+            recipient,
+            message: message.to_string(),
+            items,
+            reward,
+            status: GiftStatus::Pending,
+            sent_at: now,
+        };
+
+        Self::notify(&gift, world, outbox);
+        self.gifts.insert(gift.id, gift);
+
+        self.next_id
+    }
+
+    fn answer(
+        &mut self,
+        player: PlayerId,
+        gift: GiftId,
+        status: GiftStatus,
+        world: &World,
+        outbox: &mut Outbox,
+    ) -> Result<&mut Gift, AppCode> {
+        let answered = self.gifts.get_mut(&gift).ok_or(AppCode::NotFound)?;
+
+        if answered.recipient != player {
+            return Err(AppCode::Perm);
+        }
+
+        if !answered.status.is_open() {
+            return Err(AppCode::State);
+        }
+
+        answered.status = status;
+        Self::notify(answered, world, outbox);
+
+        Ok(answered)
+    }
+
+    fn return_items(gift: &mut Gift, inventory: &mut Inventory) {
+        if let Some(sender) = gift.sender {
+            for item in std::mem::take(&mut gift.items) {
+                inventory.restore(item, sender);
+            }
+        }
+    }
+
+    /// `GiftStatusNotify` to whichever side of the gift is in the world right now
+    fn notify(gift: &Gift, world: &World, outbox: &mut Outbox) {
+        let players = gift.sender.into_iter().chain([gift.recipient]);
+
+        for player in players {
+            if world.player_location(player).is_some() {
+                outbox.push(
+                    player,
+                    ClientEvent::GiftStatusNotify {
+                        gift: gift.id,
+                        status: gift.status,
+                    },
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::currency::{Amount, COINS};
+    use chrono::TimeZone;
+
+    const SENDER: PlayerId = 1;
+    const RECIPIENT: PlayerId = 2;
+
+    fn at(day: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, day, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn sending_takes_every_item_or_none() {
+        let mut gifts = Gifts::default();
+        let mut inventory = Inventory::default();
+        let world = World::default();
+        let mut outbox = Outbox::default();
+        let mine = inventory.grant(SENDER, 1, 1)[0];
+        let theirs = inventory.grant(RECIPIENT, 1, 1)[0];
+
+        assert_eq!(
+            gifts.send_gift(
+                SENDER,
+                RECIPIENT,
+                &[mine, theirs],
+                "",
+                &mut inventory,
+                &world,
+                &mut outbox,
+                at(1)
+            ),
+            Err(AppCode::ItemNotOwnedBySessionPlayer)
+        );
+        assert!(inventory.owns(SENDER, mine));
+        assert_eq!(
+            gifts.send_gift(
+                SENDER,
+                SENDER,
+                &[mine],
+                "",
+                &mut inventory,
+                &world,
+                &mut outbox,
+                at(1)
+            ),
+            Err(AppCode::Input)
+        );
+    }
+
+    #[test]
+    fn only_the_recipient_answers_and_only_once() {
+        let mut gifts = Gifts::default();
+        let mut inventory = Inventory::default();
+        let mut wallets = Wallets::default();
+        let world = World::default();
+        let mut outbox = Outbox::default();
+        let items = inventory.grant(SENDER, 1, 2);
+
+        let gift = gifts
+            .send_gift(
+                SENDER,
+                RECIPIENT,
+                &items,
+                "Enjoy",
+                &mut inventory,
+                &world,
+                &mut outbox,
+                at(1),
+            )
+            .unwrap();
+        assert_eq!(inventory.items(SENDER).count(), 0);
+        assert_eq!(
+            gifts.claim_gift(
+                SENDER,
+                gift,
+                &mut inventory,
+                &mut wallets,
+                &world,
+                &mut outbox
+            ),
+            Err(AppCode::Perm)
+        );
+
+        gifts
+            .manage_gift_request(
+                RECIPIENT,
+                gift,
+                false,
+                &mut inventory,
+                &mut wallets,
+                &world,
+                &mut outbox,
+            )
+            .unwrap();
+        assert_eq!(inventory.items(SENDER).count(), 2);
+        assert_eq!(
+            gifts.claim_gift(
+                RECIPIENT,
+                gift,
+                &mut inventory,
+                &mut wallets,
+                &world,
+                &mut outbox
+            ),
+            Err(AppCode::State)
+        );
+        assert_eq!(
+            gifts
+                .find_gift_by_player_gift_id(RECIPIENT, 1)
+                .unwrap()
+                .status,
+            GiftStatus::Declined
+        );
+    }
+
+    #[test]
+    fn unanswered_gifts_go_back_but_game_gifts_wait() {
+        let mut gifts = Gifts::default();
+        let mut inventory = Inventory::default();
+        let mut wallets = Wallets::default();
+        let world = World::default();
+        let mut outbox = Outbox::default();
+        let item = inventory.grant(SENDER, 1, 1)[0];
+
+        let sent = gifts
+            .send_gift(
+                SENDER,
+                RECIPIENT,
+                &[item],
+                "",
+                &mut inventory,
+                &world,
+                &mut outbox,
+                at(1),
+            )
+            .unwrap();
+        let reward = Reward {
+            items: Vec::new(),
+            currencies: vec![Amount {
+                currency: COINS,
+                amount: 5,
+            }],
+        };
+        let free =
+            gifts.give_claimable_gift(RECIPIENT, reward, "Welcome", &world, &mut outbox, at(1));
+
+        gifts.expire_gifts(&mut inventory, &world, &mut outbox, at(14));
+        assert_eq!(gifts.gift(sent).unwrap().status, GiftStatus::Pending);
+
+        gifts.expire_gifts(&mut inventory, &world, &mut outbox, at(15));
+        assert_eq!(gifts.gift(sent).unwrap().status, GiftStatus::Expired);
+        assert!(inventory.owns(SENDER, item));
+
+        gifts
+            .claim_gift(
+                RECIPIENT,
+                free,
+                &mut inventory,
+                &mut wallets,
+                &world,
+                &mut outbox,
+            )
+            .unwrap();
+        assert_eq!(wallets.balance(RECIPIENT, COINS), 5);
+    }
+}
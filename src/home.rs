@@ -0,0 +1,677 @@
+use crate::currency::{Amount, Wallets};
+use crate::data;
+use crate::inventory::{Footprint, Inventory, InventoryItem, InventoryItemId, ItemCatalog};
+use crate::message::AppCode;
+use crate::session::{Outbox, PlayerId};
+use crate::world::{LocationId, ObjectId, Position, World};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+
+pub type ThemeId = u32;
+
+/// Homes get sync locations of their own, well clear of village ids
+pub const HOME_LOCATIONS: LocationId = 1 << 40;
+
+/// World units per yard tile
+pub const TILE_SIZE: f32 = 1.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HomeTheme {
+    pub id: ThemeId,
+    pub name: String,
+    /// Free themes are unlocked for everybody
+    #[serde(default)]
+    pub cost: Vec<Amount>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct YardSize {
+    pub width: u32,
+    pub depth: u32,
+}
+
+impl Default for YardSize {
+    fn default() -> Self {
+        Self {
+            width: 32,
+            depth: 32,
+        }
+    }
+}
+
+/// What `data/homes.json` looks like
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HomeData {
+    pub yard: YardSize,
+    pub themes: Vec<HomeTheme>,
+    pub default_theme: ThemeId,
+}
+
+/// Where an item sits in the yard, in tiles from the corner
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct YardPlacement {
+    pub item: InventoryItemId,
+    pub x: u32,
+    pub z: u32,
+    /// Turned a quarter, swapping width and depth
+    #[serde(default)]
+    pub rotated: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct YardItem {
+    pub placement: YardPlacement,
+    /// Held here while it's out in the yard
+    pub item: InventoryItem,
+    footprint: Footprint,
+    object: Option<ObjectId>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Home {
+    pub owner: PlayerId,
+    /// Layout of the inside, the client owns the format
+    pub maze: String,
+    pub theme: ThemeId,
+    pub locked: bool,
+    pub invited: BTreeSet<PlayerId>,
+    pub yard: BTreeMap<InventoryItemId, YardItem>,
+    /// The house itself while the home is loaded
+    object: Option<ObjectId>,
+}
+
+#[derive(Debug, Default)]
+pub struct Homes {
+    yard: YardSize,
+    themes: BTreeMap<ThemeId, HomeTheme>,
+    default_theme: ThemeId,
+    homes: HashMap<PlayerId, Home>,
+    unlocked: HashMap<PlayerId, BTreeSet<ThemeId>>,
+}
+
+impl Homes {
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self::from_data(data::load(path)?))
+    }
+
+    pub fn from_data(data: HomeData) -> Self {
+        Self {
+            yard: data.yard,
+            themes: data
+                .themes
+                .into_iter()
+                .map(|theme| (theme.id, theme))
+                .collect(),
+            default_theme: data.default_theme,
+            homes: HashMap::new(),
+            unlocked: HashMap::new(),
+        }
+    }
+
+    /// Player ids too big to fit above `HOME_LOCATIONS` don't get a home
+    pub fn location(owner: PlayerId) -> Result<LocationId, AppCode> {
+        HOME_LOCATIONS.checked_add(owner).ok_or(AppCode::Input)
+    }
+
+    pub fn owner_of(location: LocationId) -> Option<PlayerId> {
+        location.checked_sub(HOME_LOCATIONS)
+    }
+
+    /// `GetHomeMaze`
+    pub fn home_maze(&mut self, owner: PlayerId) -> &str {
+        &self.home_mut(owner).maze
+    }
+
+    /// `UpdateHomeMaze`
+    pub fn update_home_maze(&mut self, owner: PlayerId, maze: String) {
+        self.home_mut(owner).maze = maze;
+    }
+
+    /// `LockHome`, guests without an invitation get put out. Returns who had to leave
+    pub fn lock_home(
+        &mut self,
+        owner: PlayerId,
+        locked: bool,
+        world: &mut World,
+        outbox: &mut Outbox,
+    ) -> Vec<PlayerId> {
+        self.home_mut(owner).locked = locked;
+        self.evict(owner, world, outbox)
+    }
+
+    /// `ManageHomeInvitations`, taking an invitation back from a locked home puts the guest out
+    pub fn manage_home_invitation(
+        &mut self,
+        owner: PlayerId,
+        guest: PlayerId,
+        invite: bool,
+        world: &mut World,
+        outbox: &mut Outbox,
+    ) -> Vec<PlayerId> {
+        let home = self.home_mut(owner);
+
+        if invite {
+            home.invited.insert(guest);
+        } else {
+            home.invited.remove(&guest);
+        }
+
+        self.evict(owner, world, outbox)
+    }
+
+    /// `GetHomeInvitations`, whose homes the player has been invited to
+    pub fn home_invitations(&self, guest: PlayerId) -> Vec<PlayerId> {
+        let mut owners: Vec<PlayerId> = self
+            .homes
+            .values()
+            .filter(|home| home.invited.contains(&guest))
+            .map(|home| home.owner)
+            .collect();
+
+        owners.sort();
+        owners
+    }
+
+    pub fn can_enter(&self, player: PlayerId, owner: PlayerId) -> bool {
+        player == owner
+            || self
+                .homes
+                .get(&owner)
+                .is_none_or(|home| !home.locked || home.invited.contains(&player))
+    }
+
+    /// `EnterBuilding`, loads the home as a sync location if nobody is in it yet
+    pub fn enter_building(
+        &mut self,
+        player: PlayerId,
+        owner: PlayerId,
+        position: Position,
+        world: &mut World,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        if !self.can_enter(player, owner) {
+            return Err(AppCode::Perm);
+        }
+
+        let location = Self::location(owner)?;
+
+        if world.location(location).is_none() {
+            world.add_location(location);
+            let home = self.home_mut(owner);
+
+            home.object = world
+                .spawn_object(
+                    location,
+                    Some(owner),
+                    home.theme as u64,
+                    Position::default(),
+                    Self::house_properties(home),
+                    outbox,
+                )
+                .ok();
+
+            for yard_item in home.yard.values_mut() {
+                yard_item.object = Self::spawn(owner, yard_item, world, outbox);
+            }
+        }
+
+        world.enter_loc(player, location, position, outbox)
+    }
+
+    /// Unload homes everyone has left
+    pub fn close_empty_homes(&mut self, world: &mut World, outbox: &mut Outbox) {
+        for home in self.homes.values_mut() {
+            let Ok(location) = Self::location(home.owner) else {
+                continue;
+            };
+
+            if world
+                .location(location)
+                .is_some_and(|loaded| loaded.players.is_empty())
+            {
+                world.remove_location(location, outbox);
+                home.object = None;
+
+                for yard_item in home.yard.values_mut() {
+                    yard_item.object = None;
+                }
+            }
+        }
+    }
+
+    /// `GetHomeThemes`
+    pub fn home_themes(&self) -> impl Iterator<Item = &HomeTheme> {
+        self.themes.values()
+    }
+
+    /// `GetPlayerHomeThemes`
+    pub fn player_home_themes(&self, player: PlayerId) -> Vec<&HomeTheme> {
+        self.themes
+            .values()
+            .filter(|theme| self.has_theme(player, theme.id))
+            .collect()
+    }
+
+    pub fn unlock_home_theme(
+        &mut self,
+        player: PlayerId,
+        theme: ThemeId,
+        wallets: &mut Wallets,
+    ) -> Result<(), AppCode> {
+        let definition = self.themes.get(&theme).ok_or(AppCode::NotFound)?;
+
+        if self.has_theme(player, theme) {
+            return Err(AppCode::DupRequest);
+        }
+
+        for cost in definition.cost.iter() {
+            if wallets.balance(player, cost.currency) < cost.amount {
+                return Err(AppCode::InsufficientFunds);
+            }
+        }
+
+        for cost in definition.cost.iter() {
+            wallets.debit(player, cost.currency, cost.amount)?;
+        }
+
+        self.unlocked.entry(player).or_default().insert(theme);
+        Ok(())
+    }
+
+    /// `UpdateHomeTheme`
+    pub fn update_home_theme(
+        &mut self,
+        owner: PlayerId,
+        theme: ThemeId,
+        world: &mut World,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        if !self.themes.contains_key(&theme) {
+            return Err(AppCode::NotFound);
+        }
+
+        if !self.has_theme(owner, theme) {
+            return Err(AppCode::Perm);
+        }
+
+        let home = self.home_mut(owner);
+        home.theme = theme;
+
+        if let (Some(object), Ok(location)) = (home.object, Self::location(owner)) {
+            let properties = Self::house_properties(home);
+            let _ = world.update_object(location, object, properties, outbox);
+        }
+
+        Ok(())
+    }
+
+    /// `GetYardItems`
+    pub fn yard_items(&mut self, owner: PlayerId) -> Vec<YardPlacement> {
+        self.home_mut(owner)
+            .yard
+            .values()
+            .map(|yard_item| yard_item.placement)
+            .collect()
+    }
+
+    /// `PlaceYardItem`, the item comes out of the inventory until it's removed again
+    pub fn place_yard_item(
+        &mut self,
+        owner: PlayerId,
+        placement: YardPlacement,
+        inventory: &mut Inventory,
+        catalog: &ItemCatalog,
+        world: &mut World,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        let location = Self::location(owner)?;
+        let owned = inventory
+            .get(placement.item)
+            .ok_or(AppCode::InventoryItemNotExist)?;
+        let footprint = catalog
+            .item(owned.item)
+            .ok()
+            .and_then(|definition| definition.footprint)
+            .ok_or(AppCode::InvalidItemOrBuildingPlacement)?;
+
+        let yard = self.yard;
+        let home = self.home_mut(owner);
+        Self::check_space(yard, &home.yard, placement, footprint)?;
+
+        let item = inventory.remove(owner, placement.item)?;
+        let mut yard_item = YardItem {
+            placement,
+            item,
+            footprint,
+            object: None,
+        };
+
+        if world.location(location).is_some() {
+            yard_item.object = Self::spawn(owner, &yard_item, world, outbox);
+        }
+
+        home.yard.insert(placement.item, yard_item);
+        Ok(())
+    }
+
+    /// `RemoveYardItem`, back into the inventory it goes
+    pub fn remove_yard_item(
+        &mut self,
+        owner: PlayerId,
+        item: InventoryItemId,
+        inventory: &mut Inventory,
+        world: &mut World,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        let location = Self::location(owner)?;
+        let yard_item = self
+            .home_mut(owner)
+            .yard
+            .remove(&item)
+            .ok_or(AppCode::NotFound)?;
+
+        if let Some(object) = yard_item.object {
+            let _ = world.despawn_object(location, object, outbox);
+        }
+
+        inventory.restore(yard_item.item, owner);
+        Ok(())
+    }
+
+    /// `UpdateYard`, moves items already in the yard. Every move is checked against
+    /// where everything else ends up, so two items can swap places
+    pub fn update_yard(
+        &mut self,
+        owner: PlayerId,
+        moves: &[YardPlacement],
+        world: &mut World,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        let location = Self::location(owner)?;
+        let yard = self.yard;
+        let home = self.home_mut(owner);
+        let mut arranged = home.yard.clone();
+
+        for placement in moves {
+            arranged
+                .get_mut(&placement.item)
+                .ok_or(AppCode::NotFound)?
+                .placement = *placement;
+        }
+
+        for yard_item in arranged.values() {
+            Self::check_space(yard, &arranged, yard_item.placement, yard_item.footprint)?;
+        }
+
+        home.yard = arranged;
+
+        for placement in moves {
+            if let Some(object) = home.yard[&placement.item].object {
+                let _ = world.place_object(location, object, Self::position(placement), outbox);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `others` can include the item being placed, it's skipped
+    fn check_space(
+        yard: YardSize,
+        others: &BTreeMap<InventoryItemId, YardItem>,
+        placement: YardPlacement,
+        footprint: Footprint,
+    ) -> Result<(), AppCode> {
+        let area = Self::area(placement, footprint);
+
+        if area.2 > yard.width || area.3 > yard.depth {
+            return Err(AppCode::InvalidItemOrBuildingPlacement);
+        }
+
+        let overlaps = others
+            .values()
+            .filter(|other| other.placement.item != placement.item)
+            .any(|other| {
+                let other = Self::area(other.placement, other.footprint);
+                area.0 < other.2 && other.0 < area.2 && area.1 < other.3 && other.1 < area.3
+            });
+
+        if overlaps {
+            return Err(AppCode::NotEnoughSpace);
+        }
+
+        Ok(())
+    }
+
+    /// Tiles covered as `(x, z, x end, z end)`
+    fn area(placement: YardPlacement, footprint: Footprint) -> (u32, u32, u32, u32) {
+        let (width, depth) = if placement.rotated {
+            (footprint.depth, footprint.width)
+        } else {
+            (footprint.width, footprint.depth)
+        };
+
+        (
+            placement.x,
+            placement.z,
+            placement.x.saturating_add(width),
+            placement.z.saturating_add(depth),
+        )
+    }
+
+    fn position(placement: &YardPlacement) -> Position {
+        Position {
+            x: placement.x as f32 * TILE_SIZE,
+            y: 0.0,
+            z: placement.z as f32 * TILE_SIZE,
+            heading: if placement.rotated { 90.0 } else { 0.0 },
+        }
+    }
+
+    fn spawn(
+        owner: PlayerId,
+        yard_item: &YardItem,
+        world: &mut World,
+        outbox: &mut Outbox,
+    ) -> Option<ObjectId> {
+        world
+            .spawn_object(
+                Self::location(owner).ok()?,
+                Some(owner),
+                yard_item.item.item,
+                Self::position(&yard_item.placement),
+                BTreeMap::from([("item".to_string(), yard_item.item.id.to_string())]),
+                outbox,
+            )
+            .ok()
+    }
+
+    /// Puts out anyone in a loaded home who isn't allowed in any more
+    fn evict(&self, owner: PlayerId, world: &mut World, outbox: &mut Outbox) -> Vec<PlayerId> {
+        let Some(loaded) = Self::location(owner)
+            .ok()
+            .and_then(|location| world.location(location))
+        else {
+            return Vec::new();
+        };
+
+        let mut evicted: Vec<PlayerId> = loaded
+            .players
+            .keys()
+            .copied()
+            .filter(|&player| !self.can_enter(player, owner))
+            .collect();
+        evicted.sort();
+
+        for &player in evicted.iter() {
+            let _ = world.exit_loc(player, outbox);
+        }
+
+        evicted
+    }
+
+    fn house_properties(home: &Home) -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("owner".to_string(), home.owner.to_string()),
+            ("theme".to_string(), home.theme.to_string()),
+        ])
+    }
+
+    fn has_theme(&self, player: PlayerId, theme: ThemeId) -> bool {
+        self.themes
+            .get(&theme)
+            .is_some_and(|definition| definition.cost.is_empty())
+            || self
+                .unlocked
+                .get(&player)
+                .is_some_and(|unlocked| unlocked.contains(&theme))
+    }
+
+    fn home_mut(&mut self, owner: PlayerId) -> &mut Home {
+        let theme = self.default_theme;
+
+        self.homes.entry(owner).or_insert_with(|| Home {
+            owner,
+            theme,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inventory::{ItemData, ItemDefinition};
+
+    const OWNER: PlayerId = 1;
+    const FRIEND: PlayerId = 2;
+    const STRANGER: PlayerId = 3;
+    const BENCH: u64 = 10;
+
+    fn visited_home() -> (Homes, World, Outbox) {
+        let mut homes = Homes::default();
+        let mut world = World::default();
+        let mut outbox = Outbox::default();
+
+        for player in [OWNER, FRIEND, STRANGER] {
+            homes
+                .enter_building(player, OWNER, Position::default(), &mut world, &mut outbox)
+                .unwrap();
+        }
+
+        (homes, world, outbox)
+    }
+
+    fn visitors(world: &World) -> Vec<PlayerId> {
+        let mut players: Vec<PlayerId> = world
+            .location(Homes::location(OWNER).unwrap())
+            .unwrap()
+            .players
+            .keys()
+            .copied()
+            .collect();
+        players.sort();
+        players
+    }
+
+    #[test]
+    fn locking_puts_uninvited_guests_out() {
+        let (mut homes, mut world, mut outbox) = visited_home();
+
+        homes.manage_home_invitation(OWNER, FRIEND, true, &mut world, &mut outbox);
+        assert_eq!(
+            homes.lock_home(OWNER, true, &mut world, &mut outbox),
+            vec![STRANGER]
+        );
+        assert_eq!(visitors(&world), vec![OWNER, FRIEND]);
+
+        assert_eq!(
+            homes.manage_home_invitation(OWNER, FRIEND, false, &mut world, &mut outbox),
+            vec![FRIEND]
+        );
+        assert_eq!(visitors(&world), vec![OWNER]);
+        assert_eq!(
+            homes.enter_building(
+                STRANGER,
+                OWNER,
+                Position::default(),
+                &mut world,
+                &mut outbox
+            ),
+            Err(AppCode::Perm)
+        );
+    }
+
+    #[test]
+    fn huge_player_ids_have_no_home() {
+        assert_eq!(Homes::location(PlayerId::MAX), Err(AppCode::Input));
+        assert_eq!(
+            Homes::owner_of(Homes::location(OWNER).unwrap()),
+            Some(OWNER)
+        );
+    }
+
+    #[test]
+    fn yard_items_cannot_overlap() {
+        let catalog = ItemCatalog::from_data(ItemData {
+            items: vec![ItemDefinition {
+                id: BENCH,
+                name: "Bench".to_string(),
+                kind: None,
+                slots: Vec::new(),
+                body_slot: None,
+                footprint: Some(Footprint { width: 3, depth: 1 }),
+            }],
+        });
+        let mut homes = Homes::default();
+        let mut inventory = Inventory::default();
+        let mut world = World::default();
+        let mut outbox = Outbox::default();
+        let benches = inventory.grant(OWNER, BENCH, 2);
+        let at = |item, x, rotated| YardPlacement {
+            item,
+            x,
+            z: 0,
+            rotated,
+        };
+
+        homes
+            .place_yard_item(
+                OWNER,
+                at(benches[0], 0, false),
+                &mut inventory,
+                &catalog,
+                &mut world,
+                &mut outbox,
+            )
+            .unwrap();
+        assert_eq!(
+            homes.place_yard_item(
+                OWNER,
+                at(benches[1], 2, false),
+                &mut inventory,
+                &catalog,
+                &mut world,
+                &mut outbox
+            ),
+            Err(AppCode::NotEnoughSpace)
+        );
+        homes
+            .place_yard_item(
+                OWNER,
+                at(benches[1], 3, true),
+                &mut inventory,
+                &catalog,
+                &mut world,
+                &mut outbox,
+            )
+            .unwrap();
+        assert_eq!(inventory.items(OWNER).count(), 0);
+
+        homes
+            .remove_yard_item(OWNER, benches[0], &mut inventory, &mut world, &mut outbox)
+            .unwrap();
+        assert!(inventory.owns(OWNER, benches[0]));
+    }
+}
@@ -0,0 +1,299 @@
+use crate::session::PlayerId;
+use crate::world::{ObjectId, Position};
+use std::collections::{HashMap, HashSet};
+
+/// How big a grid cell is, in world units
+pub const CELL_SIZE: f32 = 16.0;
+/// How far a client sees until it sends an `UpdateFilter`
+pub const DEFAULT_RADIUS: f32 = 48.0;
+/// Nobody gets to ask for the whole village
+pub const MAX_RADIUS: f32 = 256.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Entity {
+    Player(PlayerId),
+    Object(ObjectId),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sight {
+    /// The viewer didn't know where this was, it needs a `PosRecap`
+    Entered,
+    /// Still in range, send the movement along
+    Visible,
+    /// Went out of range, stop sending updates
+    Left,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Change {
+    pub viewer: PlayerId,
+    pub entity: Entity,
+    pub position: Position,
+    pub sight: Sight,
+}
+
+type Cell = (i32, i32);
+
+/// Uniform grid over the ground plane of one location
+#[derive(Debug)]
+pub struct Interest {
+    cell_size: f32,
+    cells: HashMap<Cell, HashSet<Entity>>,
+    positions: HashMap<Entity, Position>,
+    radius: HashMap<PlayerId, f32>,
+    visible: HashMap<PlayerId, HashSet<Entity>>,
+    seen_by: HashMap<Entity, HashSet<PlayerId>>,
+}
+
+impl Default for Interest {
+    fn default() -> Self {
+        Self::new(CELL_SIZE)
+    }
+}
+
+impl Interest {
+    pub fn new(cell_size: f32) -> Self {
+        Self {
+            cell_size,
+            cells: HashMap::new(),
+            positions: HashMap::new(),
+            radius: HashMap::new(),
+            visible: HashMap::new(),
+            seen_by: HashMap::new(),
+        }
+    }
+
+    pub fn radius(&self, player: PlayerId) -> f32 {
+        self.radius.get(&player).copied().unwrap_or(DEFAULT_RADIUS)
+    }
+
+    pub fn can_see(&self, viewer: PlayerId, entity: Entity) -> bool {
+        self.visible
+            .get(&viewer)
+            .is_some_and(|visible| visible.contains(&entity))
+    }
+
+    /// Insert or move an entity and work out who gained or lost sight of it
+    pub fn place(&mut self, entity: Entity, position: Position) -> Vec<Change> {
+        let cell = self.cell(position);
+
+        if let Some(old) = self.positions.insert(entity, position) {
+            let old = self.cell(old);
+            if old != cell {
+                self.leave_cell(entity, old);
+            }
+        }
+        self.cells.entry(cell).or_default().insert(entity);
+
+        let mut changes = self.update_viewers_of(entity, position);
+
+        if let Entity::Player(player) = entity {
+            changes.extend(self.update_view_of(player));
+        }
+
+        changes
+    }
+
+    pub fn remove(&mut self, entity: Entity) {
+        if let Some(position) = self.positions.remove(&entity) {
+            let cell = self.cell(position);
+            self.leave_cell(entity, cell);
+        }
+
+        for viewer in self.seen_by.remove(&entity).unwrap_or_default() {
+            if let Some(visible) = self.visible.get_mut(&viewer) {
+                visible.remove(&entity);
+            }
+        }
+
+        if let Entity::Player(player) = entity {
+            for seen in self.visible.remove(&player).unwrap_or_default() {
+                if let Some(viewers) = self.seen_by.get_mut(&seen) {
+                    viewers.remove(&player);
+                }
+            }
+            self.radius.remove(&player);
+        }
+    }
+
+    /// What `SyncMessage::UpdateFilter` ends up calling. Nonsense radii are ignored
+    pub fn set_radius(&mut self, player: PlayerId, radius: f32) -> Vec<Change> {
+        if !radius.is_finite() {
+            return Vec::new();
+        }
+
+        self.radius.insert(player, radius.clamp(0.0, MAX_RADIUS));
+        self.update_view_of(player)
+    }
+
+    fn cell(&self, position: Position) -> Cell {
+        (
+            (position.x / self.cell_size).floor() as i32,
+            (position.z / self.cell_size).floor() as i32,
+        )
+    }
+
+    fn leave_cell(&mut self, entity: Entity, cell: Cell) {
+        if let Some(entities) = self.cells.get_mut(&cell) {
+            entities.remove(&entity);
+            if entities.is_empty() {
+                self.cells.remove(&cell);
+            }
+        }
+    }
+
+    fn nearby(&self, position: Position, radius: f32) -> impl Iterator<Item = Entity> + '_ {
+        let (x, z) = self.cell(position);
+        let reach = (radius / self.cell_size).ceil() as i32;
+
+        // Cells out at the edge of an i32 just run into the edge
+        (x.saturating_sub(reach)..=x.saturating_add(reach))
+            .flat_map(move |x| {
+                (z.saturating_sub(reach)..=z.saturating_add(reach)).map(move |z| (x, z))
+            })
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .copied()
+    }
+
+    fn in_range(&self, viewer: PlayerId, position: Position) -> bool {
+        let Some(origin) = self.positions.get(&Entity::Player(viewer)) else {
+            return false;
+        };
+
+        let (dx, dz) = (origin.x - position.x, origin.z - position.z);
+        let radius = self.radius(viewer);
+
+        dx * dx + dz * dz <= radius * radius
+    }
+
+    fn see(&mut self, viewer: PlayerId, entity: Entity, now: bool) -> Option<Sight> {
+        let was = self.can_see(viewer, entity);
+
+        match (was, now) {
+            (false, true) => {
+                self.visible.entry(viewer).or_default().insert(entity);
+                self.seen_by.entry(entity).or_default().insert(viewer);
+                Some(Sight::Entered)
+            }
+            (true, true) => Some(Sight::Visible),
+            (true, false) => {
+                self.visible.entry(viewer).or_default().remove(&entity);
+                self.seen_by.entry(entity).or_default().remove(&viewer);
+                Some(Sight::Left)
+            }
+            (false, false) => None,
+        }
+    }
+
+    /// Everyone who could be looking at `entity` now that it is at `position`
+    fn update_viewers_of(&mut self, entity: Entity, position: Position) -> Vec<Change> {
+        let widest = self.radius.values().copied().fold(DEFAULT_RADIUS, f32::max);
+
+        let mut viewers: HashSet<PlayerId> = self.seen_by.get(&entity).cloned().unwrap_or_default();
+        viewers.extend(
+            self.nearby(position, widest)
+                .filter_map(|nearby| match nearby {
+                    Entity::Player(player) => Some(player),
+                    Entity::Object(_) => None,
+                }),
+        );
+
+        let mut changes = Vec::new();
+
+        for viewer in viewers {
+            if entity == Entity::Player(viewer) {
+                continue;
+            }
+
+            let now = self.in_range(viewer, position);
+            if let Some(sight) = self.see(viewer, entity, now) {
+                changes.push(Change {
+                    viewer,
+                    entity,
+                    position,
+                    sight,
+                });
+            }
+        }
+
+        changes
+    }
+
+    /// Everything `player` could be looking at from where they are now.
+    /// Only reports things coming and going, they didn't move so there's nothing to forward
+    fn update_view_of(&mut self, player: PlayerId) -> Vec<Change> {
+        let Some(&origin) = self.positions.get(&Entity::Player(player)) else {
+            return Vec::new();
+        };
+
+        let mut candidates: HashSet<Entity> =
+            self.visible.get(&player).cloned().unwrap_or_default();
+        candidates.extend(self.nearby(origin, self.radius(player)));
+        candidates.remove(&Entity::Player(player));
+
+        let mut changes = Vec::new();
+
+        for entity in candidates {
+            let position = self.positions[&entity];
+            let now = self.in_range(player, position);
+
+            if let Some(sight @ (Sight::Entered | Sight::Left)) = self.see(player, entity, now) {
+                changes.push(Change {
+                    viewer: player,
+                    entity,
+                    position,
+                    sight,
+                });
+            }
+        }
+
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: f32, z: f32) -> Position {
+        Position {
+            x,
+            z,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn players_see_what_is_in_range() {
+        let mut interest = Interest::default();
+
+        interest.place(Entity::Player(1), at(0.0, 0.0));
+        let changes = interest.place(Entity::Object(9), at(10.0, 0.0));
+
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].sight, Sight::Entered);
+        assert!(interest.can_see(1, Entity::Object(9)));
+
+        let changes = interest.place(Entity::Object(9), at(500.0, 0.0));
+        assert_eq!(changes[0].sight, Sight::Left);
+        assert!(!interest.can_see(1, Entity::Object(9)));
+    }
+
+    #[test]
+    fn extreme_positions_and_radii_dont_break_anything() {
+        let mut interest = Interest::default();
+
+        interest.place(Entity::Player(1), at(f32::MAX, f32::MIN));
+        interest.place(Entity::Player(2), at(-3.0e38, 3.0e38));
+        interest.place(Entity::Object(9), at(f32::MAX, f32::MIN));
+
+        interest.set_radius(1, MAX_RADIUS);
+        interest.set_radius(2, f32::NAN);
+        assert_eq!(interest.radius(2), DEFAULT_RADIUS);
+
+        interest.set_radius(2, f32::INFINITY);
+        assert_eq!(interest.radius(2), DEFAULT_RADIUS);
+    }
+}
@@ -0,0 +1,962 @@
+use crate::currency::{Amount, Wallets};
+use crate::data;
+use crate::message::AppCode;
+use crate::session::{Outbox, PlayerId};
+use crate::world::{LocationId, ObjectId, Position, World};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+
+/// An entry in the item catalog
+pub type ItemId = u64;
+/// One copy of an item that somebody owns
+pub type InventoryItemId = u64;
+/// Children that came loose when the item they were on was taken out, see `relink`
+pub type Links = Vec<(InventoryItemId, Attachment)>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InventoryItem {
+    pub id: InventoryItemId,
+    pub item: ItemId,
+    pub owner: PlayerId,
+    #[serde(default)]
+    pub attached: Option<Attachment>,
+    /// Only ever set on the top of a composed item, its children go wherever it goes
+    #[serde(default)]
+    pub placed: Option<Placement>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub parent: InventoryItemId,
+    pub slot: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Placement {
+    pub location: LocationId,
+    pub object: ObjectId,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ItemStack {
+    pub item: ItemId,
+    pub count: u32,
+}
+
+/// Items and currency handed out by quests, awards and the like
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Reward {
+    #[serde(default)]
+    pub items: Vec<ItemStack>,
+    #[serde(default)]
+    pub currencies: Vec<Amount>,
+}
+
+impl Reward {
+    pub fn grant(&self, player: PlayerId, inventory: &mut Inventory, wallets: &mut Wallets) {
+        for stack in self.items.iter() {
+            inventory.grant(player, stack.item, stack.count);
+        }
+
+        for amount in self.currencies.iter() {
+            wallets.credit(player, amount.currency, amount.amount);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ItemSlot {
+    pub name: String,
+    /// Item kinds that can be attached here
+    pub accepts: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ItemDefinition {
+    pub id: ItemId,
+    pub name: String,
+    #[serde(default)]
+    pub kind: Option<String>,
+    #[serde(default)]
+    pub slots: Vec<ItemSlot>,
+    /// Where it goes on an avatar, if it can be worn
+    #[serde(default)]
+    pub body_slot: Option<String>,
+    /// Tiles it takes up in a yard, if it can go in one
+    #[serde(default)]
+    pub footprint: Option<Footprint>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Footprint {
+    pub width: u32,
+    pub depth: u32,
+}
+
+/// What `data/items.json` looks like
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ItemData {
+    #[serde(default)]
+    pub items: Vec<ItemDefinition>,
+}
+
+#[derive(Debug, Default)]
+pub struct ItemCatalog {
+    items: BTreeMap<ItemId, ItemDefinition>,
+}
+
+impl ItemCatalog {
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self::from_data(data::load(path)?))
+    }
+
+    pub fn from_data(data: ItemData) -> Self {
+        Self {
+            items: data.items.into_iter().map(|item| (item.id, item)).collect(),
+        }
+    }
+
+    /// `GetItemById`
+    pub fn item(&self, item: ItemId) -> Result<&ItemDefinition, AppCode> {
+        self.items.get(&item).ok_or(AppCode::NotFound)
+    }
+}
+
+/// An item with everything attached to it, `GetComposedItem` and `SaveComposedItem`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ComposedItem {
+    pub id: InventoryItemId,
+    pub item: ItemId,
+    #[serde(default)]
+    pub slot: Option<String>,
+    #[serde(default)]
+    pub children: Vec<ComposedItem>,
+}
+
+/// What a saved inventory looks like, the indexes get rebuilt on load
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct StoredInventory {
+    items: BTreeMap<InventoryItemId, InventoryItem>,
+    next_id: InventoryItemId,
+}
+
+/// Every item anybody owns. Owners and attachments are indexed, so looking up a
+/// player's items or an item's children never walks the whole thing
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(from = "StoredInventory", into = "StoredInventory")]
+pub struct Inventory {
+    items: BTreeMap<InventoryItemId, InventoryItem>,
+    by_owner: HashMap<PlayerId, BTreeSet<InventoryItemId>>,
+    by_parent: HashMap<InventoryItemId, BTreeSet<InventoryItemId>>,
+    next_id: InventoryItemId,
+}
+
+impl From<StoredInventory> for Inventory {
+    fn from(stored: StoredInventory) -> Self {
+        let mut inventory = Self {
+            next_id: stored.next_id,
+            ..Default::default()
+        };
+
+        for item in stored.items.into_values() {
+            inventory.insert(item);
+        }
+
+        inventory
+    }
+}
+
+impl From<Inventory> for StoredInventory {
+    fn from(inventory: Inventory) -> Self {
+        Self {
+            items: inventory.items,
+            next_id: inventory.next_id,
+        }
+    }
+}
+
+impl Inventory {
+    pub fn get(&self, id: InventoryItemId) -> Option<&InventoryItem> {
+        self.items.get(&id)
+    }
+
+    /// `GetInventoryObjects`
+    pub fn items(&self, player: PlayerId) -> impl Iterator<Item = &InventoryItem> {
+        self.by_owner
+            .get(&player)
+            .into_iter()
+            .flatten()
+            .map(|id| &self.items[id])
+    }
+
+    pub fn count(&self, player: PlayerId, item: ItemId) -> usize {
+        self.items(player)
+            .filter(|owned| owned.item == item)
+            .count()
+    }
+
+    /// Copies that aren't attached or placed, the ones `take` can have
+    pub fn free_count(&self, player: PlayerId, item: ItemId) -> usize {
+        self.items(player)
+            .filter(|owned| {
+                owned.item == item && owned.attached.is_none() && owned.placed.is_none()
+            })
+            .count()
+    }
+
+    pub fn owns(&self, player: PlayerId, id: InventoryItemId) -> bool {
+        self.items.get(&id).is_some_and(|item| item.owner == player)
+    }
+
+    pub fn grant(&mut self, player: PlayerId, item: ItemId, count: u32) -> Vec<InventoryItemId> {
+        (0..count)
+            .map(|_| {
+                self.next_id += 1;
+                self.insert(InventoryItem {
+                    id: self.next_id,
+                    item,
+                    owner: player,
+                    attached: None,
+                    placed: None,
+                });
+                self.next_id
+            })
+            .collect()
+    }
+
+    /// Take one specific item out of a player's inventory. Anything attached to it
+    /// stays behind, loose
+    pub fn remove(
+        &mut self,
+        player: PlayerId,
+        id: InventoryItemId,
+    ) -> Result<InventoryItem, AppCode> {
+        self.remove_linked(player, id).map(|(item, _)| item)
+    }
+
+    /// `remove`, also handing back what came loose so it can be put back with `relink`
+    pub fn remove_linked(
+        &mut self,
+        player: PlayerId,
+        id: InventoryItemId,
+    ) -> Result<(InventoryItem, Links), AppCode> {
+        let item = self.owned(player, id)?;
+
+        if item.attached.is_some() || item.placed.is_some() {
+            return Err(AppCode::ChildAlreadyPlaced);
+        }
+
+        let links = self.release_children(id);
+        Ok((self.take_out(id), links))
+    }
+
+    /// Take `count` copies of an item, all of them or none. Attached or placed copies don't count
+    pub fn take(
+        &mut self,
+        player: PlayerId,
+        item: ItemId,
+        count: u32,
+    ) -> Result<Vec<InventoryItem>, AppCode> {
+        self.take_linked(player, item, count)
+            .map(|(items, _)| items)
+    }
+
+    /// `take`, also handing back what came loose so it can be put back with `relink`
+    pub fn take_linked(
+        &mut self,
+        player: PlayerId,
+        item: ItemId,
+        count: u32,
+    ) -> Result<(Vec<InventoryItem>, Links), AppCode> {
+        let ids: Vec<InventoryItemId> = self
+            .items(player)
+            .filter(|owned| {
+                owned.item == item && owned.attached.is_none() && owned.placed.is_none()
+            })
+            .map(|owned| owned.id)
+            .take(count as usize)
+            .collect();
+
+        if ids.len() < count as usize {
+            return Err(AppCode::InventoryItemNotExist);
+        }
+
+        let mut links = Links::new();
+        let taken = ids
+            .into_iter()
+            .map(|id| {
+                links.extend(self.release_children(id));
+                self.take_out(id)
+            })
+            .collect();
+
+        Ok((taken, links))
+    }
+
+    /// Put an item that was taken out back in, possibly for somebody else
+    pub fn restore(&mut self, mut item: InventoryItem, owner: PlayerId) {
+        item.owner = owner;
+        item.attached = None;
+        item.placed = None;
+        self.insert(item);
+    }
+
+    /// `restore` under a brand new id, for items that were held somewhere that outlives
+    /// the inventory, like mail on disk. Their old id might belong to something else by now
+    pub fn restore_as_new(&mut self, mut item: InventoryItem, owner: PlayerId) -> InventoryItemId {
+        self.next_id += 1;
+        item.id = self.next_id;
+        self.restore(item, owner);
+
+        self.next_id
+    }
+
+    /// Puts children back onto whatever they came loose from. Links are skipped if
+    /// either end has gone, changed hands or been used for something else since
+    pub fn relink(&mut self, links: Links) {
+        for (child, attachment) in links {
+            let Some(parent) = self.items.get(&attachment.parent) else {
+                continue;
+            };
+
+            let fits = self.items.get(&child).is_some_and(|child| {
+                child.owner == parent.owner && child.attached.is_none() && child.placed.is_none()
+            });
+
+            if fits && self.root(attachment.parent) != child {
+                self.set_attached(child, Some(attachment));
+            }
+        }
+    }
+
+    /// Hands over a whole composed item, children and all
+    pub fn transfer(
+        &mut self,
+        from: PlayerId,
+        to: PlayerId,
+        id: InventoryItemId,
+    ) -> Result<(), AppCode> {
+        let item = self.owned(from, id)?;
+
+        if item.attached.is_some() || item.placed.is_some() {
+            return Err(AppCode::ChildAlreadyPlaced);
+        }
+
+        for id in self.subtree(id) {
+            let item = self.items.get_mut(&id).unwrap();
+            let from = std::mem::replace(&mut item.owner, to);
+
+            if let Some(owned) = self.by_owner.get_mut(&from) {
+                owned.remove(&id);
+                if owned.is_empty() {
+                    self.by_owner.remove(&from);
+                }
+            }
+            self.by_owner.entry(to).or_default().insert(id);
+        }
+
+        Ok(())
+    }
+
+    /// Everything attached straight onto `id`
+    pub fn children(&self, id: InventoryItemId) -> impl Iterator<Item = &InventoryItem> {
+        self.by_parent
+            .get(&id)
+            .into_iter()
+            .flatten()
+            .map(|child| &self.items[child])
+    }
+
+    /// The item at the top of whatever `id` is attached to
+    pub fn root(&self, mut id: InventoryItemId) -> InventoryItemId {
+        while let Some(attached) = self.items.get(&id).and_then(|item| item.attached.as_ref()) {
+            id = attached.parent;
+        }
+
+        id
+    }
+
+    /// Where the composed item `id` is part of sits in the world, if anywhere
+    pub fn placement(&self, id: InventoryItemId) -> Option<Placement> {
+        self.items.get(&self.root(id)).and_then(|root| root.placed)
+    }
+
+    /// `AttachSingleItem`
+    pub fn attach_single_item(
+        &mut self,
+        player: PlayerId,
+        parent: InventoryItemId,
+        slot: &str,
+        child: InventoryItemId,
+        catalog: &ItemCatalog,
+    ) -> Result<(), AppCode> {
+        self.attach_items(player, parent, &[(slot.to_string(), child)], catalog)
+    }
+
+    /// `AttachItems`, all of them or none. Items out in the world have to go through
+    /// `attach_and_place_item` so everyone nearby sees the change
+    pub fn attach_items(
+        &mut self,
+        player: PlayerId,
+        parent: InventoryItemId,
+        children: &[(String, InventoryItemId)],
+        catalog: &ItemCatalog,
+    ) -> Result<(), AppCode> {
+        if self.placement(parent).is_some() {
+            return Err(AppCode::InvalidPlacement);
+        }
+
+        self.attach_all(player, parent, children, catalog)
+    }
+
+    /// `DetachItems`, children of the detached items stay on them
+    pub fn detach_items(
+        &mut self,
+        player: PlayerId,
+        children: &[InventoryItemId],
+    ) -> Result<(), AppCode> {
+        for &child in children {
+            if self.owned(player, child)?.attached.is_none() {
+                return Err(AppCode::State);
+            }
+
+            if self.placement(child).is_some() {
+                return Err(AppCode::InvalidPlacement);
+            }
+        }
+
+        for child in children {
+            self.set_attached(*child, None);
+        }
+
+        Ok(())
+    }
+
+    /// `GetComposedItem`
+    pub fn composed_item(&self, id: InventoryItemId) -> Result<ComposedItem, AppCode> {
+        let item = self.items.get(&id).ok_or(AppCode::InventoryItemNotExist)?;
+
+        Ok(ComposedItem {
+            id,
+            item: item.item,
+            slot: item.attached.as_ref().map(|attached| attached.slot.clone()),
+            children: self
+                .children(id)
+                .map(|child| self.composed_item(child.id))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// `SaveComposedItem`, rearranges everything under the top item to match `layout`.
+    /// Items dropped from the layout come off loose
+    pub fn save_composed_item(
+        &mut self,
+        player: PlayerId,
+        layout: &ComposedItem,
+        catalog: &ItemCatalog,
+    ) -> Result<(), AppCode> {
+        let root = self.owned(player, layout.id)?;
+
+        if root.attached.is_some() {
+            return Err(AppCode::ChildAlreadyPlaced);
+        }
+
+        if root.placed.is_some() {
+            return Err(AppCode::InvalidPlacement);
+        }
+
+        let before: Vec<(InventoryItemId, Option<Attachment>)> = self
+            .subtree(layout.id)
+            .into_iter()
+            .map(|id| (id, self.items[&id].attached.clone()))
+            .collect();
+
+        for (id, _) in before.iter().skip(1) {
+            self.set_attached(*id, None);
+        }
+
+        if let Err(e) = self.attach_layout(player, layout, catalog) {
+            for id in self.subtree(layout.id).into_iter().skip(1) {
+                self.set_attached(id, None);
+            }
+
+            for (id, attached) in before {
+                self.set_attached(id, attached);
+            }
+
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Put a composed item out in the player's current location. It shows up as one
+    /// object carrying its whole layout
+    pub fn place_item(
+        &mut self,
+        player: PlayerId,
+        id: InventoryItemId,
+        position: Position,
+        world: &mut World,
+        outbox: &mut Outbox,
+    ) -> Result<ObjectId, AppCode> {
+        let item = self.owned(player, id)?;
+
+        if item.attached.is_some() {
+            return Err(AppCode::ChildAlreadyPlaced);
+        }
+
+        if item.placed.is_some() {
+            return Err(AppCode::InvalidPlacement);
+        }
+
+        let location = world.player_location(player).ok_or(AppCode::NotFound)?;
+        let object = world.spawn_object(
+            location,
+            Some(player),
+            item.item,
+            position,
+            self.object_properties(id)?,
+            outbox,
+        )?;
+
+        self.items.get_mut(&id).unwrap().placed = Some(Placement { location, object });
+        Ok(object)
+    }
+
+    /// `DockItem`, picks a placed item back up into the inventory
+    pub fn dock_item(
+        &mut self,
+        player: PlayerId,
+        id: InventoryItemId,
+        world: &mut World,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        let placed = self.owned(player, id)?.placed.ok_or(AppCode::State)?;
+
+        let _ = world.despawn_object(placed.location, placed.object, outbox);
+        self.items.get_mut(&id).unwrap().placed = None;
+
+        Ok(())
+    }
+
+    /// `AttachAndPlaceItem`, attaches onto something that's already out in the world
+    #[allow(clippy::too_many_arguments)]
+    pub fn attach_and_place_item(
+        &mut self,
+        player: PlayerId,
+        parent: InventoryItemId,
+        slot: &str,
+        child: InventoryItemId,
+        catalog: &ItemCatalog,
+        world: &mut World,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        if self.placement(parent).is_none() {
+            return Err(AppCode::InvalidPlacement);
+        }
+
+        self.attach_all(player, parent, &[(slot.to_string(), child)], catalog)?;
+        self.refresh_placed(parent, world, outbox)
+    }
+
+    /// `RemoveAndDetachMazeItem`, takes a child off something out in the world
+    pub fn remove_and_detach_maze_item(
+        &mut self,
+        player: PlayerId,
+        child: InventoryItemId,
+        world: &mut World,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        let parent = self
+            .owned(player, child)?
+            .attached
+            .as_ref()
+            .ok_or(AppCode::State)?
+            .parent;
+
+        if self.placement(parent).is_none() {
+            return Err(AppCode::InvalidPlacement);
+        }
+
+        self.set_attached(child, None);
+        self.refresh_placed(parent, world, outbox)
+    }
+
+    fn attach_all(
+        &mut self,
+        player: PlayerId,
+        parent: InventoryItemId,
+        children: &[(String, InventoryItemId)],
+        catalog: &ItemCatalog,
+    ) -> Result<(), AppCode> {
+        for (done, (slot, child)) in children.iter().enumerate() {
+            if let Err(e) = self.attach(player, parent, slot, *child, catalog) {
+                for (_, attached) in children[..done].iter() {
+                    self.set_attached(*attached, None);
+                }
+
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn attach(
+        &mut self,
+        player: PlayerId,
+        parent: InventoryItemId,
+        slot: &str,
+        child: InventoryItemId,
+        catalog: &ItemCatalog,
+    ) -> Result<(), AppCode> {
+        let parent_item = self.owned(player, parent)?.item;
+        let child_item = self.owned(player, child)?;
+
+        if child_item.attached.is_some() || child_item.placed.is_some() {
+            return Err(AppCode::ChildAlreadyPlaced);
+        }
+
+        let definition = catalog
+            .item(parent_item)
+            .ok()
+            .and_then(|parent| parent.slots.iter().find(|candidate| candidate.name == slot))
+            .ok_or(AppCode::SlotNotOnParent)?;
+
+        if self.children(parent).any(|attached| {
+            attached
+                .attached
+                .as_ref()
+                .is_some_and(|attached| attached.slot == slot)
+        }) {
+            return Err(AppCode::SlotAlreadyUsed);
+        }
+
+        let fits = catalog
+            .item(child_item.item)
+            .ok()
+            .and_then(|child| child.kind.as_ref())
+            .is_some_and(|kind| definition.accepts.contains(kind));
+
+        if !fits {
+            return Err(AppCode::IncompatibleSlot);
+        }
+
+        // Attaching something onto its own child would make a loop
+        if self.root(parent) == child {
+            return Err(AppCode::InvalidPlacement);
+        }
+
+        self.set_attached(
+            child,
+            Some(Attachment {
+                parent,
+                slot: slot.to_string(),
+            }),
+        );
+
+        Ok(())
+    }
+
+    fn attach_layout(
+        &mut self,
+        player: PlayerId,
+        layout: &ComposedItem,
+        catalog: &ItemCatalog,
+    ) -> Result<(), AppCode> {
+        for child in layout.children.iter() {
+            let slot = child.slot.as_deref().ok_or(AppCode::Input)?;
+
+            self.attach(player, layout.id, slot, child.id, catalog)?;
+            self.attach_layout(player, child, catalog)?;
+        }
+
+        Ok(())
+    }
+
+    fn refresh_placed(
+        &mut self,
+        id: InventoryItemId,
+        world: &mut World,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        let root = self.root(id);
+        let placed = self.items[&root].placed.ok_or(AppCode::InvalidPlacement)?;
+
+        world.update_object(
+            placed.location,
+            placed.object,
+            self.object_properties(root)?,
+            outbox,
+        )
+    }
+
+    fn object_properties(&self, id: InventoryItemId) -> Result<BTreeMap<String, String>, AppCode> {
+        let composition =
+            serde_json::to_string(&self.composed_item(id)?).map_err(|_| AppCode::InternalError)?;
+
+        Ok(BTreeMap::from([
+            ("item".to_string(), id.to_string()),
+            ("composition".to_string(), composition),
+        ]))
+    }
+
+    /// `id` and everything under it, parents before children
+    fn subtree(&self, id: InventoryItemId) -> Vec<InventoryItemId> {
+        let mut found = vec![id];
+        let mut next = 0;
+
+        while next < found.len() {
+            let children: Vec<InventoryItemId> =
+                self.children(found[next]).map(|child| child.id).collect();
+
+            found.extend(children);
+            next += 1;
+        }
+
+        found
+    }
+
+    fn release_children(&mut self, id: InventoryItemId) -> Links {
+        self.by_parent
+            .remove(&id)
+            .into_iter()
+            .flatten()
+            .filter_map(|child| {
+                let attached = self.items.get_mut(&child)?.attached.take()?;
+                Some((child, attached))
+            })
+            .collect()
+    }
+
+    fn insert(&mut self, item: InventoryItem) {
+        if self.items.contains_key(&item.id) {
+            self.release_children(item.id);
+            self.take_out(item.id);
+        }
+
+        self.by_owner.entry(item.owner).or_default().insert(item.id);
+        if let Some(attached) = item.attached.as_ref() {
+            self.by_parent
+                .entry(attached.parent)
+                .or_default()
+                .insert(item.id);
+        }
+
+        self.items.insert(item.id, item);
+    }
+
+    /// Takes an item out of the inventory and the indexes. Its children are the
+    /// caller's problem, see `release_children`
+    fn take_out(&mut self, id: InventoryItemId) -> InventoryItem {
+        let mut item = self.items.remove(&id).unwrap();
+
+        if let Some(owned) = self.by_owner.get_mut(&item.owner) {
+            owned.remove(&id);
+            if owned.is_empty() {
+                self.by_owner.remove(&item.owner);
+            }
+        }
+        self.set_link(id, item.attached.take(), None);
+
+        item
+    }
+
+    fn set_attached(&mut self, id: InventoryItemId, attached: Option<Attachment>) {
+        let item = self.items.get_mut(&id).unwrap();
+        let before = std::mem::replace(&mut item.attached, attached.clone());
+
+        self.set_link(id, before, attached.as_ref());
+    }
+
+    fn set_link(
+        &mut self,
+        id: InventoryItemId,
+        before: Option<Attachment>,
+        after: Option<&Attachment>,
+    ) {
+        if let Some(before) = before {
+            if let Some(children) = self.by_parent.get_mut(&before.parent) {
+                children.remove(&id);
+                if children.is_empty() {
+                    self.by_parent.remove(&before.parent);
+                }
+            }
+        }
+
+        if let Some(after) = after {
+            self.by_parent.entry(after.parent).or_default().insert(id);
+        }
+    }
+
+    fn owned(&self, player: PlayerId, id: InventoryItemId) -> Result<&InventoryItem, AppCode> {
+        match self.items.get(&id) {
+            None => Err(AppCode::InventoryItemNotExist),
+            Some(item) if item.owner != player => Err(AppCode::ItemNotOwnedBySessionPlayer),
+            Some(item) => Ok(item),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOX: ItemId = 1;
+    const LID: ItemId = 2;
+
+    /// Boxes have a lid slot and a side slot, lids are boxes too so they stack
+    fn catalog() -> ItemCatalog {
+        let slots = vec![
+            ItemSlot {
+                name: "lid".to_string(),
+                accepts: vec!["lid".to_string()],
+            },
+            ItemSlot {
+                name: "side".to_string(),
+                accepts: vec!["lid".to_string()],
+            },
+        ];
+
+        ItemCatalog::from_data(ItemData {
+            items: vec![
+                ItemDefinition {
+                    id: BOX,
+                    name: "Box".to_string(),
+                    kind: None,
+                    slots: slots.clone(),
+                    body_slot: None,
+                    footprint: None,
+                },
+                ItemDefinition {
+                    id: LID,
+                    name: "Lid".to_string(),
+                    kind: Some("lid".to_string()),
+                    slots,
+                    body_slot: None,
+                    footprint: None,
+                },
+            ],
+        })
+    }
+
+    fn children(inventory: &Inventory, id: InventoryItemId) -> Vec<InventoryItemId> {
+        inventory.children(id).map(|child| child.id).collect()
+    }
+
+    #[test]
+    fn attaching_is_all_or_nothing() {
+        let catalog = catalog();
+        let mut inventory = Inventory::default();
+        let base = inventory.grant(1, BOX, 1)[0];
+        let lids = inventory.grant(1, LID, 2);
+
+        assert_eq!(
+            inventory.attach_items(
+                1,
+                base,
+                &[("lid".to_string(), lids[0]), ("lid".to_string(), lids[1])],
+                &catalog,
+            ),
+            Err(AppCode::SlotAlreadyUsed)
+        );
+        assert!(children(&inventory, base).is_empty());
+        assert!(inventory.get(lids[0]).unwrap().attached.is_none());
+
+        inventory
+            .attach_items(
+                1,
+                base,
+                &[("lid".to_string(), lids[0]), ("side".to_string(), lids[1])],
+                &catalog,
+            )
+            .unwrap();
+        assert_eq!(children(&inventory, base), lids);
+    }
+
+    #[test]
+    fn bad_layouts_leave_the_old_one_in_place() {
+        let catalog = catalog();
+        let mut inventory = Inventory::default();
+        let base = inventory.grant(1, BOX, 1)[0];
+        let lids = inventory.grant(1, LID, 2);
+        inventory
+            .attach_single_item(1, base, "lid", lids[0], &catalog)
+            .unwrap();
+
+        let layout = ComposedItem {
+            id: base,
+            item: BOX,
+            slot: None,
+            children: vec![
+                ComposedItem {
+                    id: lids[1],
+                    item: LID,
+                    slot: Some("lid".to_string()),
+                    children: Vec::new(),
+                },
+                ComposedItem {
+                    id: lids[0],
+                    item: LID,
+                    slot: Some("nowhere".to_string()),
+                    children: Vec::new(),
+                },
+            ],
+        };
+
+        assert_eq!(
+            inventory.save_composed_item(1, &layout, &catalog),
+            Err(AppCode::SlotNotOnParent)
+        );
+        assert_eq!(children(&inventory, base), vec![lids[0]]);
+        assert!(inventory.get(lids[1]).unwrap().attached.is_none());
+    }
+
+    #[test]
+    fn items_cannot_go_onto_their_own_children() {
+        let catalog = catalog();
+        let mut inventory = Inventory::default();
+        let lids = inventory.grant(1, LID, 3);
+
+        inventory
+            .attach_single_item(1, lids[0], "lid", lids[1], &catalog)
+            .unwrap();
+        inventory
+            .attach_single_item(1, lids[1], "lid", lids[2], &catalog)
+            .unwrap();
+        inventory.detach_items(1, &[lids[1]]).unwrap();
+
+        // lids[0] is loose now, but lids[1] -> lids[2] still hangs together
+        assert_eq!(
+            inventory.attach_single_item(1, lids[2], "side", lids[1], &catalog),
+            Err(AppCode::InvalidPlacement)
+        );
+        assert_eq!(inventory.root(lids[2]), lids[1]);
+    }
+
+    #[test]
+    fn indexes_follow_items_around() {
+        let catalog = catalog();
+        let mut inventory = Inventory::default();
+        let base = inventory.grant(1, BOX, 1)[0];
+        let lid = inventory.grant(1, LID, 1)[0];
+        inventory
+            .attach_single_item(1, base, "lid", lid, &catalog)
+            .unwrap();
+
+        let saved = serde_json::to_string(&inventory).unwrap();
+        let mut inventory: Inventory = serde_json::from_str(&saved).unwrap();
+        assert_eq!(children(&inventory, base), vec![lid]);
+
+        inventory.transfer(1, 2, base).unwrap();
+        assert_eq!(inventory.items(1).count(), 0);
+        assert_eq!(inventory.items(2).count(), 2);
+
+        let (_, links) = inventory.remove_linked(2, base).unwrap();
+        assert_eq!(links.len(), 1);
+        assert!(inventory.get(lid).unwrap().attached.is_none());
+        assert_eq!(inventory.grant(2, BOX, 1), vec![3]);
+    }
+}
@@ -0,0 +1,33 @@
+pub mod clock;
+pub mod content;
+pub mod contest;
+pub mod context;
+pub mod crafting;
+pub mod currency;
+pub mod daily_award;
+pub mod data;
+pub mod filter;
+pub mod friends;
+pub mod garden;
+pub mod gift;
+pub mod home;
+pub mod interest;
+pub mod inventory;
+pub mod mail;
+pub mod message;
+pub mod minigame;
+pub mod notification;
+pub mod npc;
+pub mod outfit;
+pub mod progression;
+pub mod quest;
+pub mod random;
+pub mod rules;
+pub mod session;
+pub mod shard;
+pub mod shared_quest;
+pub mod social;
+pub mod store;
+pub mod surprise;
+pub mod village;
+pub mod world;
@@ -0,0 +1,447 @@
+use crate::data;
+use crate::filter::{ChatMode, WordFilter};
+use crate::inventory::{Inventory, InventoryItem, InventoryItemId};
+use crate::message::AppCode;
+use crate::session::PlayerId;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+pub type MailId = u64;
+
+/// Most messages an inbox or sent folder keeps. The oldest ones that have been dealt
+/// with make room for new mail
+pub const MAX_FOLDER_MESSAGES: usize = 100;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MailMessage {
+    pub id: MailId,
+    pub sender: PlayerId,
+    pub recipient: PlayerId,
+    pub subject: String,
+    pub body: String,
+    /// Unix seconds
+    pub sent_at: i64,
+    pub read: bool,
+    /// Held here until the recipient claims them
+    #[serde(default)]
+    pub attachments: Vec<InventoryItem>,
+    #[serde(default)]
+    in_inbox: bool,
+    #[serde(default)]
+    in_sent: bool,
+}
+
+impl MailMessage {
+    /// Safe to throw away without losing anything
+    fn dealt_with(&self) -> bool {
+        self.read && self.attachments.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Folder {
+    Inbox,
+    Sent,
+}
+
+/// `GetMessagesCount`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessagesCount {
+    pub total: usize,
+    pub unread: usize,
+}
+
+/// Everybody's mail. Sending and claiming are written to disk straight away, read
+/// flags pile up until the next `flush`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Mailbox {
+    messages: BTreeMap<MailId, MailMessage>,
+    next_id: MailId,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    /// Changed since it was last written
+    #[serde(skip)]
+    dirty: bool,
+}
+
+impl Mailbox {
+    /// Picks up whatever was saved at `path` last time. No file just means no mail yet
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+
+        let mut mailbox: Self = if path.exists() {
+            data::load(path)?
+        } else {
+            Self::default()
+        };
+
+        mailbox.path = Some(path.to_path_buf());
+        Ok(mailbox)
+    }
+
+    /// `SendEmailMessage`
+    pub fn send_email_message(
+        &mut self,
+        sender: PlayerId,
+        recipient: PlayerId,
+        subject: &str,
+        body: &str,
+        filter: &WordFilter,
+        now: DateTime<Utc>,
+    ) -> Result<MailId, AppCode> {
+        self.send(sender, recipient, subject, body, Vec::new(), filter, now)
+    }
+
+    /// `SendEmailAttachment`, the items leave the sender's inventory straight away
+    #[allow(clippy::too_many_arguments)]
+    pub fn send_email_attachment(
+        &mut self,
+        sender: PlayerId,
+        recipient: PlayerId,
+        subject: &str,
+        body: &str,
+        items: &[InventoryItemId],
+        inventory: &mut Inventory,
+        filter: &WordFilter,
+        now: DateTime<Utc>,
+    ) -> Result<MailId, AppCode> {
+        if items.is_empty() {
+            return Err(AppCode::Input);
+        }
+
+        let mut attachments = Vec::new();
+        for &item in items {
+            match inventory.remove(sender, item) {
+                Ok(taken) => attachments.push(taken),
+                Err(e) => {
+                    for taken in attachments {
+                        inventory.restore(taken, sender);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        self.send(
+            sender,
+            recipient,
+            subject,
+            body,
+            attachments.clone(),
+            filter,
+            now,
+        )
+        .inspect_err(|_| {
+            for taken in attachments {
+                inventory.restore(taken, sender);
+            }
+        })
+    }
+
+    /// `ListInboxMessages`, newest first
+    pub fn list_inbox_messages(&self, player: PlayerId) -> Vec<&MailMessage> {
+        self.messages
+            .values()
+            .rev()
+            .filter(|message| message.in_inbox && message.recipient == player)
+            .collect()
+    }
+
+    /// `ListSentMessages`, newest first
+    pub fn list_sent_messages(&self, player: PlayerId) -> Vec<&MailMessage> {
+        self.messages
+            .values()
+            .rev()
+            .filter(|message| message.in_sent && message.sender == player)
+            .collect()
+    }
+
+    /// `GetInboxMessage`, reading it marks it read
+    pub fn get_inbox_message(
+        &mut self,
+        player: PlayerId,
+        message: MailId,
+    ) -> Result<MailMessage, AppCode> {
+        let found = self.inbox_message_mut(player, message)?;
+        let unread = !found.read;
+
+        found.read = true;
+        let found = found.clone();
+
+        self.dirty |= unread;
+        Ok(found)
+    }
+
+    /// `MarkInboxMessage`
+    pub fn mark_inbox_message(
+        &mut self,
+        player: PlayerId,
+        message: MailId,
+        read: bool,
+    ) -> Result<(), AppCode> {
+        self.inbox_message_mut(player, message)?.read = read;
+        self.dirty = true;
+
+        Ok(())
+    }
+
+    /// `GetMessagesCount`
+    pub fn messages_count(&self, player: PlayerId) -> MessagesCount {
+        let inbox = self.list_inbox_messages(player);
+
+        MessagesCount {
+            total: inbox.len(),
+            unread: inbox.iter().filter(|message| !message.read).count(),
+        }
+    }
+
+    /// Moves a message's attachments into the recipient's inventory. They get new ids
+    /// there, the inventory isn't saved and may have given the old ones out since
+    pub fn claim_attachments(
+        &mut self,
+        player: PlayerId,
+        message: MailId,
+        inventory: &mut Inventory,
+    ) -> Result<Vec<InventoryItemId>, AppCode> {
+        let found = self.inbox_message_mut(player, message)?;
+
+        if found.attachments.is_empty() {
+            return Err(AppCode::NotFound);
+        }
+
+        let claimed = std::mem::take(&mut found.attachments)
+            .into_iter()
+            .map(|item| inventory.restore_as_new(item, player))
+            .collect();
+
+        self.persist();
+        Ok(claimed)
+    }
+
+    /// `GetPlayerAttachmentItemsBySenderId`, unclaimed items waiting for `player` from `sender`
+    pub fn attachment_items_by_sender(
+        &self,
+        player: PlayerId,
+        sender: PlayerId,
+    ) -> Vec<&InventoryItem> {
+        self.messages
+            .values()
+            .filter(|message| message.recipient == player && message.sender == sender)
+            .flat_map(|message| message.attachments.iter())
+            .collect()
+    }
+
+    /// `ListSendingPlayerAttachmentItems`, items the player sent that nobody has claimed yet
+    pub fn sending_player_attachment_items(&self, player: PlayerId) -> Vec<&InventoryItem> {
+        self.messages
+            .values()
+            .filter(|message| message.sender == player)
+            .flat_map(|message| message.attachments.iter())
+            .collect()
+    }
+
+    /// Write everything out now, rather than waiting for the next `flush`
+    pub fn save(&mut self) -> std::io::Result<()> {
+        if let Some(path) = &self.path {
+            data::save(path, self)?;
+        }
+
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Writes out read flags and anything else still waiting. Run every poll
+    pub fn flush(&mut self) {
+        if self.dirty {
+            self.persist();
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn send(
+        &mut self,
+        sender: PlayerId,
+        recipient: PlayerId,
+        subject: &str,
+        body: &str,
+        attachments: Vec<InventoryItem>,
+        filter: &WordFilter,
+        now: DateTime<Utc>,
+    ) -> Result<MailId, AppCode> {
+        if sender == recipient {
+            return Err(AppCode::Input);
+        }
+
+        filter.check_chat(subject, ChatMode::Open)?;
+        filter.check_chat(body, ChatMode::Open)?;
+
+        self.make_room(recipient, Folder::Inbox)?;
+        self.make_room(sender, Folder::Sent)?;
+
+        self.next_id += 1;
+        self.messages.insert(
+            self.next_id,
+            MailMessage {
+                id: self.next_id,
+                sender,
+                recipient,
+                subject: subject.to_string(),
+                body: body.to_string(),
+                sent_at: now.timestamp(),
+                read: false,
+                attachments,
+                in_inbox: true,
+                in_sent: true,
+            },
+        );
+
+        self.persist();
+        Ok(self.next_id)
+    }
+
+    /// Drops the oldest message from a full folder. Inbox mail has to be read with nothing
+    /// left to claim first, so an inbox full of unread mail can't take any more
+    fn make_room(&mut self, player: PlayerId, folder: Folder) -> Result<(), AppCode> {
+        let in_folder = |message: &MailMessage| match folder {
+            Folder::Inbox => message.in_inbox && message.recipient == player,
+            Folder::Sent => message.in_sent && message.sender == player,
+        };
+
+        let messages: Vec<&MailMessage> = self
+            .messages
+            .values()
+            .filter(|message| in_folder(message))
+            .collect();
+
+        if messages.len() < MAX_FOLDER_MESSAGES {
+            return Ok(());
+        }
+
+        let oldest = messages
+            .into_iter()
+            .find(|message| folder == Folder::Sent || message.dealt_with())
+            .ok_or(AppCode::NotEnoughSpace)?
+            .id;
+
+        let dropped = self.messages.get_mut(&oldest).unwrap();
+        match folder {
+            Folder::Inbox => dropped.in_inbox = false,
+            Folder::Sent => dropped.in_sent = false,
+        }
+
+        // Attachments stay put until the recipient has claimed them
+        if !dropped.in_inbox && !dropped.in_sent {
+            self.messages.remove(&oldest);
+        }
+
+        Ok(())
+    }
+
+    fn inbox_message_mut(
+        &mut self,
+        player: PlayerId,
+        message: MailId,
+    ) -> Result<&mut MailMessage, AppCode> {
+        self.messages
+            .get_mut(&message)
+            .filter(|message| message.in_inbox && message.recipient == player)
+            .ok_or(AppCode::NotFound)
+    }
+
+    /// Saves now. If that fails it stays dirty and the next `flush` has another go
+    fn persist(&mut self) {
+        self.dirty = true;
+        if let Err(e) = self.save() {
+            log::warn!("Could not save mail: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const SENDER: PlayerId = 1;
+    const RECIPIENT: PlayerId = 2;
+
+    fn at(day: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, day, 12, 0, 0).unwrap()
+    }
+
+    fn scratch(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("mail-{}-{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn claimed_attachments_do_not_clobber_items_made_after_a_restart() {
+        let path = scratch("restart");
+        let filter = WordFilter::default();
+        let mut mailbox = Mailbox::open(&path).unwrap();
+        let mut inventory = Inventory::default();
+        let sent = inventory.grant(SENDER, 7, 1);
+
+        let message = mailbox
+            .send_email_attachment(
+                SENDER,
+                RECIPIENT,
+                "hi",
+                "",
+                &sent,
+                &mut inventory,
+                &filter,
+                at(1),
+            )
+            .unwrap();
+
+        // The inventory starts over, and hands the attachment's old id to something else
+        let mut mailbox = Mailbox::open(&path).unwrap();
+        let mut inventory = Inventory::default();
+        let other = inventory.grant(SENDER, 9, 1)[0];
+        assert_eq!(other, sent[0]);
+
+        let claimed = mailbox
+            .claim_attachments(RECIPIENT, message, &mut inventory)
+            .unwrap();
+
+        assert_ne!(claimed[0], other);
+        assert_eq!(inventory.get(other).unwrap().item, 9);
+        assert_eq!(inventory.get(other).unwrap().owner, SENDER);
+        assert_eq!(inventory.get(claimed[0]).unwrap().item, 7);
+        assert!(inventory.owns(RECIPIENT, claimed[0]));
+        assert_eq!(
+            mailbox.claim_attachments(RECIPIENT, message, &mut inventory),
+            Err(AppCode::NotFound)
+        );
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn read_flags_wait_for_a_flush() {
+        let path = scratch("flush");
+        let filter = WordFilter::default();
+        let mut mailbox = Mailbox::open(&path).unwrap();
+        let message = mailbox
+            .send_email_message(SENDER, RECIPIENT, "hi", "", &filter, at(1))
+            .unwrap();
+
+        assert!(mailbox.get_inbox_message(RECIPIENT, message).unwrap().read);
+        assert!(!Mailbox::open(&path).unwrap().messages[&message].read);
+
+        mailbox.flush();
+        assert!(Mailbox::open(&path).unwrap().messages[&message].read);
+
+        mailbox
+            .mark_inbox_message(RECIPIENT, message, false)
+            .unwrap();
+        mailbox.flush();
+        assert!(!Mailbox::open(&path).unwrap().messages[&message].read);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,19 +1,18 @@
-mod context;
-mod message;
-
-use std::io::Write;
-
-use crate::context::AmazingWorldServer;
-use crate::message::decode_message;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpListener;
-use tokio::runtime::{Handle, Runtime};
+use amazing_world::context::AmazingWorldServer;
+use amazing_world::data;
+use amazing_world::shard::ServerConfig;
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
 
-    let mut server = AmazingWorldServer::new("0.0.0.0".parse().unwrap()).await;
+    // Pass a config file to run more than one server side by side
+    let config: ServerConfig = match std::env::args().nth(1) {
+        Some(path) => data::load(path).unwrap(),
+        None => ServerConfig::default(),
+    };
+
+    let mut server = AmazingWorldServer::start(config).await.unwrap();
 
     loop {
         server.poll().await;
@@ -1,19 +1,13 @@
-use binrw::BinReaderExt;
 use bitvec::field::BitField;
-use bitvec::prelude::{Lsb0, Msb0};
+use bitvec::prelude::{BitVec, Msb0};
 use bitvec::view::BitView;
-use chrono::{DateTime, Utc};
 use nom::combinator::cond;
-use nom::error::Error;
 use nom::sequence::{tuple, Tuple};
+use nom::IResult;
 use nom::{bits, bytes};
-use nom::{IResult, Parser};
 use num_enum::TryFromPrimitive;
-use std::io::Cursor;
-use std::mem::size_of;
-use std::ops::Deref;
 
-#[derive(PartialEq, Eq, Hash, Debug, TryFromPrimitive)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, TryFromPrimitive)]
 #[repr(i64)]
 pub enum ClientMessage {
     AddObject = 1,
@@ -45,7 +39,7 @@ pub enum ClientMessage {
     ChangeObjectState = 28,
 }
 
-#[derive(PartialEq, Eq, Hash, Debug, TryFromPrimitive)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, TryFromPrimitive)]
 #[repr(i64)]
 pub enum UserMessage {
     GetAvatars = 1,
@@ -622,6 +616,7 @@ pub enum UserMessage {
     EnhanceRecipe = 577,
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, TryFromPrimitive)]
 #[repr(i64)]
 pub enum UserMessage2 {
@@ -630,7 +625,7 @@ pub enum UserMessage2 {
     MfAgent = 12,
 }
 
-#[derive(PartialEq, Eq, Hash, Debug, TryFromPrimitive)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, TryFromPrimitive)]
 #[repr(i64)]
 pub enum SyncMessage {
     AddObject = 1,
@@ -689,7 +684,7 @@ pub enum SyncMessage {
     Relogin = 55,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum AppCode {
     Ilg = -1,
     Ok = 0,
@@ -866,9 +861,9 @@ pub struct Message {
 pub fn decode_message(buffer: &[u8]) -> Option<Message> {
     let (_, buffer) = get_message_data(buffer).unwrap();
     let buffer = (buffer, 0);
-    let (buffer, (gsf_request_null, message_header_null)) = get_start_bits(buffer).unwrap();
+    let (buffer, (_gsf_request_null, message_header_null)) = get_start_bits(buffer).unwrap();
 
-    let (buffer, header) = cond(
+    let (_, header) = cond(
         !message_header_null,
         tuple((get_number::<4>, get_message_type, get_number::<4>)),
     )(buffer)
@@ -885,6 +880,32 @@ pub fn decode_message(buffer: &[u8]) -> Option<Message> {
     None
 }
 
+/// The other way round from `decode_message`, just the header like decoding
+pub fn encode_message(message_type: &MessageType, flags: u32, request_id: u32) -> Vec<u8> {
+    let (service_class, message) = match message_type {
+        MessageType::User(message) => (ServiceClass::UserServer as i64, *message as i64),
+        MessageType::Sync(message) => (ServiceClass::SyncServer as i64, *message as i64),
+        MessageType::Client(message) => (ServiceClass::Client as i64, *message as i64),
+    };
+
+    // Both start bits clear, then every number at its full four bytes
+    let mut bits: BitVec<u8, Msb0> = BitVec::repeat(false, 2);
+
+    for number in [flags as i64, service_class, message, request_id as i64] {
+        bits.push(false);
+
+        let start = bits.len();
+        bits.resize(start + 32, false);
+        bits[start..].store_be(number as i32);
+    }
+
+    let body = bits.into_vec();
+    let mut frame = vec![body.len() as u8];
+    frame.extend(body);
+
+    frame
+}
+
 pub fn get_start_bits(buffer: (&[u8], usize)) -> IResult<(&[u8], usize), (bool, bool)> {
     (bits::complete::bool, bits::complete::bool).parse(buffer)
 }
@@ -981,3 +1002,23 @@ pub fn get_size<const MAX_BYTES: usize>(buffer: (&[u8], usize)) -> IResult<(&[u8
 
     Ok((buffer, if byte_size != 0 { byte_size * 8 } else { 4 }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoded_headers_decode_again() {
+        for message_type in [
+            MessageType::User(UserMessage::StartGame),
+            MessageType::Sync(SyncMessage::MoveObject),
+            MessageType::Client(ClientMessage::ChangeObjectState),
+        ] {
+            let frame = encode_message(&message_type, 3, 77);
+            let message = decode_message(&frame).unwrap();
+
+            assert_eq!(message.message_type, message_type);
+            assert_eq!((message.flags, message.request_id), (3, 77));
+        }
+    }
+}
@@ -0,0 +1,522 @@
+use crate::data;
+use crate::friends::Friends;
+use crate::message::AppCode;
+use crate::quest::{QuestEngine, QuestId};
+use crate::session::PlayerId;
+use crate::world::LocationId;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+pub type GameId = u32;
+pub type GameOfferId = u64;
+
+/// Biggest save state a game can hand us
+pub const MAX_SAVE_BYTES: usize = 64 * 1024;
+
+/// Most sent games a player can have waiting on them
+pub const MAX_GAME_OFFERS: usize = 20;
+
+/// Gets a say in whether a score is believable, on top of the limits in the data file
+pub type ScoreHook = fn(&GameDefinition, &GameSession, u64, DateTime<Utc>) -> bool;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GameDefinition {
+    pub id: GameId,
+    pub name: String,
+    /// Where in the world it's played, anywhere if there isn't one
+    #[serde(default)]
+    pub zone: Option<LocationId>,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub max_score: Option<u64>,
+    /// Nobody scores faster than this
+    #[serde(default)]
+    pub max_points_per_second: Option<u64>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+/// What `data/games.json` looks like
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GameData {
+    pub games: Vec<GameDefinition>,
+}
+
+/// A game somebody is playing right now
+#[derive(Debug, Clone, PartialEq)]
+pub struct GameSession {
+    pub game: GameId,
+    pub player: PlayerId,
+    /// Set for `CreateQuestGame`, finishing counts towards the quest
+    pub quest: Option<QuestId>,
+    pub started_at: DateTime<Utc>,
+}
+
+/// `EndGame`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GameResult {
+    pub game: GameId,
+    pub score: u64,
+    pub best: u64,
+    pub new_best: bool,
+}
+
+/// `GetPlayerGamesByZone`, one of these for every game in the zone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlayerGame {
+    pub game: GameId,
+    pub best: Option<u64>,
+    pub saved: bool,
+}
+
+/// Whatever the game wanted kept. We never look inside
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SavedGame {
+    pub game: GameId,
+    pub player: PlayerId,
+    pub state: Vec<u8>,
+    pub saved_at: DateTime<Utc>,
+}
+
+/// A copy of a save on its way to a friend
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GameOffer {
+    pub id: GameOfferId,
+    pub from: PlayerId,
+    pub to: PlayerId,
+    pub game: GameId,
+    pub state: Vec<u8>,
+    pub sent_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default)]
+pub struct Minigames {
+    games: BTreeMap<GameId, GameDefinition>,
+    hooks: HashMap<GameId, ScoreHook>,
+    sessions: HashMap<PlayerId, GameSession>,
+    best: HashMap<(PlayerId, GameId), u64>,
+    saves: BTreeMap<(PlayerId, GameId), SavedGame>,
+    offers: BTreeMap<GameOfferId, GameOffer>,
+    next_id: GameOfferId,
+}
+
+impl Minigames {
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self::from_data(data::load(path)?))
+    }
+
+    pub fn from_data(data: GameData) -> Self {
+        Self {
+            games: data.games.into_iter().map(|game| (game.id, game)).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Runs when a game of this kind ends. One per game, a new one replaces the old
+    pub fn register_score_hook(&mut self, game: GameId, hook: ScoreHook) {
+        self.hooks.insert(game, hook);
+    }
+
+    pub fn game(&self, game: GameId) -> Result<&GameDefinition, AppCode> {
+        self.games.get(&game).ok_or(AppCode::InvalidGameId)
+    }
+
+    /// `GetGames`, the ones that can be played
+    pub fn games(&self) -> Vec<&GameDefinition> {
+        self.games.values().filter(|game| game.enabled).collect()
+    }
+
+    /// `GetAllGames`, switched off ones too
+    pub fn all_games(&self) -> Vec<&GameDefinition> {
+        self.games.values().collect()
+    }
+
+    /// `GetPlayerGamesByZone`
+    pub fn player_games_by_zone(&self, player: PlayerId, zone: LocationId) -> Vec<PlayerGame> {
+        self.games()
+            .into_iter()
+            .filter(|game| game.zone == Some(zone))
+            .map(|game| PlayerGame {
+                game: game.id,
+                best: self.best.get(&(player, game.id)).copied(),
+                saved: self.saves.contains_key(&(player, game.id)),
+            })
+            .collect()
+    }
+
+    pub fn session(&self, player: PlayerId) -> Option<&GameSession> {
+        self.sessions.get(&player)
+    }
+
+    /// `StartGame`, anything the player was already playing is dropped without a score
+    pub fn start_game(
+        &mut self,
+        player: PlayerId,
+        game: GameId,
+        now: DateTime<Utc>,
+    ) -> Result<(), AppCode> {
+        self.start(player, game, None, now)
+    }
+
+    /// `CreateQuestGame`, a game played for one of the player's active quests
+    pub fn create_quest_game(
+        &mut self,
+        player: PlayerId,
+        game: GameId,
+        quest: QuestId,
+        quests: &QuestEngine,
+        now: DateTime<Utc>,
+    ) -> Result<(), AppCode> {
+        if !quests
+            .player_quest(player, quest)
+            .is_some_and(|state| state.is_active())
+        {
+            return Err(AppCode::State);
+        }
+
+        self.start(player, game, Some(quest), now)
+    }
+
+    /// `EndGame`, the score has to get past the game's limits and its hook. A quest
+    /// game counts as a `game` event towards the quest it was played for
+    pub fn end_game(
+        &mut self,
+        player: PlayerId,
+        score: u64,
+        quests: &mut QuestEngine,
+        now: DateTime<Utc>,
+    ) -> Result<GameResult, AppCode> {
+        let session = self.sessions.remove(&player).ok_or(AppCode::State)?;
+        let definition = self.game(session.game)?;
+
+        if !Self::believable(definition, &session, score, now)
+            || self
+                .hooks
+                .get(&session.game)
+                .is_some_and(|hook| !hook(definition, &session, score, now))
+        {
+            return Err(AppCode::TooManyPoints);
+        }
+
+        let best = self.best.entry((player, session.game)).or_default();
+        let new_best = score > *best;
+        *best = (*best).max(score);
+
+        if let Some(quest) = session.quest {
+            quests.record_quest_event(player, quest, "game", session.game as u64, 1);
+        }
+
+        Ok(GameResult {
+            game: session.game,
+            score,
+            best: *best,
+            new_best,
+        })
+    }
+
+    /// `SaveGameState`, replaces whatever the player had saved for the game
+    pub fn save_game_state(
+        &mut self,
+        player: PlayerId,
+        game: GameId,
+        state: Vec<u8>,
+        now: DateTime<Utc>,
+    ) -> Result<(), AppCode> {
+        self.game(game)?;
+
+        if state.len() > MAX_SAVE_BYTES {
+            return Err(AppCode::NoSpace);
+        }
+
+        self.saves.insert(
+            (player, game),
+            SavedGame {
+                game,
+                player,
+                state,
+                saved_at: now,
+            },
+        );
+        Ok(())
+    }
+
+    /// `ListSavedGames`
+    pub fn list_saved_games(&self, player: PlayerId) -> Vec<&SavedGame> {
+        self.saves
+            .range((player, GameId::MIN)..=(player, GameId::MAX))
+            .map(|(_, save)| save)
+            .collect()
+    }
+
+    /// `LoadGame`
+    pub fn load_game(&self, player: PlayerId, game: GameId) -> Result<&SavedGame, AppCode> {
+        self.saves.get(&(player, game)).ok_or(AppCode::NotFound)
+    }
+
+    /// `CmsGetGameState`, for operators looking at anyone's save
+    pub fn cms_game_state(&self, player: PlayerId, game: GameId) -> Result<&SavedGame, AppCode> {
+        self.load_game(player, game)
+    }
+
+    /// `SendGame`, a copy of the sender's save for a friend. Later saves don't change it.
+    /// Refused once the friend has `MAX_GAME_OFFERS` waiting
+    pub fn send_game(
+        &mut self,
+        from: PlayerId,
+        to: PlayerId,
+        game: GameId,
+        friends: &Friends,
+        now: DateTime<Utc>,
+    ) -> Result<GameOfferId, AppCode> {
+        if !friends.are_friends(from, to) {
+            return Err(AppCode::InvalidRelationship);
+        }
+
+        let state = self.load_game(from, game)?.state.clone();
+
+        if self.game_offers(to).len() >= MAX_GAME_OFFERS {
+            return Err(AppCode::NoSpace);
+        }
+
+        self.next_id += 1;
+        self.offers.insert(
+            self.next_id,
+            GameOffer {
+                id: self.next_id,
+                from,
+                to,
+                game,
+                state,
+                sent_at: now,
+            },
+        );
+
+        Ok(self.next_id)
+    }
+
+    /// Offers waiting on the player
+    pub fn game_offers(&self, player: PlayerId) -> Vec<&GameOffer> {
+        self.offers
+            .values()
+            .filter(|offer| offer.to == player)
+            .collect()
+    }
+
+    /// `AcceptGame`, the sent save takes the place of the player's own
+    pub fn accept_game(
+        &mut self,
+        player: PlayerId,
+        offer: GameOfferId,
+        now: DateTime<Utc>,
+    ) -> Result<GameId, AppCode> {
+        let offer = self.take_offer(player, offer)?;
+        let game = offer.game;
+
+        self.save_game_state(player, game, offer.state, now)?;
+        Ok(game)
+    }
+
+    /// `RejectGame`
+    pub fn reject_game(&mut self, player: PlayerId, offer: GameOfferId) -> Result<(), AppCode> {
+        self.take_offer(player, offer).map(|_| ())
+    }
+
+    fn take_offer(&mut self, player: PlayerId, offer: GameOfferId) -> Result<GameOffer, AppCode> {
+        match self.offers.get(&offer) {
+            Some(found) if found.to == player => {
+                self.offers.remove(&offer).ok_or(AppCode::NotFound)
+            }
+            _ => Err(AppCode::NotFound),
+        }
+    }
+
+    fn start(
+        &mut self,
+        player: PlayerId,
+        game: GameId,
+        quest: Option<QuestId>,
+        now: DateTime<Utc>,
+    ) -> Result<(), AppCode> {
+        if !self.game(game)?.enabled {
+            return Err(AppCode::InvalidGameId);
+        }
+
+        self.sessions.insert(
+            player,
+            GameSession {
+                game,
+                player,
+                quest,
+                started_at: now,
+            },
+        );
+        Ok(())
+    }
+
+    /// The limits from the data file
+    fn believable(
+        definition: &GameDefinition,
+        session: &GameSession,
+        score: u64,
+        now: DateTime<Utc>,
+    ) -> bool {
+        if definition.max_score.is_some_and(|max| score > max) {
+            return false;
+        }
+
+        let seconds = (now - session.started_at).num_seconds().max(1) as u64;
+        definition
+            .max_points_per_second
+            .is_none_or(|rate| score <= rate.saturating_mul(seconds))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inventory::Reward;
+    use crate::quest::{Objective, QuestData, QuestDefinition};
+    use chrono::TimeZone;
+
+    const GAME: GameId = 1;
+    const OFF: GameId = 2;
+
+    fn at(second: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, second).unwrap()
+    }
+
+    fn minigames() -> Minigames {
+        let game = |id, enabled| GameDefinition {
+            id,
+            name: String::new(),
+            zone: None,
+            enabled,
+            max_score: Some(1000),
+            max_points_per_second: Some(10),
+        };
+
+        Minigames::from_data(GameData {
+            games: vec![game(GAME, true), game(OFF, false)],
+        })
+    }
+
+    #[test]
+    fn scores_have_to_be_believable() {
+        let mut games = minigames();
+        let mut quests = QuestEngine::default();
+
+        assert_eq!(games.start_game(1, OFF, at(0)), Err(AppCode::InvalidGameId));
+        assert_eq!(
+            games.end_game(1, 5, &mut quests, at(0)),
+            Err(AppCode::State)
+        );
+
+        games.start_game(1, GAME, at(0)).unwrap();
+        assert_eq!(
+            games.end_game(1, 50, &mut quests, at(2)),
+            Err(AppCode::TooManyPoints)
+        );
+
+        games.start_game(1, GAME, at(0)).unwrap();
+        let result = games.end_game(1, 20, &mut quests, at(2)).unwrap();
+        assert!(result.new_best);
+
+        games.register_score_hook(GAME, |_, _, score, _| score % 2 == 0);
+        games.start_game(1, GAME, at(0)).unwrap();
+        assert_eq!(
+            games.end_game(1, 7, &mut quests, at(2)),
+            Err(AppCode::TooManyPoints)
+        );
+
+        games.start_game(1, GAME, at(0)).unwrap();
+        let result = games.end_game(1, 10, &mut quests, at(2)).unwrap();
+        assert_eq!((result.best, result.new_best), (20, false));
+    }
+
+    #[test]
+    fn saves_only_go_to_friends_and_only_the_recipient_takes_them() {
+        let mut games = minigames();
+        let mut friends = Friends::default();
+
+        assert_eq!(
+            games.save_game_state(1, GAME, vec![0; MAX_SAVE_BYTES + 1], at(0)),
+            Err(AppCode::NoSpace)
+        );
+        games.save_game_state(1, GAME, vec![1], at(0)).unwrap();
+        assert_eq!(
+            games.send_game(1, 2, GAME, &friends, at(0)),
+            Err(AppCode::InvalidRelationship)
+        );
+
+        friends.add(1, 2);
+        let offer = games.send_game(1, 2, GAME, &friends, at(0)).unwrap();
+        games.save_game_state(1, GAME, vec![2], at(1)).unwrap();
+
+        assert_eq!(games.accept_game(3, offer, at(1)), Err(AppCode::NotFound));
+        assert_eq!(games.accept_game(2, offer, at(1)), Ok(GAME));
+        assert_eq!(games.load_game(2, GAME).unwrap().state, vec![1]);
+        assert_eq!(games.reject_game(2, offer), Err(AppCode::NotFound));
+    }
+
+    #[test]
+    fn friends_can_only_have_so_many_games_waiting() {
+        let mut games = minigames();
+        let mut friends = Friends::default();
+
+        friends.add(1, 2);
+        games.save_game_state(1, GAME, vec![1], at(0)).unwrap();
+        for _ in 0..MAX_GAME_OFFERS {
+            games.send_game(1, 2, GAME, &friends, at(0)).unwrap();
+        }
+        assert_eq!(
+            games.send_game(1, 2, GAME, &friends, at(0)),
+            Err(AppCode::NoSpace)
+        );
+
+        let offer = games.game_offers(2)[0].id;
+        games.reject_game(2, offer).unwrap();
+        assert!(games.send_game(1, 2, GAME, &friends, at(0)).is_ok());
+    }
+
+    #[test]
+    fn quest_games_only_count_towards_their_own_quest() {
+        let mut games = minigames();
+        let quest = |id| QuestDefinition {
+            id,
+            name: String::new(),
+            parent: None,
+            npc: None,
+            objectives: vec![Objective {
+                id: 1,
+                kind: "game".to_string(),
+                target: GAME as u64,
+                count: 1,
+            }],
+            reward: Reward::default(),
+        };
+        let mut quests = QuestEngine::from_data(QuestData {
+            quests: vec![quest(1), quest(2)],
+        });
+
+        quests.accept_quest(1, 1).unwrap();
+        quests.accept_quest(1, 2).unwrap();
+        games.create_quest_game(1, GAME, 1, &quests, at(0)).unwrap();
+        games.end_game(1, 10, &mut quests, at(2)).unwrap();
+
+        assert_eq!(
+            quests.player_quest(1, 1).unwrap().progress.get(&1),
+            Some(&1)
+        );
+        assert_eq!(quests.player_quest(1, 2).unwrap().progress.get(&1), None);
+
+        games.start_game(1, GAME, at(0)).unwrap();
+        games.end_game(1, 10, &mut quests, at(2)).unwrap();
+        assert_eq!(quests.player_quest(1, 2).unwrap().progress.get(&1), None);
+    }
+}
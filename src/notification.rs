@@ -0,0 +1,448 @@
+use crate::data;
+use crate::message::AppCode;
+use crate::session::{ClientEvent, Outbox, PlayerId};
+use crate::world::World;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+pub type CategoryId = u32;
+pub type NotificationId = u64;
+
+pub const FRIENDS: CategoryId = 1;
+pub const GIFTS: CategoryId = 2;
+pub const QUESTS: CategoryId = 3;
+pub const VILLAGES: CategoryId = 4;
+pub const SYSTEM: CategoryId = 5;
+
+/// Most notifications kept for one player, the oldest go first
+pub const MAX_NOTIFICATIONS: usize = 50;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NotificationCategory {
+    pub id: CategoryId,
+    pub name: String,
+    /// Whether players get these before they've set an option for it
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+/// What `data/notifications.json` looks like
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct NotificationData {
+    #[serde(default)]
+    pub categories: Vec<NotificationCategory>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NotificationOption {
+    pub category: CategoryId,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub id: NotificationId,
+    /// Counts up separately for each player
+    pub player_notification_id: u64,
+    pub player: PlayerId,
+    pub category: CategoryId,
+    pub text: String,
+    /// Whatever the client needs to act on it, like the gift or village it's about
+    pub params: BTreeMap<String, String>,
+    pub created_at: DateTime<Utc>,
+    pub read: bool,
+    pub acknowledged: bool,
+}
+
+/// `GetSystemNotifications`, the ones that go out to everybody
+#[derive(Debug, Clone, PartialEq)]
+pub struct SystemNotification {
+    pub text: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default)]
+pub struct Notifications {
+    categories: BTreeMap<CategoryId, NotificationCategory>,
+    options: HashMap<PlayerId, BTreeMap<CategoryId, bool>>,
+    notifications: HashMap<PlayerId, Vec<Notification>>,
+    counters: HashMap<PlayerId, u64>,
+    system: Vec<SystemNotification>,
+    next_id: NotificationId,
+}
+
+impl Notifications {
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self::from_data(data::load(path)?))
+    }
+
+    pub fn from_data(data: NotificationData) -> Self {
+        Self {
+            categories: data
+                .categories
+                .into_iter()
+                .map(|category| (category.id, category))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Store a notification for the player and show it straight away if they're online.
+    /// Nothing happens if they've switched the category off
+    #[allow(clippy::too_many_arguments)]
+    pub fn publish(
+        &mut self,
+        player: PlayerId,
+        category: CategoryId,
+        text: &str,
+        params: BTreeMap<String, String>,
+        world: &World,
+        outbox: &mut Outbox,
+        now: DateTime<Utc>,
+    ) -> Option<NotificationId> {
+        if !self.is_enabled(player, category) {
+            return None;
+        }
+
+        let counter = self.counters.entry(player).or_default();
+        *counter += 1;
+
+        self.next_id += 1;
+        let notification = Notification {
+            id: self.next_id,
+            player_notification_id: *counter,
+            player,
+            category,
+            text: text.to_string(),
+            params,
+            created_at: now,
+            read: false,
+            acknowledged: false,
+        };
+
+        if world.player_location(player).is_some() {
+            outbox.push(
+                player,
+                ClientEvent::PlayerNotificationNotify(notification.clone()),
+            );
+        }
+
+        let stored = self.notifications.entry(player).or_default();
+        stored.push(notification);
+        if stored.len() > MAX_NOTIFICATIONS {
+            stored.remove(0);
+        }
+
+        Some(self.next_id)
+    }
+
+    /// `SendNotificationCsTool`, goes out to everyone online and waits in
+    /// `GetSystemNotifications` for everyone else
+    pub fn send_system_notification(
+        &mut self,
+        text: &str,
+        world: &World,
+        outbox: &mut Outbox,
+        now: DateTime<Utc>,
+    ) {
+        for player in world.players() {
+            if self.is_enabled(player, SYSTEM) {
+                outbox.push(
+                    player,
+                    ClientEvent::Notification {
+                        from: None,
+                        text: text.to_string(),
+                    },
+                );
+            }
+        }
+
+        self.system.push(SystemNotification {
+            text: text.to_string(),
+            created_at: now,
+        });
+    }
+
+    /// `GetSystemNotifications`
+    pub fn system_notifications(&self) -> &[SystemNotification] {
+        &self.system
+    }
+
+    /// `GetNotifications` and `GetNotificationByPlayerId`, newest first
+    pub fn notifications(&self, player: PlayerId) -> Vec<&Notification> {
+        self.notifications
+            .get(&player)
+            .map(|stored| stored.iter().rev().collect())
+            .unwrap_or_default()
+    }
+
+    /// `GetNotificationByPlayerNotificationId`
+    pub fn notification(
+        &self,
+        player: PlayerId,
+        player_notification_id: u64,
+    ) -> Result<&Notification, AppCode> {
+        self.notifications
+            .get(&player)
+            .and_then(|stored| {
+                stored.iter().find(|notification| {
+                    notification.player_notification_id == player_notification_id
+                })
+            })
+            .ok_or(AppCode::NotFound)
+    }
+
+    /// `UpdateNotification`, marks it read or unread
+    pub fn update_notification(
+        &mut self,
+        player: PlayerId,
+        notification: NotificationId,
+        read: bool,
+    ) -> Result<(), AppCode> {
+        self.notification_mut(player, notification)?.read = read;
+        Ok(())
+    }
+
+    /// `AcknowledgeNotification`, the player has dealt with it. That counts as reading it too
+    pub fn acknowledge_notification(
+        &mut self,
+        player: PlayerId,
+        notification: NotificationId,
+    ) -> Result<(), AppCode> {
+        let acknowledged = self.notification_mut(player, notification)?;
+        acknowledged.read = true;
+        acknowledged.acknowledged = true;
+
+        Ok(())
+    }
+
+    /// `ClearNotifications` and `ClearNotificationsByPlayerId`
+    pub fn clear_notifications(&mut self, player: PlayerId) {
+        self.notifications.remove(&player);
+    }
+
+    /// `GetNotificationCategories`
+    pub fn categories(&self) -> impl Iterator<Item = &NotificationCategory> {
+        self.categories.values()
+    }
+
+    /// `GetNotificationCategory`
+    pub fn category(&self, category: CategoryId) -> Result<&NotificationCategory, AppCode> {
+        self.categories.get(&category).ok_or(AppCode::NotFound)
+    }
+
+    /// `GetNotificationOptions`, every category, whether or not the player has set it
+    pub fn options(&self, player: PlayerId) -> Vec<NotificationOption> {
+        self.categories
+            .keys()
+            .map(|&category| NotificationOption {
+                category,
+                enabled: self.is_enabled(player, category),
+            })
+            .collect()
+    }
+
+    /// `GetNotificationOptionByCategory`
+    pub fn option(
+        &self,
+        player: PlayerId,
+        category: CategoryId,
+    ) -> Result<NotificationOption, AppCode> {
+        self.category(category)?;
+
+        Ok(NotificationOption {
+            category,
+            enabled: self.is_enabled(player, category),
+        })
+    }
+
+    /// `SetNotificationOptions`, all of them or none
+    pub fn set_notification_options(
+        &mut self,
+        player: PlayerId,
+        options: &[NotificationOption],
+    ) -> Result<(), AppCode> {
+        if options
+            .iter()
+            .any(|option| !self.categories.contains_key(&option.category))
+        {
+            return Err(AppCode::NotFound);
+        }
+
+        self.options.entry(player).or_default().extend(
+            options
+                .iter()
+                .map(|option| (option.category, option.enabled)),
+        );
+
+        Ok(())
+    }
+
+    /// `UpdateNotificationOption`
+    pub fn update_notification_option(
+        &mut self,
+        player: PlayerId,
+        option: NotificationOption,
+    ) -> Result<(), AppCode> {
+        self.set_notification_options(player, &[option])
+    }
+
+    pub fn is_enabled(&self, player: PlayerId, category: CategoryId) -> bool {
+        self.options
+            .get(&player)
+            .and_then(|options| options.get(&category))
+            .copied()
+            .or_else(|| {
+                self.categories
+                    .get(&category)
+                    .map(|category| category.enabled)
+            })
+            .unwrap_or(true)
+    }
+
+    fn notification_mut(
+        &mut self,
+        player: PlayerId,
+        notification: NotificationId,
+    ) -> Result<&mut Notification, AppCode> {
+        self.notifications
+            .get_mut(&player)
+            .and_then(|stored| stored.iter_mut().find(|stored| stored.id == notification))
+            .ok_or(AppCode::NotFound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::Position;
+    use chrono::TimeZone;
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap()
+    }
+
+    fn notifications() -> Notifications {
+        Notifications::from_data(NotificationData {
+            categories: vec![
+                NotificationCategory {
+                    id: GIFTS,
+                    name: "Gifts".to_string(),
+                    enabled: true,
+                },
+                NotificationCategory {
+                    id: SYSTEM,
+                    name: "System".to_string(),
+                    enabled: false,
+                },
+            ],
+        })
+    }
+
+    #[test]
+    fn options_decide_what_gets_published() {
+        let mut notifications = notifications();
+        let world = World::default();
+        let mut outbox = Outbox::default();
+        let mut publish = |notifications: &mut Notifications, category| {
+            notifications.publish(1, category, "", BTreeMap::new(), &world, &mut outbox, now())
+        };
+
+        assert!(publish(&mut notifications, GIFTS).is_some());
+        assert!(publish(&mut notifications, SYSTEM).is_none());
+
+        notifications
+            .update_notification_option(
+                1,
+                NotificationOption {
+                    category: GIFTS,
+                    enabled: false,
+                },
+            )
+            .unwrap();
+        assert!(publish(&mut notifications, GIFTS).is_none());
+        assert_eq!(
+            notifications.set_notification_options(
+                1,
+                &[
+                    NotificationOption {
+                        category: SYSTEM,
+                        enabled: true,
+                    },
+                    NotificationOption {
+                        category: 99,
+                        enabled: true,
+                    },
+                ],
+            ),
+            Err(AppCode::NotFound)
+        );
+        assert!(!notifications.is_enabled(1, SYSTEM));
+        assert_eq!(notifications.notifications(1).len(), 1);
+    }
+
+    #[test]
+    fn only_online_players_hear_straight_away() {
+        let mut notifications = notifications();
+        let mut world = World::default();
+        let mut outbox = Outbox::default();
+        world.add_location(1);
+        world
+            .enter_loc(1, 1, Position::default(), &mut outbox)
+            .unwrap();
+        outbox.drain_all();
+
+        for player in [1, 2] {
+            notifications.publish(
+                player,
+                GIFTS,
+                "",
+                BTreeMap::new(),
+                &world,
+                &mut outbox,
+                now(),
+            );
+        }
+
+        assert!(matches!(
+            outbox.drain(1)[..],
+            [ClientEvent::PlayerNotificationNotify(_)]
+        ));
+        assert!(outbox.is_empty());
+        assert_eq!(notifications.notifications(2).len(), 1);
+    }
+
+    #[test]
+    fn players_only_touch_their_own_and_old_ones_fall_off() {
+        let mut notifications = notifications();
+        let world = World::default();
+        let mut outbox = Outbox::default();
+        let first = (0..=MAX_NOTIFICATIONS)
+            .map(|_| {
+                notifications
+                    .publish(1, GIFTS, "", BTreeMap::new(), &world, &mut outbox, now())
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(notifications.notifications(1).len(), MAX_NOTIFICATIONS);
+        assert_eq!(
+            notifications.update_notification(1, first[0], true),
+            Err(AppCode::NotFound)
+        );
+        assert_eq!(
+            notifications.acknowledge_notification(2, first[1]),
+            Err(AppCode::NotFound)
+        );
+
+        notifications.acknowledge_notification(1, first[1]).unwrap();
+        let acknowledged = notifications.notification(1, 2).unwrap();
+        assert!(acknowledged.read && acknowledged.acknowledged);
+    }
+}
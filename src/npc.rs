@@ -0,0 +1,552 @@
+use crate::data;
+use crate::message::AppCode;
+use crate::session::{ClientEvent, Outbox, PlayerId};
+use crate::world::{LocationId, ObjectId, Position, World};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+pub type NpcId = u64;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NpcDefinition {
+    pub id: NpcId,
+    pub name: String,
+    pub asset: u64,
+    pub location: LocationId,
+    #[serde(default)]
+    pub spawn: Position,
+    #[serde(default)]
+    pub script: Option<String>,
+    /// Relationship XP each kind of `NpcInteraction` is worth
+    #[serde(default)]
+    pub interactions: HashMap<String, u32>,
+    #[serde(default)]
+    pub gifts: Vec<NpcGift>,
+    /// Relationship level needed before they accept a friend request
+    #[serde(default)]
+    pub friend_level: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NpcGift {
+    pub level: u32,
+    pub item: u64,
+    pub count: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Waypoint {
+    pub position: Position,
+    /// Seconds to stand around once we get there
+    #[serde(default)]
+    pub wait: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PathScript {
+    pub name: String,
+    pub speed: f32,
+    #[serde(default, rename = "loop")]
+    pub looping: bool,
+    pub waypoints: Vec<Waypoint>,
+}
+
+/// What `data/npcs.json` looks like
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct NpcData {
+    #[serde(default)]
+    pub npcs: Vec<NpcDefinition>,
+    #[serde(default)]
+    pub scripts: Vec<PathScript>,
+    /// XP needed for each relationship level, level 0 is free
+    #[serde(default)]
+    pub relationship_levels: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Relationship {
+    pub npc: NpcId,
+    pub xp: u32,
+    pub level: u32,
+    pub friend: bool,
+}
+
+#[derive(Debug)]
+struct RunningNpc {
+    location: LocationId,
+    object: ObjectId,
+    script: Option<String>,
+    waypoint: usize,
+    position: Position,
+    waiting: f32,
+    paused: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct NpcRuntime {
+    definitions: BTreeMap<NpcId, NpcDefinition>,
+    scripts: HashMap<String, PathScript>,
+    levels: Vec<u32>,
+    running: BTreeMap<NpcId, RunningNpc>,
+    relationships: HashMap<(PlayerId, NpcId), Relationship>,
+}
+
+impl NpcRuntime {
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self::from_data(data::load(path)?))
+    }
+
+    pub fn from_data(data: NpcData) -> Self {
+        Self {
+            definitions: data.npcs.into_iter().map(|npc| (npc.id, npc)).collect(),
+            scripts: data
+                .scripts
+                .into_iter()
+                .map(|script| (script.name.clone(), script))
+                .collect(),
+            levels: data.relationship_levels,
+            running: BTreeMap::new(),
+            relationships: HashMap::new(),
+        }
+    }
+
+    pub fn npc(&self, npc: NpcId) -> Option<&NpcDefinition> {
+        self.definitions.get(&npc)
+    }
+
+    /// `GetNpcs`, optionally only the ones living in one location
+    pub fn npcs(&self, location: Option<LocationId>) -> Vec<&NpcDefinition> {
+        self.definitions
+            .values()
+            .filter(|npc| location.is_none_or(|location| npc.location == location))
+            .collect()
+    }
+
+    pub fn is_running(&self, npc: NpcId) -> bool {
+        self.running.contains_key(&npc)
+    }
+
+    /// `StartNpcs`
+    pub fn start_npcs(
+        &mut self,
+        location: LocationId,
+        world: &mut World,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        let mut started = Vec::new();
+
+        for npc in self.definitions.values() {
+            if npc.location != location || self.running.contains_key(&npc.id) {
+                continue;
+            }
+
+            let properties = BTreeMap::from([
+                ("npc".to_string(), npc.id.to_string()),
+                ("name".to_string(), npc.name.clone()),
+            ]);
+            let object =
+                world.spawn_object(location, None, npc.asset, npc.spawn, properties, outbox)?;
+
+            self.running.insert(
+                npc.id,
+                RunningNpc {
+                    location,
+                    object,
+                    script: npc.script.clone(),
+                    waypoint: 0,
+                    position: npc.spawn,
+                    waiting: 0.0,
+                    paused: false,
+                },
+            );
+            started.push(npc.id);
+        }
+
+        if !started.is_empty() {
+            world.broadcast(location, ClientEvent::UpdateNpcs { npcs: started }, outbox);
+        }
+
+        Ok(())
+    }
+
+    /// `StopNpcs`
+    pub fn stop_npcs(&mut self, location: LocationId, world: &mut World, outbox: &mut Outbox) {
+        let stopping: Vec<NpcId> = self
+            .running
+            .iter()
+            .filter(|(_, running)| running.location == location)
+            .map(|(&npc, _)| npc)
+            .collect();
+
+        for npc in stopping {
+            let running = self.running.remove(&npc).unwrap();
+
+            world.broadcast(location, ClientEvent::StopNpc { npc }, outbox);
+            let _ = world.despawn_object(location, running.object, outbox);
+        }
+    }
+
+    pub fn pause_npc(
+        &mut self,
+        npc: NpcId,
+        world: &mut World,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        let running = self.running.get_mut(&npc).ok_or(AppCode::NotFound)?;
+
+        running.paused = true;
+        world.broadcast(running.location, ClientEvent::StopNpc { npc }, outbox);
+
+        Ok(())
+    }
+
+    pub fn resume_npc(
+        &mut self,
+        npc: NpcId,
+        world: &mut World,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        let running = self.running.get_mut(&npc).ok_or(AppCode::NotFound)?;
+
+        running.paused = false;
+        world.broadcast(
+            running.location,
+            ClientEvent::UpdateNpcs { npcs: vec![npc] },
+            outbox,
+        );
+
+        Ok(())
+    }
+
+    /// `UpdateNpcScript`, the NPC starts again from the first waypoint of the new script
+    pub fn update_npc_script(
+        &mut self,
+        npc: NpcId,
+        script: Option<String>,
+        world: &mut World,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        if script
+            .as_ref()
+            .is_some_and(|script| !self.scripts.contains_key(script))
+        {
+            return Err(AppCode::NotFound);
+        }
+
+        let running = self.running.get_mut(&npc).ok_or(AppCode::NotFound)?;
+
+        running.script = script;
+        running.waypoint = 0;
+        running.waiting = 0.0;
+        world.broadcast(
+            running.location,
+            ClientEvent::UpdateNpcs { npcs: vec![npc] },
+            outbox,
+        );
+
+        Ok(())
+    }
+
+    /// Walk every running NPC `elapsed` seconds further along its script
+    pub fn tick(&mut self, elapsed: f32, world: &mut World, outbox: &mut Outbox) {
+        for running in self.running.values_mut() {
+            if running.paused {
+                continue;
+            }
+
+            let Some(script) = running
+                .script
+                .as_ref()
+                .and_then(|script| self.scripts.get(script))
+            else {
+                continue;
+            };
+
+            if running.waypoint >= script.waypoints.len() {
+                continue;
+            }
+
+            if running.waiting > 0.0 {
+                running.waiting -= elapsed;
+                continue;
+            }
+
+            let target = &script.waypoints[running.waypoint];
+            let (dx, dy, dz) = (
+                target.position.x - running.position.x,
+                target.position.y - running.position.y,
+                target.position.z - running.position.z,
+            );
+            let distance = (dx * dx + dy * dy + dz * dz).sqrt();
+            let step = script.speed * elapsed;
+
+            if step >= distance {
+                running.position = target.position;
+                running.waiting = target.wait;
+                running.waypoint += 1;
+
+                if running.waypoint >= script.waypoints.len() && script.looping {
+                    running.waypoint = 0;
+                }
+            } else {
+                running.position.x += dx / distance * step;
+                running.position.y += dy / distance * step;
+                running.position.z += dz / distance * step;
+                running.position.heading = dx.atan2(dz).to_degrees();
+            }
+
+            let _ = world.place_object(running.location, running.object, running.position, outbox);
+        }
+    }
+
+    /// `NpcInteraction`
+    pub fn interact(
+        &mut self,
+        player: PlayerId,
+        npc: NpcId,
+        interaction: &str,
+    ) -> Result<Relationship, AppCode> {
+        let xp = *self
+            .definitions
+            .get(&npc)
+            .ok_or(AppCode::NotFound)?
+            .interactions
+            .get(interaction)
+            .ok_or(AppCode::InavlidAction)?;
+
+        self.add_relationship_xp(player, npc, xp)
+    }
+
+    pub fn add_relationship_xp(
+        &mut self,
+        player: PlayerId,
+        npc: NpcId,
+        xp: u32,
+    ) -> Result<Relationship, AppCode> {
+        if !self.definitions.contains_key(&npc) {
+            return Err(AppCode::NotFound);
+        }
+
+        let relationship =
+            self.relationships
+                .entry((player, npc))
+                .or_insert_with(|| Relationship {
+                    npc,
+                    ..Default::default()
+                });
+
+        relationship.xp = relationship.xp.saturating_add(xp);
+        relationship.level = self
+            .levels
+            .iter()
+            .filter(|&&needed| relationship.xp >= needed)
+            .count() as u32;
+
+        Ok(relationship.clone())
+    }
+
+    pub fn relationship(&self, player: PlayerId, npc: NpcId) -> Relationship {
+        self.relationships
+            .get(&(player, npc))
+            .cloned()
+            .unwrap_or(Relationship {
+                npc,
+                ..Default::default()
+            })
+    }
+
+    /// `GetNpcRelationships`
+    pub fn relationships(&self, player: PlayerId) -> Vec<Relationship> {
+        let mut relationships: Vec<Relationship> = self
+            .relationships
+            .iter()
+            .filter(|((owner, _), _)| *owner == player)
+            .map(|(_, relationship)| relationship.clone())
+            .collect();
+
+        relationships.sort_by_key(|relationship| relationship.npc);
+        relationships
+    }
+
+    /// `GetNpcRelationshipLevels`
+    pub fn relationship_levels(&self) -> &[u32] {
+        &self.levels
+    }
+
+    /// `GetNpcGifts`, what the NPC is willing to give at the player's current level
+    pub fn npc_gifts(&self, player: PlayerId, npc: NpcId) -> Result<Vec<NpcGift>, AppCode> {
+        let definition = self.definitions.get(&npc).ok_or(AppCode::NotFound)?;
+        let level = self.relationship(player, npc).level;
+
+        Ok(definition
+            .gifts
+            .iter()
+            .filter(|gift| gift.level <= level)
+            .cloned()
+            .collect())
+    }
+
+    /// `ManageNpcFriendRequest`
+    pub fn manage_friend_request(
+        &mut self,
+        player: PlayerId,
+        npc: NpcId,
+        befriend: bool,
+    ) -> Result<Relationship, AppCode> {
+        let needed = self
+            .definitions
+            .get(&npc)
+            .ok_or(AppCode::NotFound)?
+            .friend_level;
+
+        let mut relationship = self.relationship(player, npc);
+        if befriend && relationship.level < needed {
+            return Err(AppCode::InvalidRelationship);
+        }
+
+        relationship.friend = befriend;
+        self.relationships
+            .insert((player, npc), relationship.clone());
+
+        Ok(relationship)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: f32, z: f32) -> Position {
+        Position {
+            x,
+            z,
+            ..Default::default()
+        }
+    }
+
+    fn npcs() -> NpcRuntime {
+        NpcRuntime::from_data(NpcData {
+            npcs: vec![NpcDefinition {
+                id: 1,
+                name: "Mayor".to_string(),
+                asset: 5001,
+                location: 1,
+                spawn: at(0.0, 0.0),
+                script: Some("rounds".to_string()),
+                interactions: HashMap::from([("wave".to_string(), 60)]),
+                gifts: vec![NpcGift {
+                    level: 2,
+                    item: 9501,
+                    count: 1,
+                }],
+                friend_level: 2,
+            }],
+            scripts: vec![PathScript {
+                name: "rounds".to_string(),
+                speed: 2.0,
+                looping: true,
+                waypoints: vec![
+                    Waypoint {
+                        position: at(4.0, 0.0),
+                        wait: 1.0,
+                    },
+                    Waypoint {
+                        position: at(0.0, 0.0),
+                        wait: 0.0,
+                    },
+                ],
+            }],
+            relationship_levels: vec![50, 100],
+        })
+    }
+
+    fn running() -> (NpcRuntime, World, Outbox) {
+        let mut npcs = npcs();
+        let mut world = World::default();
+        let mut outbox = Outbox::default();
+
+        world.add_location(1);
+        npcs.start_npcs(1, &mut world, &mut outbox).unwrap();
+
+        (npcs, world, outbox)
+    }
+
+    fn position(world: &World) -> Position {
+        world
+            .location(1)
+            .unwrap()
+            .objects
+            .values()
+            .next()
+            .unwrap()
+            .position
+    }
+
+    #[test]
+    fn starting_and_stopping_spawns_and_removes_the_object() {
+        let (mut npcs, mut world, mut outbox) = running();
+
+        assert!(npcs.is_running(1));
+        assert_eq!(world.location(1).unwrap().objects.len(), 1);
+
+        // Starting again doesn't make a second one
+        npcs.start_npcs(1, &mut world, &mut outbox).unwrap();
+        assert_eq!(world.location(1).unwrap().objects.len(), 1);
+
+        npcs.stop_npcs(1, &mut world, &mut outbox);
+        assert!(!npcs.is_running(1));
+        assert!(world.location(1).unwrap().objects.is_empty());
+    }
+
+    #[test]
+    fn npcs_walk_their_script_and_loop() {
+        let (mut npcs, mut world, mut outbox) = running();
+
+        npcs.tick(1.0, &mut world, &mut outbox);
+        assert_eq!(position(&world).x, 2.0);
+
+        npcs.tick(1.0, &mut world, &mut outbox);
+        assert_eq!(position(&world).x, 4.0);
+
+        // Waiting at the waypoint
+        npcs.tick(1.0, &mut world, &mut outbox);
+        assert_eq!(position(&world).x, 4.0);
+
+        npcs.tick(1.5, &mut world, &mut outbox);
+        assert_eq!(position(&world).x, 1.0);
+
+        npcs.pause_npc(1, &mut world, &mut outbox).unwrap();
+        npcs.tick(1.0, &mut world, &mut outbox);
+        assert_eq!(position(&world).x, 1.0);
+
+        npcs.resume_npc(1, &mut world, &mut outbox).unwrap();
+        npcs.tick(1.0, &mut world, &mut outbox);
+        assert_eq!(position(&world).x, 0.0);
+
+        // Back round to the first waypoint
+        npcs.tick(1.0, &mut world, &mut outbox);
+        assert_eq!(position(&world).x, 2.0);
+    }
+
+    #[test]
+    fn relationships_unlock_gifts_and_friendship() {
+        let mut npcs = npcs();
+
+        assert_eq!(npcs.interact(7, 1, "dance"), Err(AppCode::InavlidAction));
+        assert_eq!(npcs.interact(7, 1, "wave").unwrap().level, 1);
+        assert!(npcs.npc_gifts(7, 1).unwrap().is_empty());
+        assert_eq!(
+            npcs.manage_friend_request(7, 1, true),
+            Err(AppCode::InvalidRelationship)
+        );
+
+        let relationship = npcs.interact(7, 1, "wave").unwrap();
+        assert_eq!((relationship.xp, relationship.level), (120, 2));
+        assert_eq!(npcs.npc_gifts(7, 1).unwrap().len(), 1);
+        assert!(npcs.manage_friend_request(7, 1, true).unwrap().friend);
+
+        // Somebody else starts from scratch
+        assert_eq!(npcs.relationship(8, 1).level, 0);
+    }
+}
@@ -0,0 +1,445 @@
+use crate::filter::WordFilter;
+use crate::inventory::{Inventory, InventoryItemId, ItemCatalog};
+use crate::message::AppCode;
+use crate::session::{Outbox, PlayerId};
+use crate::world::World;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+pub type OutfitNo = u8;
+
+/// How many outfits every avatar gets to save
+pub const MAX_OUTFITS: OutfitNo = 5;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Outfit {
+    pub number: OutfitNo,
+    pub name: String,
+    /// Body slot to the item worn there
+    pub items: BTreeMap<String, InventoryItemId>,
+}
+
+#[derive(Debug, Default)]
+pub struct Wardrobe {
+    outfits: HashMap<PlayerId, BTreeMap<OutfitNo, Outfit>>,
+    current: HashMap<PlayerId, OutfitNo>,
+    worn: HashMap<PlayerId, BTreeMap<String, InventoryItemId>>,
+}
+
+impl Wardrobe {
+    /// `GetOutfits`
+    pub fn outfits(&self, player: PlayerId) -> Vec<&Outfit> {
+        self.outfits
+            .get(&player)
+            .map(|outfits| outfits.values().collect())
+            .unwrap_or_default()
+    }
+
+    /// `GetOutfitItems`
+    pub fn outfit_items(
+        &self,
+        player: PlayerId,
+        number: OutfitNo,
+    ) -> Result<&BTreeMap<String, InventoryItemId>, AppCode> {
+        Self::check_number(number)?;
+
+        self.outfits
+            .get(&player)
+            .and_then(|outfits| outfits.get(&number))
+            .map(|outfit| &outfit.items)
+            .ok_or(AppCode::NotFound)
+    }
+
+    pub fn current_outfit(&self, player: PlayerId) -> Option<OutfitNo> {
+        self.current.get(&player).copied()
+    }
+
+    /// What the avatar has on right now
+    pub fn worn(&self, player: PlayerId) -> BTreeMap<String, InventoryItemId> {
+        self.worn.get(&player).cloned().unwrap_or_default()
+    }
+
+    /// `AddOutfit`, saving over an existing number just renames it
+    pub fn add_outfit(
+        &mut self,
+        player: PlayerId,
+        number: OutfitNo,
+        name: &str,
+        filter: &WordFilter,
+    ) -> Result<(), AppCode> {
+        Self::check_number(number)?;
+        filter.validate_name(name)?;
+
+        self.outfits
+            .entry(player)
+            .or_default()
+            .entry(number)
+            .or_insert_with(|| Outfit {
+                number,
+                ..Default::default()
+            })
+            .name = name.trim().to_string();
+
+        Ok(())
+    }
+
+    /// `RemoveOutfit`
+    pub fn remove_outfit(&mut self, player: PlayerId, number: OutfitNo) -> Result<(), AppCode> {
+        self.outfit_mut(player, number)?;
+        self.outfits.get_mut(&player).unwrap().remove(&number);
+
+        if self.current.get(&player) == Some(&number) {
+            self.current.remove(&player);
+        }
+
+        Ok(())
+    }
+
+    /// `AddOutfitItems`, each one goes in its own body slot
+    pub fn add_outfit_items(
+        &mut self,
+        player: PlayerId,
+        number: OutfitNo,
+        items: &[InventoryItemId],
+        inventory: &Inventory,
+        catalog: &ItemCatalog,
+    ) -> Result<(), AppCode> {
+        let adding = Self::fit(player, items, inventory, catalog)?;
+        let outfit = self.outfit_mut(player, number)?;
+
+        if adding.keys().any(|slot| outfit.items.contains_key(slot)) {
+            return Err(AppCode::SlotAlreadyUsed);
+        }
+
+        outfit.items.extend(adding);
+        Ok(())
+    }
+
+    /// `RemoveOutfitItems`
+    pub fn remove_outfit_items(
+        &mut self,
+        player: PlayerId,
+        number: OutfitNo,
+        items: &[InventoryItemId],
+    ) -> Result<(), AppCode> {
+        let outfit = self.outfit_mut(player, number)?;
+
+        if items
+            .iter()
+            .any(|item| !outfit.items.values().any(|saved| saved == item))
+        {
+            return Err(AppCode::NotFound);
+        }
+
+        outfit.items.retain(|_, saved| !items.contains(saved));
+        Ok(())
+    }
+
+    /// `ReplaceOutfitItems`
+    pub fn replace_outfit_items(
+        &mut self,
+        player: PlayerId,
+        number: OutfitNo,
+        items: &[InventoryItemId],
+        inventory: &Inventory,
+        catalog: &ItemCatalog,
+    ) -> Result<(), AppCode> {
+        let replacing = Self::fit(player, items, inventory, catalog)?;
+
+        self.outfit_mut(player, number)?.items = replacing;
+        Ok(())
+    }
+
+    /// `SetCurrentOutfit`, the outfit the avatar logs in wearing. They put it on straight away
+    pub fn set_current_outfit(
+        &mut self,
+        player: PlayerId,
+        number: OutfitNo,
+        inventory: &Inventory,
+        catalog: &ItemCatalog,
+        world: &World,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        self.dress_avatar(player, number, inventory, catalog, world, outbox)?;
+        self.current.insert(player, number);
+
+        Ok(())
+    }
+
+    /// `DressAvatar`, swaps everything worn for a saved outfit. Items that have been sold,
+    /// traded or put down since it was saved are left off
+    pub fn dress_avatar(
+        &mut self,
+        player: PlayerId,
+        number: OutfitNo,
+        inventory: &Inventory,
+        catalog: &ItemCatalog,
+        world: &World,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        let items: Vec<InventoryItemId> = self
+            .outfit_items(player, number)?
+            .values()
+            .copied()
+            .filter(|&item| Self::can_wear(player, item, inventory))
+            .collect();
+
+        let wearing = Self::fit(player, &items, inventory, catalog)?;
+
+        self.worn.insert(player, wearing);
+        self.show(player, world, outbox);
+
+        Ok(())
+    }
+
+    /// `DressAvatarItems`, puts items on over whatever is in their slots
+    pub fn dress_avatar_items(
+        &mut self,
+        player: PlayerId,
+        items: &[InventoryItemId],
+        inventory: &Inventory,
+        catalog: &ItemCatalog,
+        world: &World,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        let wearing = Self::fit(player, items, inventory, catalog)?;
+
+        self.worn.entry(player).or_default().extend(wearing);
+        self.show(player, world, outbox);
+
+        Ok(())
+    }
+
+    /// `UndressAvatar`, takes off whatever is in `slots`. No slots takes off everything
+    pub fn undress_avatar(
+        &mut self,
+        player: PlayerId,
+        slots: &[String],
+        world: &World,
+        outbox: &mut Outbox,
+    ) {
+        let worn = self.worn.entry(player).or_default();
+
+        if slots.is_empty() {
+            worn.clear();
+        } else {
+            worn.retain(|slot, _| !slots.contains(slot));
+        }
+
+        self.show(player, world, outbox);
+    }
+
+    /// Takes off anything that has left the player's inventory, or gone out into the
+    /// world, since they put it on. Gifts, mail, sales and placing all end up here
+    pub fn take_off_missing(&mut self, inventory: &Inventory, world: &World, outbox: &mut Outbox) {
+        let mut changed = Vec::new();
+
+        for (&player, worn) in self.worn.iter_mut() {
+            let before = worn.len();
+
+            worn.retain(|_, item| Self::can_wear(player, *item, inventory));
+
+            if worn.len() != before {
+                changed.push(player);
+            }
+        }
+
+        for player in changed {
+            self.show(player, world, outbox);
+        }
+    }
+
+    fn show(&self, player: PlayerId, world: &World, outbox: &mut Outbox) {
+        let worn = self.worn(player);
+
+        let properties = worn
+            .into_iter()
+            .map(|(slot, item)| (format!("outfit.{}", slot), item.to_string()))
+            .collect();
+
+        world.change_avatar(player, properties, outbox);
+    }
+
+    /// The player's own, and not out in the world or attached to anything
+    fn can_wear(player: PlayerId, item: InventoryItemId, inventory: &Inventory) -> bool {
+        inventory
+            .get(item)
+            .is_some_and(|owned| owned.owner == player && owned.attached.is_none())
+            && inventory.placement(item).is_none()
+    }
+
+    /// Work out the body slot of every item, making sure the player owns it and it can be worn
+    fn fit(
+        player: PlayerId,
+        items: &[InventoryItemId],
+        inventory: &Inventory,
+        catalog: &ItemCatalog,
+    ) -> Result<BTreeMap<String, InventoryItemId>, AppCode> {
+        let mut fitted = BTreeMap::new();
+
+        for &item in items {
+            let owned = inventory.get(item).ok_or(AppCode::InventoryItemNotExist)?;
+
+            if owned.owner != player {
+                return Err(AppCode::ItemNotOwnedBySessionPlayer);
+            }
+
+            if !Self::can_wear(player, item, inventory) {
+                return Err(AppCode::ChildAlreadyPlaced);
+            }
+
+            let slot = catalog
+                .item(owned.item)
+                .ok()
+                .and_then(|definition| definition.body_slot.clone())
+                .ok_or(AppCode::IncompatibleSlot)?;
+
+            if fitted.insert(slot, item).is_some() {
+                return Err(AppCode::SlotAlreadyUsed);
+            }
+        }
+
+        Ok(fitted)
+    }
+
+    fn outfit_mut(&mut self, player: PlayerId, number: OutfitNo) -> Result<&mut Outfit, AppCode> {
+        Self::check_number(number)?;
+
+        self.outfits
+            .get_mut(&player)
+            .and_then(|outfits| outfits.get_mut(&number))
+            .ok_or(AppCode::NotFound)
+    }
+
+    fn check_number(number: OutfitNo) -> Result<(), AppCode> {
+        if number >= MAX_OUTFITS {
+            return Err(AppCode::InvalidOutfitNo);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inventory::{ItemData, ItemDefinition};
+    use crate::world::Position;
+
+    const HAT: u64 = 1;
+    const SCARF: u64 = 2;
+
+    fn catalog() -> ItemCatalog {
+        let wearable = |id, slot: &str| ItemDefinition {
+            id,
+            name: slot.to_string(),
+            kind: None,
+            slots: Vec::new(),
+            body_slot: Some(slot.to_string()),
+            footprint: None,
+        };
+
+        ItemCatalog::from_data(ItemData {
+            items: vec![wearable(HAT, "head"), wearable(SCARF, "neck")],
+        })
+    }
+
+    #[test]
+    fn items_that_leave_come_off() {
+        let catalog = catalog();
+        let mut wardrobe = Wardrobe::default();
+        let mut inventory = Inventory::default();
+        let mut world = World::default();
+        let mut outbox = Outbox::default();
+
+        world.add_location(1);
+        world
+            .enter_loc(1, 1, Position::default(), &mut outbox)
+            .unwrap();
+        let hat = inventory.grant(1, HAT, 1)[0];
+        let scarf = inventory.grant(1, SCARF, 1)[0];
+        wardrobe
+            .dress_avatar_items(1, &[hat, scarf], &inventory, &catalog, &world, &mut outbox)
+            .unwrap();
+
+        inventory.transfer(1, 2, hat).unwrap();
+        inventory
+            .place_item(1, scarf, Position::default(), &mut world, &mut outbox)
+            .unwrap();
+        wardrobe.take_off_missing(&inventory, &world, &mut outbox);
+
+        assert!(wardrobe.worn(1).is_empty());
+    }
+
+    #[test]
+    fn outfits_only_take_wearable_items_in_free_slots() {
+        let catalog = catalog();
+        let filter = WordFilter::default();
+        let mut wardrobe = Wardrobe::default();
+        let mut inventory = Inventory::default();
+        let hats = inventory.grant(1, HAT, 2);
+
+        assert_eq!(
+            wardrobe.add_outfit(1, MAX_OUTFITS, "Sunday", &filter),
+            Err(AppCode::InvalidOutfitNo)
+        );
+        wardrobe.add_outfit(1, 0, " Sunday ", &filter).unwrap();
+        assert_eq!(wardrobe.outfits(1)[0].name, "Sunday");
+
+        assert_eq!(
+            wardrobe.add_outfit_items(1, 0, &hats, &inventory, &catalog),
+            Err(AppCode::SlotAlreadyUsed)
+        );
+        wardrobe
+            .add_outfit_items(1, 0, &hats[..1], &inventory, &catalog)
+            .unwrap();
+        assert_eq!(
+            wardrobe.add_outfit_items(1, 0, &hats[1..], &inventory, &catalog),
+            Err(AppCode::SlotAlreadyUsed)
+        );
+        assert_eq!(wardrobe.outfit_items(1, 0).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn outfits_go_on_without_whatever_was_sold() {
+        let catalog = catalog();
+        let filter = WordFilter::default();
+        let mut wardrobe = Wardrobe::default();
+        let mut inventory = Inventory::default();
+        let mut world = World::default();
+        let mut outbox = Outbox::default();
+        let hat = inventory.grant(1, HAT, 1)[0];
+        let scarf = inventory.grant(1, SCARF, 1)[0];
+
+        wardrobe.add_outfit(1, 0, "Sunday", &filter).unwrap();
+        wardrobe
+            .add_outfit_items(1, 0, &[hat, scarf], &inventory, &catalog)
+            .unwrap();
+        inventory.transfer(1, 2, hat).unwrap();
+
+        wardrobe
+            .set_current_outfit(1, 0, &inventory, &catalog, &world, &mut outbox)
+            .unwrap();
+        assert_eq!(wardrobe.current_outfit(1), Some(0));
+        assert_eq!(
+            wardrobe.worn(1),
+            BTreeMap::from([("neck".to_string(), scarf)])
+        );
+
+        world.add_location(1);
+        world
+            .enter_loc(1, 1, Position::default(), &mut outbox)
+            .unwrap();
+        inventory
+            .place_item(1, scarf, Position::default(), &mut world, &mut outbox)
+            .unwrap();
+        assert_eq!(
+            wardrobe.dress_avatar_items(1, &[scarf], &inventory, &catalog, &world, &mut outbox),
+            Err(AppCode::ChildAlreadyPlaced)
+        );
+        assert_eq!(
+            wardrobe.add_outfit_items(1, 0, &[scarf], &inventory, &catalog),
+            Err(AppCode::ChildAlreadyPlaced)
+        );
+    }
+}
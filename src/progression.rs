@@ -0,0 +1,594 @@
+use crate::currency::Wallets;
+use crate::data;
+use crate::inventory::{Inventory, Reward};
+use crate::message::AppCode;
+use crate::rules::Formula;
+use crate::session::{ClientEvent, Outbox, PlayerId};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+pub type StatId = u32;
+pub type AchievementId = u64;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatType {
+    pub id: StatId,
+    pub name: String,
+}
+
+/// Unlocked the first time `stat` reaches `threshold`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Achievement {
+    pub id: AchievementId,
+    pub name: String,
+    pub stat: String,
+    pub threshold: i64,
+    #[serde(default)]
+    pub reward: Reward,
+}
+
+/// Total XP needed to reach `level`, set from the cstool. Levels without one use the curve
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserLevel {
+    pub level: u32,
+    pub experience: u64,
+}
+
+/// Energy from `level` upwards, until the next entry takes over
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EnergyLevel {
+    pub level: u32,
+    pub max: u32,
+    /// Seconds it takes to get one point back
+    pub regen_seconds: u32,
+}
+
+impl Default for EnergyLevel {
+    fn default() -> Self {
+        Self {
+            level: 1,
+            max: 100,
+            regen_seconds: 60,
+        }
+    }
+}
+
+/// What `data/progression.json` looks like
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProgressionData {
+    pub stat_types: Vec<StatType>,
+    pub achievements: Vec<Achievement>,
+    /// XP needed for level `n + 1` is `curve(n)`
+    pub xp_curve: Formula,
+    pub max_level: u32,
+    pub user_levels: Vec<UserLevel>,
+    pub energy_levels: Vec<EnergyLevel>,
+}
+
+impl Default for ProgressionData {
+    fn default() -> Self {
+        Self {
+            stat_types: Vec::new(),
+            achievements: Vec::new(),
+            xp_curve: Formula {
+                kind: "xp".to_string(),
+                base: 0.0,
+                factor: 100.0,
+                exponent: 1.5,
+            },
+            max_level: 50,
+            user_levels: Vec::new(),
+            energy_levels: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayerProgress {
+    pub experience: u64,
+    pub level: u32,
+    pub energy: u32,
+    /// When `energy` was last topped up by regen
+    pub energy_updated: DateTime<Utc>,
+    pub stats: BTreeMap<String, i64>,
+    pub achievements: BTreeMap<AchievementId, DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+pub struct Progression {
+    stat_types: BTreeMap<StatId, StatType>,
+    achievements: BTreeMap<AchievementId, Achievement>,
+    xp_curve: Formula,
+    max_level: u32,
+    user_levels: BTreeMap<u32, u64>,
+    energy_levels: BTreeMap<u32, EnergyLevel>,
+    players: HashMap<PlayerId, PlayerProgress>,
+}
+
+impl Default for Progression {
+    fn default() -> Self {
+        Self::from_data(ProgressionData::default())
+    }
+}
+
+impl Progression {
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self::from_data(data::load(path)?))
+    }
+
+    /// Energy levels with a `regen_seconds` of 0 are left out, with a warning
+    pub fn from_data(data: ProgressionData) -> Self {
+        Self {
+            stat_types: data
+                .stat_types
+                .into_iter()
+                .map(|stat| (stat.id, stat))
+                .collect(),
+            achievements: data
+                .achievements
+                .into_iter()
+                .map(|achievement| (achievement.id, achievement))
+                .collect(),
+            xp_curve: data.xp_curve,
+            max_level: data.max_level.max(1),
+            user_levels: data
+                .user_levels
+                .into_iter()
+                .map(|level| (level.level, level.experience))
+                .collect(),
+            energy_levels: data
+                .energy_levels
+                .into_iter()
+                .filter(|level| {
+                    if level.regen_seconds == 0 {
+                        log::warn!(
+                            "Energy level {} never regenerates, skipping it",
+                            level.level
+                        );
+                    }
+                    level.regen_seconds > 0
+                })
+                .map(|level| (level.level, level))
+                .collect(),
+            players: HashMap::new(),
+        }
+    }
+
+    /// `GetAchievements`
+    pub fn achievements(&self) -> impl Iterator<Item = &Achievement> {
+        self.achievements.values()
+    }
+
+    /// `GetAchievementById`
+    pub fn achievement(&self, achievement: AchievementId) -> Result<&Achievement, AppCode> {
+        self.achievements.get(&achievement).ok_or(AppCode::NotFound)
+    }
+
+    /// `GetPlayerAchievements`, with when each one was unlocked
+    pub fn player_achievements(&self, player: PlayerId) -> BTreeMap<AchievementId, DateTime<Utc>> {
+        self.players
+            .get(&player)
+            .map(|progress| progress.achievements.clone())
+            .unwrap_or_default()
+    }
+
+    /// `GetStatsType`
+    pub fn stat_types(&self) -> impl Iterator<Item = &StatType> {
+        self.stat_types.values()
+    }
+
+    /// `GetPlayerStats`
+    pub fn player_stats(&self, player: PlayerId) -> BTreeMap<String, i64> {
+        self.players
+            .get(&player)
+            .map(|progress| progress.stats.clone())
+            .unwrap_or_default()
+    }
+
+    /// `AddToPlayerStat`, hands out any achievement the new value unlocks and returns them
+    pub fn add_to_player_stat(
+        &mut self,
+        player: PlayerId,
+        stat: &str,
+        delta: i64,
+        inventory: &mut Inventory,
+        wallets: &mut Wallets,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<AchievementId>, AppCode> {
+        if !self.stat_types.values().any(|known| known.name == stat) {
+            return Err(AppCode::NotFound);
+        }
+
+        self.progress_mut(player, now);
+        let progress = self.players.get_mut(&player).unwrap();
+        let value = progress.stats.entry(stat.to_string()).or_default();
+        *value = value.saturating_add(delta);
+        let value = *value;
+
+        let mut unlocked = Vec::new();
+
+        for achievement in self.achievements.values() {
+            if achievement.stat != stat
+                || value < achievement.threshold
+                || progress.achievements.contains_key(&achievement.id)
+            {
+                continue;
+            }
+
+            progress.achievements.insert(achievement.id, now);
+            achievement.reward.grant(player, inventory, wallets);
+            unlocked.push(achievement.id);
+        }
+
+        Ok(unlocked)
+    }
+
+    /// `GetRequiredExperience`, total XP needed to reach `level`
+    pub fn required_experience(&self, level: u32) -> u64 {
+        if level <= 1 {
+            return 0;
+        }
+
+        self.user_levels
+            .get(&level)
+            .copied()
+            .unwrap_or_else(|| self.xp_curve.evaluate((level - 1) as f64).max(0.0).round() as u64)
+    }
+
+    pub fn level_for(&self, experience: u64) -> u32 {
+        (2..=self.max_level)
+            .take_while(|&level| experience >= self.required_experience(level))
+            .last()
+            .unwrap_or(1)
+    }
+
+    pub fn experience(&self, player: PlayerId) -> u64 {
+        self.players
+            .get(&player)
+            .map(|progress| progress.experience)
+            .unwrap_or(0)
+    }
+
+    pub fn level(&self, player: PlayerId) -> u32 {
+        self.players
+            .get(&player)
+            .map(|progress| progress.level)
+            .unwrap_or(1)
+    }
+
+    /// Levelling up refills energy and sends `LevelStatusNotify`. Returns the new level
+    pub fn add_experience(
+        &mut self,
+        player: PlayerId,
+        experience: u64,
+        now: DateTime<Utc>,
+        outbox: &mut Outbox,
+    ) -> u32 {
+        let total = self
+            .progress_mut(player, now)
+            .experience
+            .saturating_add(experience);
+        let level = self.level_for(total);
+        let max = self.energy_level(level).max;
+
+        let progress = self.progress_mut(player, now);
+        let levelled_up = level > progress.level;
+
+        progress.experience = total;
+        progress.level = level;
+
+        if levelled_up {
+            progress.energy = progress.energy.max(max);
+            progress.energy_updated = now;
+            outbox.push(
+                player,
+                ClientEvent::LevelStatusNotify {
+                    level,
+                    experience: total,
+                },
+            );
+        }
+
+        level
+    }
+
+    /// Energy settings for a player level
+    pub fn energy_level(&self, level: u32) -> EnergyLevel {
+        self.energy_levels
+            .range(..=level)
+            .next_back()
+            .map(|(_, energy)| *energy)
+            .unwrap_or_default()
+    }
+
+    /// Current energy, with whatever came back since it was last looked at
+    pub fn energy(&mut self, player: PlayerId, now: DateTime<Utc>) -> u32 {
+        self.regenerate(player, now)
+    }
+
+    pub fn spend_energy(
+        &mut self,
+        player: PlayerId,
+        amount: u32,
+        now: DateTime<Utc>,
+    ) -> Result<u32, AppCode> {
+        let energy = self.regenerate(player, now);
+
+        if energy < amount {
+            return Err(AppCode::State);
+        }
+
+        // Regen at the cap already moved `energy_updated` up to now, so it starts ticking from here
+        let progress = self.progress_mut(player, now);
+        progress.energy = energy - amount;
+
+        Ok(progress.energy)
+    }
+
+    /// `GetUserLevelCstool`
+    pub fn user_levels(&self) -> Vec<UserLevel> {
+        (1..=self.max_level)
+            .map(|level| UserLevel {
+                level,
+                experience: self.required_experience(level),
+            })
+            .collect()
+    }
+
+    /// `CreateOrUpdateUserLevelCstool`
+    pub fn create_or_update_user_level(&mut self, level: UserLevel) -> Result<(), AppCode> {
+        if level.level <= 1 {
+            return Err(AppCode::Input);
+        }
+
+        self.user_levels.insert(level.level, level.experience);
+        self.max_level = self.max_level.max(level.level);
+        self.relevel();
+
+        Ok(())
+    }
+
+    /// `GetEnergyLevelCstool`
+    pub fn energy_levels(&self) -> Vec<EnergyLevel> {
+        self.energy_levels.values().copied().collect()
+    }
+
+    /// `CreateOrUpdateEnergyLevelCstool`
+    pub fn create_or_update_energy_level(&mut self, level: EnergyLevel) -> Result<(), AppCode> {
+        if level.regen_seconds == 0 {
+            return Err(AppCode::Input);
+        }
+
+        self.energy_levels.insert(level.level, level);
+        Ok(())
+    }
+
+    fn regenerate(&mut self, player: PlayerId, now: DateTime<Utc>) -> u32 {
+        let level = self.progress_mut(player, now).level;
+        let settings = self.energy_level(level);
+        let progress = self.progress_mut(player, now);
+
+        if progress.energy >= settings.max {
+            progress.energy_updated = now;
+            return progress.energy;
+        }
+
+        let regen_seconds = settings.regen_seconds as i64;
+        let missing = (settings.max - progress.energy) as i64;
+        let gained =
+            ((now - progress.energy_updated).num_seconds() / regen_seconds).clamp(0, missing);
+
+        // No more than the time that has gone by, so it can't overflow
+        progress.energy_updated += Duration::seconds(regen_seconds * gained);
+        progress.energy += gained as u32;
+
+        progress.energy
+    }
+
+    /// Curve changes from the cstool move players to whatever level their XP is now worth
+    fn relevel(&mut self) {
+        let levels: Vec<(PlayerId, u32)> = self
+            .players
+            .iter()
+            .map(|(&player, progress)| (player, self.level_for(progress.experience)))
+            .collect();
+
+        for (player, level) in levels {
+            self.players.get_mut(&player).unwrap().level = level;
+        }
+    }
+
+    fn progress_mut(&mut self, player: PlayerId, now: DateTime<Utc>) -> &mut PlayerProgress {
+        let max = self.energy_level(1).max;
+
+        self.players
+            .entry(player)
+            .or_insert_with(|| PlayerProgress {
+                experience: 0,
+                level: 1,
+                energy: max,
+                energy_updated: now,
+                stats: BTreeMap::new(),
+                achievements: BTreeMap::new(),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::currency::{Amount, COINS};
+    use chrono::TimeZone;
+
+    fn at(minute: u32, second: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 1, 12, minute, second)
+            .unwrap()
+    }
+
+    fn progression() -> Progression {
+        Progression::from_data(ProgressionData {
+            energy_levels: vec![
+                EnergyLevel {
+                    level: 1,
+                    max: 10,
+                    regen_seconds: 30,
+                },
+                EnergyLevel {
+                    level: 5,
+                    max: 20,
+                    regen_seconds: 0,
+                },
+            ],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn energy_levels_that_never_regenerate_are_skipped() {
+        let progression = progression();
+
+        assert_eq!(progression.energy_levels().len(), 1);
+        assert_eq!(progression.energy_level(5).regen_seconds, 30);
+    }
+
+    #[test]
+    fn spent_energy_comes_back_a_point_at_a_time() {
+        let mut progression = progression();
+
+        assert_eq!(progression.spend_energy(1, 4, at(0, 0)), Ok(6));
+        assert_eq!(progression.energy(1, at(0, 59)), 7);
+        assert_eq!(progression.energy(1, at(1, 0)), 8);
+        assert_eq!(
+            progression.spend_energy(1, 9, at(1, 0)),
+            Err(AppCode::State)
+        );
+        assert_eq!(progression.energy(1, at(30, 0)), 10);
+    }
+
+    #[test]
+    fn regen_after_a_long_time_away_stops_at_the_cap() {
+        let mut progression = Progression::from_data(ProgressionData {
+            energy_levels: vec![EnergyLevel {
+                level: 1,
+                max: u32::MAX,
+                regen_seconds: 1,
+            }],
+            ..Default::default()
+        });
+
+        progression.spend_energy(1, u32::MAX, at(0, 0)).unwrap();
+        let later = Utc.with_ymd_and_hms(2200, 1, 1, 0, 0, 0).unwrap();
+        assert_eq!(progression.energy(1, later), u32::MAX);
+    }
+
+    #[test]
+    fn levels_follow_the_xp_curve_up_to_the_max() {
+        let progression = Progression::from_data(ProgressionData {
+            max_level: 4,
+            ..Default::default()
+        });
+
+        assert_eq!(progression.required_experience(1), 0);
+        assert_eq!(progression.required_experience(2), 100);
+        assert_eq!(progression.required_experience(3), 283);
+        assert_eq!(progression.level_for(99), 1);
+        assert_eq!(progression.level_for(100), 2);
+        assert_eq!(progression.level_for(282), 2);
+        assert_eq!(progression.level_for(283), 3);
+        assert_eq!(progression.level_for(u64::MAX), 4);
+    }
+
+    #[test]
+    fn levelling_up_refills_energy_and_tells_the_player() {
+        let mut progression = progression();
+        let mut outbox = Outbox::default();
+
+        progression.spend_energy(1, 10, at(0, 0)).unwrap();
+        assert_eq!(progression.add_experience(1, 50, at(0, 0), &mut outbox), 1);
+        assert!(outbox.is_empty());
+
+        assert_eq!(progression.add_experience(1, 50, at(0, 0), &mut outbox), 2);
+        assert_eq!(
+            outbox.drain(1),
+            [ClientEvent::LevelStatusNotify {
+                level: 2,
+                experience: 100
+            }]
+        );
+        assert_eq!(progression.energy(1, at(0, 0)), 10);
+    }
+
+    #[test]
+    fn changing_the_curve_moves_players_to_their_new_level() {
+        let mut progression = progression();
+        let mut outbox = Outbox::default();
+
+        progression.add_experience(1, 150, at(0, 0), &mut outbox);
+        assert_eq!(progression.level(1), 2);
+
+        assert_eq!(
+            progression.create_or_update_user_level(UserLevel {
+                level: 1,
+                experience: 10,
+            }),
+            Err(AppCode::Input)
+        );
+        progression
+            .create_or_update_user_level(UserLevel {
+                level: 2,
+                experience: 200,
+            })
+            .unwrap();
+        assert_eq!(progression.level(1), 1);
+        assert_eq!(progression.experience(1), 150);
+    }
+
+    #[test]
+    fn achievements_unlock_once_when_a_stat_gets_there() {
+        let mut progression = Progression::from_data(ProgressionData {
+            stat_types: vec![StatType {
+                id: 1,
+                name: "trees".to_string(),
+            }],
+            achievements: vec![Achievement {
+                id: 1,
+                name: "Forester".to_string(),
+                stat: "trees".to_string(),
+                threshold: 3,
+                reward: Reward {
+                    items: Vec::new(),
+                    currencies: vec![Amount {
+                        currency: COINS,
+                        amount: 5,
+                    }],
+                },
+            }],
+            ..Default::default()
+        });
+        let mut inventory = Inventory::default();
+        let mut wallets = Wallets::default();
+        let mut add = |delta| {
+            progression.add_to_player_stat(
+                1,
+                "trees",
+                delta,
+                &mut inventory,
+                &mut wallets,
+                at(0, 0),
+            )
+        };
+
+        assert_eq!(add(2), Ok(Vec::new()));
+        assert_eq!(add(1), Ok(vec![1]));
+        assert_eq!(add(5), Ok(Vec::new()));
+        assert_eq!(wallets.balance(1, COINS), 5);
+        assert_eq!(
+            progression.add_to_player_stat(1, "rocks", 1, &mut inventory, &mut wallets, at(0, 0)),
+            Err(AppCode::NotFound)
+        );
+        assert!(progression.player_achievements(1).contains_key(&1));
+    }
+}
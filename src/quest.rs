@@ -0,0 +1,617 @@
+use crate::currency::Wallets;
+use crate::data;
+use crate::inventory::{Inventory, ItemId, Reward};
+use crate::message::AppCode;
+use crate::npc::NpcId;
+use crate::session::PlayerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+
+pub type QuestId = u64;
+pub type ObjectiveId = u32;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuestDefinition {
+    pub id: QuestId,
+    pub name: String,
+    /// Children only open up once their parent is done
+    #[serde(default)]
+    pub parent: Option<QuestId>,
+    /// Who offers the quest, if anybody
+    #[serde(default)]
+    pub npc: Option<NpcId>,
+    #[serde(default)]
+    pub objectives: Vec<Objective>,
+    #[serde(default)]
+    pub reward: Reward,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Objective {
+    pub id: ObjectiveId,
+    /// "collect" counts copies of the item in the player's inventory, anything else
+    /// ("talk", "visit", ...) is counted from events
+    pub kind: String,
+    pub target: u64,
+    #[serde(default = "one")]
+    pub count: u32,
+}
+
+fn one() -> u32 {
+    1
+}
+
+/// What `data/quests.json` looks like
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct QuestData {
+    #[serde(default)]
+    pub quests: Vec<QuestDefinition>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QuestStatus {
+    Accepted,
+    Started,
+    Completed,
+    Rejected,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlayerQuest {
+    pub quest: QuestId,
+    pub status: QuestStatus,
+    pub progress: BTreeMap<ObjectiveId, u32>,
+    pub items: BTreeMap<ItemId, u32>,
+}
+
+impl PlayerQuest {
+    pub fn is_active(&self) -> bool {
+        matches!(self.status, QuestStatus::Accepted | QuestStatus::Started)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct QuestEngine {
+    quests: BTreeMap<QuestId, QuestDefinition>,
+    children: HashMap<QuestId, BTreeSet<QuestId>>,
+    players: HashMap<PlayerId, BTreeMap<QuestId, PlayerQuest>>,
+}
+
+impl QuestEngine {
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self::from_data(data::load(path)?))
+    }
+
+    /// Parent loops are cut at the first quest in the file that's part of one, which
+    /// loses its parent with a warning. Quests hanging off a loop keep theirs
+    pub fn from_data(mut data: QuestData) -> Self {
+        let mut parents: HashMap<QuestId, QuestId> = data
+            .quests
+            .iter()
+            .filter_map(|quest| quest.parent.map(|parent| (quest.id, parent)))
+            .collect();
+
+        for quest in data.quests.iter_mut() {
+            let mut seen = BTreeSet::new();
+            let mut parent = quest.parent;
+
+            while let Some(current) = parent {
+                if current == quest.id {
+                    log::warn!(
+                        "Quest {} is its own ancestor, dropping its parent",
+                        quest.id
+                    );
+                    quest.parent = None;
+                    parents.remove(&quest.id);
+                    break;
+                }
+
+                // A loop further up that this quest isn't part of, it gets cut at one of its own
+                if !seen.insert(current) {
+                    break;
+                }
+
+                parent = parents.get(&current).copied();
+            }
+        }
+
+        let mut children: HashMap<QuestId, BTreeSet<QuestId>> = HashMap::new();
+
+        for quest in data.quests.iter() {
+            if let Some(parent) = quest.parent {
+                children.entry(parent).or_default().insert(quest.id);
+            }
+        }
+
+        Self {
+            quests: data
+                .quests
+                .into_iter()
+                .map(|quest| (quest.id, quest))
+                .collect(),
+            children,
+            players: HashMap::new(),
+        }
+    }
+
+    /// `GetQuests`
+    pub fn quests(&self) -> impl Iterator<Item = &QuestDefinition> {
+        self.quests.values()
+    }
+
+    /// `GetQuestById`
+    pub fn quest(&self, quest: QuestId) -> Result<&QuestDefinition, AppCode> {
+        self.quests.get(&quest).ok_or(AppCode::NotFound)
+    }
+
+    /// `GetQuestByParentId`, just the direct children
+    pub fn quests_by_parent(&self, parent: QuestId) -> Vec<&QuestDefinition> {
+        self.children
+            .get(&parent)
+            .into_iter()
+            .flatten()
+            .filter_map(|child| self.quests.get(child))
+            .collect()
+    }
+
+    /// `GetQuestAllFromParent`, the whole chain under a quest
+    pub fn all_quests_from_parent(&self, parent: QuestId) -> Vec<&QuestDefinition> {
+        let mut found = Vec::new();
+        let mut seen = BTreeSet::from([parent]);
+        let mut pending = vec![parent];
+
+        while let Some(quest) = pending.pop() {
+            for child in self.quests_by_parent(quest) {
+                if seen.insert(child.id) {
+                    pending.push(child.id);
+                    found.push(child);
+                }
+            }
+        }
+
+        found
+    }
+
+    /// `GetQuestFromParent`, the next quest in a chain the player can pick up
+    pub fn quest_from_parent(&self, player: PlayerId, parent: QuestId) -> Option<&QuestDefinition> {
+        self.quests_by_parent(parent)
+            .into_iter()
+            .find(|quest| self.is_available(player, quest.id))
+    }
+
+    /// `GetPlayerQuests`
+    pub fn player_quests(&self, player: PlayerId) -> Vec<&PlayerQuest> {
+        self.players
+            .get(&player)
+            .map(|quests| quests.values().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn player_quest(&self, player: PlayerId, quest: QuestId) -> Option<&PlayerQuest> {
+        self.players
+            .get(&player)
+            .and_then(|quests| quests.get(&quest))
+    }
+
+    pub fn is_available(&self, player: PlayerId, quest: QuestId) -> bool {
+        let Some(definition) = self.quests.get(&quest) else {
+            return false;
+        };
+
+        let parent_done = definition.parent.is_none_or(|parent| {
+            self.player_quest(player, parent)
+                .is_some_and(|parent| parent.status == QuestStatus::Completed)
+        });
+
+        let taken = self
+            .player_quest(player, quest)
+            .is_some_and(|quest| quest.status != QuestStatus::Rejected);
+
+        parent_done && !taken
+    }
+
+    /// `GetNpcsWithQuestOffer`
+    pub fn npcs_with_quest_offer(&self, player: PlayerId) -> BTreeSet<NpcId> {
+        self.quests
+            .values()
+            .filter(|quest| self.is_available(player, quest.id))
+            .filter_map(|quest| quest.npc)
+            .collect()
+    }
+
+    /// `AcceptQuest`
+    pub fn accept_quest(&mut self, player: PlayerId, quest: QuestId) -> Result<(), AppCode> {
+        self.quest(quest)?;
+
+        if !self.is_available(player, quest) {
+            return Err(AppCode::State);
+        }
+
+        self.players.entry(player).or_default().insert(
+            quest,
+            PlayerQuest {
+                quest,
+                status: QuestStatus::Accepted,
+                progress: BTreeMap::new(),
+                items: BTreeMap::new(),
+            },
+        );
+
+        Ok(())
+    }
+
+    /// `StartQuest`
+    pub fn start_quest(&mut self, player: PlayerId, quest: QuestId) -> Result<(), AppCode> {
+        let state = self.player_quest_mut(player, quest)?;
+
+        if state.status != QuestStatus::Accepted {
+            return Err(AppCode::State);
+        }
+
+        state.status = QuestStatus::Started;
+        Ok(())
+    }
+
+    /// `RejectQuest`, the player said no to the offer but can change their mind later
+    pub fn reject_quest(&mut self, player: PlayerId, quest: QuestId) -> Result<(), AppCode> {
+        self.quest(quest)?;
+
+        if !self.is_available(player, quest) {
+            return Err(AppCode::State);
+        }
+
+        self.players.entry(player).or_default().insert(
+            quest,
+            PlayerQuest {
+                quest,
+                status: QuestStatus::Rejected,
+                progress: BTreeMap::new(),
+                items: BTreeMap::new(),
+            },
+        );
+
+        Ok(())
+    }
+
+    /// `AbandonQuest`, all progress and quest items are thrown away
+    pub fn abandon_quest(&mut self, player: PlayerId, quest: QuestId) -> Result<(), AppCode> {
+        if !self.player_quest_mut(player, quest)?.is_active() {
+            return Err(AppCode::State);
+        }
+
+        self.players.get_mut(&player).unwrap().remove(&quest);
+        Ok(())
+    }
+
+    /// `AddQuestItem`, the client's own notes about the quest. They don't count
+    /// towards "collect" objectives, the inventory does
+    pub fn add_quest_item(
+        &mut self,
+        player: PlayerId,
+        quest: QuestId,
+        item: ItemId,
+        count: u32,
+    ) -> Result<u32, AppCode> {
+        let state = self.active_quest_mut(player, quest)?;
+        let held = state.items.entry(item).or_default();
+
+        *held = held.saturating_add(count);
+        Ok(*held)
+    }
+
+    /// `UpdateQuestItem`
+    pub fn update_quest_item(
+        &mut self,
+        player: PlayerId,
+        quest: QuestId,
+        item: ItemId,
+        count: u32,
+    ) -> Result<(), AppCode> {
+        let state = self.active_quest_mut(player, quest)?;
+
+        if count == 0 {
+            state.items.remove(&item);
+        } else {
+            state.items.insert(item, count);
+        }
+
+        Ok(())
+    }
+
+    /// `GetQuestItems`
+    pub fn quest_items(
+        &self,
+        player: PlayerId,
+        quest: QuestId,
+    ) -> Result<&BTreeMap<ItemId, u32>, AppCode> {
+        self.player_quest(player, quest)
+            .map(|state| &state.items)
+            .ok_or(AppCode::NotFound)
+    }
+
+    /// Count something the player did towards every active quest that wants it
+    pub fn record_event(&mut self, player: PlayerId, kind: &str, target: u64, amount: u32) {
+        let Some(quests) = self.players.get_mut(&player) else {
+            return;
+        };
+
+        for state in quests.values_mut().filter(|state| state.is_active()) {
+            if let Some(definition) = self.quests.get(&state.quest) {
+                Self::count_event(definition, state, kind, target, amount);
+            }
+        }
+    }
+
+    /// `record_event`, for one quest only. Nothing happens if it isn't active
+    pub fn record_quest_event(
+        &mut self,
+        player: PlayerId,
+        quest: QuestId,
+        kind: &str,
+        target: u64,
+        amount: u32,
+    ) {
+        let (Some(definition), Some(state)) = (
+            self.quests.get(&quest),
+            self.players
+                .get_mut(&player)
+                .and_then(|quests| quests.get_mut(&quest))
+                .filter(|state| state.is_active()),
+        ) else {
+            return;
+        };
+
+        Self::count_event(definition, state, kind, target, amount);
+    }
+
+    /// `QuestEventInProgress`, does any active quest still care about this event
+    pub fn quest_event_in_progress(
+        &self,
+        player: PlayerId,
+        kind: &str,
+        target: u64,
+        inventory: &Inventory,
+    ) -> bool {
+        self.player_quests(player)
+            .into_iter()
+            .filter(|state| state.is_active())
+            .any(|state| {
+                self.quests[&state.quest]
+                    .objectives
+                    .iter()
+                    .any(|objective| {
+                        objective.kind == kind
+                            && objective.target == target
+                            && !Self::objective_done(player, state, objective, inventory)
+                    })
+            })
+    }
+
+    pub fn is_complete(&self, player: PlayerId, quest: QuestId, inventory: &Inventory) -> bool {
+        let (Some(definition), Some(state)) =
+            (self.quests.get(&quest), self.player_quest(player, quest))
+        else {
+            return false;
+        };
+
+        definition
+            .objectives
+            .iter()
+            .all(|objective| Self::objective_done(player, state, objective, inventory))
+    }
+
+    /// `CompleteQuest`, takes whatever had to be collected, hands out the reward and
+    /// returns the quests this opened up
+    pub fn complete_quest(
+        &mut self,
+        player: PlayerId,
+        quest: QuestId,
+        inventory: &mut Inventory,
+        wallets: &mut Wallets,
+    ) -> Result<Vec<QuestId>, AppCode> {
+        self.active_quest_mut(player, quest)?;
+
+        if !self.is_complete(player, quest, inventory) {
+            return Err(AppCode::State);
+        }
+
+        let mut collected = Vec::new();
+        for objective in self.quests[&quest].objectives.iter() {
+            if objective.kind != "collect" {
+                continue;
+            }
+
+            match inventory.take(player, objective.target, objective.count) {
+                Ok(taken) => collected.extend(taken),
+                Err(e) => {
+                    for taken in collected {
+                        inventory.restore(taken, player);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let state = self.player_quest_mut(player, quest)?;
+        state.status = QuestStatus::Completed;
+        state.items.clear();
+
+        self.quests[&quest].reward.grant(player, inventory, wallets);
+
+        Ok(self
+            .quests_by_parent(quest)
+            .into_iter()
+            .map(|child| child.id)
+            .filter(|&child| self.is_available(player, child))
+            .collect())
+    }
+
+    /// For quests finished some other way, like with a group. Players who never took the
+    /// quest on themselves get it recorded too. No reward is handed out here
+    pub fn mark_completed(&mut self, player: PlayerId, quest: QuestId) {
+        let state = self
+            .players
+            .entry(player)
+            .or_default()
+            .entry(quest)
+            .or_insert_with(|| PlayerQuest {
+                quest,
+                status: QuestStatus::Completed,
+                progress: BTreeMap::new(),
+                items: BTreeMap::new(),
+            });
+
+        state.status = QuestStatus::Completed;
+        state.items.clear();
+    }
+
+    fn count_event(
+        definition: &QuestDefinition,
+        state: &mut PlayerQuest,
+        kind: &str,
+        target: u64,
+        amount: u32,
+    ) {
+        for objective in definition.objectives.iter() {
+            if objective.kind == kind && objective.target == target {
+                let progress = state.progress.entry(objective.id).or_default();
+                *progress = progress.saturating_add(amount).min(objective.count);
+            }
+        }
+    }
+
+    fn objective_done(
+        player: PlayerId,
+        state: &PlayerQuest,
+        objective: &Objective,
+        inventory: &Inventory,
+    ) -> bool {
+        let progress = match objective.kind.as_str() {
+            "collect" => inventory.free_count(player, objective.target) as u32,
+            _ => state.progress.get(&objective.id).copied().unwrap_or(0),
+        };
+
+        progress >= objective.count
+    }
+
+    fn player_quest_mut(
+        &mut self,
+        player: PlayerId,
+        quest: QuestId,
+    ) -> Result<&mut PlayerQuest, AppCode> {
+        self.players
+            .get_mut(&player)
+            .and_then(|quests| quests.get_mut(&quest))
+            .ok_or(AppCode::NotFound)
+    }
+
+    fn active_quest_mut(
+        &mut self,
+        player: PlayerId,
+        quest: QuestId,
+    ) -> Result<&mut PlayerQuest, AppCode> {
+        let state = self.player_quest_mut(player, quest)?;
+
+        if !state.is_active() {
+            return Err(AppCode::State);
+        }
+
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::currency::{Amount, COINS};
+
+    const ACORN: ItemId = 7;
+
+    fn quest(id: QuestId, parent: Option<QuestId>, objectives: Vec<Objective>) -> QuestDefinition {
+        QuestDefinition {
+            id,
+            name: format!("quest {id}"),
+            parent,
+            npc: None,
+            objectives,
+            reward: Reward {
+                items: Vec::new(),
+                currencies: vec![Amount {
+                    currency: COINS,
+                    amount: 10,
+                }],
+            },
+        }
+    }
+
+    fn collect(count: u32) -> Vec<Objective> {
+        vec![Objective {
+            id: 1,
+            kind: "collect".to_string(),
+            target: ACORN,
+            count,
+        }]
+    }
+
+    #[test]
+    fn collecting_counts_the_inventory_not_what_the_client_says() {
+        let mut quests = QuestEngine::from_data(QuestData {
+            quests: vec![quest(1, None, collect(3))],
+        });
+        let mut inventory = Inventory::default();
+        let mut wallets = Wallets::default();
+
+        quests.accept_quest(1, 1).unwrap();
+        quests.add_quest_item(1, 1, ACORN, 3).unwrap();
+
+        assert!(!quests.is_complete(1, 1, &inventory));
+        assert_eq!(
+            quests.complete_quest(1, 1, &mut inventory, &mut wallets),
+            Err(AppCode::State)
+        );
+
+        inventory.grant(1, ACORN, 4);
+        assert!(quests.is_complete(1, 1, &inventory));
+
+        quests
+            .complete_quest(1, 1, &mut inventory, &mut wallets)
+            .unwrap();
+        assert_eq!(inventory.count(1, ACORN), 1);
+        assert_eq!(wallets.balance(1, COINS), 10);
+    }
+
+    #[test]
+    fn parent_cycles_are_broken_at_load() {
+        let quests = QuestEngine::from_data(QuestData {
+            quests: vec![
+                quest(1, Some(3), Vec::new()),
+                quest(2, Some(1), Vec::new()),
+                quest(3, Some(2), Vec::new()),
+                quest(4, Some(4), Vec::new()),
+            ],
+        });
+
+        // Only the link that closed the loop goes, 1 is a root now
+        assert_eq!(quests.quest(1).unwrap().parent, None);
+        assert_eq!(quests.all_quests_from_parent(1).len(), 2);
+        assert_eq!(quests.quest(4).unwrap().parent, None);
+        assert!(quests.all_quests_from_parent(4).is_empty());
+    }
+
+    #[test]
+    fn quests_hanging_off_a_loop_keep_their_parent() {
+        let quests = QuestEngine::from_data(QuestData {
+            quests: vec![
+                quest(1, Some(2), Vec::new()),
+                quest(2, Some(3), Vec::new()),
+                quest(3, Some(2), Vec::new()),
+            ],
+        });
+
+        assert_eq!(quests.quest(1).unwrap().parent, Some(2));
+        assert_eq!(quests.quest(2).unwrap().parent, None);
+        assert_eq!(quests.quest(3).unwrap().parent, Some(2));
+        assert_eq!(quests.all_quests_from_parent(2).len(), 2);
+    }
+}
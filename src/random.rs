@@ -0,0 +1,102 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// A small SplitMix64 generator. The same seed always rolls the same numbers,
+/// which is what lets rotations be replayed and tested
+#[derive(Debug, Clone)]
+pub struct SeededRng {
+    state: u64,
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// A generator for one particular combination of things, like a seed, a window and a player
+    pub fn from_parts(parts: &[u64]) -> Self {
+        let mut rng = Self::new(0);
+
+        for &part in parts {
+            rng.state ^= part;
+            rng.state = rng.next_u64();
+        }
+
+        rng
+    }
+
+    /// A seed nobody can guess, for when the data file doesn't pin one down
+    pub fn random_seed() -> u64 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(0);
+        hasher.finish()
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Somewhere in `0..n`, `n` has to be more than zero
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    /// The index of one of `weights`, more likely the heavier it is. Nothing if they're all zero
+    pub fn pick_weighted(&mut self, weights: &[u32]) -> Option<usize> {
+        let total: u64 = weights.iter().map(|&weight| weight as u64).sum();
+        if total == 0 {
+            return None;
+        }
+
+        let mut roll = self.below(total);
+        weights.iter().position(|&weight| {
+            if roll < weight as u64 {
+                return true;
+            }
+
+            roll -= weight as u64;
+            false
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rng_is_stable() {
+        let mut rng = SeededRng::new(1);
+        let rolls: Vec<u64> = (0..3).map(|_| rng.next_u64()).collect();
+
+        // Published SplitMix64 output for a seed of 1
+        assert_eq!(
+            rolls,
+            vec![
+                0x910a_2dec_8902_5cc1,
+                0xbeeb_8da1_658e_ec67,
+                0xf893_a2ee_fb32_555e
+            ]
+        );
+    }
+
+    #[test]
+    fn weighted_picks_follow_the_weights() {
+        let mut rng = SeededRng::new(7);
+        let mut counts = [0; 3];
+
+        for _ in 0..10_000 {
+            counts[rng.pick_weighted(&[70, 25, 5]).unwrap()] += 1;
+        }
+
+        assert!((6_500..7_500).contains(&counts[0]), "{:?}", counts);
+        assert!((2_000..3_000).contains(&counts[1]), "{:?}", counts);
+        assert!((300..700).contains(&counts[2]), "{:?}", counts);
+        assert_eq!(rng.pick_weighted(&[0, 0]), None);
+    }
+}
@@ -0,0 +1,970 @@
+use crate::currency::Wallets;
+use crate::data;
+use crate::inventory::{Inventory, Reward};
+use crate::message::AppCode;
+use crate::session::{ClientEvent, Outbox, PlayerId};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::path::Path;
+
+pub type StateObjectId = u64;
+pub type RuleId = u64;
+pub type AwardId = u64;
+pub type ArmId = u64;
+pub type RuleSetId = u64;
+pub type AwardSetId = u64;
+pub type RuledObjectId = u64;
+
+/// A number the rules can look at. Either one of the player's stats or a value kept here
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StateObject {
+    pub id: StateObjectId,
+    pub name: String,
+    #[serde(default)]
+    pub stat: Option<String>,
+    #[serde(default)]
+    pub initial: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Equal,
+    GreaterOrEqual,
+    Greater,
+}
+
+impl Comparison {
+    pub fn test(self, value: i64, against: i64) -> bool {
+        match self {
+            Comparison::Less => value < against,
+            Comparison::LessOrEqual => value <= against,
+            Comparison::Equal => value == against,
+            Comparison::GreaterOrEqual => value >= against,
+            Comparison::Greater => value > against,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rule {
+    pub id: RuleId,
+    pub name: String,
+    pub state_object: StateObjectId,
+    pub comparison: Comparison,
+    pub value: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Award {
+    pub id: AwardId,
+    pub name: String,
+    #[serde(default)]
+    pub reward: Reward,
+}
+
+/// Award rule mapping, hands out an award the first time a rule passes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Arm {
+    pub id: ArmId,
+    pub state_object: StateObjectId,
+    pub rule: RuleId,
+    pub award: AwardId,
+}
+
+/// Rule set rule mapping (RSRM), every rule has to pass for the award set to be handed out
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RuleSet {
+    pub id: RuleSetId,
+    pub name: String,
+    #[serde(default)]
+    pub rules: BTreeSet<RuleId>,
+    #[serde(default)]
+    pub award_set: Option<AwardSetId>,
+}
+
+/// Award set award mapping (ASAM)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AwardSet {
+    pub id: AwardSetId,
+    pub name: String,
+    #[serde(default)]
+    pub awards: BTreeSet<AwardId>,
+}
+
+/// Something in the game (item, zone, quest, ...) with a rule set on it.
+/// Without one of its own it uses its parent's
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RuledObject {
+    pub id: RuledObjectId,
+    pub object_id: u64,
+    #[serde(default)]
+    pub parent: Option<RuledObjectId>,
+    #[serde(default)]
+    pub rule_set: Option<RuleSetId>,
+}
+
+/// `base + factor * x ^ exponent`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Formula {
+    pub kind: String,
+    pub base: f64,
+    pub factor: f64,
+    pub exponent: f64,
+}
+
+impl Formula {
+    pub fn evaluate(&self, x: f64) -> f64 {
+        self.base + self.factor * x.powf(self.exponent)
+    }
+}
+
+/// How a rule is doing for one player, `GetRuleInstanceInfo` and `GetRuleCount`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RuleInstance {
+    pub rule: RuleId,
+    pub last_value: i64,
+    pub passing: bool,
+    /// How many times it went from failing to passing
+    pub count: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FullRule {
+    pub rule: Rule,
+    pub state_object: Option<StateObject>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FullAward {
+    pub award: Award,
+    pub arms: Vec<Arm>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FullRuledObject {
+    pub ruled_object: RuledObject,
+    pub rule_set: Option<RuleSet>,
+    pub rules: Vec<Rule>,
+    pub awards: Vec<Award>,
+}
+
+/// What `data/rules.json` looks like
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RulesData {
+    pub state_objects: Vec<StateObject>,
+    pub rules: Vec<Rule>,
+    pub awards: Vec<Award>,
+    pub arms: Vec<Arm>,
+    pub rule_sets: Vec<RuleSet>,
+    pub award_sets: Vec<AwardSet>,
+    pub ruled_objects: Vec<RuledObject>,
+    pub formulas: Vec<Formula>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum AwardSource {
+    Arm(ArmId),
+    /// Keyed by the rule set rather than the object, so children inheriting
+    /// their parent's rule set don't pay it out again
+    RuleSet(RuleSetId),
+}
+
+/// Rows keyed by id, handing out new ids as they get added
+#[derive(Debug)]
+struct Table<T> {
+    rows: BTreeMap<u64, T>,
+    next_id: u64,
+}
+
+impl<T: Clone> Table<T> {
+    fn new(rows: impl IntoIterator<Item = (u64, T)>) -> Self {
+        let rows: BTreeMap<u64, T> = rows.into_iter().collect();
+        let next_id = rows.keys().next_back().copied().unwrap_or(0);
+
+        Self { rows, next_id }
+    }
+
+    fn add(&mut self, make: impl FnOnce(u64) -> T) -> u64 {
+        self.next_id += 1;
+        self.rows.insert(self.next_id, make(self.next_id));
+        self.next_id
+    }
+
+    fn update(&mut self, id: u64, row: T) -> Result<(), AppCode> {
+        match self.rows.get_mut(&id) {
+            Some(existing) => {
+                *existing = row;
+                Ok(())
+            }
+            None => Err(AppCode::NotFound),
+        }
+    }
+
+    fn delete(&mut self, id: u64) -> Result<T, AppCode> {
+        self.rows.remove(&id).ok_or(AppCode::NotFound)
+    }
+
+    fn get(&self, id: u64) -> Result<&T, AppCode> {
+        self.rows.get(&id).ok_or(AppCode::NotFound)
+    }
+
+    fn get_mut(&mut self, id: u64) -> Result<&mut T, AppCode> {
+        self.rows.get_mut(&id).ok_or(AppCode::NotFound)
+    }
+
+    fn all(&self) -> Vec<T> {
+        self.rows.values().cloned().collect()
+    }
+}
+
+impl<T: Clone> Default for Table<T> {
+    fn default() -> Self {
+        Self::new([])
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RulesEngine {
+    state_objects: Table<StateObject>,
+    rules: Table<Rule>,
+    awards: Table<Award>,
+    arms: Table<Arm>,
+    rule_sets: Table<RuleSet>,
+    award_sets: Table<AwardSet>,
+    ruled_objects: Table<RuledObject>,
+    formulas: HashMap<String, Formula>,
+    state: HashMap<(PlayerId, StateObjectId), i64>,
+    instances: HashMap<(PlayerId, RuleId), RuleInstance>,
+    awarded: HashSet<(PlayerId, AwardSource)>,
+    queue: HashMap<PlayerId, VecDeque<AwardId>>,
+}
+
+impl RulesEngine {
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self::from_data(data::load(path)?))
+    }
+
+    pub fn from_data(data: RulesData) -> Self {
+        Self {
+            state_objects: Table::new(data.state_objects.into_iter().map(|row| (row.id, row))),
+            rules: Table::new(data.rules.into_iter().map(|row| (row.id, row))),
+            awards: Table::new(data.awards.into_iter().map(|row| (row.id, row))),
+            arms: Table::new(data.arms.into_iter().map(|row| (row.id, row))),
+            rule_sets: Table::new(data.rule_sets.into_iter().map(|row| (row.id, row))),
+            award_sets: Table::new(data.award_sets.into_iter().map(|row| (row.id, row))),
+            ruled_objects: Table::new(data.ruled_objects.into_iter().map(|row| (row.id, row))),
+            formulas: data
+                .formulas
+                .into_iter()
+                .map(|formula| (formula.kind.clone(), formula))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    // State objects
+
+    pub fn add_state_object(
+        &mut self,
+        name: &str,
+        stat: Option<String>,
+        initial: i64,
+    ) -> StateObjectId {
+        self.state_objects.add(|id| StateObject {
+            id,
+            name: name.to_string(),
+            stat,
+            initial,
+        })
+    }
+
+    pub fn update_state_object(&mut self, state_object: StateObject) -> Result<(), AppCode> {
+        self.state_objects.update(state_object.id, state_object)
+    }
+
+    pub fn state_objects(&self) -> Vec<StateObject> {
+        self.state_objects.all()
+    }
+
+    // Rules
+
+    pub fn add_rule(
+        &mut self,
+        name: &str,
+        state_object: StateObjectId,
+        comparison: Comparison,
+        value: i64,
+    ) -> Result<RuleId, AppCode> {
+        self.state_objects.get(state_object)?;
+
+        Ok(self.rules.add(|id| Rule {
+            id,
+            name: name.to_string(),
+            state_object,
+            comparison,
+            value,
+        }))
+    }
+
+    pub fn update_rule(&mut self, rule: Rule) -> Result<(), AppCode> {
+        self.state_objects.get(rule.state_object)?;
+        self.rules.update(rule.id, rule)
+    }
+
+    /// Also drops it from every rule set and ARM that used it
+    pub fn delete_rule(&mut self, rule: RuleId) -> Result<(), AppCode> {
+        self.rules.delete(rule)?;
+
+        for rule_set in self.rule_sets.rows.values_mut() {
+            rule_set.rules.remove(&rule);
+        }
+        self.arms.rows.retain(|_, arm| arm.rule != rule);
+        self.instances.retain(|(_, instance), _| *instance != rule);
+
+        Ok(())
+    }
+
+    pub fn rule(&self, rule: RuleId) -> Result<&Rule, AppCode> {
+        self.rules.get(rule)
+    }
+
+    pub fn full_rule(&self, rule: RuleId) -> Result<FullRule, AppCode> {
+        let rule = self.rules.get(rule)?.clone();
+
+        Ok(FullRule {
+            state_object: self.state_objects.get(rule.state_object).ok().cloned(),
+            rule,
+        })
+    }
+
+    pub fn all_rules(&self) -> Vec<Rule> {
+        self.rules.all()
+    }
+
+    // Awards
+
+    pub fn add_award(&mut self, name: &str, reward: Reward) -> AwardId {
+        self.awards.add(|id| Award {
+            id,
+            name: name.to_string(),
+            reward,
+        })
+    }
+
+    pub fn update_award(&mut self, award: Award) -> Result<(), AppCode> {
+        self.awards.update(award.id, award)
+    }
+
+    pub fn delete_award(&mut self, award: AwardId) -> Result<(), AppCode> {
+        self.awards.delete(award)?;
+
+        for award_set in self.award_sets.rows.values_mut() {
+            award_set.awards.remove(&award);
+        }
+        self.arms.rows.retain(|_, arm| arm.award != award);
+
+        Ok(())
+    }
+
+    pub fn award(&self, award: AwardId) -> Result<&Award, AppCode> {
+        self.awards.get(award)
+    }
+
+    pub fn full_award(&self, award: AwardId) -> Result<FullAward, AppCode> {
+        Ok(FullAward {
+            award: self.awards.get(award)?.clone(),
+            arms: self.arms_by_award(award),
+        })
+    }
+
+    pub fn all_awards(&self) -> Vec<Award> {
+        self.awards.all()
+    }
+
+    // ARMs
+
+    pub fn add_arm(
+        &mut self,
+        state_object: StateObjectId,
+        rule: RuleId,
+        award: AwardId,
+    ) -> Result<ArmId, AppCode> {
+        self.check_arm(state_object, rule, award)?;
+
+        Ok(self.arms.add(|id| Arm {
+            id,
+            state_object,
+            rule,
+            award,
+        }))
+    }
+
+    pub fn update_arm(&mut self, arm: Arm) -> Result<(), AppCode> {
+        self.check_arm(arm.state_object, arm.rule, arm.award)?;
+        self.arms.update(arm.id, arm)
+    }
+
+    pub fn arm(&self, arm: ArmId) -> Result<&Arm, AppCode> {
+        self.arms.get(arm)
+    }
+
+    pub fn all_arms(&self) -> Vec<Arm> {
+        self.arms.all()
+    }
+
+    pub fn arms_by_award(&self, award: AwardId) -> Vec<Arm> {
+        self.arms_where(|arm| arm.award == award)
+    }
+
+    pub fn arms_by_rule(&self, rule: RuleId) -> Vec<Arm> {
+        self.arms_where(|arm| arm.rule == rule)
+    }
+
+    /// `GetArmByStateObject` and `StateObjectArm`
+    pub fn arms_by_state_object(&self, state_object: StateObjectId) -> Vec<Arm> {
+        self.arms_where(|arm| arm.state_object == state_object)
+    }
+
+    pub fn award_by_arm(&self, arm: ArmId) -> Result<&Award, AppCode> {
+        self.awards.get(self.arms.get(arm)?.award)
+    }
+
+    pub fn rule_by_arm(&self, arm: ArmId) -> Result<&Rule, AppCode> {
+        self.rules.get(self.arms.get(arm)?.rule)
+    }
+
+    pub fn state_object_by_arm(&self, arm: ArmId) -> Result<&StateObject, AppCode> {
+        self.state_objects.get(self.arms.get(arm)?.state_object)
+    }
+
+    // Rule sets and award sets
+
+    pub fn add_rule_set(&mut self, name: &str, award_set: Option<AwardSetId>) -> RuleSetId {
+        self.rule_sets.add(|id| RuleSet {
+            id,
+            name: name.to_string(),
+            rules: BTreeSet::new(),
+            award_set,
+        })
+    }
+
+    pub fn rule_sets(&self) -> Vec<RuleSet> {
+        self.rule_sets.all()
+    }
+
+    pub fn add_rules_to_rsrm(
+        &mut self,
+        rule_set: RuleSetId,
+        rules: &[RuleId],
+    ) -> Result<(), AppCode> {
+        for &rule in rules {
+            self.rules.get(rule)?;
+        }
+
+        self.rule_sets.get_mut(rule_set)?.rules.extend(rules);
+        Ok(())
+    }
+
+    pub fn delete_rule_from_rsrm(
+        &mut self,
+        rule_set: RuleSetId,
+        rule: RuleId,
+    ) -> Result<(), AppCode> {
+        if !self.rule_sets.get_mut(rule_set)?.rules.remove(&rule) {
+            return Err(AppCode::NotFound);
+        }
+
+        Ok(())
+    }
+
+    /// Ruled objects using it fall back to their parent's rule set
+    pub fn delete_rsrm(&mut self, rule_set: RuleSetId) -> Result<(), AppCode> {
+        self.rule_sets.delete(rule_set)?;
+
+        for ruled_object in self.ruled_objects.rows.values_mut() {
+            if ruled_object.rule_set == Some(rule_set) {
+                ruled_object.rule_set = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn add_award_set(&mut self, name: &str) -> AwardSetId {
+        self.award_sets.add(|id| AwardSet {
+            id,
+            name: name.to_string(),
+            awards: BTreeSet::new(),
+        })
+    }
+
+    /// `GetAwardSets`
+    pub fn award_sets(&self) -> Vec<AwardSet> {
+        self.award_sets.all()
+    }
+
+    pub fn award_set(&self, award_set: AwardSetId) -> Result<&AwardSet, AppCode> {
+        self.award_sets.get(award_set)
+    }
+
+    pub fn add_awards_to_asam(
+        &mut self,
+        award_set: AwardSetId,
+        awards: &[AwardId],
+    ) -> Result<(), AppCode> {
+        for &award in awards {
+            self.awards.get(award)?;
+        }
+
+        self.award_sets.get_mut(award_set)?.awards.extend(awards);
+        Ok(())
+    }
+
+    pub fn delete_award_from_asam(
+        &mut self,
+        award_set: AwardSetId,
+        award: AwardId,
+    ) -> Result<(), AppCode> {
+        if !self.award_sets.get_mut(award_set)?.awards.remove(&award) {
+            return Err(AppCode::NotFound);
+        }
+
+        Ok(())
+    }
+
+    pub fn delete_asam(&mut self, award_set: AwardSetId) -> Result<(), AppCode> {
+        self.award_sets.delete(award_set)?;
+
+        for rule_set in self.rule_sets.rows.values_mut() {
+            if rule_set.award_set == Some(award_set) {
+                rule_set.award_set = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Ruled objects
+
+    pub fn add_ruled_object(
+        &mut self,
+        object_id: u64,
+        parent: Option<RuledObjectId>,
+    ) -> Result<RuledObjectId, AppCode> {
+        if let Some(parent) = parent {
+            self.ruled_objects.get(parent)?;
+        }
+
+        Ok(self.ruled_objects.add(|id| RuledObject {
+            id,
+            object_id,
+            parent,
+            rule_set: None,
+        }))
+    }
+
+    pub fn update_ruled_object(&mut self, ruled_object: RuledObject) -> Result<(), AppCode> {
+        if let Some(parent) = ruled_object.parent {
+            self.ruled_objects.get(parent)?;
+        }
+
+        self.ruled_objects.update(ruled_object.id, ruled_object)
+    }
+
+    /// Children get moved up to the deleted object's parent
+    pub fn delete_ruled_object(&mut self, ruled_object: RuledObjectId) -> Result<(), AppCode> {
+        let removed = self.ruled_objects.delete(ruled_object)?;
+
+        for child in self.ruled_objects.rows.values_mut() {
+            if child.parent == Some(ruled_object) {
+                child.parent = removed.parent;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn assign_rule_set_to_ruled_object(
+        &mut self,
+        ruled_object: RuledObjectId,
+        rule_set: Option<RuleSetId>,
+    ) -> Result<(), AppCode> {
+        if let Some(rule_set) = rule_set {
+            self.rule_sets.get(rule_set)?;
+        }
+
+        self.ruled_objects.get_mut(ruled_object)?.rule_set = rule_set;
+        Ok(())
+    }
+
+    pub fn ruled_object(&self, ruled_object: RuledObjectId) -> Result<&RuledObject, AppCode> {
+        self.ruled_objects.get(ruled_object)
+    }
+
+    pub fn ruled_object_by_object_id(&self, object_id: u64) -> Option<&RuledObject> {
+        self.ruled_objects
+            .rows
+            .values()
+            .find(|ruled_object| ruled_object.object_id == object_id)
+    }
+
+    /// `GetRuledObjectByHierarchy`, from the root down to `ruled_object`
+    pub fn ruled_object_hierarchy(
+        &self,
+        ruled_object: RuledObjectId,
+    ) -> Result<Vec<RuledObject>, AppCode> {
+        let mut chain = vec![self.ruled_objects.get(ruled_object)?.clone()];
+
+        while let Some(parent) = chain.last().and_then(|last| last.parent) {
+            // Stop on a cycle instead of spinning forever
+            if chain.iter().any(|seen| seen.id == parent) {
+                break;
+            }
+
+            match self.ruled_objects.get(parent) {
+                Ok(parent) => chain.push(parent.clone()),
+                Err(_) => break,
+            }
+        }
+
+        chain.reverse();
+        Ok(chain)
+    }
+
+    pub fn all_ruled_objects(&self) -> Vec<RuledObject> {
+        self.ruled_objects.all()
+    }
+
+    pub fn full_ruled_object(
+        &self,
+        ruled_object: RuledObjectId,
+    ) -> Result<FullRuledObject, AppCode> {
+        let rule_set = self
+            .effective_rule_set(ruled_object)?
+            .and_then(|rule_set| self.rule_sets.get(rule_set).ok())
+            .cloned();
+
+        let rules = rule_set
+            .iter()
+            .flat_map(|rule_set| rule_set.rules.iter())
+            .filter_map(|&rule| self.rules.get(rule).ok().cloned())
+            .collect();
+
+        let awards = rule_set
+            .as_ref()
+            .and_then(|rule_set| rule_set.award_set)
+            .and_then(|award_set| self.award_sets.get(award_set).ok())
+            .iter()
+            .flat_map(|award_set| award_set.awards.iter())
+            .filter_map(|&award| self.awards.get(award).ok().cloned())
+            .collect();
+
+        Ok(FullRuledObject {
+            ruled_object: self.ruled_objects.get(ruled_object)?.clone(),
+            rule_set,
+            rules,
+            awards,
+        })
+    }
+
+    pub fn effective_rule_set(
+        &self,
+        ruled_object: RuledObjectId,
+    ) -> Result<Option<RuleSetId>, AppCode> {
+        Ok(self
+            .ruled_object_hierarchy(ruled_object)?
+            .iter()
+            .rev()
+            .find_map(|ruled_object| ruled_object.rule_set))
+    }
+
+    /// `GetFormulaByType`
+    pub fn formula(&self, kind: &str) -> Option<&Formula> {
+        self.formulas.get(kind)
+    }
+
+    // Per player state
+
+    pub fn state_value(
+        &self,
+        player: PlayerId,
+        state_object: StateObjectId,
+        stats: &BTreeMap<String, i64>,
+    ) -> i64 {
+        let Ok(definition) = self.state_objects.get(state_object) else {
+            return 0;
+        };
+
+        if let Some(stat) = definition.stat.as_ref() {
+            return stats.get(stat).copied().unwrap_or(definition.initial);
+        }
+
+        self.state
+            .get(&(player, state_object))
+            .copied()
+            .unwrap_or(definition.initial)
+    }
+
+    pub fn add_to_state(
+        &mut self,
+        player: PlayerId,
+        state_object: StateObjectId,
+        delta: i64,
+    ) -> Result<i64, AppCode> {
+        let initial = self.state_objects.get(state_object)?.initial;
+        let value = self.state.entry((player, state_object)).or_insert(initial);
+
+        *value = value.saturating_add(delta);
+        Ok(*value)
+    }
+
+    /// `GetRuleInstanceInfo`
+    pub fn rule_instance(&self, player: PlayerId, rule: RuleId) -> RuleInstance {
+        self.instances
+            .get(&(player, rule))
+            .cloned()
+            .unwrap_or(RuleInstance {
+                rule,
+                ..Default::default()
+            })
+    }
+
+    /// `GetRuleCount`
+    pub fn rule_count(&self, player: PlayerId, rule: RuleId) -> u32 {
+        self.rule_instance(player, rule).count
+    }
+
+    /// Run every rule for a player and queue any award they just earned.
+    /// Each ARM and rule set only pays out once per player, however many ruled
+    /// objects share the rule set
+    pub fn evaluate(&mut self, player: PlayerId, stats: &BTreeMap<String, i64>) -> Vec<AwardId> {
+        let results: Vec<(RuleId, i64, bool)> = self
+            .rules
+            .rows
+            .values()
+            .map(|rule| {
+                let value = self.state_value(player, rule.state_object, stats);
+                (rule.id, value, rule.comparison.test(value, rule.value))
+            })
+            .collect();
+
+        let mut passing = BTreeSet::new();
+
+        for (rule, value, passed) in results {
+            let instance = self
+                .instances
+                .entry((player, rule))
+                .or_insert_with(|| RuleInstance {
+                    rule,
+                    ..Default::default()
+                });
+
+            if passed && !instance.passing {
+                instance.count += 1;
+            }
+            instance.passing = passed;
+            instance.last_value = value;
+
+            if passed {
+                passing.insert(rule);
+            }
+        }
+
+        let mut earned: Vec<(AwardSource, AwardId)> = self
+            .arms
+            .rows
+            .values()
+            .filter(|arm| passing.contains(&arm.rule))
+            .map(|arm| (AwardSource::Arm(arm.id), arm.award))
+            .collect();
+
+        let rule_sets: BTreeSet<RuleSetId> = self
+            .ruled_objects
+            .rows
+            .keys()
+            .filter_map(|&ruled_object| self.effective_rule_set(ruled_object).ok().flatten())
+            .collect();
+
+        for rule_set in rule_sets {
+            let Ok(rule_set) = self.rule_sets.get(rule_set) else {
+                continue;
+            };
+
+            if rule_set.rules.is_empty() || !rule_set.rules.is_subset(&passing) {
+                continue;
+            }
+
+            let awards = rule_set
+                .award_set
+                .and_then(|award_set| self.award_sets.get(award_set).ok())
+                .map(|award_set| award_set.awards.clone())
+                .unwrap_or_default();
+
+            earned.extend(
+                awards
+                    .into_iter()
+                    .map(|award| (AwardSource::RuleSet(rule_set.id), award)),
+            );
+        }
+
+        let fresh: HashSet<AwardSource> = earned
+            .iter()
+            .map(|(source, _)| *source)
+            .filter(|source| !self.awarded.contains(&(player, *source)))
+            .collect();
+
+        let queued: Vec<AwardId> = earned
+            .into_iter()
+            .filter(|(source, _)| fresh.contains(source))
+            .map(|(_, award)| award)
+            .collect();
+
+        self.awarded
+            .extend(fresh.into_iter().map(|source| (player, source)));
+        self.queue
+            .entry(player)
+            .or_default()
+            .extend(queued.iter().copied());
+
+        queued
+    }
+
+    /// `AddAwardToAwardQueue`
+    pub fn add_award_to_award_queue(
+        &mut self,
+        player: PlayerId,
+        award: AwardId,
+    ) -> Result<(), AppCode> {
+        self.awards.get(award)?;
+        self.queue.entry(player).or_default().push_back(award);
+        Ok(())
+    }
+
+    pub fn queued_awards(&self, player: PlayerId) -> Vec<AwardId> {
+        self.queue
+            .get(&player)
+            .map(|queue| queue.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Hand out everything in the player's award queue and tell them with `AddPlayerAwardNotify`
+    pub fn deliver_awards(
+        &mut self,
+        player: PlayerId,
+        inventory: &mut Inventory,
+        wallets: &mut Wallets,
+        outbox: &mut Outbox,
+    ) -> Vec<AwardId> {
+        let queued = self.queue.remove(&player).unwrap_or_default();
+
+        queued
+            .into_iter()
+            .filter(|&award| match self.awards.get(award) {
+                Ok(definition) => {
+                    definition.reward.grant(player, inventory, wallets);
+                    outbox.push(player, ClientEvent::AddPlayerAwardNotify { award });
+                    true
+                }
+                Err(_) => false,
+            })
+            .collect()
+    }
+
+    fn check_arm(
+        &self,
+        state_object: StateObjectId,
+        rule: RuleId,
+        award: AwardId,
+    ) -> Result<(), AppCode> {
+        self.state_objects.get(state_object)?;
+        self.awards.get(award)?;
+
+        if self.rules.get(rule)?.state_object != state_object {
+            return Err(AppCode::Input);
+        }
+
+        Ok(())
+    }
+
+    fn arms_where(&self, filter: impl Fn(&Arm) -> bool) -> Vec<Arm> {
+        self.arms
+            .rows
+            .values()
+            .filter(|arm| filter(arm))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::currency::{Amount, COINS};
+
+    fn coins(amount: u64) -> Reward {
+        Reward {
+            items: Vec::new(),
+            currencies: vec![Amount {
+                currency: COINS,
+                amount,
+            }],
+        }
+    }
+
+    /// A zone with two rooms under it, all sharing the zone's rule set
+    fn rules() -> (RulesEngine, AwardId) {
+        let mut rules = RulesEngine::default();
+
+        let steps = rules.add_state_object("steps", Some("steps".to_string()), 0);
+        let rule = rules
+            .add_rule("walked", steps, Comparison::GreaterOrEqual, 100)
+            .unwrap();
+        let award = rules.add_award("Explorer", coins(50));
+        let award_set = rules.add_award_set("explorer");
+        rules.add_awards_to_asam(award_set, &[award]).unwrap();
+        let rule_set = rules.add_rule_set("explore", Some(award_set));
+        rules.add_rules_to_rsrm(rule_set, &[rule]).unwrap();
+
+        let zone = rules.add_ruled_object(10, None).unwrap();
+        rules
+            .assign_rule_set_to_ruled_object(zone, Some(rule_set))
+            .unwrap();
+        rules.add_ruled_object(11, Some(zone)).unwrap();
+        rules.add_ruled_object(12, Some(zone)).unwrap();
+
+        (rules, award)
+    }
+
+    #[test]
+    fn inherited_rule_sets_pay_out_once() {
+        let (mut rules, award) = rules();
+        let stats = BTreeMap::from([("steps".to_string(), 120)]);
+
+        assert_eq!(rules.evaluate(1, &stats), vec![award]);
+        assert!(rules.evaluate(1, &stats).is_empty());
+        assert_eq!(rules.queued_awards(1), vec![award]);
+    }
+
+    #[test]
+    fn awards_wait_for_the_rules_to_pass() {
+        let (mut rules, award) = rules();
+        let mut inventory = Inventory::default();
+        let mut wallets = Wallets::default();
+        let mut outbox = Outbox::default();
+
+        assert!(rules
+            .evaluate(1, &BTreeMap::from([("steps".to_string(), 99)]))
+            .is_empty());
+        rules.evaluate(1, &BTreeMap::from([("steps".to_string(), 100)]));
+
+        assert_eq!(
+            rules.deliver_awards(1, &mut inventory, &mut wallets, &mut outbox),
+            vec![award]
+        );
+        assert_eq!(wallets.balance(1, COINS), 50);
+        assert!(rules.queued_awards(1).is_empty());
+    }
+}
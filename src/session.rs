@@ -0,0 +1,155 @@
+use crate::gift::{GiftId, GiftStatus};
+use crate::interest::Entity;
+use crate::message::{ClientMessage, MessageType, UserMessage};
+use crate::notification::Notification;
+use crate::npc::NpcId;
+use crate::rules::AwardId;
+use crate::village::InviteStatus;
+use crate::world::{LocationId, ObjectId, Position, WorldObject};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+pub type PlayerId = u64;
+
+/// Something the server wants to tell a client, either a sync update or a user server notify
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientEvent {
+    AddObject(WorldObject),
+    MoveObject {
+        object: ObjectId,
+        position: Position,
+    },
+    RemoveObject {
+        object: ObjectId,
+    },
+    ChangeObject(WorldObject),
+    ServerChangeObject(WorldObject),
+    ChangeObjectState {
+        object: ObjectId,
+        state: u8,
+    },
+    AddPlayer {
+        player: PlayerId,
+        position: Position,
+    },
+    MovePlayer {
+        player: PlayerId,
+        position: Position,
+    },
+    RemovePlayer {
+        player: PlayerId,
+    },
+    PosRecap {
+        entity: Entity,
+        position: Position,
+    },
+    UpdateNpcs {
+        npcs: Vec<NpcId>,
+    },
+    StopNpc {
+        npc: NpcId,
+    },
+    ChangeServer {
+        address: SocketAddr,
+        token: u64,
+    },
+    Chat {
+        player: PlayerId,
+        text: String,
+    },
+    /// A notice that isn't kept anywhere, from another player or from the game
+    Notification {
+        from: Option<PlayerId>,
+        text: String,
+    },
+    AddPlayerAwardNotify {
+        award: AwardId,
+    },
+    LevelStatusNotify {
+        level: u32,
+        experience: u64,
+    },
+    VillageInviteStatusNotify {
+        village: LocationId,
+        player: PlayerId,
+        status: InviteStatus,
+    },
+    GiftStatusNotify {
+        gift: GiftId,
+        status: GiftStatus,
+    },
+    PlayerNotificationNotify(Notification),
+}
+
+impl ClientEvent {
+    pub fn message(&self) -> MessageType {
+        match self {
+            ClientEvent::AddObject(_) => MessageType::Client(ClientMessage::AddObject),
+            ClientEvent::MoveObject { .. } => MessageType::Client(ClientMessage::MoveObject),
+            ClientEvent::RemoveObject { .. } => MessageType::Client(ClientMessage::RemoveObject),
+            ClientEvent::ChangeObject(_) => MessageType::Client(ClientMessage::ChangeObject),
+            ClientEvent::ServerChangeObject(_) => {
+                MessageType::Client(ClientMessage::ServerChangeObject)
+            }
+            ClientEvent::ChangeObjectState { .. } => {
+                MessageType::Client(ClientMessage::ChangeObjectState)
+            }
+            ClientEvent::AddPlayer { .. } => MessageType::Client(ClientMessage::AddPlayer),
+            ClientEvent::MovePlayer { .. } => MessageType::Client(ClientMessage::MovePlayer),
+            ClientEvent::RemovePlayer { .. } => MessageType::Client(ClientMessage::RemovePlayer),
+            ClientEvent::PosRecap { .. } => MessageType::Client(ClientMessage::PosRecap),
+            ClientEvent::UpdateNpcs { .. } => MessageType::Client(ClientMessage::UpdateNpcs),
+            ClientEvent::StopNpc { .. } => MessageType::Client(ClientMessage::StopNpc),
+            ClientEvent::ChangeServer { .. } => MessageType::Client(ClientMessage::ChangeServer),
+            ClientEvent::Chat { .. } => MessageType::Client(ClientMessage::Chat),
+            ClientEvent::Notification { .. } => MessageType::Client(ClientMessage::Notification),
+            ClientEvent::AddPlayerAwardNotify { .. } => {
+                MessageType::User(UserMessage::AddPlayerAwardNotify)
+            }
+            ClientEvent::LevelStatusNotify { .. } => {
+                MessageType::User(UserMessage::LevelStatusNotify)
+            }
+            ClientEvent::VillageInviteStatusNotify { .. } => {
+                MessageType::User(UserMessage::VillageInviteStatusNotify)
+            }
+            ClientEvent::GiftStatusNotify { .. } => {
+                MessageType::User(UserMessage::GiftStatusNotify)
+            }
+            ClientEvent::PlayerNotificationNotify(_) => {
+                MessageType::User(UserMessage::PlayerNotificationNotify)
+            }
+        }
+    }
+}
+
+/// Events waiting to be written out to each player's session
+#[derive(Debug, Default)]
+pub struct Outbox {
+    queued: HashMap<PlayerId, Vec<ClientEvent>>,
+}
+
+impl Outbox {
+    pub fn push(&mut self, player: PlayerId, event: ClientEvent) {
+        self.queued.entry(player).or_default().push(event);
+    }
+
+    pub fn drain(&mut self, player: PlayerId) -> Vec<ClientEvent> {
+        self.queued.remove(&player).unwrap_or_default()
+    }
+
+    /// Everything for everyone, leaving the outbox empty
+    pub fn drain_all(&mut self) -> HashMap<PlayerId, Vec<ClientEvent>> {
+        std::mem::take(&mut self.queued)
+    }
+
+    pub fn pending(&self, player: PlayerId) -> &[ClientEvent] {
+        self.queued
+            .get(&player)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queued.values().all(Vec::is_empty)
+    }
+}
@@ -0,0 +1,700 @@
+use crate::home::HOME_LOCATIONS;
+use crate::message::AppCode;
+use crate::session::PlayerId;
+use crate::village::Village;
+use crate::world::{LocationId, Position, WorldObject};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+pub type ServerId = u32;
+
+/// Servers hand out village and object ids from `id << 32`, which has to stay
+/// under `HOME_LOCATIONS`
+pub const MAX_SERVER_ID: ServerId = (HOME_LOCATIONS >> 32) as ServerId - 1;
+
+/// How long a handed off client has to turn up with its token
+pub const HANDOFF_TTL: Duration = Duration::from_secs(60);
+
+/// How to start one server process. Several of these can run side by side,
+/// each owning its own villages and knowing where a few of the others are
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub id: ServerId,
+    pub name: String,
+    pub address: IpAddr,
+    pub client_port: u16,
+    pub peer_port: u16,
+    pub villages: Vec<LocationId>,
+    pub peers: Vec<SocketAddr>,
+    pub max_villages: usize,
+    /// Every server in the shard needs the same one. Peers that don't know it get turned away
+    pub peer_secret: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            id: 1,
+            name: "Amazing World".to_string(),
+            address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            client_port: 8182,
+            peer_port: 8183,
+            villages: Vec::new(),
+            peers: Vec::new(),
+            max_villages: 64,
+            peer_secret: String::new(),
+        }
+    }
+}
+
+/// What `ListServers`, `FindServer` and `GetShards` hand out
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub id: ServerId,
+    pub name: String,
+    pub client_address: SocketAddr,
+    pub peer_address: SocketAddr,
+}
+
+/// Everything a server needs to pick up a player without them logging back in
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionHandoff {
+    pub player: PlayerId,
+    pub village: LocationId,
+    pub position: Position,
+    /// The EULA version the player accepted, the new server doesn't know about it yet
+    #[serde(default)]
+    pub eula: Option<u32>,
+}
+
+/// What lives in a village besides its players, so the new owner doesn't open an empty one.
+/// NPCs aren't in here, the new owner starts its own
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct VillageSnapshot {
+    pub objects: Vec<WorldObject>,
+    pub village: Option<Village>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PeerEnvelope {
+    secret: String,
+    request: PeerRequest,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum PeerRequest {
+    Hello(ServerInfo),
+    ListServers,
+    BindQuery {
+        village: LocationId,
+    },
+    BindVillageNotify {
+        village: LocationId,
+        server: ServerId,
+    },
+    VillageHandoffQuery {
+        village: LocationId,
+    },
+    VillageHandoff {
+        village: LocationId,
+        snapshot: VillageSnapshot,
+    },
+    UserSessionHandoff(SessionHandoff),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum PeerReply {
+    Ok,
+    Welcome {
+        servers: Vec<ServerInfo>,
+        bindings: Vec<(LocationId, ServerId)>,
+    },
+    Servers(Vec<ServerInfo>),
+    Bound(Option<ServerInfo>),
+    Accepted(bool),
+    Token(u64),
+    Rejected,
+}
+
+#[derive(Debug)]
+struct ShardState {
+    info: ServerInfo,
+    max_villages: usize,
+    servers: HashMap<ServerId, ServerInfo>,
+    bindings: HashMap<LocationId, ServerId>,
+    secret: String,
+    pending: HashMap<u64, (SessionHandoff, Instant)>,
+    arrived_villages: Vec<(LocationId, VillageSnapshot)>,
+}
+
+impl ShardState {
+    fn owned_villages(&self) -> usize {
+        self.bindings
+            .values()
+            .filter(|&&server| server == self.info.id)
+            .count()
+    }
+
+    fn peer_addresses(&self) -> Vec<SocketAddr> {
+        self.servers
+            .values()
+            .filter(|server| server.id != self.info.id)
+            .map(|server| server.peer_address)
+            .collect()
+    }
+
+    fn owner(&self, village: LocationId) -> Option<ServerInfo> {
+        self.bindings
+            .get(&village)
+            .and_then(|server| self.servers.get(server))
+            .cloned()
+    }
+
+    fn handle(&mut self, request: PeerRequest) -> PeerReply {
+        log::debug!("Peer request on server {}: {:?}", self.info.id, request);
+
+        match request {
+            PeerRequest::Hello(info) => {
+                self.servers.insert(info.id, info);
+                PeerReply::Welcome {
+                    servers: self.servers.values().cloned().collect(),
+                    bindings: self.bindings.iter().map(|(&v, &s)| (v, s)).collect(),
+                }
+            }
+            PeerRequest::ListServers => {
+                PeerReply::Servers(self.servers.values().cloned().collect())
+            }
+            PeerRequest::BindQuery { village } => PeerReply::Bound(self.owner(village)),
+            PeerRequest::BindVillageNotify { village, server } => {
+                self.bindings.insert(village, server);
+                PeerReply::Ok
+            }
+            PeerRequest::VillageHandoffQuery { .. } => {
+                PeerReply::Accepted(self.owned_villages() < self.max_villages)
+            }
+            PeerRequest::VillageHandoff { village, snapshot } => {
+                self.bindings.insert(village, self.info.id);
+                self.arrived_villages.push((village, snapshot));
+                PeerReply::Ok
+            }
+            PeerRequest::UserSessionHandoff(handoff) => {
+                if self.bindings.get(&handoff.village) != Some(&self.info.id) {
+                    return PeerReply::Rejected;
+                }
+
+                let now = Instant::now();
+                self.pending
+                    .retain(|_, (_, sent)| now.duration_since(*sent) < HANDOFF_TTL);
+
+                let token = new_token(handoff.player);
+                self.pending.insert(token, (handoff, now));
+                PeerReply::Token(token)
+            }
+        }
+    }
+}
+
+/// This server's view of the other servers and which villages each of them owns
+#[derive(Debug, Clone)]
+pub struct Shard {
+    state: Arc<Mutex<ShardState>>,
+}
+
+impl Shard {
+    pub async fn start(config: &ServerConfig, client_address: SocketAddr) -> std::io::Result<Self> {
+        if config.id > MAX_SERVER_ID {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("server id {} is over {}", config.id, MAX_SERVER_ID),
+            ));
+        }
+
+        let listener = TcpListener::bind((config.address, config.peer_port)).await?;
+
+        let info = ServerInfo {
+            id: config.id,
+            name: config.name.clone(),
+            client_address,
+            peer_address: listener.local_addr()?,
+        };
+
+        let shard = Self {
+            state: Arc::new(Mutex::new(ShardState {
+                info: info.clone(),
+                max_villages: config.max_villages,
+                servers: HashMap::from([(info.id, info.clone())]),
+                bindings: config
+                    .villages
+                    .iter()
+                    .map(|&village| (village, info.id))
+                    .collect(),
+                secret: config.peer_secret.clone(),
+                pending: HashMap::new(),
+                arrived_villages: Vec::new(),
+            })),
+        };
+
+        if config.peer_secret.is_empty() && !config.peers.is_empty() {
+            log::warn!("No peer secret set, anyone who can reach the peer port can join the shard");
+        }
+
+        tokio::spawn(shard.clone().serve(listener));
+
+        for &peer in config.peers.iter() {
+            match shard.ask(peer, PeerRequest::Hello(info.clone())).await {
+                Ok(PeerReply::Welcome { servers, bindings }) => {
+                    let mut state = shard.lock();
+                    for server in servers {
+                        state.servers.entry(server.id).or_insert(server);
+                    }
+                    for (village, server) in bindings {
+                        state.bindings.entry(village).or_insert(server);
+                    }
+                }
+                Ok(reply) => log::warn!("Peer {} answered hello with {:?}", peer, reply),
+                Err(e) => log::warn!("Could not reach peer {}: {}", peer, e),
+            }
+        }
+
+        for &village in config.villages.iter() {
+            shard.notify_peers(village, info.id).await;
+        }
+
+        Ok(shard)
+    }
+
+    pub fn info(&self) -> ServerInfo {
+        self.lock().info.clone()
+    }
+
+    /// `ListServers` and `GetShards`
+    pub fn servers(&self) -> Vec<ServerInfo> {
+        let mut servers: Vec<ServerInfo> = self.lock().servers.values().cloned().collect();
+        servers.sort_by_key(|server| server.id);
+        servers
+    }
+
+    pub fn owns(&self, village: LocationId) -> bool {
+        let state = self.lock();
+        state.bindings.get(&village) == Some(&state.info.id)
+    }
+
+    /// `FindServer`, asking around if nobody has told us yet
+    pub async fn find_server(&self, village: LocationId) -> Option<ServerInfo> {
+        let (known, peers) = {
+            let state = self.lock();
+            (state.owner(village), state.peer_addresses())
+        };
+
+        if known.is_some() {
+            return known;
+        }
+
+        for peer in peers {
+            if let Ok(PeerReply::Bound(Some(owner))) =
+                self.ask(peer, PeerRequest::BindQuery { village }).await
+            {
+                let mut state = self.lock();
+                state.bindings.insert(village, owner.id);
+                state.servers.entry(owner.id).or_insert(owner.clone());
+                return Some(owner);
+            }
+        }
+
+        None
+    }
+
+    /// `Bind`, take ownership of a village and tell everyone
+    pub async fn bind(&self, village: LocationId) {
+        let id = {
+            let mut state = self.lock();
+            let id = state.info.id;
+            state.bindings.insert(village, id);
+            state
+                .arrived_villages
+                .push((village, VillageSnapshot::default()));
+            id
+        };
+
+        self.notify_peers(village, id).await;
+    }
+
+    /// `UserSessionHandoff`, returns where the client has to go and the token to show when it gets there
+    pub async fn hand_off_session(
+        &self,
+        handoff: SessionHandoff,
+    ) -> Result<(ServerInfo, u64), AppCode> {
+        let owner = self
+            .find_server(handoff.village)
+            .await
+            .ok_or(AppCode::NotFound)?;
+
+        match self
+            .ask(owner.peer_address, PeerRequest::UserSessionHandoff(handoff))
+            .await
+        {
+            Ok(PeerReply::Token(token)) => Ok((owner, token)),
+            Ok(_) => Err(AppCode::State),
+            Err(e) => {
+                log::warn!("Session handoff to server {} failed: {}", owner.id, e);
+                Err(AppCode::NotReady)
+            }
+        }
+    }
+
+    /// The client showed up with its handoff token. Tokens work once and only for a little while
+    pub fn claim(&self, token: u64) -> Option<SessionHandoff> {
+        self.lock()
+            .pending
+            .remove(&token)
+            .filter(|(_, sent)| sent.elapsed() < HANDOFF_TTL)
+            .map(|(handoff, _)| handoff)
+    }
+
+    /// `VillageHandoffQuery` then `VillageHandoff`, giving one of our villages and
+    /// everything in it to another server
+    pub async fn hand_off_village(
+        &self,
+        village: LocationId,
+        to: ServerId,
+        snapshot: VillageSnapshot,
+    ) -> Result<(), AppCode> {
+        let target = {
+            let state = self.lock();
+            if state.bindings.get(&village) != Some(&state.info.id) {
+                return Err(AppCode::Perm);
+            }
+            state.servers.get(&to).cloned().ok_or(AppCode::NotFound)?
+        };
+
+        match self
+            .ask(
+                target.peer_address,
+                PeerRequest::VillageHandoffQuery { village },
+            )
+            .await
+        {
+            Ok(PeerReply::Accepted(true)) => {}
+            Ok(_) => return Err(AppCode::NoSpace),
+            Err(_) => return Err(AppCode::NotReady),
+        }
+
+        if self
+            .ask(
+                target.peer_address,
+                PeerRequest::VillageHandoff { village, snapshot },
+            )
+            .await
+            .is_err()
+        {
+            return Err(AppCode::NotReady);
+        }
+
+        self.lock().bindings.insert(village, to);
+        self.notify_peers(village, to).await;
+
+        Ok(())
+    }
+
+    /// Villages that were handed to us and still need a location in the world
+    pub fn take_arrived_villages(&self) -> Vec<(LocationId, VillageSnapshot)> {
+        std::mem::take(&mut self.lock().arrived_villages)
+    }
+
+    async fn notify_peers(&self, village: LocationId, server: ServerId) {
+        let peers = self.lock().peer_addresses();
+
+        for peer in peers {
+            if let Err(e) = self
+                .ask(peer, PeerRequest::BindVillageNotify { village, server })
+                .await
+            {
+                log::warn!("Could not tell {} about village {}: {}", peer, village, e);
+            }
+        }
+    }
+
+    async fn serve(self, listener: TcpListener) {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else {
+                continue;
+            };
+
+            let shard = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = shard.answer(stream).await {
+                    log::warn!("Peer connection failed: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn answer(&self, stream: TcpStream) -> std::io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        while let Some(line) = lines.next_line().await? {
+            let envelope: PeerEnvelope = serde_json::from_str(&line)?;
+
+            let reply = {
+                let mut state = self.lock();
+
+                if same_secret(&envelope.secret, &state.secret) {
+                    state.handle(envelope.request)
+                } else {
+                    log::warn!("Turned away a peer with the wrong secret");
+                    PeerReply::Rejected
+                }
+            };
+
+            let mut reply = serde_json::to_string(&reply)?;
+            reply.push('\n');
+            writer.write_all(reply.as_bytes()).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn ask(&self, peer: SocketAddr, request: PeerRequest) -> std::io::Result<PeerReply> {
+        let secret = self.lock().secret.clone();
+
+        send(peer, &PeerEnvelope { secret, request }).await
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ShardState> {
+        self.state.lock().unwrap()
+    }
+}
+
+async fn send(peer: SocketAddr, envelope: &PeerEnvelope) -> std::io::Result<PeerReply> {
+    let stream = TcpStream::connect(peer).await?;
+    let (reader, mut writer) = stream.into_split();
+
+    let mut line = serde_json::to_string(envelope)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await?;
+
+    let reply = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or(std::io::ErrorKind::UnexpectedEof)?;
+
+    Ok(serde_json::from_str(&reply)?)
+}
+
+/// Takes as long whichever byte is the first wrong one
+fn same_secret(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Something a client can't guess to steal someone else's session
+fn new_token(player: PlayerId) -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(player);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::AmazingWorldServer;
+    use crate::session::ClientEvent;
+
+    async fn local_server(
+        id: ServerId,
+        villages: Vec<LocationId>,
+        peers: Vec<SocketAddr>,
+    ) -> AmazingWorldServer {
+        server_with_secret(id, villages, peers, "shh").await
+    }
+
+    async fn server_with_secret(
+        id: ServerId,
+        villages: Vec<LocationId>,
+        peers: Vec<SocketAddr>,
+        secret: &str,
+    ) -> AmazingWorldServer {
+        AmazingWorldServer::start(ServerConfig {
+            id,
+            name: format!("Server {}", id),
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            client_port: 0,
+            peer_port: 0,
+            villages,
+            peers,
+            peer_secret: secret.to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn server_ids_stay_clear_of_home_locations() {
+        let config = ServerConfig {
+            id: MAX_SERVER_ID + 1,
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            peer_port: 0,
+            ..Default::default()
+        };
+        let client = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+
+        assert!(Shard::start(&config, client).await.is_err());
+        assert!(((MAX_SERVER_ID as LocationId + 1) << 32) <= HOME_LOCATIONS);
+    }
+
+    #[tokio::test]
+    async fn servers_find_each_other() {
+        let first = local_server(1, vec![100], vec![]).await;
+        let second = local_server(2, vec![200], vec![first.shard.info().peer_address]).await;
+
+        assert_eq!(first.shard.servers().len(), 2);
+        assert_eq!(second.shard.servers().len(), 2);
+        assert_eq!(first.shard.find_server(200).await.unwrap().id, 2);
+        assert_eq!(second.shard.find_server(100).await.unwrap().id, 1);
+        assert!(first.shard.find_server(300).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn player_moves_between_servers_without_logging_out() {
+        let mut first = local_server(1, vec![100], vec![]).await;
+        let mut second = local_server(2, vec![200], vec![first.shard.info().peer_address]).await;
+
+        let position = Position {
+            x: 4.0,
+            ..Default::default()
+        };
+
+        let eula = first.content.eula().unwrap().version;
+        first.content.accept_eula(7, eula).unwrap();
+        first
+            .world
+            .enter_loc(7, 100, Position::default(), &mut first.outbox)
+            .unwrap();
+        first.outbox.drain(7);
+
+        first.hand_off_player(7, 200, position).await.unwrap();
+        assert_eq!(first.world.player_location(7), None);
+
+        let token = match first.outbox.drain(7).as_slice() {
+            [ClientEvent::ChangeServer { address, token }] => {
+                assert_eq!(*address, second.client_address());
+                *token
+            }
+            events => panic!("expected a server change, got {:?}", events),
+        };
+
+        assert_eq!(second.claim_handoff(token + 1), Err(AppCode::InvalidToken));
+        assert_eq!(second.claim_handoff(token), Ok(7));
+        assert_eq!(second.content.check_eula(7), Ok(()));
+        assert_eq!(second.world.player_location(7), Some(200));
+        assert_eq!(second.world.location(200).unwrap().players[&7], position);
+
+        // The token only works once
+        assert_eq!(second.claim_handoff(token), Err(AppCode::InvalidToken));
+    }
+
+    #[tokio::test]
+    async fn village_changes_owner_with_its_players() {
+        let mut first = local_server(1, vec![100], vec![]).await;
+        let mut second = local_server(2, vec![], vec![first.shard.info().peer_address]).await;
+
+        let eula = first.content.eula().unwrap().version;
+        first.content.accept_eula(7, eula).unwrap();
+        first
+            .world
+            .enter_loc(7, 100, Position::default(), &mut first.outbox)
+            .unwrap();
+        first.outbox.drain(7);
+
+        first.hand_off_village(100, 2).await.unwrap();
+
+        assert!(first.world.location(100).is_none());
+        assert!(second.shard.owns(100));
+        assert_eq!(first.shard.find_server(100).await.unwrap().id, 2);
+
+        let token = first
+            .outbox
+            .drain(7)
+            .into_iter()
+            .find_map(|event| match event {
+                ClientEvent::ChangeServer { token, .. } => Some(token),
+                _ => None,
+            })
+            .unwrap();
+
+        assert_eq!(second.claim_handoff(token), Ok(7));
+        assert_eq!(second.world.player_location(7), Some(100));
+    }
+
+    #[tokio::test]
+    async fn peers_need_the_secret() {
+        let first = local_server(1, vec![100], vec![]).await;
+        let second =
+            server_with_secret(2, vec![200], vec![first.shard.info().peer_address], "guess").await;
+
+        assert_eq!(first.shard.servers().len(), 1);
+        assert_eq!(second.shard.servers().len(), 1);
+        assert!(second.shard.find_server(100).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn village_state_goes_along_with_the_village() {
+        let mut first = local_server(1, vec![100], vec![]).await;
+        let mut second = local_server(2, vec![], vec![first.shard.info().peer_address]).await;
+
+        let allocation = first
+            .villages
+            .allocate_player_village(7, &mut first.world)
+            .unwrap();
+        assert_eq!(allocation.village, 100);
+
+        let object = first
+            .world
+            .spawn_object(
+                100,
+                Some(7),
+                5,
+                Position::default(),
+                Default::default(),
+                &mut first.outbox,
+            )
+            .unwrap();
+
+        first.hand_off_village(100, 2).await.unwrap();
+        second.sync_shard();
+
+        assert!(first.villages.village(100).is_err());
+        assert_eq!(
+            first.villages.home_village(7),
+            Err(AppCode::PlayerHasNoHomeVillage)
+        );
+
+        let moved = &second.world.location(100).unwrap().objects[&object];
+        assert_eq!(moved.owner, Some(7));
+        assert_eq!(second.villages.home_village(7), Ok(100));
+        assert_eq!(
+            second
+                .villages
+                .village(100)
+                .unwrap()
+                .plots
+                .get(&allocation.plot),
+            Some(&7)
+        );
+    }
+}
@@ -0,0 +1,700 @@
+use crate::currency::Wallets;
+use crate::friends::Friends;
+use crate::inventory::{Inventory, Reward};
+use crate::message::AppCode;
+use crate::quest::{ObjectiveId, QuestEngine, QuestId, QuestStatus};
+use crate::session::PlayerId;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+pub type HostedQuestId = u64;
+pub type TransactionId = u64;
+
+/// How long a group has to accept its reward. After that whoever hasn't is dropped
+/// from the group and the rest can try again
+pub const SETTLE_HOURS: i64 = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostedQuestStatus {
+    Open,
+    /// Objectives are done and the reward is being handed out
+    Settling(TransactionId),
+    Finished,
+}
+
+/// A quest one player hosts and their friends play through together
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostedQuest {
+    pub id: HostedQuestId,
+    pub quest: QuestId,
+    pub host: PlayerId,
+    pub members: BTreeSet<PlayerId>,
+    pub invited: BTreeSet<PlayerId>,
+    /// Shared between everyone in the group
+    pub progress: BTreeMap<ObjectiveId, u32>,
+    pub status: HostedQuestStatus,
+}
+
+/// Two phases so nobody gets paid twice or not at all: every member accepts,
+/// then each accepted member is finalized exactly once, whenever they get to it
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuestTransaction {
+    pub id: TransactionId,
+    pub hosted: HostedQuestId,
+    pub reward: Reward,
+    pub members: BTreeSet<PlayerId>,
+    pub accepted: BTreeSet<PlayerId>,
+    pub granted: BTreeSet<PlayerId>,
+    pub opened_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default)]
+pub struct SharedQuests {
+    hosted: BTreeMap<HostedQuestId, HostedQuest>,
+    transactions: HashMap<TransactionId, QuestTransaction>,
+    next_id: u64,
+}
+
+impl SharedQuests {
+    pub fn hosted_quest(&self, hosted: HostedQuestId) -> Result<&HostedQuest, AppCode> {
+        self.hosted.get(&hosted).ok_or(AppCode::NotFound)
+    }
+
+    pub fn transaction(&self, transaction: TransactionId) -> Result<&QuestTransaction, AppCode> {
+        self.transactions.get(&transaction).ok_or(AppCode::NotFound)
+    }
+
+    /// `SendQuestInvite`, the host needs to be on the quest and the invitee needs to be a friend
+    pub fn send_quest_invite(
+        &mut self,
+        host: PlayerId,
+        quest: QuestId,
+        invitee: PlayerId,
+        friends: &Friends,
+        quests: &QuestEngine,
+    ) -> Result<HostedQuestId, AppCode> {
+        if !quests
+            .player_quest(host, quest)
+            .is_some_and(|state| state.is_active())
+        {
+            return Err(AppCode::State);
+        }
+
+        if !friends.are_friends(host, invitee) {
+            return Err(AppCode::InvalidRelationship);
+        }
+
+        Self::check_joiner(invitee, quest, quests)?;
+
+        let id = match self.hosted.values().find(|hosted| {
+            hosted.host == host && hosted.quest == quest && hosted.status == HostedQuestStatus::Open
+        }) {
+            Some(hosted) => hosted.id,
+            None => {
+                self.next_id += 1;
+                self.hosted.insert(
+                    self.next_id,
+                    HostedQuest {
+                        id: self.next_id,
+                        quest,
+                        host,
+                        members: BTreeSet::from([host]),
+                        invited: BTreeSet::new(),
+                        progress: BTreeMap::new(),
+                        status: HostedQuestStatus::Open,
+                    },
+                );
+                self.next_id
+            }
+        };
+
+        let hosted = self.hosted.get_mut(&id).unwrap();
+        if hosted.members.contains(&invitee) {
+            return Err(AppCode::DupRequest);
+        }
+
+        hosted.invited.insert(invitee);
+        Ok(id)
+    }
+
+    /// `RemoveQuestInvite`
+    pub fn remove_quest_invite(
+        &mut self,
+        host: PlayerId,
+        hosted: HostedQuestId,
+        invitee: PlayerId,
+    ) -> Result<(), AppCode> {
+        let hosted = self.hosted_mut(hosted)?;
+
+        if hosted.host != host {
+            return Err(AppCode::Perm);
+        }
+
+        if !hosted.invited.remove(&invitee) {
+            return Err(AppCode::NotFound);
+        }
+
+        Ok(())
+    }
+
+    /// `AcceptQuestInvite`
+    pub fn accept_quest_invite(
+        &mut self,
+        player: PlayerId,
+        hosted: HostedQuestId,
+        quests: &QuestEngine,
+    ) -> Result<(), AppCode> {
+        let hosted = self.open_mut(hosted)?;
+
+        if !hosted.invited.contains(&player) {
+            return Err(AppCode::NotFound);
+        }
+
+        Self::check_joiner(player, hosted.quest, quests)?;
+
+        hosted.invited.remove(&player);
+
+        hosted.members.insert(player);
+        Ok(())
+    }
+
+    /// `DeclineQuestInvite`
+    pub fn decline_quest_invite(
+        &mut self,
+        player: PlayerId,
+        hosted: HostedQuestId,
+    ) -> Result<(), AppCode> {
+        if !self.hosted_mut(hosted)?.invited.remove(&player) {
+            return Err(AppCode::NotFound);
+        }
+
+        Ok(())
+    }
+
+    /// `JoinQuest`, friends of the host can drop in without an invite
+    pub fn join_quest(
+        &mut self,
+        player: PlayerId,
+        hosted: HostedQuestId,
+        friends: &Friends,
+        quests: &QuestEngine,
+    ) -> Result<(), AppCode> {
+        let hosted = self.open_mut(hosted)?;
+
+        if !hosted.invited.contains(&player) && !friends.are_friends(hosted.host, player) {
+            return Err(AppCode::InvalidRelationship);
+        }
+
+        if hosted.members.contains(&player) {
+            return Err(AppCode::DupRequest);
+        }
+
+        Self::check_joiner(player, hosted.quest, quests)?;
+
+        hosted.invited.remove(&player);
+        hosted.members.insert(player);
+        Ok(())
+    }
+
+    /// `GetHostedQuests`
+    pub fn hosted_quests(&self, host: PlayerId) -> Vec<&HostedQuest> {
+        self.hosted
+            .values()
+            .filter(|hosted| hosted.host == host)
+            .collect()
+    }
+
+    /// `GetInvitedQuests`
+    pub fn invited_quests(&self, player: PlayerId) -> Vec<&HostedQuest> {
+        self.hosted
+            .values()
+            .filter(|hosted| hosted.invited.contains(&player))
+            .collect()
+    }
+
+    /// `GetInvitedPlayerQuest`
+    pub fn invited_player_quest(
+        &self,
+        player: PlayerId,
+        hosted: HostedQuestId,
+    ) -> Result<&HostedQuest, AppCode> {
+        self.hosted
+            .get(&hosted)
+            .filter(|hosted| hosted.invited.contains(&player))
+            .ok_or(AppCode::NotFound)
+    }
+
+    /// `GetPlayersInQuest`
+    pub fn players_in_quest(&self, hosted: HostedQuestId) -> Result<Vec<PlayerId>, AppCode> {
+        Ok(self.hosted_quest(hosted)?.members.iter().copied().collect())
+    }
+
+    /// Count something a member did for the whole group. Returns the members whose
+    /// clients need to hear about the new progress
+    pub fn record_event(
+        &mut self,
+        player: PlayerId,
+        kind: &str,
+        target: u64,
+        amount: u32,
+        quests: &QuestEngine,
+    ) -> Vec<PlayerId> {
+        let mut notify = BTreeSet::new();
+
+        for hosted in self.hosted.values_mut().filter(|hosted| {
+            hosted.status == HostedQuestStatus::Open && hosted.members.contains(&player)
+        }) {
+            let Ok(definition) = quests.quest(hosted.quest) else {
+                continue;
+            };
+
+            for objective in definition.objectives.iter() {
+                if objective.kind == kind && objective.target == target {
+                    let progress = hosted.progress.entry(objective.id).or_default();
+                    *progress = progress.saturating_add(amount).min(objective.count);
+                    notify.extend(hosted.members.iter().copied());
+                }
+            }
+        }
+
+        notify.into_iter().collect()
+    }
+
+    pub fn is_complete(&self, hosted: HostedQuestId, quests: &QuestEngine) -> bool {
+        let Some(hosted) = self.hosted.get(&hosted) else {
+            return false;
+        };
+
+        let Ok(definition) = quests.quest(hosted.quest) else {
+            return false;
+        };
+
+        definition.objectives.iter().all(|objective| {
+            hosted.progress.get(&objective.id).copied().unwrap_or(0) >= objective.count
+        })
+    }
+
+    /// `AcceptQuestTransaction`, the first member to accept opens the transaction.
+    /// Accepting again just hands back the same transaction
+    pub fn accept_quest_transaction(
+        &mut self,
+        player: PlayerId,
+        hosted: HostedQuestId,
+        quests: &QuestEngine,
+        now: DateTime<Utc>,
+    ) -> Result<TransactionId, AppCode> {
+        let complete = self.is_complete(hosted, quests);
+        let state = self.hosted.get(&hosted).ok_or(AppCode::NotFound)?;
+
+        if !state.members.contains(&player) {
+            return Err(AppCode::Perm);
+        }
+
+        let transaction = match state.status {
+            HostedQuestStatus::Settling(transaction) => transaction,
+            HostedQuestStatus::Finished => return Err(AppCode::State),
+            HostedQuestStatus::Open if !complete => return Err(AppCode::State),
+            HostedQuestStatus::Open => {
+                self.next_id += 1;
+                let transaction = QuestTransaction {
+                    id: self.next_id,
+                    hosted,
+                    reward: quests.quest(state.quest)?.reward.clone(),
+                    members: state.members.clone(),
+                    accepted: BTreeSet::new(),
+                    granted: BTreeSet::new(),
+                    opened_at: now,
+                };
+
+                self.transactions.insert(transaction.id, transaction);
+                self.hosted_mut(hosted)?.status = HostedQuestStatus::Settling(self.next_id);
+                self.next_id
+            }
+        };
+
+        self.transactions
+            .get_mut(&transaction)
+            .unwrap()
+            .accepted
+            .insert(player);
+
+        Ok(transaction)
+    }
+
+    /// `FinalizeQuestTransaction`, pays one member out once every member has accepted.
+    /// Returns false if they were already paid, here or by finishing the quest on their own
+    pub fn finalize_quest_transaction(
+        &mut self,
+        player: PlayerId,
+        transaction: TransactionId,
+        quests: &mut QuestEngine,
+        inventory: &mut Inventory,
+        wallets: &mut Wallets,
+    ) -> Result<bool, AppCode> {
+        let settling = self
+            .transactions
+            .get_mut(&transaction)
+            .ok_or(AppCode::NotFound)?;
+
+        if !settling.members.contains(&player) {
+            return Err(AppCode::Perm);
+        }
+
+        if settling.accepted != settling.members {
+            return Err(AppCode::State);
+        }
+
+        if !settling.granted.insert(player) {
+            return Ok(false);
+        }
+
+        let hosted = settling.hosted;
+        let everyone_paid = settling.granted == settling.members;
+        let state = self.hosted.get_mut(&hosted).ok_or(AppCode::NotFound)?;
+
+        if everyone_paid {
+            state.status = HostedQuestStatus::Finished;
+        }
+
+        if Self::already_completed(player, state.quest, quests) {
+            return Ok(false);
+        }
+
+        settling.reward.grant(player, inventory, wallets);
+        quests.mark_completed(player, state.quest);
+
+        Ok(true)
+    }
+
+    /// `CancelQuestTransaction`, the host giving up on members who won't accept.
+    /// Only before anyone has been paid
+    pub fn cancel_quest_transaction(
+        &mut self,
+        host: PlayerId,
+        transaction: TransactionId,
+    ) -> Result<(), AppCode> {
+        let settling = self.transaction(transaction)?;
+
+        if self.hosted_quest(settling.hosted)?.host != host {
+            return Err(AppCode::Perm);
+        }
+
+        if settling.accepted == settling.members {
+            return Err(AppCode::State);
+        }
+
+        self.reopen(transaction);
+        Ok(())
+    }
+
+    /// Reopens groups whose reward went unaccepted for `SETTLE_HOURS`, returns them
+    pub fn expire_transactions(&mut self, now: DateTime<Utc>) -> Vec<HostedQuestId> {
+        let expired: Vec<TransactionId> = self
+            .transactions
+            .values()
+            .filter(|settling| {
+                settling.accepted != settling.members
+                    && now - settling.opened_at >= Duration::hours(SETTLE_HOURS)
+            })
+            .map(|settling| settling.id)
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|transaction| self.reopen(transaction))
+            .collect()
+    }
+
+    /// Throws away a transaction nobody was paid from. Members who hadn't accepted
+    /// leave the group, the host always stays
+    fn reopen(&mut self, transaction: TransactionId) -> Option<HostedQuestId> {
+        let settling = self.transactions.remove(&transaction)?;
+        let hosted = self.hosted.get_mut(&settling.hosted)?;
+
+        hosted
+            .members
+            .retain(|member| *member == hosted.host || settling.accepted.contains(member));
+        hosted.status = HostedQuestStatus::Open;
+
+        Some(hosted.id)
+    }
+
+    fn already_completed(player: PlayerId, quest: QuestId, quests: &QuestEngine) -> bool {
+        quests
+            .player_quest(player, quest)
+            .is_some_and(|state| state.status == QuestStatus::Completed)
+    }
+
+    /// Anyone who already finished the quest, or is doing it on their own, can't
+    /// be in a group for it too
+    fn check_joiner(player: PlayerId, quest: QuestId, quests: &QuestEngine) -> Result<(), AppCode> {
+        match quests.player_quest(player, quest) {
+            Some(state) if state.is_active() || state.status == QuestStatus::Completed => {
+                Err(AppCode::State)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn hosted_mut(&mut self, hosted: HostedQuestId) -> Result<&mut HostedQuest, AppCode> {
+        self.hosted.get_mut(&hosted).ok_or(AppCode::NotFound)
+    }
+
+    fn open_mut(&mut self, hosted: HostedQuestId) -> Result<&mut HostedQuest, AppCode> {
+        let hosted = self.hosted_mut(hosted)?;
+
+        if hosted.status != HostedQuestStatus::Open {
+            return Err(AppCode::State);
+        }
+
+        Ok(hosted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::currency::{Amount, COINS};
+    use crate::quest::{Objective, QuestData, QuestDefinition};
+    use chrono::TimeZone;
+
+    const HOST: PlayerId = 1;
+    const FRIEND: PlayerId = 2;
+    const OTHER: PlayerId = 3;
+
+    fn quests() -> QuestEngine {
+        QuestEngine::from_data(QuestData {
+            quests: vec![QuestDefinition {
+                id: 1,
+                name: "Lanterns".to_string(),
+                parent: None,
+                npc: None,
+                objectives: vec![Objective {
+                    id: 1,
+                    kind: "light".to_string(),
+                    target: 9,
+                    count: 2,
+                }],
+                reward: Reward {
+                    items: Vec::new(),
+                    currencies: vec![Amount {
+                        currency: COINS,
+                        amount: 25,
+                    }],
+                },
+            }],
+        })
+    }
+
+    fn at(hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 1, 0, 0, 0).unwrap() + Duration::hours(hour as i64)
+    }
+
+    /// HOST and FRIEND have done the objectives together, nobody has accepted yet
+    fn finished_group(quests: &mut QuestEngine, shared: &mut SharedQuests) -> HostedQuestId {
+        quests.accept_quest(HOST, 1).unwrap();
+        let hosted = shared
+            .send_quest_invite(HOST, 1, FRIEND, &friends(), quests)
+            .unwrap();
+        shared.accept_quest_invite(FRIEND, hosted, quests).unwrap();
+        shared.record_event(FRIEND, "light", 9, 2, quests);
+
+        hosted
+    }
+
+    fn friends() -> Friends {
+        let mut friends = Friends::default();
+        friends.add(HOST, FRIEND);
+        friends.add(HOST, OTHER);
+        friends
+    }
+
+    #[test]
+    fn players_already_on_the_quest_cannot_join_a_group() {
+        let mut quests = quests();
+        let friends = friends();
+        let mut shared = SharedQuests::default();
+
+        quests.accept_quest(HOST, 1).unwrap();
+        quests.accept_quest(FRIEND, 1).unwrap();
+
+        assert_eq!(
+            shared.send_quest_invite(HOST, 1, FRIEND, &friends, &quests),
+            Err(AppCode::State)
+        );
+
+        let hosted = shared
+            .send_quest_invite(HOST, 1, OTHER, &friends, &quests)
+            .unwrap();
+        assert_eq!(
+            shared.join_quest(FRIEND, hosted, &friends, &quests),
+            Err(AppCode::State)
+        );
+
+        quests.accept_quest(OTHER, 1).unwrap();
+        assert_eq!(
+            shared.accept_quest_invite(OTHER, hosted, &quests),
+            Err(AppCode::State)
+        );
+        assert!(shared
+            .hosted_quest(hosted)
+            .unwrap()
+            .invited
+            .contains(&OTHER));
+    }
+
+    #[test]
+    fn nobody_is_paid_until_every_member_accepts() {
+        let mut quests = quests();
+        let friends = friends();
+        let mut inventory = Inventory::default();
+        let mut wallets = Wallets::default();
+        let mut shared = SharedQuests::default();
+
+        quests.accept_quest(HOST, 1).unwrap();
+        let hosted = shared
+            .send_quest_invite(HOST, 1, FRIEND, &friends, &quests)
+            .unwrap();
+        shared.accept_quest_invite(FRIEND, hosted, &quests).unwrap();
+        shared.record_event(FRIEND, "light", 9, 2, &quests);
+
+        let transaction = shared
+            .accept_quest_transaction(HOST, hosted, &quests, at(0))
+            .unwrap();
+        assert_eq!(
+            shared.finalize_quest_transaction(
+                HOST,
+                transaction,
+                &mut quests,
+                &mut inventory,
+                &mut wallets
+            ),
+            Err(AppCode::State)
+        );
+        assert_eq!(
+            shared.finalize_quest_transaction(
+                OTHER,
+                transaction,
+                &mut quests,
+                &mut inventory,
+                &mut wallets
+            ),
+            Err(AppCode::Perm)
+        );
+
+        shared
+            .accept_quest_transaction(FRIEND, hosted, &quests, at(0))
+            .unwrap();
+        for player in [HOST, FRIEND, HOST] {
+            shared
+                .finalize_quest_transaction(
+                    player,
+                    transaction,
+                    &mut quests,
+                    &mut inventory,
+                    &mut wallets,
+                )
+                .unwrap();
+        }
+
+        assert_eq!(wallets.balance(HOST, COINS), 25);
+        assert_eq!(wallets.balance(FRIEND, COINS), 25);
+        assert_eq!(
+            shared.hosted_quest(hosted).unwrap().status,
+            HostedQuestStatus::Finished
+        );
+        assert_eq!(
+            quests.player_quest(HOST, 1).unwrap().status,
+            QuestStatus::Completed
+        );
+    }
+
+    #[test]
+    fn nobody_is_paid_for_the_same_quest_twice() {
+        let mut quests = quests();
+        let mut friends = friends();
+        let mut inventory = Inventory::default();
+        let mut wallets = Wallets::default();
+        let mut shared = SharedQuests::default();
+        let hosted = finished_group(&mut quests, &mut shared);
+
+        let transaction = shared
+            .accept_quest_transaction(HOST, hosted, &quests, at(0))
+            .unwrap();
+        shared
+            .accept_quest_transaction(FRIEND, hosted, &quests, at(0))
+            .unwrap();
+
+        // The host finishes on their own in the meantime and is paid for that instead
+        quests.record_event(HOST, "light", 9, 2);
+        quests
+            .complete_quest(HOST, 1, &mut inventory, &mut wallets)
+            .unwrap();
+
+        for player in [HOST, FRIEND] {
+            shared
+                .finalize_quest_transaction(
+                    player,
+                    transaction,
+                    &mut quests,
+                    &mut inventory,
+                    &mut wallets,
+                )
+                .unwrap();
+        }
+        assert_eq!(wallets.balance(HOST, COINS), 25);
+        assert_eq!(wallets.balance(FRIEND, COINS), 25);
+        assert_eq!(
+            shared.hosted_quest(hosted).unwrap().status,
+            HostedQuestStatus::Finished
+        );
+
+        // A paid member can't sign up with somebody else's group for it
+        friends.add(OTHER, FRIEND);
+        quests.accept_quest(OTHER, 1).unwrap();
+        assert_eq!(
+            quests.player_quest(FRIEND, 1).unwrap().status,
+            QuestStatus::Completed
+        );
+        assert_eq!(
+            shared.send_quest_invite(OTHER, 1, FRIEND, &friends, &quests),
+            Err(AppCode::State)
+        );
+    }
+
+    #[test]
+    fn members_who_never_accept_are_dropped_in_the_end() {
+        let mut quests = quests();
+        let mut shared = SharedQuests::default();
+        let hosted = finished_group(&mut quests, &mut shared);
+
+        let transaction = shared
+            .accept_quest_transaction(HOST, hosted, &quests, at(0))
+            .unwrap();
+        assert_eq!(
+            shared.cancel_quest_transaction(FRIEND, transaction),
+            Err(AppCode::Perm)
+        );
+        assert!(shared
+            .expire_transactions(at(SETTLE_HOURS as u32 - 1))
+            .is_empty());
+        assert_eq!(
+            shared.expire_transactions(at(SETTLE_HOURS as u32)),
+            [hosted]
+        );
+
+        let group = shared.hosted_quest(hosted).unwrap();
+        assert_eq!(group.status, HostedQuestStatus::Open);
+        assert_eq!(group.members, BTreeSet::from([HOST]));
+        assert_eq!(shared.transaction(transaction), Err(AppCode::NotFound));
+
+        // Everyone left can settle up
+        let transaction = shared
+            .accept_quest_transaction(HOST, hosted, &quests, at(30))
+            .unwrap();
+        assert_eq!(
+            shared.cancel_quest_transaction(HOST, transaction),
+            Err(AppCode::State)
+        );
+    }
+}
@@ -0,0 +1,288 @@
+use crate::message::AppCode;
+use crate::session::PlayerId;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Most bookmarks a player can keep
+pub const MAX_BOOKMARKS: usize = 200;
+
+/// Most things one player can like at once, so nobody can push up rankings alone
+pub const MAX_LIKES: usize = 500;
+
+/// The kinds of thing players can like or bookmark
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum ObjectType {
+    Home,
+    Maze,
+    Store,
+    Village,
+}
+
+/// One particular home, maze, store or village
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct ObjectRef {
+    pub kind: ObjectType,
+    pub id: u64,
+}
+
+/// `ListTopLikes`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TopLike {
+    pub object: ObjectRef,
+    pub likes: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bookmark {
+    pub object: ObjectRef,
+    pub added_at: DateTime<Utc>,
+}
+
+/// Likes and bookmarks. Like counts are kept ranked as they change, so top
+/// lists only ever walk as far as they need to
+#[derive(Debug, Default)]
+pub struct SocialSignals {
+    likes: HashMap<PlayerId, BTreeSet<ObjectRef>>,
+    counts: HashMap<ObjectRef, u32>,
+    ranking: BTreeMap<ObjectType, BTreeSet<(Reverse<u32>, ObjectRef)>>,
+    bookmarks: HashMap<PlayerId, BTreeMap<ObjectRef, DateTime<Utc>>>,
+}
+
+impl SocialSignals {
+    /// `AddPlayerLike`, returns how many likes it has now
+    pub fn add_player_like(&mut self, player: PlayerId, object: ObjectRef) -> Result<u32, AppCode> {
+        let likes = self.likes.entry(player).or_default();
+
+        if likes.contains(&object) {
+            return Err(AppCode::DupRequest);
+        }
+
+        if likes.len() >= MAX_LIKES {
+            return Err(AppCode::NoSpace);
+        }
+
+        likes.insert(object);
+
+        Ok(self.change_count(object, 1))
+    }
+
+    /// `RemovePlayerLike`
+    pub fn remove_player_like(
+        &mut self,
+        player: PlayerId,
+        object: ObjectRef,
+    ) -> Result<u32, AppCode> {
+        if !self
+            .likes
+            .get_mut(&player)
+            .is_some_and(|likes| likes.remove(&object))
+        {
+            return Err(AppCode::NotFound);
+        }
+
+        Ok(self.change_count(object, -1))
+    }
+
+    /// `ListPlayerLikes`, everything the player likes, or just one kind of thing
+    pub fn player_likes(&self, player: PlayerId, kind: Option<ObjectType>) -> Vec<ObjectRef> {
+        self.likes
+            .get(&player)
+            .into_iter()
+            .flatten()
+            .filter(|object| kind.is_none_or(|kind| object.kind == kind))
+            .copied()
+            .collect()
+    }
+
+    pub fn likes(&self, object: ObjectRef) -> u32 {
+        self.counts.get(&object).copied().unwrap_or_default()
+    }
+
+    /// `ListTopLikes`, most liked first
+    pub fn top_likes(&self, kind: ObjectType, limit: usize) -> Vec<TopLike> {
+        self.ranking
+            .get(&kind)
+            .into_iter()
+            .flatten()
+            .take(limit)
+            .map(|&(Reverse(likes), object)| TopLike { object, likes })
+            .collect()
+    }
+
+    /// `AddBookmark`
+    pub fn add_bookmark(
+        &mut self,
+        player: PlayerId,
+        object: ObjectRef,
+        now: DateTime<Utc>,
+    ) -> Result<(), AppCode> {
+        let bookmarks = self.bookmarks.entry(player).or_default();
+
+        if bookmarks.contains_key(&object) {
+            return Err(AppCode::DupRequest);
+        }
+
+        if bookmarks.len() >= MAX_BOOKMARKS {
+            return Err(AppCode::NoSpace);
+        }
+
+        bookmarks.insert(object, now);
+        Ok(())
+    }
+
+    /// `RemoveBookmark`
+    pub fn remove_bookmark(&mut self, player: PlayerId, object: ObjectRef) -> Result<(), AppCode> {
+        self.bookmarks
+            .get_mut(&player)
+            .and_then(|bookmarks| bookmarks.remove(&object))
+            .map(|_| ())
+            .ok_or(AppCode::NotFound)
+    }
+
+    /// `ListBookmarks`, newest first
+    pub fn bookmarks(&self, player: PlayerId, kind: Option<ObjectType>) -> Vec<Bookmark> {
+        let mut bookmarks: Vec<Bookmark> = self
+            .bookmarks
+            .get(&player)
+            .into_iter()
+            .flatten()
+            .filter(|(object, _)| kind.is_none_or(|kind| object.kind == kind))
+            .map(|(&object, &added_at)| Bookmark { object, added_at })
+            .collect();
+
+        bookmarks.sort_by_key(|bookmark| Reverse(bookmark.added_at));
+        bookmarks
+    }
+
+    /// `ListBookmarkObjectTypes`, the kinds of thing the player has bookmarked
+    pub fn bookmark_object_types(&self, player: PlayerId) -> BTreeSet<ObjectType> {
+        self.bookmarks
+            .get(&player)
+            .into_iter()
+            .flat_map(|bookmarks| bookmarks.keys())
+            .map(|object| object.kind)
+            .collect()
+    }
+
+    fn change_count(&mut self, object: ObjectRef, by: i32) -> u32 {
+        let count = self.counts.entry(object).or_default();
+        let ranking = self.ranking.entry(object.kind).or_default();
+
+        ranking.remove(&(Reverse(*count), object));
+        *count = count.saturating_add_signed(by);
+        let count = *count;
+
+        if count == 0 {
+            self.counts.remove(&object);
+        } else {
+            ranking.insert((Reverse(count), object));
+        }
+
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(day: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, day, 12, 0, 0).unwrap()
+    }
+
+    fn home(id: u64) -> ObjectRef {
+        ObjectRef {
+            kind: ObjectType::Home,
+            id,
+        }
+    }
+
+    #[test]
+    fn top_likes_follow_every_change() {
+        let mut social = SocialSignals::default();
+        let maze = ObjectRef {
+            kind: ObjectType::Maze,
+            id: 1,
+        };
+
+        assert_eq!(social.add_player_like(1, home(1)), Ok(1));
+        assert_eq!(social.add_player_like(1, home(1)), Err(AppCode::DupRequest));
+        assert_eq!(social.add_player_like(1, home(2)), Ok(1));
+        assert_eq!(social.add_player_like(2, home(2)), Ok(2));
+        assert_eq!(social.add_player_like(2, maze), Ok(1));
+
+        let top = social.top_likes(ObjectType::Home, 10);
+        assert_eq!(
+            top.iter()
+                .map(|top| (top.object, top.likes))
+                .collect::<Vec<_>>(),
+            [(home(2), 2), (home(1), 1)]
+        );
+
+        assert_eq!(
+            social.remove_player_like(2, home(1)),
+            Err(AppCode::NotFound)
+        );
+        assert_eq!(social.remove_player_like(1, home(1)), Ok(0));
+        assert_eq!(social.remove_player_like(1, home(2)), Ok(1));
+        assert_eq!(social.top_likes(ObjectType::Home, 10).len(), 1);
+        assert_eq!(social.likes(home(1)), 0);
+        assert_eq!(social.player_likes(2, Some(ObjectType::Maze)), [maze]);
+    }
+
+    #[test]
+    fn likes_are_capped_per_player() {
+        let mut social = SocialSignals::default();
+
+        for id in 0..MAX_LIKES as u64 {
+            social.add_player_like(1, home(id)).unwrap();
+        }
+        assert_eq!(
+            social.add_player_like(1, home(MAX_LIKES as u64)),
+            Err(AppCode::NoSpace)
+        );
+        assert_eq!(social.likes(home(MAX_LIKES as u64)), 0);
+        assert_eq!(social.add_player_like(2, home(MAX_LIKES as u64)), Ok(1));
+
+        social.remove_player_like(1, home(0)).unwrap();
+        assert_eq!(social.add_player_like(1, home(MAX_LIKES as u64)), Ok(2));
+    }
+
+    #[test]
+    fn bookmarks_are_capped_and_newest_first() {
+        let mut social = SocialSignals::default();
+
+        social.add_bookmark(1, home(1), at(1)).unwrap();
+        social.add_bookmark(1, home(2), at(2)).unwrap();
+        assert_eq!(
+            social.add_bookmark(1, home(1), at(3)),
+            Err(AppCode::DupRequest)
+        );
+        assert_eq!(
+            social
+                .bookmarks(1, None)
+                .iter()
+                .map(|bookmark| bookmark.object)
+                .collect::<Vec<_>>(),
+            [home(2), home(1)]
+        );
+
+        for id in 3..=MAX_BOOKMARKS as u64 {
+            social.add_bookmark(1, home(id), at(3)).unwrap();
+        }
+        assert_eq!(
+            social.add_bookmark(1, home(0), at(3)),
+            Err(AppCode::NoSpace)
+        );
+
+        social.remove_bookmark(1, home(1)).unwrap();
+        assert_eq!(social.remove_bookmark(1, home(1)), Err(AppCode::NotFound));
+        assert_eq!(
+            social.bookmark_object_types(1),
+            BTreeSet::from([ObjectType::Home])
+        );
+    }
+}
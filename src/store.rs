@@ -0,0 +1,467 @@
+use crate::currency::{Amount, CurrencyId, Wallets};
+use crate::inventory::{Inventory, InventoryItem, InventoryItemId, ItemCatalog};
+use crate::message::AppCode;
+use crate::session::PlayerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+pub type ListingId = u64;
+
+/// Most anything can be listed for, in any currency
+pub const MAX_PRICE: u64 = 1_000_000_000;
+
+/// An item up for sale. The store holds on to it until it's sold or taken back
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listing {
+    pub id: ListingId,
+    pub seller: PlayerId,
+    pub item: InventoryItem,
+    pub price: Amount,
+    /// The item's kind in the catalog, what searches filter on
+    pub category: Option<String>,
+}
+
+/// `SearchUserStoreItems`, anything left out matches everything
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StoreSearch {
+    pub category: Option<String>,
+    pub currency: Option<CurrencyId>,
+    pub min_price: Option<u64>,
+    pub max_price: Option<u64>,
+}
+
+impl StoreSearch {
+    fn matches(&self, listing: &Listing) -> bool {
+        (self.category.is_none() || self.category == listing.category)
+            && self
+                .currency
+                .is_none_or(|currency| currency == listing.price.currency)
+            && self.min_price.is_none_or(|min| listing.price.amount >= min)
+            && self.max_price.is_none_or(|max| listing.price.amount <= max)
+    }
+}
+
+/// `UserStoreInfo`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoreInfo {
+    pub owner: PlayerId,
+    pub listings: usize,
+    pub decorations: usize,
+    pub visitors: usize,
+    /// Sales waiting for `RedeemUserStoreSales`
+    pub proceeds: BTreeMap<CurrencyId, u64>,
+}
+
+#[derive(Debug, Default)]
+struct UserStore {
+    listings: BTreeSet<ListingId>,
+    decorations: BTreeMap<InventoryItemId, InventoryItem>,
+    visitors: BTreeSet<PlayerId>,
+    proceeds: BTreeMap<CurrencyId, u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct UserStores {
+    stores: HashMap<PlayerId, UserStore>,
+    listings: BTreeMap<ListingId, Listing>,
+    /// Which store each browsing player is in
+    browsing: HashMap<PlayerId, PlayerId>,
+    next_id: ListingId,
+}
+
+impl UserStores {
+    pub fn listing(&self, listing: ListingId) -> Result<&Listing, AppCode> {
+        self.listings.get(&listing).ok_or(AppCode::NotFound)
+    }
+
+    /// `GetAllUserStoreItems`
+    pub fn all_user_store_items(&self, owner: PlayerId) -> Vec<&Listing> {
+        self.stores
+            .get(&owner)
+            .map(|store| {
+                store
+                    .listings
+                    .iter()
+                    .map(|listing| &self.listings[listing])
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// `SearchUserStoreItems`, every store at once, cheapest first
+    pub fn search_user_store_items(&self, search: &StoreSearch) -> Vec<&Listing> {
+        let mut found: Vec<&Listing> = self
+            .listings
+            .values()
+            .filter(|listing| search.matches(listing))
+            .collect();
+
+        found.sort_by_key(|listing| (listing.price.currency, listing.price.amount, listing.id));
+        found
+    }
+
+    /// `AddUserStoreItem`
+    pub fn add_user_store_item(
+        &mut self,
+        seller: PlayerId,
+        item: InventoryItemId,
+        price: Amount,
+        inventory: &mut Inventory,
+        catalog: &ItemCatalog,
+    ) -> Result<ListingId, AppCode> {
+        Self::check_price(price)?;
+
+        let escrowed = inventory.remove(seller, item)?;
+        let category = catalog
+            .item(escrowed.item)
+            .ok()
+            .and_then(|definition| definition.kind.clone());
+
+        self.next_id += 1;
+        self.listings.insert(
+            self.next_id,
+            Listing {
+                id: self.next_id,
+                seller,
+                item: escrowed,
+                price,
+                category,
+            },
+        );
+        self.stores
+            .entry(seller)
+            .or_default()
+            .listings
+            .insert(self.next_id);
+
+        Ok(self.next_id)
+    }
+
+    /// `UpdateUserStoreItem`, changes the price
+    pub fn update_user_store_item(
+        &mut self,
+        seller: PlayerId,
+        listing: ListingId,
+        price: Amount,
+    ) -> Result<(), AppCode> {
+        Self::check_price(price)?;
+
+        self.listing_mut(seller, listing)?.price = price;
+        Ok(())
+    }
+
+    /// `RemoveUserStoreItem`, the item goes back to the seller
+    pub fn remove_user_store_item(
+        &mut self,
+        seller: PlayerId,
+        listing: ListingId,
+        inventory: &mut Inventory,
+    ) -> Result<(), AppCode> {
+        self.listing_mut(seller, listing)?;
+
+        let removed = self.unlist(listing);
+        inventory.restore(removed.item, seller);
+
+        Ok(())
+    }
+
+    /// `PurchaseUserStoreItems`, all of them or none. The money waits in each seller's
+    /// store until they redeem it. Totals that don't fit in a u64 turn the whole basket away
+    pub fn purchase_user_store_items(
+        &mut self,
+        buyer: PlayerId,
+        listings: &[ListingId],
+        inventory: &mut Inventory,
+        wallets: &mut Wallets,
+    ) -> Result<Vec<InventoryItemId>, AppCode> {
+        let mut total: BTreeMap<CurrencyId, u64> = BTreeMap::new();
+        let mut proceeds: BTreeMap<(PlayerId, CurrencyId), u64> = BTreeMap::new();
+
+        for (i, id) in listings.iter().enumerate() {
+            let listing = self.listing(*id)?;
+
+            if listing.seller == buyer || listings[..i].contains(id) {
+                return Err(AppCode::Input);
+            }
+
+            let price = listing.price;
+            let owed = proceeds
+                .entry((listing.seller, price.currency))
+                .or_insert_with(|| {
+                    self.stores
+                        .get(&listing.seller)
+                        .and_then(|store| store.proceeds.get(&price.currency))
+                        .copied()
+                        .unwrap_or_default()
+                });
+            *owed = owed.checked_add(price.amount).ok_or(AppCode::Input)?;
+
+            let spent = total.entry(price.currency).or_default();
+            *spent = spent.checked_add(price.amount).ok_or(AppCode::Input)?;
+        }
+
+        let mut paid = Vec::new();
+        for (&currency, &amount) in total.iter() {
+            if let Err(e) = wallets.debit(buyer, currency, amount) {
+                for (currency, amount) in paid {
+                    wallets.credit(buyer, currency, amount);
+                }
+                return Err(e);
+            }
+
+            paid.push((currency, amount));
+        }
+
+        for ((seller, currency), owed) in proceeds {
+            self.stores
+                .entry(seller)
+                .or_default()
+                .proceeds
+                .insert(currency, owed);
+        }
+
+        Ok(listings
+            .iter()
+            .map(|&listing| {
+                let sold = self.unlist(listing);
+                let id = sold.item.id;
+                inventory.restore(sold.item, buyer);
+                id
+            })
+            .collect())
+    }
+
+    /// `RedeemUserStoreSales`, moves everything sold so far into the owner's wallet
+    pub fn redeem_user_store_sales(
+        &mut self,
+        owner: PlayerId,
+        wallets: &mut Wallets,
+    ) -> BTreeMap<CurrencyId, u64> {
+        let proceeds = self
+            .stores
+            .get_mut(&owner)
+            .map(|store| std::mem::take(&mut store.proceeds))
+            .unwrap_or_default();
+
+        for (&currency, &amount) in proceeds.iter() {
+            wallets.credit(owner, currency, amount);
+        }
+
+        proceeds
+    }
+
+    /// `DecorateUserStore`, puts an item on show. It isn't for sale
+    pub fn decorate_user_store(
+        &mut self,
+        owner: PlayerId,
+        item: InventoryItemId,
+        inventory: &mut Inventory,
+    ) -> Result<(), AppCode> {
+        let decoration = inventory.remove(owner, item)?;
+
+        self.stores
+            .entry(owner)
+            .or_default()
+            .decorations
+            .insert(item, decoration);
+
+        Ok(())
+    }
+
+    /// `UndecorateUserStore`
+    pub fn undecorate_user_store(
+        &mut self,
+        owner: PlayerId,
+        item: InventoryItemId,
+        inventory: &mut Inventory,
+    ) -> Result<(), AppCode> {
+        let decoration = self
+            .stores
+            .get_mut(&owner)
+            .and_then(|store| store.decorations.remove(&item))
+            .ok_or(AppCode::NotFound)?;
+
+        inventory.restore(decoration, owner);
+        Ok(())
+    }
+
+    /// `DecoratedUserStoreItems`
+    pub fn decorated_user_store_items(&self, owner: PlayerId) -> Vec<&InventoryItem> {
+        self.stores
+            .get(&owner)
+            .map(|store| store.decorations.values().collect())
+            .unwrap_or_default()
+    }
+
+    /// `UserStoreInfo`
+    pub fn user_store_info(&self, owner: PlayerId) -> StoreInfo {
+        let store = self.stores.get(&owner);
+
+        StoreInfo {
+            owner,
+            listings: store.map_or(0, |store| store.listings.len()),
+            decorations: store.map_or(0, |store| store.decorations.len()),
+            visitors: store.map_or(0, |store| store.visitors.len()),
+            proceeds: store
+                .map(|store| store.proceeds.clone())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// `EnterStore`, leaving whatever store they were in before
+    pub fn enter_store(&mut self, player: PlayerId, owner: PlayerId) -> StoreInfo {
+        self.exit_store(player);
+
+        self.stores
+            .entry(owner)
+            .or_default()
+            .visitors
+            .insert(player);
+        self.browsing.insert(player, owner);
+
+        self.user_store_info(owner)
+    }
+
+    /// `ExitStore`
+    pub fn exit_store(&mut self, player: PlayerId) {
+        if let Some(owner) = self.browsing.remove(&player) {
+            if let Some(store) = self.stores.get_mut(&owner) {
+                store.visitors.remove(&player);
+            }
+        }
+    }
+
+    fn check_price(price: Amount) -> Result<(), AppCode> {
+        if price.amount == 0 || price.amount > MAX_PRICE {
+            return Err(AppCode::Input);
+        }
+
+        Ok(())
+    }
+
+    fn listing_mut(
+        &mut self,
+        seller: PlayerId,
+        listing: ListingId,
+    ) -> Result<&mut Listing, AppCode> {
+        let listing = self.listings.get_mut(&listing).ok_or(AppCode::NotFound)?;
+
+        if listing.seller != seller {
+            return Err(AppCode::ItemNotOwnedBySessionPlayer);
+        }
+
+        Ok(listing)
+    }
+
+    fn unlist(&mut self, listing: ListingId) -> Listing {
+        let removed = self.listings.remove(&listing).unwrap();
+
+        if let Some(store) = self.stores.get_mut(&removed.seller) {
+            store.listings.remove(&listing);
+        }
+
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::currency::COINS;
+
+    const SELLER: PlayerId = 1;
+    const BUYER: PlayerId = 2;
+
+    fn coins(amount: u64) -> Amount {
+        Amount {
+            currency: COINS,
+            amount,
+        }
+    }
+
+    fn list(
+        stores: &mut UserStores,
+        inventory: &mut Inventory,
+        price: u64,
+    ) -> Result<ListingId, AppCode> {
+        let item = inventory.grant(SELLER, 1, 1)[0];
+
+        stores.add_user_store_item(
+            SELLER,
+            item,
+            coins(price),
+            inventory,
+            &ItemCatalog::default(),
+        )
+    }
+
+    #[test]
+    fn prices_are_capped() {
+        let mut stores = UserStores::default();
+        let mut inventory = Inventory::default();
+
+        assert_eq!(list(&mut stores, &mut inventory, 0), Err(AppCode::Input));
+        assert_eq!(
+            list(&mut stores, &mut inventory, MAX_PRICE + 1),
+            Err(AppCode::Input)
+        );
+
+        let listing = list(&mut stores, &mut inventory, MAX_PRICE).unwrap();
+        assert_eq!(
+            stores.update_user_store_item(SELLER, listing, coins(u64::MAX)),
+            Err(AppCode::Input)
+        );
+    }
+
+    #[test]
+    fn baskets_that_overflow_are_turned_away() {
+        let mut stores = UserStores::default();
+        let mut inventory = Inventory::default();
+        let mut wallets = Wallets::default();
+        wallets.credit(BUYER, COINS, u64::MAX);
+
+        let first = list(&mut stores, &mut inventory, 10).unwrap();
+        let second = list(&mut stores, &mut inventory, 10).unwrap();
+        // Listed before prices were capped
+        stores.listings.get_mut(&second).unwrap().price = coins(u64::MAX);
+
+        assert_eq!(
+            stores.purchase_user_store_items(BUYER, &[first, second], &mut inventory, &mut wallets),
+            Err(AppCode::Input)
+        );
+        assert_eq!(wallets.balance(BUYER, COINS), u64::MAX);
+        assert_eq!(stores.all_user_store_items(SELLER).len(), 2);
+    }
+
+    #[test]
+    fn sales_wait_for_the_seller_to_redeem_them() {
+        let mut stores = UserStores::default();
+        let mut inventory = Inventory::default();
+        let mut wallets = Wallets::default();
+        wallets.credit(BUYER, COINS, 25);
+
+        let cheap = list(&mut stores, &mut inventory, 10).unwrap();
+        let dear = list(&mut stores, &mut inventory, 20).unwrap();
+
+        assert_eq!(
+            stores.purchase_user_store_items(BUYER, &[cheap, dear], &mut inventory, &mut wallets),
+            Err(AppCode::InsufficientFunds)
+        );
+        assert_eq!(
+            stores.purchase_user_store_items(BUYER, &[cheap, cheap], &mut inventory, &mut wallets),
+            Err(AppCode::Input)
+        );
+
+        let bought = stores
+            .purchase_user_store_items(BUYER, &[cheap], &mut inventory, &mut wallets)
+            .unwrap();
+        assert!(inventory.owns(BUYER, bought[0]));
+        assert_eq!(wallets.balance(BUYER, COINS), 15);
+        assert_eq!(stores.user_store_info(SELLER).proceeds[&COINS], 10);
+
+        stores.redeem_user_store_sales(SELLER, &mut wallets);
+        assert_eq!(wallets.balance(SELLER, COINS), 10);
+        assert!(stores.user_store_info(SELLER).proceeds.is_empty());
+    }
+}
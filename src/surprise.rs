@@ -0,0 +1,352 @@
+use crate::currency::Wallets;
+use crate::data;
+use crate::inventory::{Inventory, ItemId, Reward};
+use crate::message::AppCode;
+use crate::random::SeededRng;
+use crate::session::PlayerId;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+pub type SurpriseId = u32;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SurpriseEntry {
+    pub weight: u32,
+    #[serde(default)]
+    pub reward: Reward,
+}
+
+/// A weighted table of rewards a player can dip into a few times each window
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Surprise {
+    pub id: SurpriseId,
+    pub name: String,
+    pub entries: Vec<SurpriseEntry>,
+    pub claims_per_window: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeaturedItem {
+    pub item: ItemId,
+    pub weight: u32,
+}
+
+/// When windows start and how long they last. Everything rolls over at the same time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RotationSchedule {
+    /// Unix seconds the first window opened
+    pub start: i64,
+    pub window_seconds: u32,
+}
+
+impl Default for RotationSchedule {
+    fn default() -> Self {
+        Self {
+            start: 0,
+            window_seconds: 24 * 60 * 60,
+        }
+    }
+}
+
+/// What `data/surprises.json` looks like
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SurpriseData {
+    /// Leave it out to get a different rotation every time the server starts
+    pub seed: Option<u64>,
+    pub schedule: RotationSchedule,
+    pub surprises: Vec<Surprise>,
+    pub featured: Vec<FeaturedItem>,
+    /// How many featured items are out each window
+    pub featured_slots: usize,
+}
+
+/// `GetPlayerDynamicSurprises`, one of these for every surprise table
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlayerSurprise {
+    pub surprise: SurpriseId,
+    /// What the next claim gives, nothing once the window's claims are used up
+    pub reward: Option<Reward>,
+    pub claimed: u32,
+    pub remaining: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct Surprises {
+    seed: u64,
+    schedule: RotationSchedule,
+    surprises: BTreeMap<SurpriseId, Surprise>,
+    featured: Vec<FeaturedItem>,
+    featured_slots: usize,
+    /// Claims per player and table, only for the window they were made in
+    claims: HashMap<(PlayerId, SurpriseId), (u64, u32)>,
+}
+
+impl Surprises {
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self::from_data(data::load(path)?))
+    }
+
+    pub fn from_data(data: SurpriseData) -> Self {
+        Self {
+            seed: data.seed.unwrap_or_else(SeededRng::random_seed),
+            schedule: data.schedule,
+            surprises: data
+                .surprises
+                .into_iter()
+                .map(|surprise| (surprise.id, surprise))
+                .collect(),
+            featured: data.featured,
+            featured_slots: data.featured_slots,
+            claims: HashMap::new(),
+        }
+    }
+
+    /// Which rotation window `now` falls in
+    pub fn window(&self, now: DateTime<Utc>) -> u64 {
+        let since = (now.timestamp() - self.schedule.start).max(0);
+
+        (since / self.schedule.window_seconds.max(1) as i64) as u64
+    }
+
+    /// When the current window closes
+    pub fn next_rotation(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let next = self.schedule.start
+            + (self.window(now) as i64 + 1) * self.schedule.window_seconds.max(1) as i64;
+
+        DateTime::from_timestamp(next, 0).unwrap_or(now)
+    }
+
+    /// `GetAllFeaturedItems`, everything that can come up and how likely it is
+    pub fn all_featured_items(&self) -> &[FeaturedItem] {
+        &self.featured
+    }
+
+    /// `GetFeaturedItems`, this window's pick. Every server with the same seed picks the same
+    pub fn featured_items(&self, now: DateTime<Utc>) -> Vec<ItemId> {
+        let mut rng = SeededRng::from_parts(&[self.seed, self.window(now)]);
+        let mut pool = self.featured.clone();
+        let mut picked = Vec::new();
+
+        while picked.len() < self.featured_slots {
+            let weights: Vec<u32> = pool.iter().map(|featured| featured.weight).collect();
+            let Some(index) = rng.pick_weighted(&weights) else {
+                break;
+            };
+
+            picked.push(pool.remove(index).item);
+        }
+
+        picked
+    }
+
+    /// `GetDynamicSurprise`, what the player's next claim from the table gives this window
+    pub fn dynamic_surprise(
+        &self,
+        player: PlayerId,
+        surprise: SurpriseId,
+        now: DateTime<Utc>,
+    ) -> Result<PlayerSurprise, AppCode> {
+        let table = self.surprises.get(&surprise).ok_or(AppCode::NotFound)?;
+        let window = self.window(now);
+        let claimed = self.claimed(player, surprise, window);
+        let remaining = table.claims_per_window.saturating_sub(claimed);
+
+        let reward = if remaining > 0 {
+            self.roll(table, player, window, claimed).cloned()
+        } else {
+            None
+        };
+
+        Ok(PlayerSurprise {
+            surprise,
+            reward,
+            claimed,
+            remaining,
+        })
+    }
+
+    /// `GetPlayerDynamicSurprises`
+    pub fn player_dynamic_surprises(
+        &self,
+        player: PlayerId,
+        now: DateTime<Utc>,
+    ) -> Vec<PlayerSurprise> {
+        self.surprises
+            .keys()
+            .filter_map(|&surprise| self.dynamic_surprise(player, surprise, now).ok())
+            .collect()
+    }
+
+    /// `ClaimDynamicSurprise`, hands out what `GetDynamicSurprise` showed
+    pub fn claim_dynamic_surprise(
+        &mut self,
+        player: PlayerId,
+        surprise: SurpriseId,
+        inventory: &mut Inventory,
+        wallets: &mut Wallets,
+        now: DateTime<Utc>,
+    ) -> Result<Reward, AppCode> {
+        let offered = self.dynamic_surprise(player, surprise, now)?;
+
+        if offered.remaining == 0 {
+            return Err(AppCode::InvalidCooldown);
+        }
+
+        let reward = offered.reward.ok_or(AppCode::NotFound)?;
+        reward.grant(player, inventory, wallets);
+
+        self.claims
+            .insert((player, surprise), (self.window(now), offered.claimed + 1));
+
+        Ok(reward)
+    }
+
+    fn claimed(&self, player: PlayerId, surprise: SurpriseId, window: u64) -> u32 {
+        self.claims
+            .get(&(player, surprise))
+            .filter(|(claimed_in, _)| *claimed_in == window)
+            .map_or(0, |&(_, claimed)| claimed)
+    }
+
+    /// Each claim gets its own roll, the same one however many times it's looked at
+    fn roll<'a>(
+        &self,
+        table: &'a Surprise,
+        player: PlayerId,
+        window: u64,
+        claim: u32,
+    ) -> Option<&'a Reward> {
+        let mut rng =
+            SeededRng::from_parts(&[self.seed, window, player, table.id as u64, claim as u64]);
+        let weights: Vec<u32> = table.entries.iter().map(|entry| entry.weight).collect();
+
+        rng.pick_weighted(&weights)
+            .map(|index| &table.entries[index].reward)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::currency::{Amount, COINS};
+
+    fn coins(amount: u64) -> Reward {
+        Reward {
+            items: Vec::new(),
+            currencies: vec![Amount {
+                currency: COINS,
+                amount,
+            }],
+        }
+    }
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(seconds, 0).unwrap()
+    }
+
+    fn surprises(seed: u64) -> Surprises {
+        Surprises::from_data(SurpriseData {
+            seed: Some(seed),
+            schedule: RotationSchedule {
+                start: 1_000,
+                window_seconds: 100,
+            },
+            surprises: vec![Surprise {
+                id: 1,
+                name: "Mystery Box".to_string(),
+                entries: vec![
+                    SurpriseEntry {
+                        weight: 70,
+                        reward: coins(5),
+                    },
+                    SurpriseEntry {
+                        weight: 25,
+                        reward: coins(50),
+                    },
+                    SurpriseEntry {
+                        weight: 5,
+                        reward: coins(500),
+                    },
+                ],
+                claims_per_window: 2,
+            }],
+            featured: (1..=10)
+                .map(|item| FeaturedItem { item, weight: 1 })
+                .collect(),
+            featured_slots: 3,
+        })
+    }
+
+    #[test]
+    fn same_seed_same_rolls() {
+        let first = surprises(42);
+        let second = surprises(42);
+
+        for window in 0..20 {
+            let now = at(1_000 + window * 100);
+
+            assert_eq!(first.featured_items(now), second.featured_items(now));
+            assert_eq!(
+                first.player_dynamic_surprises(7, now),
+                second.player_dynamic_surprises(7, now)
+            );
+        }
+    }
+
+    #[test]
+    fn featured_items_rotate_with_the_window() {
+        let surprises = surprises(42);
+
+        let first = surprises.featured_items(at(1_000));
+        assert_eq!(first.len(), 3);
+        assert_eq!(first, surprises.featured_items(at(1_099)));
+
+        let mut sorted = first.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(sorted.len(), 3);
+
+        let changed =
+            (1..10).any(|window| surprises.featured_items(at(1_000 + window * 100)) != first);
+        assert!(changed);
+        assert_eq!(surprises.next_rotation(at(1_050)), at(1_100));
+    }
+
+    #[test]
+    fn claims_are_limited_per_window_and_match_what_was_shown() {
+        let mut surprises = surprises(42);
+        let mut inventory = Inventory::default();
+        let mut wallets = Wallets::default();
+        let now = at(1_000);
+
+        let mut total = 0;
+        for _ in 0..2 {
+            let shown = surprises
+                .dynamic_surprise(7, 1, now)
+                .unwrap()
+                .reward
+                .unwrap();
+            let claimed = surprises
+                .claim_dynamic_surprise(7, 1, &mut inventory, &mut wallets, now)
+                .unwrap();
+
+            assert_eq!(shown, claimed);
+            total += claimed.currencies[0].amount;
+        }
+
+        assert_eq!(wallets.balance(7, COINS), total);
+        assert_eq!(
+            surprises.claim_dynamic_surprise(7, 1, &mut inventory, &mut wallets, now),
+            Err(AppCode::InvalidCooldown)
+        );
+
+        let next = at(1_100);
+        assert_eq!(surprises.dynamic_surprise(7, 1, next).unwrap().remaining, 2);
+        assert!(surprises
+            .claim_dynamic_surprise(7, 1, &mut inventory, &mut wallets, next)
+            .is_ok());
+    }
+}
@@ -0,0 +1,877 @@
+use crate::data;
+use crate::filter::WordFilter;
+use crate::inventory::{Inventory, InventoryItem, InventoryItemId};
+use crate::message::AppCode;
+use crate::session::{ClientEvent, Outbox, PlayerId};
+use crate::shard::ServerId;
+use crate::world::{LocationId, ObjectId, Position, World};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+
+pub type TemplateId = u32;
+pub type RoleId = u32;
+pub type PlotId = u32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum VillagePermission {
+    Invite,
+    Build,
+    ManageRoles,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VillageRole {
+    pub id: RoleId,
+    pub name: String,
+    #[serde(default)]
+    pub permissions: BTreeSet<VillagePermission>,
+}
+
+/// A CMS village template. Locked ones need the player to be at least `level`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VillageTemplate {
+    pub id: TemplateId,
+    pub name: String,
+    pub plots: u32,
+    #[serde(default)]
+    pub level: u32,
+}
+
+/// What `data/villages.json` looks like
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VillageData {
+    pub templates: Vec<VillageTemplate>,
+    pub roles: Vec<VillageRole>,
+    /// Template for villages the server opens by itself
+    pub public_template: TemplateId,
+    pub owner_role: RoleId,
+    pub resident_role: RoleId,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InviteStatus {
+    Sent,
+    Accepted,
+    Rejected,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VillageItem {
+    pub object: ObjectId,
+    pub placed_by: PlayerId,
+    pub position: Position,
+    /// Held here while it's out in the village
+    pub item: InventoryItem,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Village {
+    pub id: LocationId,
+    pub name: String,
+    pub template: TemplateId,
+    pub owner: Option<PlayerId>,
+    pub private: bool,
+    pub residents: BTreeMap<PlayerId, RoleId>,
+    pub plots: BTreeMap<PlotId, PlayerId>,
+    /// Invitee to whoever invited them
+    pub invites: BTreeMap<PlayerId, PlayerId>,
+    pub items: BTreeMap<ObjectId, VillageItem>,
+}
+
+/// `VillageInfo`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VillageSummary {
+    pub id: LocationId,
+    pub name: String,
+    pub template: TemplateId,
+    pub owner: Option<PlayerId>,
+    pub private: bool,
+    pub residents: usize,
+    pub free_plots: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Allocation {
+    pub village: LocationId,
+    pub plot: PlotId,
+    /// A brand new village had to be opened, the shard needs to hear about it
+    pub created: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Villages {
+    templates: BTreeMap<TemplateId, VillageTemplate>,
+    roles: BTreeMap<RoleId, VillageRole>,
+    public_template: TemplateId,
+    owner_role: RoleId,
+    resident_role: RoleId,
+    villages: BTreeMap<LocationId, Village>,
+    homes: HashMap<PlayerId, LocationId>,
+    next_id: LocationId,
+    max_villages: usize,
+}
+
+impl Villages {
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self::from_data(data::load(path)?))
+    }
+
+    pub fn from_data(data: VillageData) -> Self {
+        Self {
+            templates: data
+                .templates
+                .into_iter()
+                .map(|template| (template.id, template))
+                .collect(),
+            roles: data.roles.into_iter().map(|role| (role.id, role)).collect(),
+            public_template: data.public_template,
+            owner_role: data.owner_role,
+            resident_role: data.resident_role,
+            ..Default::default()
+        }
+    }
+
+    /// New villages get ids from a range only this server hands out
+    pub fn set_server(&mut self, server: ServerId, max_villages: usize) {
+        self.next_id = (server as LocationId) << 32;
+        self.max_villages = max_villages;
+    }
+
+    /// Keep track of a village that's already a sync location, like the ones from the config
+    pub fn open(&mut self, village: LocationId) {
+        let template = self.public_template;
+
+        self.villages.entry(village).or_insert_with(|| Village {
+            id: village,
+            name: format!("Village {}", village),
+            template,
+            owner: None,
+            private: false,
+            residents: BTreeMap::new(),
+            plots: BTreeMap::new(),
+            invites: BTreeMap::new(),
+            items: BTreeMap::new(),
+        });
+    }
+
+    /// Takes a village out along with everyone's home in it, for handing it to another server
+    pub fn close(&mut self, village: LocationId) -> Option<Village> {
+        let state = self.villages.remove(&village)?;
+        self.homes.retain(|_, home| *home != village);
+
+        Some(state)
+    }
+
+    /// A village another server handed us, residents and all
+    pub fn adopt(&mut self, village: Village) {
+        for &player in village.residents.keys() {
+            self.homes.insert(player, village.id);
+        }
+
+        self.villages.insert(village.id, village);
+    }
+
+    pub fn village(&self, village: LocationId) -> Result<&Village, AppCode> {
+        self.villages.get(&village).ok_or(AppCode::NotFound)
+    }
+
+    pub fn home_village(&self, player: PlayerId) -> Result<LocationId, AppCode> {
+        self.homes
+            .get(&player)
+            .copied()
+            .ok_or(AppCode::PlayerHasNoHomeVillage)
+    }
+
+    /// `GetAllVillages`
+    pub fn all_villages(&self) -> Vec<VillageSummary> {
+        self.villages
+            .keys()
+            .filter_map(|&village| self.village_info(village).ok())
+            .collect()
+    }
+
+    /// `VillageInfo`
+    pub fn village_info(&self, village: LocationId) -> Result<VillageSummary, AppCode> {
+        let state = self.village(village)?;
+
+        Ok(VillageSummary {
+            id: state.id,
+            name: state.name.clone(),
+            template: state.template,
+            owner: state.owner,
+            private: state.private,
+            residents: state.residents.len(),
+            free_plots: self.free_plots(state),
+        })
+    }
+
+    /// `SearchVillages`, public villages with the text somewhere in their name
+    pub fn search_villages(&self, text: &str) -> Vec<VillageSummary> {
+        let text = text.to_lowercase();
+
+        self.villages
+            .values()
+            .filter(|village| !village.private && village.name.to_lowercase().contains(&text))
+            .filter_map(|village| self.village_info(village.id).ok())
+            .collect()
+    }
+
+    /// `ListVillagePlots`
+    pub fn village_plots(
+        &self,
+        village: LocationId,
+    ) -> Result<Vec<(PlotId, Option<PlayerId>)>, AppCode> {
+        let state = self.village(village)?;
+        let plots = self
+            .templates
+            .get(&state.template)
+            .map_or(0, |template| template.plots);
+
+        Ok((0..plots)
+            .map(|plot| (plot, state.plots.get(&plot).copied()))
+            .collect())
+    }
+
+    /// `ListVillageUsers`, residents and whether they're in the village right now
+    pub fn village_users(
+        &self,
+        village: LocationId,
+        world: &World,
+    ) -> Result<Vec<(PlayerId, bool)>, AppCode> {
+        let state = self.village(village)?;
+        let here = world.location(village);
+
+        Ok(state
+            .residents
+            .keys()
+            .map(|&player| {
+                (
+                    player,
+                    here.is_some_and(|location| location.players.contains_key(&player)),
+                )
+            })
+            .collect())
+    }
+
+    /// `GetCmsVillageRoles`
+    pub fn cms_village_roles(&self) -> impl Iterator<Item = &VillageRole> {
+        self.roles.values()
+    }
+
+    /// `GetVillageRoles`, everyone living in the village and their role
+    pub fn village_roles(
+        &self,
+        village: LocationId,
+    ) -> Result<&BTreeMap<PlayerId, RoleId>, AppCode> {
+        Ok(&self.village(village)?.residents)
+    }
+
+    /// `AssignVillageRole`
+    pub fn assign_village_role(
+        &mut self,
+        by: PlayerId,
+        village: LocationId,
+        player: PlayerId,
+        role: RoleId,
+    ) -> Result<(), AppCode> {
+        self.check_permission(by, village, VillagePermission::ManageRoles)?;
+
+        if !self.roles.contains_key(&role) {
+            return Err(AppCode::NotFound);
+        }
+
+        let state = self.villages.get_mut(&village).unwrap();
+        if state.owner == Some(player) {
+            return Err(AppCode::InsufficientPermission);
+        }
+
+        *state.residents.get_mut(&player).ok_or(AppCode::NotFound)? = role;
+        Ok(())
+    }
+
+    /// `GetCmsVillageTemplatesLocked`
+    pub fn templates_locked(&self, level: u32) -> Vec<&VillageTemplate> {
+        self.templates
+            .values()
+            .filter(|template| template.level > level)
+            .collect()
+    }
+
+    /// `GetCmsVillageTemplatesUnlocked`
+    pub fn templates_unlocked(&self, level: u32) -> Vec<&VillageTemplate> {
+        self.templates
+            .values()
+            .filter(|template| template.level <= level)
+            .collect()
+    }
+
+    /// `AllocatePlayerVillage`, finds the player a plot in a public village,
+    /// opening a new village when they're all full
+    pub fn allocate_player_village(
+        &mut self,
+        player: PlayerId,
+        world: &mut World,
+    ) -> Result<Allocation, AppCode> {
+        if let Some(&village) = self.homes.get(&player) {
+            let plot = self.villages[&village]
+                .plots
+                .iter()
+                .find(|(_, &holder)| holder == player)
+                .map(|(&plot, _)| plot)
+                .unwrap_or_default();
+
+            return Ok(Allocation {
+                village,
+                plot,
+                created: false,
+            });
+        }
+
+        let open = self
+            .villages
+            .values()
+            .find(|village| !village.private && self.free_plots(village) > 0)
+            .map(|village| village.id);
+
+        let (village, created) = match open {
+            Some(village) => (village, false),
+            None => {
+                let name = format!("Village {}", self.villages.len() + 1);
+                (
+                    self.create(None, &name, self.public_template, false, world)?,
+                    true,
+                )
+            }
+        };
+
+        let plot = self.settle(player, village, self.resident_role)?;
+
+        Ok(Allocation {
+            village,
+            plot,
+            created,
+        })
+    }
+
+    /// `MoveVillage`, private villages need an invite
+    pub fn move_village(
+        &mut self,
+        player: PlayerId,
+        village: LocationId,
+    ) -> Result<PlotId, AppCode> {
+        let from = self.home_village(player)?;
+        let state = self.village(village)?;
+
+        if from == village {
+            return Err(AppCode::DupRequest);
+        }
+
+        if state.private && !state.invites.contains_key(&player) {
+            return Err(AppCode::InsufficientPermission);
+        }
+
+        // Checked before leaving, or they'd end up with no home at all
+        if self.free_plots(state) == 0 {
+            return Err(AppCode::NotEnoughSpace);
+        }
+
+        if self.villages[&from].owner == Some(player) {
+            // Owners can't walk out on their own village
+            return Err(AppCode::State);
+        }
+
+        let role = self.resident_role;
+        self.leave(player);
+        let plot = self.settle(player, village, role)?;
+
+        self.villages
+            .get_mut(&village)
+            .unwrap()
+            .invites
+            .remove(&player);
+
+        Ok(plot)
+    }
+
+    /// `CreatePrivateVillage`, every player gets one. The owner moves in straight away
+    pub fn create_private_village(
+        &mut self,
+        player: PlayerId,
+        name: &str,
+        template: TemplateId,
+        level: u32,
+        filter: &WordFilter,
+        world: &mut World,
+    ) -> Result<LocationId, AppCode> {
+        filter.check_village_name(name)?;
+
+        let definition = self.templates.get(&template).ok_or(AppCode::NotFound)?;
+        if definition.level > level {
+            return Err(AppCode::InsufficientPermission);
+        }
+
+        // No plot for the owner to move into
+        if definition.plots == 0 {
+            return Err(AppCode::CreateVillageFailed);
+        }
+
+        if self
+            .villages
+            .values()
+            .any(|village| village.private && village.owner == Some(player))
+        {
+            return Err(AppCode::PendingVillageExists);
+        }
+
+        let village = self.create(Some(player), name, template, true, world)?;
+
+        // Everything that could stop the owner moving in was checked above, a new
+        // village with plots always has room for them
+        let role = self.owner_role;
+        self.leave(player);
+        self.settle(player, village, role)?;
+
+        Ok(village)
+    }
+
+    /// `SendVillageInvite`
+    pub fn send_village_invite(
+        &mut self,
+        from: PlayerId,
+        village: LocationId,
+        to: PlayerId,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        self.check_permission(from, village, VillagePermission::Invite)?;
+
+        let state = self.villages.get_mut(&village).unwrap();
+        if state.residents.contains_key(&to) || state.invites.contains_key(&to) {
+            return Err(AppCode::DupRequest);
+        }
+
+        state.invites.insert(to, from);
+        outbox.push(
+            to,
+            ClientEvent::VillageInviteStatusNotify {
+                village,
+                player: from,
+                status: InviteStatus::Sent,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// `AcceptVillageInvite`, moves the player's home there
+    pub fn accept_village_invite(
+        &mut self,
+        player: PlayerId,
+        village: LocationId,
+        outbox: &mut Outbox,
+    ) -> Result<PlotId, AppCode> {
+        let from = *self
+            .village(village)?
+            .invites
+            .get(&player)
+            .ok_or(AppCode::NotFound)?;
+
+        if self.free_plots(&self.villages[&village]) == 0 {
+            return Err(AppCode::NotEnoughSpace);
+        }
+
+        if self
+            .homes
+            .get(&player)
+            .is_some_and(|home| self.villages[home].owner == Some(player))
+        {
+            return Err(AppCode::State);
+        }
+
+        let role = self.resident_role;
+        self.leave(player);
+        let plot = self.settle(player, village, role)?;
+
+        self.villages
+            .get_mut(&village)
+            .unwrap()
+            .invites
+            .remove(&player);
+        outbox.push(
+            from,
+            ClientEvent::VillageInviteStatusNotify {
+                village,
+                player,
+                status: InviteStatus::Accepted,
+            },
+        );
+
+        Ok(plot)
+    }
+
+    /// `RejectVillageInvite`
+    pub fn reject_village_invite(
+        &mut self,
+        player: PlayerId,
+        village: LocationId,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        let from = self
+            .villages
+            .get_mut(&village)
+            .ok_or(AppCode::NotFound)?
+            .invites
+            .remove(&player)
+            .ok_or(AppCode::NotFound)?;
+
+        outbox.push(
+            from,
+            ClientEvent::VillageInviteStatusNotify {
+                village,
+                player,
+                status: InviteStatus::Rejected,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// `PlaceVillageItem`, the item comes out of the inventory while it's out
+    #[allow(clippy::too_many_arguments)]
+    pub fn place_village_item(
+        &mut self,
+        player: PlayerId,
+        village: LocationId,
+        item: InventoryItemId,
+        position: Position,
+        inventory: &mut Inventory,
+        world: &mut World,
+        outbox: &mut Outbox,
+    ) -> Result<ObjectId, AppCode> {
+        self.check_permission(player, village, VillagePermission::Build)?;
+
+        let taken = inventory.remove(player, item)?;
+        let properties = BTreeMap::from([("item".to_string(), item.to_string())]);
+
+        let object = match world.spawn_object(
+            village,
+            Some(player),
+            taken.item,
+            position,
+            properties,
+            outbox,
+        ) {
+            Ok(object) => object,
+            Err(e) => {
+                inventory.restore(taken, player);
+                return Err(e);
+            }
+        };
+
+        self.villages.get_mut(&village).unwrap().items.insert(
+            object,
+            VillageItem {
+                object,
+                placed_by: player,
+                position,
+                item: taken,
+            },
+        );
+
+        Ok(object)
+    }
+
+    /// `RemoveVillageItem`, whoever placed it gets it back
+    pub fn remove_village_item(
+        &mut self,
+        player: PlayerId,
+        village: LocationId,
+        object: ObjectId,
+        inventory: &mut Inventory,
+        world: &mut World,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        let placed_by = self
+            .village(village)?
+            .items
+            .get(&object)
+            .ok_or(AppCode::NotFound)?
+            .placed_by;
+
+        if placed_by != player {
+            self.check_permission(player, village, VillagePermission::Build)?;
+        }
+
+        let removed = self
+            .villages
+            .get_mut(&village)
+            .unwrap()
+            .items
+            .remove(&object)
+            .unwrap();
+
+        let _ = world.despawn_object(village, object, outbox);
+        inventory.restore(removed.item, removed.placed_by);
+
+        Ok(())
+    }
+
+    /// `ListVillageItems`
+    pub fn village_items(&self, village: LocationId) -> Result<Vec<&VillageItem>, AppCode> {
+        Ok(self.village(village)?.items.values().collect())
+    }
+
+    fn create(
+        &mut self,
+        owner: Option<PlayerId>,
+        name: &str,
+        template: TemplateId,
+        private: bool,
+        world: &mut World,
+    ) -> Result<LocationId, AppCode> {
+        if !self.templates.contains_key(&template) || self.villages.len() >= self.max_villages {
+            return Err(AppCode::CreateVillageFailed);
+        }
+
+        self.next_id += 1;
+        while self.villages.contains_key(&self.next_id) {
+            self.next_id += 1;
+        }
+
+        self.villages.insert(
+            self.next_id,
+            Village {
+                id: self.next_id,
+                name: name.to_string(),
+                template,
+                owner,
+                private,
+                residents: BTreeMap::new(),
+                plots: BTreeMap::new(),
+                invites: BTreeMap::new(),
+                items: BTreeMap::new(),
+            },
+        );
+        world.add_location(self.next_id);
+
+        Ok(self.next_id)
+    }
+
+    fn settle(
+        &mut self,
+        player: PlayerId,
+        village: LocationId,
+        role: RoleId,
+    ) -> Result<PlotId, AppCode> {
+        let plots = self.village_plots(village)?;
+        let plot = plots
+            .into_iter()
+            .find(|(_, holder)| holder.is_none())
+            .map(|(plot, _)| plot)
+            .ok_or(AppCode::NotEnoughSpace)?;
+
+        let state = self.villages.get_mut(&village).unwrap();
+        state.plots.insert(plot, player);
+        state.residents.insert(player, role);
+        self.homes.insert(player, village);
+
+        Ok(plot)
+    }
+
+    fn leave(&mut self, player: PlayerId) {
+        let Some(village) = self.homes.remove(&player) else {
+            return;
+        };
+
+        if let Some(state) = self.villages.get_mut(&village) {
+            state.residents.remove(&player);
+            state.plots.retain(|_, holder| *holder != player);
+        }
+    }
+
+    fn free_plots(&self, village: &Village) -> u32 {
+        let plots = self
+            .templates
+            .get(&village.template)
+            .map_or(0, |template| template.plots);
+
+        plots.saturating_sub(village.plots.len() as u32)
+    }
+
+    fn check_permission(
+        &self,
+        player: PlayerId,
+        village: LocationId,
+        permission: VillagePermission,
+    ) -> Result<(), AppCode> {
+        let state = self.village(village)?;
+
+        if state.owner == Some(player) {
+            return Ok(());
+        }
+
+        let allowed = state
+            .residents
+            .get(&player)
+            .and_then(|role| self.roles.get(role))
+            .is_some_and(|role| role.permissions.contains(&permission));
+
+        if !allowed {
+            return Err(AppCode::InsufficientPermission);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBLIC: TemplateId = 1;
+    const EMPTY: TemplateId = 2;
+    const OWNER: RoleId = 1;
+    const RESIDENT: RoleId = 2;
+
+    fn villages() -> (Villages, World) {
+        let template = |id, plots| VillageTemplate {
+            id,
+            name: format!("template {id}"),
+            plots,
+            level: 0,
+        };
+
+        let mut villages = Villages::from_data(VillageData {
+            templates: vec![template(PUBLIC, 2), template(EMPTY, 0)],
+            roles: vec![
+                VillageRole {
+                    id: OWNER,
+                    name: "Owner".to_string(),
+                    permissions: BTreeSet::from([
+                        VillagePermission::Invite,
+                        VillagePermission::Build,
+                        VillagePermission::ManageRoles,
+                    ]),
+                },
+                VillageRole {
+                    id: RESIDENT,
+                    name: "Resident".to_string(),
+                    permissions: BTreeSet::new(),
+                },
+            ],
+            public_template: PUBLIC,
+            owner_role: OWNER,
+            resident_role: RESIDENT,
+        });
+        villages.set_server(1, 10);
+
+        (villages, World::default())
+    }
+
+    #[test]
+    fn villages_with_no_room_for_the_owner_are_never_made() {
+        let (mut villages, mut world) = villages();
+        let filter = WordFilter::default();
+        let home = villages
+            .allocate_player_village(1, &mut world)
+            .unwrap()
+            .village;
+
+        assert_eq!(
+            villages.create_private_village(1, "Hideaway", EMPTY, 1, &filter, &mut world),
+            Err(AppCode::CreateVillageFailed)
+        );
+        assert_eq!(villages.all_villages().len(), 1);
+        assert_eq!(villages.home_village(1), Ok(home));
+
+        let private = villages
+            .create_private_village(1, "Hideaway", PUBLIC, 1, &filter, &mut world)
+            .unwrap();
+        assert_eq!(villages.home_village(1), Ok(private));
+        assert_eq!(villages.village_info(home).unwrap().free_plots, 2);
+        assert!(world.location(private).is_some());
+    }
+
+    #[test]
+    fn full_villages_open_a_new_one() {
+        let (mut villages, mut world) = villages();
+
+        let first = villages.allocate_player_village(1, &mut world).unwrap();
+        villages.allocate_player_village(2, &mut world).unwrap();
+        let third = villages.allocate_player_village(3, &mut world).unwrap();
+
+        assert!(first.created);
+        assert!(third.created);
+        assert_ne!(first.village, third.village);
+        assert_eq!(
+            villages.allocate_player_village(1, &mut world).unwrap(),
+            Allocation {
+                created: false,
+                ..first
+            }
+        );
+    }
+
+    #[test]
+    fn only_invited_players_move_into_private_villages() {
+        let (mut villages, mut world) = villages();
+        let filter = WordFilter::default();
+        let mut outbox = Outbox::default();
+
+        villages.allocate_player_village(2, &mut world).unwrap();
+        let private = villages
+            .create_private_village(1, "Hideaway", PUBLIC, 1, &filter, &mut world)
+            .unwrap();
+
+        assert_eq!(
+            villages.move_village(2, private),
+            Err(AppCode::InsufficientPermission)
+        );
+        assert_eq!(
+            villages.send_village_invite(2, private, 3, &mut outbox),
+            Err(AppCode::InsufficientPermission)
+        );
+
+        villages
+            .send_village_invite(1, private, 2, &mut outbox)
+            .unwrap();
+        villages
+            .accept_village_invite(2, private, &mut outbox)
+            .unwrap();
+        assert_eq!(villages.home_village(2), Ok(private));
+        assert_eq!(villages.village_roles(private).unwrap()[&2], RESIDENT);
+    }
+
+    #[test]
+    fn moving_into_a_full_village_keeps_the_old_home() {
+        let (mut villages, mut world) = villages();
+        let filter = WordFilter::default();
+        let mut outbox = Outbox::default();
+
+        let full = villages
+            .allocate_player_village(1, &mut world)
+            .unwrap()
+            .village;
+        villages.allocate_player_village(2, &mut world).unwrap();
+        let home = villages
+            .allocate_player_village(3, &mut world)
+            .unwrap()
+            .village;
+
+        assert_eq!(villages.move_village(3, full), Err(AppCode::NotEnoughSpace));
+        assert_eq!(villages.home_village(3), Ok(home));
+        assert!(villages.village(home).unwrap().residents.contains_key(&3));
+
+        // An invite gets used up by moving in on it
+        let private = villages
+            .create_private_village(4, "Hideaway", PUBLIC, 1, &filter, &mut world)
+            .unwrap();
+        villages
+            .send_village_invite(4, private, 3, &mut outbox)
+            .unwrap();
+        villages.move_village(3, private).unwrap();
+        assert_eq!(villages.home_village(3), Ok(private));
+        assert!(villages.village(private).unwrap().invites.is_empty());
+    }
+}
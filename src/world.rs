@@ -0,0 +1,764 @@
+use crate::filter::{ChatMode, WordFilter};
+use crate::interest::{Change, Entity, Interest, Sight};
+use crate::message::{AppCode, SyncMessage};
+use crate::session::{ClientEvent, Outbox, PlayerId};
+use crate::shard::ServerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+pub type LocationId = u64;
+pub type ObjectId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Position {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub heading: f32,
+}
+
+impl Position {
+    /// Clients can send anything, NaN and infinity included
+    pub fn is_finite(&self) -> bool {
+        [self.x, self.y, self.z, self.heading]
+            .iter()
+            .all(|value| value.is_finite())
+    }
+
+    fn check(&self) -> Result<(), AppCode> {
+        if !self.is_finite() {
+            return Err(AppCode::Input);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WorldObject {
+    pub id: ObjectId,
+    /// What the client should spawn for this object
+    pub asset: u64,
+    pub owner: Option<PlayerId>,
+    pub position: Position,
+    pub properties: BTreeMap<String, String>,
+}
+
+/// The decoded body of a sync server message
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyncRequest {
+    EnterLoc {
+        location: LocationId,
+        position: Position,
+    },
+    ExitLoc,
+    AddObject {
+        asset: u64,
+        position: Position,
+        properties: BTreeMap<String, String>,
+    },
+    MoveObject {
+        object: ObjectId,
+        position: Position,
+    },
+    RemoveObject {
+        object: ObjectId,
+    },
+    ChangeObject {
+        object: ObjectId,
+        properties: BTreeMap<String, String>,
+    },
+    ServerChangeObject {
+        object: ObjectId,
+        properties: BTreeMap<String, String>,
+    },
+    MovePlayer {
+        position: Position,
+    },
+    RemovePlayer {
+        player: PlayerId,
+    },
+    UpdateFilter {
+        radius: f32,
+    },
+    SendNotify {
+        player: PlayerId,
+        text: String,
+    },
+}
+
+impl SyncRequest {
+    pub fn message(&self) -> SyncMessage {
+        match self {
+            SyncRequest::EnterLoc { .. } => SyncMessage::EnterLoc,
+            SyncRequest::ExitLoc => SyncMessage::ExitLoc,
+            SyncRequest::AddObject { .. } => SyncMessage::AddObject,
+            SyncRequest::MoveObject { .. } => SyncMessage::MoveObject,
+            SyncRequest::RemoveObject { .. } => SyncMessage::RemoveObject,
+            SyncRequest::ChangeObject { .. } => SyncMessage::ChangeObject,
+            SyncRequest::ServerChangeObject { .. } => SyncMessage::ServerChangeObject,
+            SyncRequest::MovePlayer { .. } => SyncMessage::MovePlayer,
+            SyncRequest::RemovePlayer { .. } => SyncMessage::RemovePlayer,
+            SyncRequest::UpdateFilter { .. } => SyncMessage::UpdateFilter,
+            SyncRequest::SendNotify { .. } => SyncMessage::SendNotify,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Location {
+    pub objects: HashMap<ObjectId, WorldObject>,
+    pub players: HashMap<PlayerId, Position>,
+    pub interest: Interest,
+}
+
+impl Location {
+    /// Players only get to touch their own objects. NPCs and the like belong to nobody
+    fn owned(&self, player: PlayerId, object: ObjectId) -> Result<&WorldObject, AppCode> {
+        let found = self.objects.get(&object).ok_or(AppCode::NotFound)?;
+
+        if found.owner != Some(player) {
+            return Err(AppCode::ItemNotOwnedBySessionPlayer);
+        }
+
+        Ok(found)
+    }
+
+    fn broadcast(&self, except: Option<PlayerId>, event: ClientEvent, outbox: &mut Outbox) {
+        for &player in self.players.keys() {
+            if Some(player) != except {
+                outbox.push(player, event.clone());
+            }
+        }
+    }
+
+    /// Movement only goes to whoever is close enough to care
+    fn deliver(changes: Vec<Change>, outbox: &mut Outbox) {
+        for Change {
+            viewer,
+            entity,
+            position,
+            sight,
+        } in changes
+        {
+            let event = match (sight, entity) {
+                (Sight::Entered, entity) => ClientEvent::PosRecap { entity, position },
+                (Sight::Visible, Entity::Player(player)) => {
+                    ClientEvent::MovePlayer { player, position }
+                }
+                (Sight::Visible, Entity::Object(object)) => {
+                    ClientEvent::MoveObject { object, position }
+                }
+                (Sight::Left, _) => continue,
+            };
+
+            outbox.push(viewer, event);
+        }
+    }
+}
+
+/// The authoritative copy of everything in every location this server owns
+#[derive(Debug, Default)]
+pub struct World {
+    locations: HashMap<LocationId, Location>,
+    player_locations: HashMap<PlayerId, LocationId>,
+    next_object_id: ObjectId,
+}
+
+impl World {
+    /// Object ids come from a range only this server hands out, so objects
+    /// can move between servers and keep them
+    pub fn set_server(&mut self, server: ServerId) {
+        self.next_object_id = (server as ObjectId) << 32;
+    }
+
+    pub fn add_location(&mut self, location: LocationId) {
+        self.locations.entry(location).or_default();
+    }
+
+    /// Kicks everyone still inside out first
+    pub fn remove_location(&mut self, location: LocationId, outbox: &mut Outbox) {
+        let players: Vec<PlayerId> = match self.locations.get(&location) {
+            Some(location) => location.players.keys().copied().collect(),
+            None => return,
+        };
+
+        for player in players {
+            let _ = self.exit_loc(player, outbox);
+        }
+
+        self.locations.remove(&location);
+    }
+
+    pub fn location(&self, location: LocationId) -> Option<&Location> {
+        self.locations.get(&location)
+    }
+
+    pub fn broadcast(&self, location: LocationId, event: ClientEvent, outbox: &mut Outbox) {
+        if let Some(location) = self.locations.get(&location) {
+            location.broadcast(None, event, outbox);
+        }
+    }
+
+    /// Everyone who is in a location right now
+    pub fn players(&self) -> impl Iterator<Item = PlayerId> + '_ {
+        self.player_locations.keys().copied()
+    }
+
+    pub fn player_location(&self, player: PlayerId) -> Option<LocationId> {
+        self.player_locations.get(&player).copied()
+    }
+
+    pub fn apply(
+        &mut self,
+        player: PlayerId,
+        request: SyncRequest,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        log::debug!("{:?} from {}", request.message(), player);
+
+        match request {
+            SyncRequest::EnterLoc { location, position } => {
+                self.enter_loc(player, location, position, outbox)
+            }
+            SyncRequest::ExitLoc => self.exit_loc(player, outbox),
+            SyncRequest::AddObject {
+                asset,
+                position,
+                properties,
+            } => self
+                .add_object(player, asset, position, properties, outbox)
+                .map(|_| ()),
+            SyncRequest::MoveObject { object, position } => {
+                self.move_object(player, object, position, outbox)
+            }
+            SyncRequest::RemoveObject { object } => self.remove_object(player, object, outbox),
+            SyncRequest::ChangeObject { object, properties } => {
+                self.change_object(player, object, properties, false, outbox)
+            }
+            SyncRequest::ServerChangeObject { object, properties } => {
+                self.change_object(player, object, properties, true, outbox)
+            }
+            SyncRequest::MovePlayer { position } => self.move_player(player, position, outbox),
+            // Clients only get to take themselves out
+            SyncRequest::RemovePlayer { player: target } if target == player => {
+                self.exit_loc(player, outbox)
+            }
+            SyncRequest::RemovePlayer { .. } => Err(AppCode::Perm),
+            SyncRequest::UpdateFilter { radius } => self.update_filter(player, radius, outbox),
+            SyncRequest::SendNotify { player: to, text } => {
+                self.send_notify(player, to, text, outbox)
+            }
+        }
+    }
+
+    pub fn enter_loc(
+        &mut self,
+        player: PlayerId,
+        location_id: LocationId,
+        position: Position,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        position.check()?;
+
+        if !self.locations.contains_key(&location_id) {
+            return Err(AppCode::NotFound);
+        }
+
+        if self.player_locations.contains_key(&player) {
+            self.exit_loc(player, outbox)?;
+        }
+
+        let location = self.locations.get_mut(&location_id).unwrap();
+
+        // Catch the newcomer up on everything already here
+        for (&other, &other_position) in location.players.iter() {
+            outbox.push(
+                player,
+                ClientEvent::AddPlayer {
+                    player: other,
+                    position: other_position,
+                },
+            );
+        }
+
+        for object in location.objects.values() {
+            outbox.push(player, ClientEvent::AddObject(object.clone()));
+        }
+
+        location.broadcast(None, ClientEvent::AddPlayer { player, position }, outbox);
+        location.players.insert(player, position);
+        // AddPlayer already carried everyone's position
+        location.interest.place(Entity::Player(player), position);
+        self.player_locations.insert(player, location_id);
+
+        Ok(())
+    }
+
+    pub fn exit_loc(&mut self, player: PlayerId, outbox: &mut Outbox) -> Result<(), AppCode> {
+        let location = self
+            .player_locations
+            .remove(&player)
+            .and_then(|location| self.locations.get_mut(&location))
+            .ok_or(AppCode::NotFound)?;
+
+        location.players.remove(&player);
+        location.interest.remove(Entity::Player(player));
+        location.broadcast(None, ClientEvent::RemovePlayer { player }, outbox);
+
+        Ok(())
+    }
+
+    pub fn move_player(
+        &mut self,
+        player: PlayerId,
+        position: Position,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        position.check()?;
+        let location = self.current_location_mut(player)?;
+
+        location.players.insert(player, position);
+
+        let changes = location.interest.place(Entity::Player(player), position);
+        Location::deliver(changes, outbox);
+
+        Ok(())
+    }
+
+    pub fn add_object(
+        &mut self,
+        player: PlayerId,
+        asset: u64,
+        position: Position,
+        properties: BTreeMap<String, String>,
+        outbox: &mut Outbox,
+    ) -> Result<ObjectId, AppCode> {
+        let location = self.player_location(player).ok_or(AppCode::NotFound)?;
+
+        self.spawn_object(location, Some(player), asset, position, properties, outbox)
+    }
+
+    /// Put an object into a location without a player asking for it
+    pub fn spawn_object(
+        &mut self,
+        location: LocationId,
+        owner: Option<PlayerId>,
+        asset: u64,
+        position: Position,
+        properties: BTreeMap<String, String>,
+        outbox: &mut Outbox,
+    ) -> Result<ObjectId, AppCode> {
+        position.check()?;
+        let location = self.locations.get_mut(&location).ok_or(AppCode::NotFound)?;
+
+        self.next_object_id += 1;
+        let object = WorldObject {
+            id: self.next_object_id,
+            asset,
+            owner,
+            position,
+            properties,
+        };
+
+        location.broadcast(None, ClientEvent::AddObject(object.clone()), outbox);
+        location.interest.place(Entity::Object(object.id), position);
+        location.objects.insert(object.id, object);
+
+        Ok(self.next_object_id)
+    }
+
+    pub fn move_object(
+        &mut self,
+        player: PlayerId,
+        object: ObjectId,
+        position: Position,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        let location = self.player_location(player).ok_or(AppCode::NotFound)?;
+        self.current_location_mut(player)?.owned(player, object)?;
+
+        self.place_object(location, object, position, outbox)
+    }
+
+    /// Move an object without a player asking for it
+    pub fn place_object(
+        &mut self,
+        location: LocationId,
+        object: ObjectId,
+        position: Position,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        position.check()?;
+        let location = self.locations.get_mut(&location).ok_or(AppCode::NotFound)?;
+        let target = location.objects.get_mut(&object).ok_or(AppCode::NotFound)?;
+
+        target.position = position;
+
+        let changes = location.interest.place(Entity::Object(object), position);
+        Location::deliver(changes, outbox);
+
+        Ok(())
+    }
+
+    pub fn remove_object(
+        &mut self,
+        player: PlayerId,
+        object: ObjectId,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        let location = self.current_location_mut(player)?;
+
+        location.owned(player, object)?;
+        location.objects.remove(&object);
+        location.interest.remove(Entity::Object(object));
+        location.broadcast(None, ClientEvent::RemoveObject { object }, outbox);
+
+        Ok(())
+    }
+
+    /// Puts objects from another server back exactly as they were, ids and all
+    pub fn restore_objects(
+        &mut self,
+        location: LocationId,
+        objects: Vec<WorldObject>,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        let location = self.locations.get_mut(&location).ok_or(AppCode::NotFound)?;
+        let mut result = Ok(());
+
+        for object in objects {
+            if let Err(e) = object.position.check() {
+                result = Err(e);
+                continue;
+            }
+
+            if location.objects.contains_key(&object.id) {
+                result = Err(AppCode::DupKey);
+                continue;
+            }
+
+            location.broadcast(None, ClientEvent::AddObject(object.clone()), outbox);
+            location
+                .interest
+                .place(Entity::Object(object.id), object.position);
+            location.objects.insert(object.id, object);
+        }
+
+        result
+    }
+
+    pub fn despawn_object(
+        &mut self,
+        location: LocationId,
+        object: ObjectId,
+        outbox: &mut Outbox,
+    ) -> Result<WorldObject, AppCode> {
+        let location = self.locations.get_mut(&location).ok_or(AppCode::NotFound)?;
+
+        let removed = location.objects.remove(&object).ok_or(AppCode::NotFound)?;
+        location.interest.remove(Entity::Object(object));
+        location.broadcast(None, ClientEvent::RemoveObject { object }, outbox);
+
+        Ok(removed)
+    }
+
+    /// A server change is one the sender's client didn't predict, so they hear about it too
+    pub fn change_object(
+        &mut self,
+        player: PlayerId,
+        object: ObjectId,
+        properties: BTreeMap<String, String>,
+        from_server: bool,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        let location = self.current_location_mut(player)?;
+        location.owned(player, object)?;
+
+        let target = location.objects.get_mut(&object).ok_or(AppCode::NotFound)?;
+        target.properties.extend(properties);
+
+        let (except, event) = if from_server {
+            (None, ClientEvent::ServerChangeObject(target.clone()))
+        } else {
+            (Some(player), ClientEvent::ChangeObject(target.clone()))
+        };
+
+        location.broadcast(except, event, outbox);
+
+        Ok(())
+    }
+
+    /// Change an object without a player asking for it
+    pub fn update_object(
+        &mut self,
+        location: LocationId,
+        object: ObjectId,
+        properties: BTreeMap<String, String>,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        let location = self.locations.get_mut(&location).ok_or(AppCode::NotFound)?;
+        let target = location.objects.get_mut(&object).ok_or(AppCode::NotFound)?;
+
+        target.properties.extend(properties);
+
+        let event = ClientEvent::ServerChangeObject(target.clone());
+        location.broadcast(None, event, outbox);
+
+        Ok(())
+    }
+
+    /// Something like a plant growing. The state is kept on the object for anyone who turns up later
+    pub fn change_object_state(
+        &mut self,
+        location: LocationId,
+        object: ObjectId,
+        state: u8,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        let location = self.locations.get_mut(&location).ok_or(AppCode::NotFound)?;
+        let target = location.objects.get_mut(&object).ok_or(AppCode::NotFound)?;
+
+        target
+            .properties
+            .insert("state".to_string(), state.to_string());
+        location.broadcast(
+            None,
+            ClientEvent::ChangeObjectState { object, state },
+            outbox,
+        );
+
+        Ok(())
+    }
+
+    /// Tell the player and everyone who can see them that their avatar looks different.
+    /// Avatars don't have an object of their own so they go out under the player's id
+    pub fn change_avatar(
+        &self,
+        player: PlayerId,
+        properties: BTreeMap<String, String>,
+        outbox: &mut Outbox,
+    ) {
+        let Some(location) = self
+            .player_location(player)
+            .and_then(|location| self.locations.get(&location))
+        else {
+            return;
+        };
+
+        let avatar = WorldObject {
+            id: player,
+            asset: 0,
+            owner: Some(player),
+            position: location.players[&player],
+            properties,
+        };
+
+        for &viewer in location.players.keys() {
+            if viewer == player || location.interest.can_see(viewer, Entity::Player(player)) {
+                outbox.push(viewer, ClientEvent::ChangeObject(avatar.clone()));
+            }
+        }
+    }
+
+    pub fn update_filter(
+        &mut self,
+        player: PlayerId,
+        radius: f32,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        if !radius.is_finite() {
+            return Err(AppCode::Input);
+        }
+
+        let location = self.current_location_mut(player)?;
+
+        let changes = location.interest.set_radius(player, radius);
+        Location::deliver(changes, outbox);
+
+        Ok(())
+    }
+
+    pub fn chat(
+        &mut self,
+        player: PlayerId,
+        text: &str,
+        mode: ChatMode,
+        filter: &WordFilter,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        filter.check_chat(text, mode)?;
+
+        let location = self.current_location_mut(player)?;
+        location.broadcast(
+            None,
+            ClientEvent::Chat {
+                player,
+                text: text.to_string(),
+            },
+            outbox,
+        );
+
+        Ok(())
+    }
+
+    /// `SendNotify`, a notice to somebody in the same location
+    pub fn send_notify(
+        &self,
+        from: PlayerId,
+        to: PlayerId,
+        text: String,
+        outbox: &mut Outbox,
+    ) -> Result<(), AppCode> {
+        let location = self.player_location(from).ok_or(AppCode::NotFound)?;
+
+        if self.player_location(to) != Some(location) {
+            return Err(AppCode::NotFound);
+        }
+
+        outbox.push(
+            to,
+            ClientEvent::Notification {
+                from: Some(from),
+                text,
+            },
+        );
+
+        Ok(())
+    }
+
+    fn current_location_mut(&mut self, player: PlayerId) -> Result<&mut Location, AppCode> {
+        self.player_locations
+            .get(&player)
+            .and_then(|location| self.locations.get_mut(location))
+            .ok_or(AppCode::NotFound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn world() -> (World, Outbox) {
+        let mut world = World::default();
+        let mut outbox = Outbox::default();
+
+        world.add_location(1);
+        for player in [1, 2] {
+            world
+                .enter_loc(player, 1, Position::default(), &mut outbox)
+                .unwrap();
+        }
+        outbox.drain_all();
+
+        (world, outbox)
+    }
+
+    #[test]
+    fn everyone_in_the_location_hears_about_changes() {
+        let (mut world, mut outbox) = world();
+
+        let object = world
+            .add_object(1, 5, Position::default(), BTreeMap::new(), &mut outbox)
+            .unwrap();
+
+        for player in [1, 2] {
+            assert!(matches!(
+                outbox.drain(player).as_slice(),
+                [ClientEvent::AddObject(added)] if added.id == object
+            ));
+        }
+
+        world.exit_loc(2, &mut outbox).unwrap();
+        assert_eq!(
+            outbox.drain(1),
+            vec![ClientEvent::RemovePlayer { player: 2 }]
+        );
+        assert_eq!(world.player_location(2), None);
+    }
+
+    #[test]
+    fn clients_can_only_remove_themselves() {
+        let (mut world, mut outbox) = world();
+
+        assert_eq!(
+            world.apply(1, SyncRequest::RemovePlayer { player: 2 }, &mut outbox),
+            Err(AppCode::Perm)
+        );
+        assert_eq!(world.player_location(2), Some(1));
+
+        world
+            .apply(1, SyncRequest::RemovePlayer { player: 1 }, &mut outbox)
+            .unwrap();
+        assert_eq!(world.player_location(1), None);
+    }
+
+    #[test]
+    fn objects_only_change_for_their_owner() {
+        let (mut world, mut outbox) = world();
+
+        let mine = world
+            .add_object(1, 5, Position::default(), BTreeMap::new(), &mut outbox)
+            .unwrap();
+        let npc = world
+            .spawn_object(
+                1,
+                None,
+                6,
+                Position::default(),
+                BTreeMap::new(),
+                &mut outbox,
+            )
+            .unwrap();
+        let moved = Position {
+            x: 3.0,
+            ..Default::default()
+        };
+
+        for object in [mine, npc] {
+            assert_eq!(
+                world.move_object(2, object, moved, &mut outbox),
+                Err(AppCode::ItemNotOwnedBySessionPlayer)
+            );
+            assert_eq!(
+                world.change_object(2, object, BTreeMap::new(), false, &mut outbox),
+                Err(AppCode::ItemNotOwnedBySessionPlayer)
+            );
+            assert_eq!(
+                world.remove_object(2, object, &mut outbox),
+                Err(AppCode::ItemNotOwnedBySessionPlayer)
+            );
+        }
+
+        assert_eq!(
+            world.remove_object(1, npc, &mut outbox),
+            Err(AppCode::ItemNotOwnedBySessionPlayer)
+        );
+        world.move_object(1, mine, moved, &mut outbox).unwrap();
+        world.remove_object(1, mine, &mut outbox).unwrap();
+        assert!(world.location(1).unwrap().objects.contains_key(&npc));
+    }
+
+    #[test]
+    fn nonsense_positions_are_rejected() {
+        let (mut world, mut outbox) = world();
+        let nowhere = Position {
+            x: f32::NAN,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            world.move_player(1, nowhere, &mut outbox),
+            Err(AppCode::Input)
+        );
+        assert_eq!(
+            world.update_filter(1, f32::INFINITY, &mut outbox),
+            Err(AppCode::Input)
+        );
+        assert_eq!(
+            world.add_object(1, 5, nowhere, BTreeMap::new(), &mut outbox),
+            Err(AppCode::Input)
+        );
+    }
+}